@@ -0,0 +1,59 @@
+//! `cargo bench --bench codec`: how fast packets go through the codec, one
+//! at a time and in a flood from many robots.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use dummycloud::codec::{split_packet, UDPCodec};
+
+const TOKEN: &str = "0123456789abcdef";
+const STATUS: &[u8] = br#"{"id":42,"method":"props","params":{"state":8,"battery":100,"clean_area":0,"clean_time":0,"error_code":0,"dnd_enabled":0,"fan_power":102,"in_cleaning":0}}"#;
+const FLOOD: usize = 1000;
+
+fn single(c: &mut Criterion) {
+    let codec = UDPCodec::new(TOKEN);
+    let packet = codec.encode(STATUS, 1234, 100);
+    let mut group = c.benchmark_group("packet");
+    group.throughput(Throughput::Bytes(packet.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| codec.encode(black_box(STATUS), 1234, 100))
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let (header, body) = split_packet(black_box(&packet)).unwrap();
+            codec.decode_response(&header, body).unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// What a burst of robots checking in at once costs: every packet decoded
+/// and answered.
+fn flood(c: &mut Criterion) {
+    let codecs: Vec<UDPCodec> = (0..16)
+        .map(|i| UDPCodec::new(&format!("{:016}", i)))
+        .collect();
+    let packets: Vec<(usize, Vec<u8>)> = (0..FLOOD)
+        .map(|i| {
+            (
+                i % codecs.len(),
+                codecs[i % codecs.len()].encode(STATUS, i as u32, 100),
+            )
+        })
+        .collect();
+    let mut group = c.benchmark_group("flood");
+    group.throughput(Throughput::Elements(FLOOD as u64));
+    group.bench_function("decode_and_reply", |b| {
+        b.iter(|| {
+            for (device, packet) in &packets {
+                let codec = &codecs[*device];
+                let (header, body) = split_packet(packet).unwrap();
+                let json = codec.decode_response(&header, body).unwrap();
+                black_box(codec.encode(json.as_bytes(), header.device_id, header.stamp + 1));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, single, flood);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    // protox compiles the schema itself, so building doesn't need protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/dummycloud.proto");
+        let descriptors = protox::compile(["proto/dummycloud.proto"], ["proto"])?;
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,19 @@
+//! Packets signed with the right key but carrying anything at all, and
+//! bodies decrypted without checking the checksum, as `--lenient` does.
+#![no_main]
+
+use dummycloud::codec::{split_packet, UDPCodec};
+use libfuzzer_sys::fuzz_target;
+
+const TOKEN: &str = "0123456789abcdef";
+
+fuzz_target!(|data: &[u8]| {
+    let codec = UDPCodec::new(TOKEN);
+    let _ = codec.decode(data);
+    let _ = codec.decode_unverified(data);
+    // a body that made it past the checksum, which is what the key is for
+    let packet = codec.encode(data, 1, 2);
+    if let Ok((header, body)) = split_packet(&packet) {
+        let _ = codec.decode_response(&header, body);
+    }
+});
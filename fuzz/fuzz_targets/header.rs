@@ -0,0 +1,14 @@
+//! Any datagram, as it comes off the listener socket.
+#![no_main]
+
+use dummycloud::codec::{split_packet, PacketHeader, HEADER_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((header, body)) = split_packet(data) {
+        assert_eq!(HEADER_SIZE + body.len(), usize::from(header.length));
+        // whatever parses turns back into the bytes it came from
+        assert_eq!(&header.to_bytes()[..], &data[..HEADER_SIZE]);
+        assert_eq!(PacketHeader::parse(data), Ok(header));
+    }
+});
@@ -0,0 +1,36 @@
+//! What a packet decrypts to, on its way through the handlers.
+#![no_main]
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use dummycloud::clock::SystemClock;
+use dummycloud::config::Config;
+use dummycloud::models::Model;
+use dummycloud::payload::{IncomingBody, IncomingPayload};
+use dummycloud::{HandlerRegistry, Request};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let json = match std::str::from_utf8(data) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    let body: IncomingBody = match serde_json::from_str(json) {
+        Ok(body) => body,
+        Err(_) => return,
+    };
+    let config = Config::default();
+    let handlers = HandlerRegistry::with_defaults(&config, Arc::new(SystemClock));
+    let request = Request {
+        device_id: 1,
+        advertised_ip: Ipv4Addr::LOCALHOST.into(),
+        advertised_port: None,
+        model: Model::default(),
+    };
+    for payload in body.into_payloads() {
+        if let IncomingPayload::Message(message) = payload {
+            let _ = handlers.handle(&message, &request);
+        }
+    }
+});
@@ -0,0 +1,710 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Offset;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::cleaning::{self, CleaningError, RoomRef, Units};
+use crate::codes;
+use crate::commands::CommandError;
+use crate::consumables;
+use crate::db::HistoryQuery;
+use crate::devices::Device;
+use crate::http::{latest_parsed_map, LOGS};
+use crate::logs;
+use crate::map::Point;
+use crate::openapi;
+use crate::payload::ReplyPayload;
+use crate::remote;
+use crate::settings::{self, SettingError};
+use crate::summary;
+use crate::voice;
+use crate::Context;
+
+pub(crate) type ApiError = (StatusCode, Json<Value>);
+
+pub(crate) fn error(status: StatusCode, message: &str) -> ApiError {
+    (status, Json(json!({ "error": { "message": message } })))
+}
+
+pub(crate) fn device_json(device: &Device, context: &Context) -> Value {
+    json!({
+        "id": device.id,
+        "name": context.config.name_for(device.id),
+        "addr": device.addr,
+        "last_seen": device.last_seen_secs(),
+        "stamp": device.stamp,
+        "booted": device.booted_secs(),
+        "drift": device.drift,
+        "connection": device.connection,
+        "wrong_key": context.keys.looks_wrong(device.id)
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/devices",
+    responses((status = 200, description = "The robots that have checked in", body = [openapi::Device]))
+)]
+async fn list_devices(State(context): State<Arc<Context>>) -> Json<Value> {
+    let devices: Vec<Value> = context
+        .devices
+        .all()
+        .iter()
+        .map(|d| device_json(d, &context))
+        .collect();
+    Json(json!(devices))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/devices/{device_id}/state",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    responses(
+        (status = 200, description = "The robot as in `/api/devices`, with the latest `props` and `event.status` it reported as `state`", body = Object),
+        (status = 404, description = "The robot hasn't checked in", body = openapi::ApiError),
+    )
+)]
+async fn device_state(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<Value>, ApiError> {
+    state_json(&context, device_id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "unknown device"))
+}
+
+/// The robot along with what it last reported, and what that means for its
+/// model, if it has checked in.
+pub(crate) fn state_json(context: &Context, device_id: u32) -> Option<Value> {
+    let device = context.devices.get(device_id)?;
+    let mut body = device_json(&device, context);
+    let state = context.state.get(device_id).unwrap_or_default();
+    body["state"] = json!(state);
+    body["state"]["labels"] = codes::labels(context.config.model_for(device_id), &state);
+    Some(body)
+}
+
+// Caps how much of the history one request can pull out.
+const MAX_HISTORY: usize = 10_000;
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    from: Option<u64>,
+    to: Option<u64>,
+    method: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn device_history(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<Value>, ApiError> {
+    if context.db.is_none() {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "no history is kept without [storage] database",
+        ));
+    }
+    let messages = tokio::task::spawn_blocking(move || {
+        let query = HistoryQuery {
+            from: params.from,
+            to: params.to,
+            method: params.method.as_deref(),
+            limit: params.limit.unwrap_or(MAX_HISTORY).min(MAX_HISTORY),
+        };
+        match &context.db {
+            Some(db) => db.history(device_id, &query),
+            None => Ok(Vec::new()),
+        }
+    })
+    .await
+    .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "history lookup failed"))?
+    .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?;
+    Ok(Json(json!(messages)))
+}
+
+#[derive(Deserialize)]
+struct LogParams {
+    q: Option<String>,
+    file: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Searches the logs the robot last uploaded, line by line.
+async fn device_logs(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Query(params): Query<LogParams>,
+) -> Result<Json<Value>, ApiError> {
+    tokio::task::spawn_blocking(move || {
+        let path = context
+            .logs
+            .latest(&device_id.to_string(), LOGS)
+            .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?
+            .ok_or_else(|| error(StatusCode::NOT_FOUND, "no logs from this robot"))?;
+        let files = context
+            .log_index
+            .get(device_id, &path)
+            .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()))?;
+        let limit = params.limit.unwrap_or(MAX_HISTORY).min(MAX_HISTORY);
+        let query = params.q.unwrap_or_default();
+        let matches = logs::search(&files, &query, params.file.as_deref(), limit);
+        let listing: Vec<Value> = files
+            .iter()
+            .map(|f| json!({ "name": f.name, "lines": f.lines.len() }))
+            .collect();
+        Ok(Json(json!({ "files": listing, "matches": matches })))
+    })
+    .await
+    .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "log search failed"))?
+}
+
+// `Room 17` is what unnamed rooms are listed as.
+pub(crate) fn room_name(context: &Context, device_id: u32, segment: u8) -> String {
+    match context.config.room_name(device_id, segment) {
+        Some(name) => name.to_string(),
+        None => format!("Room {}", segment),
+    }
+}
+
+/// The rooms in the robot's latest map, named as in its `rooms` under
+/// `[[devices]]`.
+#[utoipa::path(
+    get,
+    path = "/api/devices/{device_id}/rooms",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    responses(
+        (status = 200, description = "The rooms in the robot's latest map", body = [openapi::Room]),
+        (status = 404, description = "No map from this robot", body = openapi::ApiError),
+        (status = 422, description = "The map couldn't be read", body = openapi::ApiError),
+    )
+)]
+async fn device_rooms(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<Value>, ApiError> {
+    let map = latest_parsed_map(Arc::clone(&context), device_id.to_string())
+        .await
+        .map_err(|status| error(status, "no usable map from this robot"))?;
+    let segments = map.image.map(|image| image.segments).unwrap_or_default();
+    let rooms: Vec<Value> = segments
+        .iter()
+        .map(|segment| {
+            json!({
+                "id": segment.id,
+                "name": room_name(&context, device_id, segment.id),
+                "area": f64::from(segment.pixels) * 0.0025,
+                "bounds": segment.bounds,
+                "center": segment.center
+            })
+        })
+        .collect();
+    Ok(Json(json!(rooms)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ZoneRequest {
+    /// Up to 5 rectangles, as `[x1, y1, x2, y2]`.
+    zones: Vec<[i32; 4]>,
+    #[serde(default = "once")]
+    repeats: u8,
+    #[serde(default)]
+    units: Units,
+}
+
+fn once() -> u8 {
+    1
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SegmentRequest {
+    rooms: Vec<RoomRef>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct GotoRequest {
+    x: i32,
+    y: i32,
+    #[serde(default)]
+    units: Units,
+}
+
+fn cleaning_error(e: CleaningError) -> ApiError {
+    error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string())
+}
+
+async fn send_built(
+    context: &Context,
+    device_id: u32,
+    command: cleaning::Command,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    context
+        .send_command(device_id, command.method, &command.params)
+        .await
+        .map(Json)
+        .map_err(command_error)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/devices/{device_id}/clean_zone",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    request_body = ZoneRequest,
+    responses(
+        (status = 200, description = "The robot's reply", body = ReplyPayload),
+        (status = 422, description = "The zones don't fit the latest map", body = openapi::ApiError),
+    )
+)]
+async fn clean_zone(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<ZoneRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let map = latest_parsed_map(Arc::clone(&context), device_id.to_string())
+        .await
+        .map_err(|status| error(status, "no usable map from this robot"))?;
+    let command = cleaning::clean_zone(&map, &request.zones, request.units, request.repeats)
+        .map_err(cleaning_error)?;
+    send_built(&context, device_id, command).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/devices/{device_id}/clean_segments",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    request_body = SegmentRequest,
+    responses(
+        (status = 200, description = "The robot's reply", body = ReplyPayload),
+        (status = 422, description = "A room isn't in the latest map", body = openapi::ApiError),
+    )
+)]
+async fn clean_segments(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<SegmentRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let map = latest_parsed_map(Arc::clone(&context), device_id.to_string())
+        .await
+        .map_err(|status| error(status, "no usable map from this robot"))?;
+    let segments = request
+        .rooms
+        .iter()
+        .map(|room| {
+            context.config.resolve_room(device_id, room).ok_or_else(|| {
+                let name = match room {
+                    RoomRef::Id(id) => id.to_string(),
+                    RoomRef::Name(name) => name.clone(),
+                };
+                error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &format!("no room called {}", name),
+                )
+            })
+        })
+        .collect::<Result<Vec<u8>, ApiError>>()?;
+    let command = cleaning::clean_segments(&map, &segments).map_err(cleaning_error)?;
+    send_built(&context, device_id, command).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/devices/{device_id}/goto",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    request_body = GotoRequest,
+    responses(
+        (status = 200, description = "The robot's reply", body = ReplyPayload),
+        (status = 422, description = "The target is off the latest map", body = openapi::ApiError),
+    )
+)]
+async fn goto_target(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<GotoRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let map = latest_parsed_map(Arc::clone(&context), device_id.to_string())
+        .await
+        .map_err(|status| error(status, "no usable map from this robot"))?;
+    let target = Point {
+        x: request.x,
+        y: request.y,
+    };
+    let command = cleaning::goto_target(&map, target, request.units).map_err(cleaning_error)?;
+    send_built(&context, device_id, command).await
+}
+
+/// The wear last reported by the robot's `get_consumable`.
+async fn device_consumables(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<consumables::Reading>, ApiError> {
+    context
+        .consumables
+        .get(device_id)
+        .map(Json)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "no consumables reported yet"))
+}
+
+/// Takes the robot over to drive it by hand. It's stopped again if no
+/// move comes in for [`remote::IDLE_TIMEOUT`].
+async fn remote_start(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let reply = context
+        .send_command(device_id, "app_rc_start", &json!([]))
+        .await
+        .map_err(command_error)?;
+    let id = context.remote.start(device_id, Instant::now());
+    tokio::spawn(remote::watch(device_id, id, Arc::clone(&context)));
+    Ok(Json(reply))
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    /// Metres a second, negative to reverse.
+    #[serde(default)]
+    velocity: f64,
+    /// Radians a second, positive turning left.
+    #[serde(default)]
+    rotation: f64,
+    /// For how long, in milliseconds.
+    #[serde(default = "default_move_duration")]
+    duration: u64,
+}
+
+fn default_move_duration() -> u64 {
+    1000
+}
+
+async fn remote_move(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<MoveRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let seqnum = context
+        .remote
+        .moved(device_id, Instant::now())
+        .ok_or_else(|| error(StatusCode::CONFLICT, "remote control hasn't been started"))?;
+    let command = remote::rc_move(request.velocity, request.rotation, request.duration, seqnum);
+    send_built(&context, device_id, command).await
+}
+
+async fn remote_stop(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    context.remote.end(device_id);
+    context
+        .send_command(device_id, "app_rc_end", &json!([]))
+        .await
+        .map(Json)
+        .map_err(command_error)
+}
+
+fn setting_error(e: SettingError) -> ApiError {
+    error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string())
+}
+
+#[derive(Deserialize)]
+struct LevelRequest {
+    level: String,
+}
+
+async fn set_fan_speed(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<LevelRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let model = context.config.model_for(device_id);
+    let command = settings::fan_speed(model, &request.level).map_err(setting_error)?;
+    send_built(&context, device_id, command).await
+}
+
+async fn set_water_level(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<LevelRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let model = context.config.model_for(device_id);
+    let command = settings::water_level(model, &request.level).map_err(setting_error)?;
+    send_built(&context, device_id, command).await
+}
+
+#[derive(Deserialize)]
+struct CarpetRequest {
+    enabled: bool,
+}
+
+async fn set_carpet_mode(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<CarpetRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    send_built(&context, device_id, settings::carpet_mode(request.enabled)).await
+}
+
+#[derive(Deserialize)]
+struct VoiceParams {
+    sid: Option<u32>,
+}
+
+/// Keeps the voice pack in the body and has the robot download and install
+/// it from us.
+async fn install_voice(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Query(params): Query<VoiceParams>,
+    pack: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let device = context
+        .devices
+        .get(device_id)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "unknown device"))?;
+    if pack.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "no voice pack in the body"));
+    }
+    let dir = context.config.storage.voice_dir.clone();
+    let (name, md5) = tokio::task::spawn_blocking(move || voice::store(&dir, &pack))
+        .await
+        .map_err(|_| {
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not store voice pack",
+            )
+        })?
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?;
+    let host = context
+        .advertised_ip(device.addr)
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?;
+    let url = format!(
+        "http://{}:{}/voice/{}",
+        host, context.config.advertise.http_port, name
+    );
+    let command = voice::install(&url, &md5, params.sid.unwrap_or(voice::CUSTOM_SID));
+    let reply = context
+        .send_command(device_id, command.method, &command.params)
+        .await
+        .map_err(command_error)?;
+    Ok(Json(json!({ "url": url, "md5": md5, "reply": reply })))
+}
+
+/// How far along the robot is with downloading and installing a pack.
+async fn voice_progress(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    context
+        .send_command(device_id, "get_sound_progress", &json!([]))
+        .await
+        .map(Json)
+        .map_err(command_error)
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+    /// How many days back to go, 30 by default.
+    days: Option<u64>,
+}
+
+/// The robot's cleaning runs added up by the day and the week, along with
+/// the totals it last gave for `get_clean_summary`.
+async fn device_stats(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Query(params): Query<StatsParams>,
+) -> Result<Json<Value>, ApiError> {
+    if context.devices.get(device_id).is_none() {
+        return Err(error(StatusCode::NOT_FOUND, "unknown device"));
+    }
+    let days = params.days.unwrap_or(30);
+    let from = context
+        .clock
+        .epoch_secs()
+        .saturating_sub(days.saturating_mul(86400));
+    let lookup = Arc::clone(&context);
+    let runs = tokio::task::spawn_blocking(move || match &lookup.db {
+        Some(db) => db.runs(device_id, from),
+        None => Ok(lookup.summary.runs(device_id, from)),
+    })
+    .await
+    .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "stats lookup failed"))?
+    .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?;
+    let offset = chrono::Local::now().offset().fix();
+    let (daily, weekly) = summary::aggregate(&runs, offset);
+    Ok(Json(json!({
+        "lifetime": context.summary.lifetime(device_id),
+        "daily": daily,
+        "weekly": weekly,
+    })))
+}
+
+/// Starts a part's wear over once it's been swapped or cleaned, then asks
+/// the robot again so the reading is up to date.
+async fn reset_consumable(
+    State(context): State<Arc<Context>>,
+    Path((device_id, part)): Path<(u32, String)>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    let key = consumables::reset_key(&part)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "no such part"))?;
+    let reply = context
+        .send_command(device_id, "reset_consumable", &json!([key]))
+        .await
+        .map_err(command_error)?;
+    let config = context.config.consumables.clone().unwrap_or_default();
+    consumables::poll(device_id, &config, &context).await;
+    Ok(Json(reply))
+}
+
+async fn list_schedules(State(context): State<Arc<Context>>) -> Json<Value> {
+    let now = chrono::DateTime::from(context.clock.now());
+    let jobs: Vec<Value> = context
+        .config
+        .schedule
+        .jobs
+        .iter()
+        .map(|job| {
+            json!({
+                "name": job.name,
+                "cron": job.cron.to_string(),
+                "device_id": job.device_id,
+                "next_run": job.cron.next_after(now).map(|t| t.timestamp()),
+                "skip_next": context.skips.is_skipped(&job.name)
+            })
+        })
+        .collect();
+    Json(json!(jobs))
+}
+
+fn find_job(context: &Context, name: &str) -> Result<(), ApiError> {
+    if context
+        .config
+        .schedule
+        .jobs
+        .iter()
+        .any(|job| job.name == name)
+    {
+        Ok(())
+    } else {
+        Err(error(StatusCode::NOT_FOUND, "no such job"))
+    }
+}
+
+async fn skip_job(
+    State(context): State<Arc<Context>>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    find_job(&context, &name)?;
+    context.skips.skip_next(&name);
+    Ok(Json(json!({ "skip_next": true })))
+}
+
+async fn unskip_job(
+    State(context): State<Arc<Context>>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    find_job(&context, &name)?;
+    context.skips.unskip(&name);
+    Ok(Json(json!({ "skip_next": false })))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CommandRequest {
+    method: String,
+    #[serde(default = "no_params")]
+    #[schema(default = json!([]))]
+    params: Value,
+}
+
+fn no_params() -> Value {
+    json!([])
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/devices/{device_id}/command",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    request_body = CommandRequest,
+    responses(
+        (status = 200, description = "The robot's reply", body = ReplyPayload),
+        (status = 403, description = "Held back: a firmware update, or do not disturb", body = openapi::ApiError),
+        (status = 404, description = "The robot hasn't checked in", body = openapi::ApiError),
+        (status = 504, description = "The robot didn't answer in time", body = openapi::ApiError),
+    )
+)]
+async fn send_command(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<u32>,
+    Json(request): Json<CommandRequest>,
+) -> Result<Json<ReplyPayload>, ApiError> {
+    context
+        .send_command(device_id, &request.method, &request.params)
+        .await
+        .map(Json)
+        .map_err(command_error)
+}
+
+pub(crate) fn command_error(e: CommandError) -> ApiError {
+    let status = match e {
+        CommandError::UnknownDevice(_) => StatusCode::NOT_FOUND,
+        CommandError::OtaBlocked | CommandError::DoNotDisturb => StatusCode::FORBIDDEN,
+        CommandError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        CommandError::NoKey(_) | CommandError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, &e.to_string())
+}
+
+/// The JSON API for dashboards and the like.
+pub fn router() -> Router<Arc<Context>> {
+    Router::new()
+        .route("/api/devices", get(list_devices))
+        .route("/api/devices/{device_id}/state", get(device_state))
+        .route("/api/devices/{device_id}/history", get(device_history))
+        .route("/api/devices/{device_id}/rooms", get(device_rooms))
+        .route("/api/devices/{device_id}/stats", get(device_stats))
+        .route("/api/devices/{device_id}/logs", get(device_logs))
+        .route("/api/devices/{device_id}/command", post(send_command))
+        .route("/api/devices/{device_id}/clean_zone", post(clean_zone))
+        .route(
+            "/api/devices/{device_id}/clean_segments",
+            post(clean_segments),
+        )
+        .route("/api/devices/{device_id}/goto", post(goto_target))
+        .route("/api/devices/{device_id}/fan_speed", post(set_fan_speed))
+        .route(
+            "/api/devices/{device_id}/water_level",
+            post(set_water_level),
+        )
+        .route(
+            "/api/devices/{device_id}/carpet_mode",
+            post(set_carpet_mode),
+        )
+        .route(
+            "/api/devices/{device_id}/voice",
+            post(install_voice).get(voice_progress),
+        )
+        .route("/api/devices/{device_id}/remote/start", post(remote_start))
+        .route("/api/devices/{device_id}/remote/move", post(remote_move))
+        .route("/api/devices/{device_id}/remote/stop", post(remote_stop))
+        .route(
+            "/api/devices/{device_id}/consumables",
+            get(device_consumables),
+        )
+        .route(
+            "/api/devices/{device_id}/consumables/{part}/reset",
+            post(reset_consumable),
+        )
+        .route("/api/schedules", get(list_schedules))
+        .route(
+            "/api/schedules/{name}/skip",
+            post(skip_job).delete(unskip_job),
+        )
+}
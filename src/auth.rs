@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use crypto::util::fixed_time_eq;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+
+use crate::config::AuthConfig;
+use crate::Context;
+
+/// What an API token lets its holder do.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// Look at robots, maps and the live event stream.
+    Read,
+    /// Send robots commands as well.
+    #[default]
+    Command,
+}
+
+/// What `token` is allowed, with `[auth]` in `config`. Without it everyone
+/// is allowed everything, like before there were tokens.
+pub fn access(config: Option<&AuthConfig>, token: Option<&str>) -> Option<Access> {
+    let config = match config {
+        Some(config) => config,
+        None => return Some(Access::Command),
+    };
+    let token = token?.as_bytes();
+    config
+        .tokens
+        .iter()
+        .find(|t| t.token.len() == token.len() && fixed_time_eq(t.token.as_bytes(), token))
+        .map(|t| t.access)
+}
+
+/// The token a request comes with: a bearer token, or `?access_token=` for
+/// WebSocket clients in browsers, which can't set headers.
+fn token<'a>(headers: &'a HeaderMap, query: Option<&'a str>) -> Option<&'a str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer.or_else(|| {
+        query?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+    })
+}
+
+fn refused(status: StatusCode, message: &str) -> Response {
+    let body = Json(json!({ "error": { "message": message } }));
+    let mut response = (status, body).into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    }
+    response
+}
+
+/// Turns away requests without a token good for them: reading takes any
+/// token, anything else one with `command` access.
+pub(crate) async fn check(
+    State(context): State<Arc<Context>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let needed = match *request.method() {
+        Method::GET | Method::HEAD => Access::Read,
+        _ => Access::Command,
+    };
+    let token = token(request.headers(), request.uri().query());
+    match access(context.config.auth.as_ref(), token) {
+        Some(access) if access >= needed => next.run(request).await,
+        Some(_) => refused(StatusCode::FORBIDDEN, "this token can only read"),
+        None => {
+            warn!(path = %request.uri().path(), "refusing API request without a valid token");
+            refused(StatusCode::UNAUTHORIZED, "a valid bearer token is needed")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn checks_tokens_and_what_they_allow() {
+        assert_eq!(access(None, None), Some(Access::Command));
+        let config = Config::parse(
+            r#"
+            [[auth.tokens]]
+            token = "dashboard"
+            access = "read"
+
+            [[auth.tokens]]
+            token = "automation"
+            "#,
+        )
+        .unwrap();
+        let auth = config.auth.as_ref();
+        assert_eq!(access(auth, Some("dashboard")), Some(Access::Read));
+        assert_eq!(access(auth, Some("automation")), Some(Access::Command));
+        assert_eq!(access(auth, Some("automatio")), None);
+        assert_eq!(access(auth, None), None);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(token(&headers, Some("x=1&access_token=abc")), Some("abc"));
+        headers.insert(header::AUTHORIZATION, "Bearer xyz".parse().unwrap());
+        assert_eq!(token(&headers, Some("access_token=abc")), Some("xyz"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+// Enough for a burst of packets in flight, without holding on to much
+// memory once it's over.
+const KEEP: usize = 32;
+
+/// Receive buffers that go back to the pool once their packet has been
+/// answered, so a busy listener isn't allocating and zeroing a fresh one for
+/// every datagram.
+pub(crate) struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    size: usize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            free: Mutex::new(Vec::new()),
+            size,
+        })
+    }
+
+    /// A buffer `size` bytes long. What's in it is whatever the last packet
+    /// left there.
+    pub fn take(self: &Arc<Self>) -> PooledBuffer {
+        let buf = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            let mut buf = BytesMut::with_capacity(self.size);
+            buf.resize(self.size, 0);
+            buf
+        });
+        PooledBuffer {
+            buf: Some(buf),
+            len: self.size,
+            pool: Arc::clone(self),
+        }
+    }
+}
+
+/// One datagram's worth of a pooled buffer.
+pub(crate) struct PooledBuffer {
+    buf: Option<BytesMut>,
+    len: usize,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Cuts it down to the `len` bytes that were received, without giving
+    /// up the rest of the buffer.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.as_ref().unwrap()[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut().unwrap()[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < KEEP {
+            free.extend(self.buf.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused_at_full_size() {
+        let pool = BufferPool::new(2048);
+        let mut first = pool.take();
+        assert_eq!(first.len(), 2048);
+        first[..5].copy_from_slice(b"hello");
+        first.truncate(5);
+        assert_eq!(&first[..], b"hello");
+        let address = first.as_ptr();
+        drop(first);
+
+        let second = pool.take();
+        assert_eq!(second.len(), 2048);
+        assert_eq!(second.as_ptr(), address);
+        // a second one while the first is out is a new buffer
+        assert_ne!(pool.take().as_ptr(), address);
+    }
+}
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::codec::to_hex;
+
+// pcapng (draft-ietf-opsawg-pcapng) blocks, written little endian.
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+// DLT_USER0: the packets are bare miio datagrams, with the peer and the
+// decrypted JSON in a comment.
+const LINKTYPE_USER0: u16 = 147;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const EPB_FLAGS: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// One line of an NDJSON capture.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the epoch, with microseconds.
+    pub time: f64,
+    pub direction: Direction,
+    pub peer: SocketAddr,
+    /// The whole datagram, in hex.
+    pub packet: String,
+    /// What the packet says, if it could be decrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<String>,
+}
+
+enum Format {
+    Ndjson,
+    Pcapng,
+}
+
+/// Writes every datagram we send or receive to a file, for working out what
+/// new firmware is up to. Files ending in `.pcapng` are written as pcapng,
+/// anything else as NDJSON.
+pub struct Capture {
+    format: Format,
+    out: Mutex<BufWriter<File>>,
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn push_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    block.resize(pad4(block.len()), 0);
+}
+
+/// Wraps a block body in its type and the length it's framed by.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let total = (12 + body.len()) as u32;
+    let mut out = Vec::with_capacity(total as usize);
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&total.to_le_bytes());
+    out
+}
+
+fn pcapng_header() -> Vec<u8> {
+    let mut section = Vec::new();
+    section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    // section length unknown
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    let mut out = block(SECTION_HEADER, &section);
+
+    let mut interface = Vec::new();
+    interface.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    // no snap length limit
+    interface.extend_from_slice(&0u32.to_le_bytes());
+    out.extend(block(INTERFACE_DESCRIPTION, &interface));
+    out
+}
+
+fn pcapng_packet(
+    time: SystemTime,
+    direction: Direction,
+    peer: SocketAddr,
+    packet: &[u8],
+    json: Option<&str>,
+) -> Vec<u8> {
+    // pcapng's default resolution is microseconds
+    let micros = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64);
+    let mut body = Vec::new();
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    body.resize(pad4(body.len()), 0);
+
+    let inbound = direction == Direction::In;
+    let flags: u32 = if inbound { 1 } else { 2 };
+    push_option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+    let comment = format!(
+        "{} {} {}",
+        if inbound { "from" } else { "to" },
+        peer,
+        json.unwrap_or("")
+    );
+    push_option(&mut body, OPT_COMMENT, comment.trim_end().as_bytes());
+    push_option(&mut body, OPT_ENDOFOPT, &[]);
+    block(ENHANCED_PACKET, &body)
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> io::Result<Capture> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let format = if path.extension().is_some_and(|ext| ext == "pcapng") {
+            out.write_all(&pcapng_header())?;
+            out.flush()?;
+            Format::Pcapng
+        } else {
+            Format::Ndjson
+        };
+        Ok(Capture {
+            format,
+            out: Mutex::new(out),
+        })
+    }
+
+    pub fn record(
+        &self,
+        direction: Direction,
+        peer: SocketAddr,
+        packet: &[u8],
+        plaintext: Option<&[u8]>,
+    ) {
+        let now = SystemTime::now();
+        let json = plaintext.map(String::from_utf8_lossy);
+        let bytes = match self.format {
+            Format::Ndjson => {
+                let record = Record {
+                    time: now
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0.0, |d| d.as_micros() as f64 / 1e6),
+                    direction,
+                    peer,
+                    packet: to_hex(packet),
+                    json: json.as_ref().map(|j| j.to_string()),
+                };
+                let mut line = serde_json::to_vec(&record).unwrap_or_default();
+                line.push(b'\n');
+                line
+            }
+            Format::Pcapng => pcapng_packet(now, direction, peer, packet, json.as_deref()),
+        };
+        // flushed every time so a capture survives us being killed
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(&bytes).and_then(|_| out.flush()) {
+            warn!(error = %e, "could not write capture");
+        }
+    }
+
+    /// Makes sure everything recorded so far has reached the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        out.flush()?;
+        out.get_ref().sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcapng_blocks_are_framed_and_aligned() {
+        let header = pcapng_header();
+        assert_eq!(&header[..4], &SECTION_HEADER.to_le_bytes());
+
+        let peer: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        let packet = pcapng_packet(
+            SystemTime::now(),
+            Direction::In,
+            peer,
+            &[0x21, 0x31, 0, 5, 9],
+            Some("{}"),
+        );
+        assert_eq!(packet.len() % 4, 0);
+        let total = u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]) as usize;
+        assert_eq!(total, packet.len());
+        assert_eq!(&packet[packet.len() - 4..], &(total as u32).to_le_bytes());
+        // the datagram followed by padding
+        assert_eq!(&packet[28..36], &[0x21, 0x31, 0, 5, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ndjson_records_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("dummycloud-capture-{}.ndjson", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let peer: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        capture.record(Direction::In, peer, &[0x21, 0x31], Some(b"{\"id\":1}"));
+        capture.record(Direction::Out, peer, &[0xff], None);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<Record> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records[0].direction, Direction::In);
+        assert_eq!(records[0].packet, "2131");
+        assert_eq!(records[0].json.as_deref(), Some("{\"id\":1}"));
+        assert_eq!(records[1].peer, peer);
+        assert_eq!(records[1].json, None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use crate::config::ChaosConfig;
+
+/// What's done to a packet on its way to the robot.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Fault {
+    Drop,
+    /// Cut short to this many bytes.
+    Truncate(usize),
+    Delay(Duration),
+}
+
+/// Whether to do something to a `len` byte packet, and what, with `roll`
+/// picking at random. The percentages are slices of every packet sent, so
+/// they add up rather than overlap.
+pub(crate) fn fault(
+    config: &ChaosConfig,
+    len: usize,
+    mut roll: impl FnMut() -> u64,
+) -> Option<Fault> {
+    // hundredths of a percent
+    let pick = (roll() % 10_000) as f64 / 100.0;
+    let truncated = config.drop_percent + config.truncate_percent;
+    if pick < config.drop_percent {
+        Some(Fault::Drop)
+    } else if pick < truncated && len > 1 {
+        Some(Fault::Truncate(1 + (roll() % (len as u64 - 1)) as usize))
+    } else if pick >= truncated && pick < truncated + config.delay_percent {
+        Some(Fault::Delay(Duration::from_millis(config.delay_ms)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_up_the_packets() {
+        let config = ChaosConfig {
+            drop_percent: 10.0,
+            truncate_percent: 5.5,
+            delay_percent: 20.0,
+            delay_ms: 300,
+        };
+        let rolls = |picks: Vec<u64>| {
+            let mut picks = picks.into_iter();
+            move || picks.next().unwrap()
+        };
+        assert_eq!(fault(&config, 48, rolls(vec![999])), Some(Fault::Drop));
+        assert_eq!(
+            fault(&config, 48, rolls(vec![1000, 46])),
+            Some(Fault::Truncate(47))
+        );
+        assert_eq!(
+            fault(&config, 48, rolls(vec![11549, 0])),
+            Some(Fault::Truncate(1))
+        );
+        assert_eq!(
+            fault(&config, 48, rolls(vec![11550])),
+            Some(Fault::Delay(Duration::from_millis(300)))
+        );
+        assert_eq!(fault(&config, 48, rolls(vec![13550])), None);
+        // there's nothing to cut a byte short
+        assert_eq!(fault(&config, 1, rolls(vec![1000])), None);
+        assert_eq!(fault(&ChaosConfig::default(), 48, rolls(vec![0])), None);
+    }
+}
@@ -0,0 +1,227 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::map::{MapImage, Point, RRMap, MM_PER_PIXEL};
+
+// The app won't send more than this, and not all firmware copes if we do.
+const MAX_ZONES: usize = 5;
+const MAX_REPEATS: u8 = 3;
+
+/// What the coordinates handed to the helpers are in.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Units {
+    /// The robot's own millimetres, as in the parsed map.
+    #[default]
+    Millimetres,
+    /// Pixels of the map image the way `map.png` draws it at scale 1,
+    /// counted from the top left.
+    Pixels,
+}
+
+/// A room by its segment id or its name, `Room 17` doing for rooms without
+/// one.
+#[derive(Deserialize, Debug, Clone, PartialEq, utoipa::ToSchema)]
+#[serde(untagged)]
+pub enum RoomRef {
+    Id(u8),
+    Name(String),
+}
+
+/// A call to send to the robot.
+#[derive(Debug, PartialEq)]
+pub struct Command {
+    pub method: &'static str,
+    pub params: Value,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum CleaningError {
+    /// The map has no image to check against.
+    #[error("the robot's map has no floor plan yet")]
+    NoImage,
+    #[error("({}, {}) is off the map", .0.x, .0.y)]
+    OutOfBounds(Point),
+    #[error("zones need some width and height")]
+    EmptyZone,
+    #[error("no zones or rooms given")]
+    NothingToClean,
+    #[error("at most {MAX_ZONES} zones at a time")]
+    TooManyZones,
+    #[error("repeats must be 1 to {MAX_REPEATS}, not {0}")]
+    Repeats(u8),
+    #[error("there's no room {0} on the map")]
+    UnknownSegment(u8),
+}
+
+fn image(map: &RRMap) -> Result<&MapImage, CleaningError> {
+    map.image.as_ref().ok_or(CleaningError::NoImage)
+}
+
+/// Turns a point into the robot's millimetres, making sure it's on the map.
+fn to_mm(image: &MapImage, units: Units, p: Point) -> Result<Point, CleaningError> {
+    let mm = match units {
+        Units::Millimetres => p,
+        // checked before anything's multiplied, so it can't overflow
+        Units::Pixels if p.x < 0 || p.x > image.width || p.y < 0 || p.y > image.height => {
+            return Err(CleaningError::OutOfBounds(p));
+        }
+        // the image's row 0 is the bottom, the PNG's the top
+        Units::Pixels => Point {
+            x: (image.left + p.x) * MM_PER_PIXEL,
+            y: (image.top + image.height - p.y) * MM_PER_PIXEL,
+        },
+    };
+    let (left, bottom) = (image.left * MM_PER_PIXEL, image.top * MM_PER_PIXEL);
+    let (right, top) = (
+        left + image.width * MM_PER_PIXEL,
+        bottom + image.height * MM_PER_PIXEL,
+    );
+    if mm.x < left || mm.x > right || mm.y < bottom || mm.y > top {
+        return Err(CleaningError::OutOfBounds(p));
+    }
+    Ok(mm)
+}
+
+/// `app_zoned_clean` for `zones` given as `[x1, y1, x2, y2]`, each cleaned
+/// `repeats` times.
+pub fn clean_zone(
+    map: &RRMap,
+    zones: &[[i32; 4]],
+    units: Units,
+    repeats: u8,
+) -> Result<Command, CleaningError> {
+    let image = image(map)?;
+    if zones.len() > MAX_ZONES {
+        return Err(CleaningError::TooManyZones);
+    }
+    if !(1..=MAX_REPEATS).contains(&repeats) {
+        return Err(CleaningError::Repeats(repeats));
+    }
+    let params = zones
+        .iter()
+        .map(|&[x1, y1, x2, y2]| {
+            let a = to_mm(image, units, Point { x: x1, y: y1 })?;
+            let b = to_mm(image, units, Point { x: x2, y: y2 })?;
+            if a.x == b.x || a.y == b.y {
+                return Err(CleaningError::EmptyZone);
+            }
+            // the robot wants the bottom left corner first
+            Ok(json!([
+                a.x.min(b.x),
+                a.y.min(b.y),
+                a.x.max(b.x),
+                a.y.max(b.y),
+                repeats
+            ]))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if params.is_empty() {
+        return Err(CleaningError::NothingToClean);
+    }
+    Ok(Command {
+        method: "app_zoned_clean",
+        params: Value::Array(params),
+    })
+}
+
+/// `app_segment_clean` for rooms on the map, by segment id.
+pub fn clean_segments(map: &RRMap, segments: &[u8]) -> Result<Command, CleaningError> {
+    let image = image(map)?;
+    if segments.is_empty() {
+        return Err(CleaningError::NothingToClean);
+    }
+    for id in segments {
+        if !image.segments.iter().any(|s| s.id == *id) {
+            return Err(CleaningError::UnknownSegment(*id));
+        }
+    }
+    Ok(Command {
+        method: "app_segment_clean",
+        params: json!(segments),
+    })
+}
+
+/// `app_goto_target`, sending the robot to a spot on the map.
+pub fn goto_target(map: &RRMap, target: Point, units: Units) -> Result<Command, CleaningError> {
+    let target = to_mm(image(map)?, units, target)?;
+    Ok(Command {
+        method: "app_goto_target",
+        params: json!([target.x, target.y]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Segment;
+
+    fn map() -> RRMap {
+        RRMap {
+            image: Some(MapImage {
+                top: 500,
+                left: 400,
+                width: 100,
+                height: 200,
+                segments: vec![Segment {
+                    id: 16,
+                    pixels: 10,
+                    bounds: [20000, 25000, 21000, 26000],
+                    center: Point { x: 20500, y: 25500 },
+                }],
+                ..MapImage::default()
+            }),
+            ..RRMap::default()
+        }
+    }
+
+    #[test]
+    fn builds_zones_in_robot_millimetres() {
+        let command = clean_zone(&map(), &[[0, 200, 10, 190]], Units::Pixels, 2).unwrap();
+        assert_eq!(command.method, "app_zoned_clean");
+        assert_eq!(command.params, json!([[20000, 25000, 20500, 25500, 2]]));
+        let same = clean_zone(
+            &map(),
+            &[[20500, 25500, 20000, 25000]],
+            Units::Millimetres,
+            2,
+        );
+        assert_eq!(same.unwrap(), command);
+
+        assert_eq!(
+            clean_zone(&map(), &[[0, 0, 10, 10]], Units::Millimetres, 1),
+            Err(CleaningError::OutOfBounds(Point { x: 0, y: 0 }))
+        );
+        assert_eq!(
+            clean_zone(&map(), &[[0, 0, 0, 10]], Units::Pixels, 1),
+            Err(CleaningError::EmptyZone)
+        );
+        assert_eq!(
+            clean_zone(&map(), &[[0, 0, 1, 1]], Units::Pixels, 4),
+            Err(CleaningError::Repeats(4))
+        );
+        assert_eq!(
+            clean_zone(&map(), &[[0, 0, i32::MAX, 1]], Units::Pixels, 1),
+            Err(CleaningError::OutOfBounds(Point { x: i32::MAX, y: 1 }))
+        );
+        assert_eq!(
+            goto_target(&map(), Point { x: 50, y: 100 }, Units::Pixels)
+                .unwrap()
+                .params,
+            json!([22500, 30000])
+        );
+    }
+
+    #[test]
+    fn only_cleans_rooms_that_are_on_the_map() {
+        assert_eq!(clean_segments(&map(), &[16]).unwrap().params, json!([16]));
+        assert_eq!(
+            clean_segments(&map(), &[16, 17]),
+            Err(CleaningError::UnknownSegment(17))
+        );
+        assert_eq!(
+            clean_segments(&RRMap::default(), &[16]),
+            Err(CleaningError::NoImage)
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::SystemTime;
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+use crate::codec::epoch_secs;
+
+/// Where the current time comes from, so tests can control it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn epoch_secs(&self) -> u64 {
+        epoch_secs(self.now())
+    }
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+pub struct FakeClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn at_epoch_secs(secs: u64) -> FakeClock {
+        FakeClock {
+            now: Mutex::new(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
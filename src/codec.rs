@@ -1,142 +1,750 @@
-use crypto::aes::{cbc_decryptor, cbc_encryptor, KeySize};
-use crypto::blockmodes::{NoPadding, PkcsPadding};
-use crypto::buffer::{RefReadBuffer, RefWriteBuffer, WriteBuffer};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crypto::aes::KeySize;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crypto::aesni::{AesNiDecryptor, AesNiEncryptor};
+use crypto::aessafe::{AesSafe128Decryptor, AesSafe128Encryptor};
 use crypto::digest::Digest;
 use crypto::md5::Md5;
-use std::str;
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
 use std::string::String;
+use std::sync::Arc;
 use std::time::SystemTime;
 
-use bytes::BufMut;
+use bytes::{Buf, BufMut};
 
+use crate::clock::Clock;
+
+// The 32 byte header every packet starts with:
+//
+//  0      2        4         8           12      16                    32
+//  | 2131 | length | unknown | device id | stamp | md5 checksum / token |
+const MAGIC_OFFSET: usize = 0;
+const LENGTH_OFFSET: usize = 2;
+const UNKNOWN_OFFSET: usize = 4;
+pub const DEVICE_ID_OFFSET: usize = 8;
+pub const STAMP_OFFSET: usize = 12;
+const CHECKSUM_OFFSET: usize = 16;
+pub const HEADER_SIZE: usize = 32;
+
+const MAGIC: [u8; 2] = [0x21, 0x31];
+const BLOCK_SIZE: usize = 16;
+
+/// What discovery hellos and our timesync fill the unknown and device id
+/// fields with.
+pub const UNSET: u32 = 0xffff_ffff;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum PacketError {
+    #[error("packet is {0} bytes long, which is too short to hold a header")]
+    TooShort(usize),
+    #[error("packet starts with {} instead of 2131", to_hex(.0))]
+    BadMagic([u8; 2]),
+    #[error("header says the packet is {claimed} bytes long, but it's {actual}")]
+    BadLength { claimed: u16, actual: usize },
+    #[error("checksum doesn't match, wrong key?")]
+    ChecksumMismatch,
+    #[error("body could not be decrypted")]
+    DecryptFailed,
+    #[error("body decrypted to something that isn't text, wrong key?")]
+    NotUtf8,
+}
+
+impl PacketError {
+    /// A short name for the kind of error, for metrics and the like.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PacketError::TooShort(_) => "too_short",
+            PacketError::BadMagic(_) => "bad_magic",
+            PacketError::BadLength { .. } => "bad_length",
+            PacketError::ChecksumMismatch => "checksum_mismatch",
+            PacketError::DecryptFailed => "decrypt_failed",
+            PacketError::NotUtf8 => "not_utf8",
+        }
+    }
+}
+
+/// The 32 byte header, field by field. Turning it back into bytes gives
+/// exactly what it was parsed from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PacketHeader {
+    /// Of the whole packet, header included.
+    pub length: u16,
+    /// 0, except in hellos and timesyncs.
+    pub unknown: u32,
+    pub device_id: u32,
+    pub stamp: u32,
+    /// The md5 checksum of the packet, except in hellos and timesyncs, where
+    /// it's 0xff filler, and in a new robot's hello reply, where it's the
+    /// token.
+    pub checksum: [u8; 16],
+}
+
+impl PacketHeader {
+    /// A header for a packet carrying `body_len` bytes, still to be signed.
+    pub fn new(device_id: u32, stamp: u32, body_len: usize) -> PacketHeader {
+        PacketHeader {
+            length: (HEADER_SIZE + body_len) as u16,
+            unknown: 0,
+            device_id,
+            stamp,
+            checksum: [0; 16],
+        }
+    }
+
+    /// The header-only packet that starts the handshake, e.g. a discovery
+    /// hello, or our timesync when given a stamp.
+    pub fn hello(stamp: u32) -> PacketHeader {
+        PacketHeader {
+            length: HEADER_SIZE as u16,
+            unknown: UNSET,
+            device_id: UNSET,
+            stamp,
+            checksum: [0xff; 16],
+        }
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<PacketHeader, PacketError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(PacketError::TooShort(packet.len()));
+        }
+        if packet[MAGIC_OFFSET..LENGTH_OFFSET] != MAGIC {
+            return Err(PacketError::BadMagic([packet[0], packet[1]]));
+        }
+        let mut fields = &packet[LENGTH_OFFSET..CHECKSUM_OFFSET];
+        let mut checksum = [0; 16];
+        checksum.copy_from_slice(&packet[CHECKSUM_OFFSET..HEADER_SIZE]);
+        Ok(PacketHeader {
+            length: fields.get_u16(),
+            unknown: fields.get_u32(),
+            device_id: fields.get_u32(),
+            stamp: fields.get_u32(),
+            checksum,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[MAGIC_OFFSET..LENGTH_OFFSET].copy_from_slice(&MAGIC);
+        header[LENGTH_OFFSET..UNKNOWN_OFFSET].copy_from_slice(&self.length.to_be_bytes());
+        header[UNKNOWN_OFFSET..DEVICE_ID_OFFSET].copy_from_slice(&self.unknown.to_be_bytes());
+        header[DEVICE_ID_OFFSET..STAMP_OFFSET].copy_from_slice(&self.device_id.to_be_bytes());
+        header[STAMP_OFFSET..CHECKSUM_OFFSET].copy_from_slice(&self.stamp.to_be_bytes());
+        header[CHECKSUM_OFFSET..].copy_from_slice(&self.checksum);
+        header
+    }
+
+    /// A discovery hello, which isn't addressed to any one robot.
+    pub fn is_discovery(&self) -> bool {
+        self.device_id == UNSET && self.unknown == UNSET
+    }
+
+    /// Whether the checksum field is filler rather than a checksum or token.
+    pub fn is_unsigned(&self) -> bool {
+        self.checksum.iter().all(|b| *b == 0) || self.checksum.iter().all(|b| *b == 0xff)
+    }
+}
+
+/// Checks that a datagram looks like a miio packet, and splits it into its
+/// header and (possibly empty) encrypted body. The body is as long as the
+/// header says, and a packet shorter than that is turned away; anything
+/// past it, like the padding some captures have, is left off.
+pub fn split_packet(packet: &[u8]) -> Result<(PacketHeader, &[u8]), PacketError> {
+    let header = PacketHeader::parse(packet)?;
+    let length = usize::from(header.length);
+    if length < HEADER_SIZE || length > packet.len() {
+        return Err(PacketError::BadLength {
+            claimed: header.length,
+            actual: packet.len(),
+        });
+    }
+    Ok((header, &packet[HEADER_SIZE..length]))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The other way round from [`to_hex`], None if it isn't hex.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// `N` bytes from the system's secure random source. Keys, secrets and
+/// nonces made of zeros would be worse than no answer, so if the system
+/// has no randomness to give this panics rather than hand them out.
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    rustls::crypto::ring::default_provider()
+        .secure_random
+        .fill(&mut bytes)
+        .expect("the system has no randomness to give");
+    bytes
+}
+
+/// Seconds since the epoch, or 0 for a clock that's somehow set before it.
+pub fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The stamp field is an unsigned 32 bit number of seconds, so it's good
+/// until 2106. Past that it wraps around, same as the robot's own counter.
+pub fn wire_stamp(epoch: u64) -> u32 {
+    (epoch & u64::from(u32::MAX)) as u32
+}
+
+/// Our answer to the robot's hello: a bare header carrying the current time.
+#[derive(Debug, PartialEq)]
+pub struct TimesyncPacket {
+    pub epoch: u64,
+}
+
+impl TimesyncPacket {
+    pub fn at(time: SystemTime) -> TimesyncPacket {
+        TimesyncPacket {
+            epoch: epoch_secs(time),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        PacketHeader::hello(wire_stamp(self.epoch)).to_bytes()
+    }
+}
+
+/// A packet that's nothing but its header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderOnly {
+    /// The robot's hello, stamped 0, which gets a timesync back.
+    Hello,
+    /// A keep-alive ping carrying the robot's stamp, which gets an ack
+    /// carrying the same stamp back.
+    Ping,
+}
+
+impl HeaderOnly {
+    /// What a packet [`split_packet`] took apart is, or None if it has a
+    /// body.
+    pub fn of(header: &PacketHeader, encrypted_body: &[u8]) -> Option<HeaderOnly> {
+        if !encrypted_body.is_empty() {
+            return None;
+        }
+        Some(match header.stamp {
+            0 => HeaderOnly::Hello,
+            _ => HeaderOnly::Ping,
+        })
+    }
+
+    /// A short name for it, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HeaderOnly::Hello => "hello",
+            HeaderOnly::Ping => "ping",
+        }
+    }
+}
+
+/// The AES-128-CBC key and IV the token is stretched into.
+fn derive_keys(token: &str) -> ([u8; 16], [u8; 16]) {
+    let mut md5er = Md5::new();
+    md5er.input_str(token);
+    let mut key = [0; 16];
+    md5er.result(&mut key);
+
+    let mut md5er = Md5::new();
+    md5er.input(&key);
+    md5er.input_str(token);
+    let mut iv = [0; 16];
+    md5er.result(&mut iv);
+    (key, iv)
+}
+
+/// md5 of the first half of the header, the token and the encrypted body.
+fn checksum(header: &PacketHeader, token: &str, encrypted_body: &[u8]) -> [u8; 16] {
+    let mut digester = Md5::new();
+    digester.input(&header.to_bytes()[..CHECKSUM_OFFSET]);
+    digester.input_str(token);
+    digester.input(encrypted_body);
+    let mut digest = [0; 16];
+    digester.result(&mut digest);
+    digest
+}
+
+/// AES-128-CBC with the key schedule worked out once, rather than for every
+/// packet like rust-crypto's `cbc_encryptor` would. Uses AES-NI where the
+/// CPU has it, same as rust-crypto.
+#[derive(Clone)]
+struct Cipher {
+    encryptor: Arc<dyn BlockEncryptor + Send + Sync>,
+    decryptor: Arc<dyn BlockDecryptor + Send + Sync>,
+    iv: [u8; 16],
+}
+
+impl Cipher {
+    fn new(key: &[u8; 16], iv: &[u8; 16]) -> Cipher {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if crypto::util::supports_aesni() {
+                return Cipher {
+                    encryptor: Arc::new(AesNiEncryptor::new(KeySize::KeySize128, key)),
+                    decryptor: Arc::new(AesNiDecryptor::new(KeySize::KeySize128, key)),
+                    iv: *iv,
+                };
+            }
+        }
+        Cipher::portable(key, iv)
+    }
+
+    fn portable(key: &[u8; 16], iv: &[u8; 16]) -> Cipher {
+        Cipher {
+            encryptor: Arc::new(AesSafe128Encryptor::new(key)),
+            decryptor: Arc::new(AesSafe128Decryptor::new(key)),
+            iv: *iv,
+        }
+    }
+
+    /// The length `message` encrypts to. PKCS padding always adds at least
+    /// one byte, so a message that's already a whole number of blocks grows
+    /// by a full block.
+    fn encrypted_len(message: &[u8]) -> usize {
+        (message.len() / BLOCK_SIZE + 1) * BLOCK_SIZE
+    }
+
+    /// Encrypts `message` onto the end of `out`.
+    fn encrypt_into(&self, message: &[u8], out: &mut Vec<u8>) {
+        let padding = (BLOCK_SIZE - message.len() % BLOCK_SIZE) as u8;
+        let mut previous = self.iv;
+        for start in (0..Cipher::encrypted_len(message)).step_by(BLOCK_SIZE) {
+            let mut block = [padding; BLOCK_SIZE];
+            let chunk = message.get(start..).unwrap_or_default();
+            let taken = chunk.len().min(BLOCK_SIZE);
+            block[..taken].copy_from_slice(&chunk[..taken]);
+            for (b, p) in block.iter_mut().zip(&previous) {
+                *b ^= p;
+            }
+            self.encryptor.encrypt_block(&block, &mut previous);
+            out.extend_from_slice(&previous);
+        }
+    }
+
+    fn decrypt(&self, encrypted_body: &[u8]) -> Result<Vec<u8>, PacketError> {
+        if !encrypted_body.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(PacketError::DecryptFailed);
+        }
+        let mut decrypted = Vec::with_capacity(encrypted_body.len());
+        let mut previous = &self.iv[..];
+        for block in encrypted_body.chunks(BLOCK_SIZE) {
+            let mut plain = [0; BLOCK_SIZE];
+            self.decryptor.decrypt_block(block, &mut plain);
+            decrypted.extend(plain.iter().zip(previous).map(|(b, p)| b ^ p));
+            previous = block;
+        }
+        Ok(decrypted)
+    }
+}
+
+// The robot null terminates its JSON before padding it, we don't. Either
+// way, what's left after dropping PKCS padding, and then anything past a
+// null with [`message`], is the message.
+fn unpad(decrypted: &[u8]) -> &[u8] {
+    match decrypted.last() {
+        Some(&n) if (1..=16).contains(&n) && usize::from(n) <= decrypted.len() => {
+            let (rest, padding) = decrypted.split_at(decrypted.len() - usize::from(n));
+            if padding.iter().all(|b| *b == n) {
+                rest
+            } else {
+                decrypted
+            }
+        }
+        _ => decrypted,
+    }
+}
+
+/// The message in an opened body, which stops at the first null.
+pub fn message(plaintext: &[u8]) -> &[u8] {
+    plaintext.split(|b| *b == 0).next().unwrap()
+}
+
+// Encrypts straight into the packet, so building one takes one allocation.
+fn seal(token: &str, cipher: &Cipher, device_id: u32, stamp: u32, message: &[u8]) -> Vec<u8> {
+    let body_len = Cipher::encrypted_len(message);
+    let mut packet = Vec::with_capacity(HEADER_SIZE + body_len);
+    packet.put_slice(&[0; HEADER_SIZE]);
+    cipher.encrypt_into(message, &mut packet);
+    let mut header = PacketHeader::new(device_id, stamp, body_len);
+    header.checksum = checksum(&header, token, &packet[HEADER_SIZE..]);
+    packet[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    packet
+}
+
+fn open(
+    token: &str,
+    cipher: &Cipher,
+    header: &PacketHeader,
+    encrypted_body: &[u8],
+) -> Result<Vec<u8>, PacketError> {
+    if header.checksum != checksum(header, token, encrypted_body) {
+        return Err(PacketError::ChecksumMismatch);
+    }
+    open_unverified(cipher, encrypted_body)
+}
+
+// The plaintext is the decrypted body cut short, so it keeps the body's
+// buffer. Binary frames can have nulls in them, so they're left in.
+fn open_unverified(cipher: &Cipher, encrypted_body: &[u8]) -> Result<Vec<u8>, PacketError> {
+    let mut decrypted = cipher.decrypt(encrypted_body)?;
+    let len = unpad(&decrypted).len();
+    decrypted.truncate(len);
+    Ok(decrypted)
+}
+
+fn text(mut plaintext: Vec<u8>) -> Result<String, PacketError> {
+    plaintext.truncate(message(&plaintext).len());
+    String::from_utf8(plaintext).map_err(|_| PacketError::NotUtf8)
+}
+
+/// Whether a decrypted body is something other than miio JSON: not text at
+/// all, or text that doesn't even start like JSON. Newer firmwares send the
+/// odd one of these binary frames, which are worth keeping to work out.
+/// Text that starts like JSON and then goes wrong is broken JSON instead.
+pub fn is_binary(plaintext: &[u8]) -> bool {
+    match std::str::from_utf8(message(plaintext)) {
+        Ok(text) => !text.trim_start().starts_with(['{', '[']),
+        Err(_) => true,
+    }
+}
+
+/// Builds a packet carrying `message` for the robot, signed with `token`.
+/// Sending more than one packet with the same token is cheaper with a
+/// [`UDPCodec`].
+pub fn encode(token: &str, device_id: u32, stamp: u32, message: &[u8]) -> Vec<u8> {
+    UDPCodec::new(token).encode(message, device_id, stamp)
+}
+
+/// Checks and decrypts a whole packet from the robot, returning its JSON.
+pub fn decode(token: &str, packet: &[u8]) -> Result<String, PacketError> {
+    UDPCodec::new(token).decode(packet)
+}
+
+/// [`encode`] and [`decode`] for one token, without deriving the keys every
+/// time.
 #[derive(Clone)]
 pub struct UDPCodec {
     pub token: String,
     pub token_key: [u8; 16],
     pub token_iv: [u8; 16],
+    cipher: Cipher,
 }
 
 impl UDPCodec {
     pub fn new(token: &str) -> UDPCodec {
-        let mut cloud_md5er = Md5::new();
-        cloud_md5er.input_str(&token);
-
-        let mut token_key: [u8; 16] = [0; 16];
-        cloud_md5er.result(&mut token_key);
-
-        let mut cloud_md5er = Md5::new();
-        cloud_md5er.input(&token_key);
-
-        cloud_md5er.input_str(&token);
-        let mut token_iv: [u8; 16] = [0; 16];
-        cloud_md5er.result(&mut token_iv);
+        let (token_key, token_iv) = derive_keys(token);
         UDPCodec {
             token: token.to_string(),
-            token_key: token_key,
-            token_iv: token_iv,
+            token_key,
+            token_iv,
+            cipher: Cipher::new(&token_key, &token_iv),
         }
     }
 
-    pub fn decode_response(&self, header: &[u8], encrypted_body: &[u8]) -> Option<String> {
-        if encrypted_body.len() == 0 {
-            return None;
-        }
-        let mut digester = Md5::new();
-        digester.input(&header[..16]);
-        digester.input_str(&self.token);
-        digester.input(encrypted_body);
+    /// Checks and decrypts a whole packet, like [`decode`].
+    pub fn decode(&self, packet: &[u8]) -> Result<String, PacketError> {
+        let (header, encrypted_body) = split_packet(packet)?;
+        self.decode_response(&header, encrypted_body)
+    }
 
-        let mut digest: [u8; 16] = [0; 16];
-        digester.result(&mut digest);
+    pub fn decode_response(
+        &self,
+        header: &PacketHeader,
+        encrypted_body: &[u8],
+    ) -> Result<String, PacketError> {
+        text(self.open(header, encrypted_body)?)
+    }
 
-        let checksum = &header[16..];
+    /// [`UDPCodec::decode_response`] without checking the checksum first,
+    /// for robots that get it wrong.
+    pub fn decode_unverified(&self, encrypted_body: &[u8]) -> Result<String, PacketError> {
+        text(self.open_unverified(encrypted_body)?)
+    }
 
-        if !checksum.iter().zip(&digest).all(|(a, b)| a == b) {
-            println!("not equal checksums");
-            return None;
-        }
-        println!("checksums are equal!");
-        let mut decipherer = cbc_decryptor(
-            KeySize::KeySize128,
-            &self.token_key,
-            &self.token_iv,
-            NoPadding,
+    /// [`UDPCodec::decode_response`] stopping short of the text, for bodies
+    /// that might not be any.
+    pub fn open(
+        &self,
+        header: &PacketHeader,
+        encrypted_body: &[u8],
+    ) -> Result<Vec<u8>, PacketError> {
+        open(&self.token, &self.cipher, header, encrypted_body)
+    }
+
+    /// [`UDPCodec::open`] without checking the checksum first.
+    pub fn open_unverified(&self, encrypted_body: &[u8]) -> Result<Vec<u8>, PacketError> {
+        open_unverified(&self.cipher, encrypted_body)
+    }
+
+    pub fn encode(&self, message: &[u8], device_id: u32, stamp: u32) -> Vec<u8> {
+        seal(&self.token, &self.cipher, device_id, stamp, message)
+    }
+
+    /// A bare, signed header with nothing in it, for the robot to see we're
+    /// still here.
+    pub fn encode_keepalive(&self, device_id: u32, clock: &dyn Clock) -> Vec<u8> {
+        let mut header = PacketHeader::new(device_id, wire_stamp(clock.epoch_secs() + 1), 0);
+        header.checksum = checksum(&header, &self.token, &[]);
+        header.to_bytes().to_vec()
+    }
+
+    /// The answer to a robot's keep-alive ping: a bare header echoing its
+    /// stamp, signed.
+    pub fn encode_ack(&self, ping: &PacketHeader) -> Vec<u8> {
+        let mut header = PacketHeader::new(ping.device_id, ping.stamp, 0);
+        header.checksum = checksum(&header, &self.token, &[]);
+        header.to_bytes().to_vec()
+    }
+
+    /// Stamped a second ahead of now, the way the real cloud does it.
+    pub fn encode_response(&self, message: &[u8], device_id: u32, clock: &dyn Clock) -> Vec<u8> {
+        let stamp = wire_stamp(clock.epoch_secs() + 1);
+        self.encode(message, device_id, stamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn rejects_packets_that_arent_miio() {
+        assert_eq!(split_packet(&[0x21, 0x31]), Err(PacketError::TooShort(2)));
+        assert_eq!(
+            split_packet(&[0xff; 32]),
+            Err(PacketError::BadMagic([0xff, 0xff]))
+        );
+
+        let packet = encode("abcdef", 1, 2, b"{}");
+        let mut absurd = packet.clone();
+        absurd[LENGTH_OFFSET..UNKNOWN_OFFSET].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert_eq!(
+            split_packet(&absurd),
+            Err(PacketError::BadLength {
+                claimed: 0xffff,
+                actual: 48
+            })
+        );
+        let mut tiny = packet.clone();
+        tiny[LENGTH_OFFSET..UNKNOWN_OFFSET].copy_from_slice(&4u16.to_be_bytes());
+        assert!(split_packet(&tiny).is_err());
+        // trailing bytes past the length aren't part of the body
+        let mut padded = packet.clone();
+        padded.extend_from_slice(&[0; 6]);
+        assert_eq!(decode("abcdef", &padded), Ok(String::from("{}")));
+    }
+
+    #[test]
+    fn tells_header_only_packets_apart() {
+        let c = UDPCodec::new("abcdef");
+        let hello = PacketHeader::hello(0);
+        assert_eq!(HeaderOnly::of(&hello, &[]), Some(HeaderOnly::Hello));
+        let mut ping = PacketHeader::new(1234, 0x0102_0304, 0);
+        ping.checksum = checksum(&ping, "abcdef", &[]);
+        assert_eq!(HeaderOnly::of(&ping, &[]), Some(HeaderOnly::Ping));
+        assert_eq!(HeaderOnly::of(&ping, &[0; 16]), None);
+
+        // the ack is what a robot signing its ping properly sent
+        let ack = c.encode_ack(&ping);
+        assert_eq!(ack, ping.to_bytes().to_vec());
+        let mut odd = ping;
+        odd.unknown = 7;
+        let ack = c.encode_ack(&odd);
+        let (header, body) = split_packet(&ack).unwrap();
+        assert_eq!((header.unknown, header.stamp), (0, 0x0102_0304));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn rejects_bodies_that_arent_text() {
+        let packet = encode("abcdef", 1, 2, &[0xc3, 0x28, b'{']);
+        assert_eq!(decode("abcdef", &packet), Err(PacketError::NotUtf8));
+        assert_eq!(PacketError::NotUtf8.kind(), "not_utf8");
+
+        // opened as bytes, it's a binary frame rather than an error
+        let (header, body) = split_packet(&packet).unwrap();
+        let plaintext = UDPCodec::new("abcdef").open(&header, body).unwrap();
+        assert_eq!(plaintext, [0xc3, 0x28, b'{']);
+        assert!(is_binary(&plaintext));
+        assert!(is_binary(b"\x01\x02ok"));
+        assert!(!is_binary(b" {\"id\": 1}"));
+        // broken JSON is still JSON, going wrong
+        assert!(!is_binary(b"[{\"id\""));
+        // nulls end a message, but not a binary frame
+        let packet = encode("abcdef", 1, 2, b"\x00\x01");
+        let (header, body) = split_packet(&packet).unwrap();
+        let plaintext = UDPCodec::new("abcdef").open(&header, body).unwrap();
+        assert_eq!(plaintext, [0, 1]);
+        assert!(is_binary(&plaintext));
+        assert!(!is_binary(b"{}\x00\x07"));
+    }
+
+    #[test]
+    fn headers_round_trip_field_by_field() {
+        let packet = include_bytes!("../tests/fixtures/otc_info.bin");
+        let header = PacketHeader::parse(packet).unwrap();
+        assert_eq!(usize::from(header.length), packet.len());
+        assert_eq!(header.unknown, 0);
+        assert_eq!(header.device_id, FIXTURE_DEVICE_ID);
+        assert!(!header.is_unsigned());
+        assert_eq!(&header.to_bytes()[..], &packet[..HEADER_SIZE]);
+
+        let hello = PacketHeader::parse(&PacketHeader::hello(UNSET).to_bytes()).unwrap();
+        assert!(hello.is_discovery());
+        assert!(hello.is_unsigned());
+        assert!(!PacketHeader::new(1, 2, 16).is_discovery());
+    }
+
+    #[test]
+    fn timesync_carries_the_current_time() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(0x5e0b_e100);
+        let packet = TimesyncPacket::at(time).to_bytes();
+        assert_eq!(&packet[..4], &[0x21, 0x31, 0x00, 0x20]);
+        assert_eq!(&packet[UNKNOWN_OFFSET..STAMP_OFFSET], &[0xff; 8]);
+        assert_eq!(
+            &packet[STAMP_OFFSET..CHECKSUM_OFFSET],
+            &[0x5e, 0x0b, 0xe1, 0x00]
+        );
+        assert_eq!(&packet[CHECKSUM_OFFSET..], &[0xff; 16]);
+    }
+
+    #[test]
+    fn timesync_survives_odd_clocks() {
+        let before_epoch = SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(10);
+        assert_eq!(TimesyncPacket::at(before_epoch).epoch, 0);
+
+        // 2038 is fine for an unsigned stamp, and 2106 wraps around
+        assert_eq!(wire_stamp(2_147_483_648), 2_147_483_648);
+        assert_eq!(wire_stamp(u64::from(u32::MAX) + 5), 4);
+    }
+
+    #[test]
+    fn rejects_packets_signed_with_another_key() {
+        let clock = FakeClock::at_epoch_secs(0x5f3b_1a2c);
+        let packet = UDPCodec::new("someoneelse").encode_response(b"{}", 1234, &clock);
+        assert_eq!(
+            &packet[STAMP_OFFSET..CHECKSUM_OFFSET],
+            &0x5f3b_1a2du32.to_be_bytes()
+        );
+        let (header, body) = split_packet(&packet).unwrap();
+        assert_eq!(
+            UDPCodec::new("abcdef").decode_response(&header, body),
+            Err(PacketError::ChecksumMismatch)
         );
 
-        let mut underlying_buffer = vec![0; encrypted_body.len()];
-        decipherer
-            .decrypt(
-                &mut RefReadBuffer::new(&encrypted_body),
-                &mut RefWriteBuffer::new(&mut underlying_buffer),
-                true,
-            )
-            .expect("something went horribly wrong decrypting");
-
-        let output = match String::from_utf8(underlying_buffer) {
-            Ok(s) => s,
-            Err(_) => String::from("{}\0"),
-        };
-        let output = output.split('\0').next().unwrap();
-        Some(String::from(output))
-    }
-
-    pub fn encode_response(&self, message: &Vec<u8>, device_id: u32) -> Vec<u8> {
-        let mut packet: Vec<u8> = vec![];
-        // byte 0: write header
-        packet.push(0x21);
-        packet.push(0x31);
-
-        //byte 2: size of encrypted body
-        let mut cipherer = cbc_encryptor(
-            KeySize::KeySize128,
-            &self.token_key,
-            &self.token_iv,
-            PkcsPadding,
+        let mut tampered = UDPCodec::new("abcdef").encode_response(b"{}", 1234, &clock);
+        tampered[CHECKSUM_OFFSET] ^= 1;
+        let (header, body) = split_packet(&tampered).unwrap();
+        let codec = UDPCodec::new("abcdef");
+        assert_eq!(
+            codec.decode_response(&header, body),
+            Err(PacketError::ChecksumMismatch)
         );
-        // Need to give the write buffer enough space to add padding bytes for AES-CBC encryption
-        let mut underlying_buffer = vec![0; message.len().next_power_of_two()];
-
-        // do the encryption, and get the number of bytes written in the underlying buffer
-        let encrypted_size: usize = {
-            let mut read_buffer = &mut RefReadBuffer::new(&message);
-            let mut write_buffer = RefWriteBuffer::new(&mut underlying_buffer);
-            cipherer
-                .encrypt(&mut read_buffer, &mut write_buffer, true)
-                .unwrap();
-            write_buffer.position()
-        };
-        let encrypted_body = &underlying_buffer[..encrypted_size];
-        packet.put_u16(32 + encrypted_size as u16);
-
-        // byte 4: write nothing
-        packet.put_u32(0);
-
-        // byte 8
-        packet.put_u32(device_id);
-
-        // byte 12: write current epoch time
-        let epoch = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
-        packet.put_u32(epoch + 1);
-
-        assert!(packet.len() == 16, "The packet should have 16 bytes by now");
-
-        //byte 16: md5 hash of the first 16 bytes of header, the token and the encrypted body
-        let mut digester = Md5::new();
-        digester.input(&packet[..16]);
-        digester.input_str(&self.token);
-        digester.input(encrypted_body);
-
-        let mut digest: [u8; 16] = [0; 16];
-        digester.result(&mut digest);
-        packet.put_slice(&digest);
-
-        assert!(packet.len() == 32, "The packet should have 32 bytes by now");
-
-        // byte 32: the rest of the encrypted body
-        packet.extend(encrypted_body);
-        packet
+        assert_eq!(codec.decode_unverified(body), Ok(String::from("{}")));
+    }
+
+    #[test]
+    fn keepalives_are_signed_headers() {
+        let clock = FakeClock::at_epoch_secs(0x5f3b_1a2c);
+        let packet = UDPCodec::new("abcdef").encode_keepalive(1234, &clock);
+        let (header, body) = split_packet(&packet).unwrap();
+        assert!(body.is_empty());
+        assert_eq!(header.length, 32);
+        assert_eq!(header.device_id, 1234);
+        assert_eq!(header.checksum, checksum(&header, "abcdef", &[]));
+    }
+
+    // Made with python's cryptography package rather than this codec, so
+    // they catch mistakes that a round trip through our own code can't.
+    const FIXTURE_TOKEN: &str = "0123456789abcdef";
+    const FIXTURE_DEVICE_ID: u32 = 277_123_456;
+
+    #[test]
+    fn decodes_a_reference_packet() {
+        let packet = include_bytes!("../tests/fixtures/otc_info.bin");
+        let json = decode(FIXTURE_TOKEN, packet).unwrap();
+        let message: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(message["method"], "_otc.info");
+        assert_eq!(message["params"]["fw_ver"], "3.5.8_004528");
+        assert_eq!(
+            decode("fedcba9876543210", packet),
+            Err(PacketError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn encodes_the_same_bytes_as_the_reference() {
+        let packet = encode(
+            FIXTURE_TOKEN,
+            FIXTURE_DEVICE_ID,
+            0x5f3b_1a2d,
+            br#"{"id":1,"result":"ok"}"#,
+        );
+        assert_eq!(
+            &packet[..],
+            &include_bytes!("../tests/fixtures/otc_info_reply.bin")[..]
+        );
+    }
+
+    #[test]
+    fn pads_messages_that_fill_whole_blocks() {
+        for len in &[16, 32, 64, 128] {
+            let message = "x".repeat(*len);
+            let packet = encode("abcdef", 1, 2, message.as_bytes());
+            assert_eq!(packet.len(), HEADER_SIZE + len + 16);
+            assert_eq!(decode("abcdef", &packet), Ok(message));
+        }
+    }
+
+    #[test]
+    fn aes_ni_and_portable_aes_agree() {
+        let (key, iv) = derive_keys("0123456789abcdef");
+        let message = br#"{"id":1,"method":"_otc.info","params":{"otu_stat":[0,0,0,0,0,0,0,0]}}"#;
+        let (mut fast, mut portable) = (Vec::new(), Vec::new());
+        Cipher::new(&key, &iv).encrypt_into(message, &mut fast);
+        Cipher::portable(&key, &iv).encrypt_into(message, &mut portable);
+        assert_eq!(fast, portable);
+        let decrypted = Cipher::portable(&key, &iv).decrypt(&fast).unwrap();
+        assert_eq!(unpad(&decrypted), &message[..]);
+        assert_eq!(
+            Cipher::portable(&key, &iv).decrypt(&fast[1..]),
+            Err(PacketError::DecryptFailed)
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn round_trips_any_message(
+            token in "[0-9a-zA-Z]{1,32}",
+            device_id: u32,
+            stamp: u32,
+            message in "[^\\x00]{0,300}",
+        ) {
+            let packet = encode(&token, device_id, stamp, message.as_bytes());
+            proptest::prop_assert_eq!(packet.len() % 16, 0);
+            proptest::prop_assert_eq!(decode(&token, &packet), Ok(message));
+            let (header, body) = split_packet(&packet).unwrap();
+            proptest::prop_assert_eq!(
+                UDPCodec::new(&token).decode_response(&header, body).is_ok(),
+                true
+            );
+        }
+
+        #[test]
+        fn never_panics_on_garbage(packet in proptest::collection::vec(proptest::num::u8::ANY, 0..200)) {
+            let _ = decode("abcdef", &packet);
+        }
     }
 }
@@ -0,0 +1,148 @@
+use serde_json::{json, Value};
+
+use crate::models::Model;
+use crate::state::DeviceState;
+
+/// Codes and what they mean.
+type Table = &'static [(u64, &'static str)];
+
+/// `state` codes every generation reports.
+const STATES: [(u64, &str); 17] = [
+    (1, "Starting"),
+    (2, "Charger disconnected"),
+    (3, "Idle"),
+    (4, "Remote control active"),
+    (5, "Cleaning"),
+    (6, "Returning home"),
+    (7, "Manual mode"),
+    (8, "Charging"),
+    (9, "Charging problem"),
+    (10, "Paused"),
+    (11, "Spot cleaning"),
+    (12, "Error"),
+    (13, "Shutting down"),
+    (14, "Updating"),
+    (15, "Docking"),
+    (100, "Charging complete"),
+    (101, "Device offline"),
+];
+
+/// Going places on the map, which the first generation can't do.
+const MAP_STATES: [(u64, &str); 3] = [
+    (16, "Going to target"),
+    (17, "Zoned cleaning"),
+    (18, "Room cleaning"),
+];
+
+/// What the S7's dock gets up to.
+const DOCK_STATES: [(u64, &str); 3] = [
+    (22, "Emptying the bin"),
+    (23, "Washing the mop"),
+    (26, "Going to wash the mop"),
+];
+
+const ERRORS: [(u64, &str); 26] = [
+    (0, "No error"),
+    (1, "Laser distance sensor error"),
+    (2, "Collision sensor error"),
+    (3, "Wheels on top of void, move robot"),
+    (4, "Clean cliff sensors, move robot"),
+    (5, "Clean main brush"),
+    (6, "Clean side brush"),
+    (7, "Main wheel stuck"),
+    (8, "Robot stuck, clear its surroundings"),
+    (9, "Dustbin missing"),
+    (10, "Clean the filter"),
+    (11, "Stuck in magnetic barrier"),
+    (12, "Low battery"),
+    (13, "Charging fault"),
+    (14, "Battery fault"),
+    (15, "Clean the wall sensor"),
+    (16, "Place robot on a flat surface"),
+    (17, "Side brush error, reboot"),
+    (18, "Fan error, reboot"),
+    (19, "Charging station has no power"),
+    (21, "Laser cover stuck"),
+    (22, "Clean the charging contacts"),
+    (23, "Docking station error"),
+    (24, "No-go zone or invisible wall detected"),
+    (254, "Dustbin full"),
+    (255, "Internal error"),
+];
+
+/// The S7's mop and dock.
+const DOCK_ERRORS: [(u64, &str); 3] = [
+    (25, "Mop not installed"),
+    (26, "Clean water tank empty"),
+    (27, "Dirty water tank full"),
+];
+
+/// The tables to look codes up in for `model`, newer ones only adding to
+/// the older ones.
+fn tables(model: Model) -> (Vec<Table>, Vec<Table>) {
+    match model {
+        Model::Gen1 => (vec![&STATES], vec![&ERRORS]),
+        Model::S5 | Model::S6 => (vec![&STATES, &MAP_STATES], vec![&ERRORS]),
+        Model::S7 => (
+            vec![&STATES, &MAP_STATES, &DOCK_STATES],
+            vec![&ERRORS, &DOCK_ERRORS],
+        ),
+    }
+}
+
+fn find(tables: &[Table], code: u64) -> Option<&'static str> {
+    tables
+        .iter()
+        .flat_map(|table| table.iter())
+        .find(|(c, _)| *c == code)
+        .map(|(_, label)| *label)
+}
+
+/// What a `state` code means for this model's robots.
+pub fn state_label(model: Model, code: u64) -> Option<&'static str> {
+    find(&tables(model).0, code)
+}
+
+/// What an `error_code` means for this model's robots.
+pub fn error_label(model: Model, code: u64) -> Option<&'static str> {
+    find(&tables(model).1, code)
+}
+
+/// The labels for the codes in a robot's state, for the API and MQTT. Codes
+/// that aren't known for the model, or weren't reported, come out as null.
+pub fn labels(model: Model, state: &DeviceState) -> Value {
+    let code = |key| state.field(key).and_then(Value::as_u64);
+    json!({
+        "state": code("state").and_then(|c| state_label(model, c)),
+        "error": code("error_code").and_then(|c| error_label(model, c)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_depend_on_the_model() {
+        assert_eq!(state_label(Model::Gen1, 8), Some("Charging"));
+        assert_eq!(state_label(Model::Gen1, 18), None);
+        assert_eq!(state_label(Model::S5, 18), Some("Room cleaning"));
+        assert_eq!(state_label(Model::S5, 23), None);
+        assert_eq!(state_label(Model::S7, 23), Some("Washing the mop"));
+        assert_eq!(error_label(Model::S6, 26), None);
+        assert_eq!(error_label(Model::S7, 26), Some("Clean water tank empty"));
+
+        let state = DeviceState {
+            status: Some(json!({"state": 12, "error_code": 9})),
+            ..DeviceState::default()
+        };
+        assert_eq!(
+            labels(Model::S5, &state),
+            json!({"state": "Error", "error": "Dustbin missing"})
+        );
+        assert_eq!(
+            labels(Model::S5, &DeviceState::default()),
+            json!({"state": null, "error": null})
+        );
+    }
+}
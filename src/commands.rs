@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tracing::info;
+
+use crate::payload::{CommandPayload, ReplyPayload};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Keep our ids well clear of the robot's own counter so they're easy to tell
+// apart when reading logs.
+const FIRST_COMMAND_ID: u32 = 100_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("device {0} hasn't checked in yet")]
+    UnknownDevice(u32),
+    #[error("no cloud key configured for device {0}")]
+    NoKey(u32),
+    /// Firmware updates are blocked, see `[ota]`.
+    #[error("firmware updates are blocked")]
+    OtaBlocked,
+    /// It's inside the `[dnd]` window.
+    #[error("it's do not disturb time")]
+    DoNotDisturb,
+    #[error("timed out waiting for the robot to reply")]
+    Timeout,
+    #[error("could not send command: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Commands that have been sent to a robot and are waiting on its reply.
+pub struct PendingCommands {
+    next_id: AtomicU32,
+    pending: Mutex<HashMap<(u32, u32), oneshot::Sender<ReplyPayload>>>,
+}
+
+impl Default for PendingCommands {
+    fn default() -> Self {
+        PendingCommands {
+            next_id: AtomicU32::new(FIRST_COMMAND_ID),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PendingCommands {
+    /// Assigns an id to a command and starts waiting for the reply to it.
+    /// Returns the command to send.
+    pub fn start(
+        &self,
+        device_id: u32,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<(u32, Vec<u8>, oneshot::Receiver<ReplyPayload>), CommandError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let command = CommandPayload { id, method, params };
+        let message = serde_json::to_vec(&command).map_err(std::io::Error::from)?;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert((device_id, id), tx);
+        info!(device_id, id, method, "sending command");
+        Ok((id, message, rx))
+    }
+
+    /// Waits for the reply to a command from [`PendingCommands::start`].
+    pub async fn wait(
+        &self,
+        device_id: u32,
+        id: u32,
+        rx: oneshot::Receiver<ReplyPayload>,
+    ) -> Result<ReplyPayload, CommandError> {
+        match tokio::time::timeout(COMMAND_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            _ => {
+                self.cancel(device_id, id);
+                Err(CommandError::Timeout)
+            }
+        }
+    }
+
+    /// Stops waiting for a reply, e.g. because the command never went out.
+    pub fn cancel(&self, device_id: u32, id: u32) {
+        self.pending.lock().unwrap().remove(&(device_id, id));
+    }
+
+    pub fn is_pending(&self, device_id: u32, id: u32) -> bool {
+        self.pending.lock().unwrap().contains_key(&(device_id, id))
+    }
+
+    /// Hands a reply from the robot to whoever sent the matching command.
+    /// Returns false if nobody was waiting on it.
+    pub fn complete(&self, device_id: u32, reply: ReplyPayload) -> bool {
+        let waiting = self.pending.lock().unwrap().remove(&(device_id, reply.id));
+        match waiting {
+            Some(tx) => tx.send(reply).is_ok(),
+            None => false,
+        }
+    }
+}
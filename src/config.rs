@@ -0,0 +1,1147 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::auth::Access;
+use crate::cleaning::RoomRef;
+use crate::compat;
+use crate::models::{Model, Preset};
+use crate::mqtt;
+use crate::policy::Action;
+use crate::schedule::Cron;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Key used for any robot that isn't listed under `[[devices]]`. Like
+    /// the devices' keys, it can be `env:NAME` or `credential:NAME`.
+    pub cloud_key: Option<String>,
+    /// An age file encrypted with a passphrase, holding `cloud_key` and
+    /// `[devices]` keys by device id, unlocked at startup.
+    pub keys_file: Option<PathBuf>,
+    /// Model of any robot that isn't listed under `[[devices]]`, or doesn't
+    /// say.
+    pub model: Model,
+    /// How often robots are told to check in with `_otc.info`, and how long
+    /// after starting up to first test their connection, in seconds, in
+    /// place of what their model's preset says.
+    pub otc_interval: Option<u32>,
+    pub otc_firsttest: Option<u32>,
+    /// Which region's servers the robot thinks it's on, as answered to
+    /// `_sync.getctrycode`. Defaults to `DE`.
+    pub country: Option<String>,
+    /// A directory of `<method>.json` files whose contents are sent back as
+    /// the result of that method, overriding the built in handlers.
+    pub handler_dir: Option<PathBuf>,
+    /// rhai scripts that answer a method each, keyed by the method. These win
+    /// over templates and the built in handlers.
+    pub scripts: HashMap<String, PathBuf>,
+    /// Where to write a capture of every packet, see `--capture`.
+    pub capture: Option<PathBuf>,
+    pub listener: ListenerConfig,
+    pub advertise: AdvertiseConfig,
+    pub devices: Vec<DeviceConfig>,
+    pub logging: LoggingConfig,
+    pub storage: StorageConfig,
+    pub render: RenderConfig,
+    /// The MQTT bridge only runs when this section is present.
+    pub mqtt: Option<MqttConfig>,
+    pub notifications: Vec<NotificationConfig>,
+    pub webhooks: WebhookConfig,
+    /// Points are only written to InfluxDB when this section is present.
+    pub influx: Option<InfluxConfig>,
+    /// Applied to messages from the robot before they go out over MQTT and
+    /// webhooks.
+    pub rules: Vec<RuleConfig>,
+    /// Holds back replies to matching calls, to see how robots cope with a
+    /// slow cloud.
+    pub delays: Vec<DelayConfig>,
+    /// The NTP server only runs when this section is present.
+    pub ntp: Option<NtpConfig>,
+    /// The DNS server only runs when this section is present.
+    pub dns: Option<DnsConfig>,
+    /// The discovery responder only runs when this section is present.
+    pub discovery: Option<DiscoveryConfig>,
+    pub session: SessionConfig,
+    /// Robots are only pinged when this section is present.
+    pub keepalive: Option<KeepaliveConfig>,
+    pub limits: LimitsConfig,
+    pub ota: OtaConfig,
+    /// Proxy mode only runs when this section is present, see `--proxy`.
+    pub proxy: Option<ProxyConfig>,
+    pub schedule: ScheduleConfig,
+    /// Consumables are only polled when this section is present.
+    pub consumables: Option<ConsumablesConfig>,
+    /// Commands are only held back at night when this section is present.
+    pub dnd: Option<DndConfig>,
+    /// HTTPS is only served when this section is present.
+    pub tls: Option<TlsConfig>,
+    /// Upload URLs are only signed and checked when this section is present.
+    pub fds: Option<FdsConfig>,
+    /// The API only takes requests with a token when this section is
+    /// present.
+    pub auth: Option<AuthConfig>,
+    /// The gRPC service only runs when this section is present, and only
+    /// in builds with the `grpc` feature.
+    pub grpc: Option<GrpcConfig>,
+    /// The Valetudo-compatible API is only served when this section is
+    /// present.
+    pub valetudo: Option<ValetudoConfig>,
+    /// Packets to robots are only tampered with when this section is
+    /// present.
+    pub chaos: Option<ChaosConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /// One address or a list of them to listen for robots on. Replies go
+    /// out from whichever one the robot's packet came in on.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+    /// Keeps the robot listener to one network interface, Linux only.
+    pub interface: Option<String>,
+    pub http_bind: SocketAddr,
+    pub control_bind: SocketAddr,
+    /// Extra sockets, each only answering its own robots.
+    pub tenants: Vec<TenantConfig>,
+    /// The biggest packet taken from a robot, in bytes. Bigger ones are
+    /// dropped rather than cut short.
+    pub max_datagram: usize,
+    /// Tasks packets are handled on, each robot's always on the same one so
+    /// they're answered in order. 0 is one per CPU.
+    pub workers: usize,
+}
+
+/// A socket of its own for some of the robots, e.g. when each VLAN's cloud
+/// traffic gets NATed to a different local port. Robots listed here are
+/// only answered on their tenant's socket, and the tenant's socket only
+/// answers them.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TenantConfig {
+    pub bind: SocketAddr,
+    /// Keeps the socket to one network interface, Linux only. Defaults to
+    /// `[listener] interface`.
+    pub interface: Option<String>,
+    pub devices: Vec<u32>,
+    /// What these robots are told to find us at in `_otc.info`, instead of
+    /// `[advertise] ip` and `port`.
+    pub advertise_ip: Option<IpAddr>,
+    pub advertise_port: Option<u16>,
+}
+
+/// Where the robot is told to find us, which isn't necessarily where we're
+/// bound when running behind NAT or in a container.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct AdvertiseConfig {
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    pub http_port: u16,
+    /// Only used with `[tls]`.
+    pub https_port: u16,
+}
+
+/// Makes the upload URLs behave like Xiaomi's FDS object store's, for
+/// firmwares that check: each is signed and expires `expiry` seconds after
+/// it was handed out, and uploads and downloads with a missing, wrong or
+/// expired signature are turned away.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FdsConfig {
+    /// What URLs are signed with. Defaults to a random one each start, so
+    /// URLs handed out before a restart stop working.
+    pub secret: String,
+    pub expiry: u64,
+    /// Turns away a second upload to the same URL.
+    pub single_use: bool,
+}
+
+/// Keeps the HTTP API, the WebSocket event stream and MQTT commands to
+/// whoever has one of `tokens`, sent as a bearer token over HTTP and as
+/// `token` in MQTT commands.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: Vec<TokenConfig>,
+    /// Takes Home Assistant's commands, which can't carry a token, anyway,
+    /// leaving it to the broker to keep its command topic to itself.
+    pub trust_homeassistant: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    /// `read`, or `command` to send commands too, which is the default.
+    #[serde(default)]
+    pub access: Access,
+}
+
+/// Serves `proto/dummycloud.proto`, for home automation that would rather
+/// speak gRPC than JSON. It takes the same `[auth]` tokens as the API.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GrpcConfig {
+    pub bind: SocketAddr,
+}
+
+/// Answers a part of Valetudo's API, for its companion apps and the
+/// Lovelace map card.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ValetudoConfig {
+    /// Which robot it's about, needed once more than one has checked in.
+    pub device_id: Option<u32>,
+}
+
+/// Serves the HTTP routes over HTTPS as well and hands out `https://` upload
+/// URLs, for firmwares that won't upload over plain HTTP. Without `cert` and
+/// `key`, both PEM files, a self-signed certificate is made up at startup.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub bind: SocketAddr,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeviceConfig {
+    pub id: u32,
+    /// Written out, or `env:NAME` or `credential:NAME` to be read at
+    /// startup, see [`crate::secrets`]. Left out, it has to come from
+    /// `keys_file`.
+    #[serde(default)]
+    pub key: String,
+    /// What to call the robot in logs, MQTT topics, the API and `dummycloud
+    /// status`, e.g. "Upstairs S5", rather than by its device id.
+    pub name: Option<String>,
+    pub model: Option<Model>,
+    /// Override `otc_interval` and `otc_firsttest` for just this robot.
+    pub otc_interval: Option<u32>,
+    pub otc_firsttest: Option<u32>,
+    /// Names for the robot's rooms, e.g. `{ Kitchen = 16 }`, by the segment
+    /// ids in its map.
+    #[serde(default)]
+    pub rooms: HashMap<String, u8>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Either a plain level like `debug`, or a full filter directive like
+    /// `info,dummycloud=trace`.
+    pub level: String,
+    pub json: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub map_dir: PathBuf,
+    /// Where the robot's log and crash dump uploads end up.
+    pub log_dir: PathBuf,
+    /// How many uploads of each kind to keep per device, 0 for all of them.
+    pub keep: usize,
+    /// Where voice packs pushed to the robots are served from.
+    pub voice_dir: PathBuf,
+    /// Where robots, their state and their stats are kept across restarts.
+    /// They're only kept in memory if this isn't set.
+    pub database: Option<PathBuf>,
+    /// How many days of messages the database keeps for the history API.
+    pub history_days: u64,
+}
+
+/// How `/api/devices/<device_id>/map.png` draws the map.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RenderConfig {
+    /// How many pixels wide each map pixel is drawn, a map pixel being 5cm.
+    pub scale: u32,
+    pub background: Color,
+    pub floor: Color,
+    pub obstacle: Color,
+    /// Whether to draw where the robot has been.
+    pub draw_path: bool,
+    pub path: Color,
+    pub charger: Color,
+    pub robot: Color,
+}
+
+/// A color written as `#rrggbb`, or `#rrggbbaa` to make it see-through.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Color, String> {
+        let hex = s
+            .strip_prefix('#')
+            .filter(|h| (h.len() == 6 || h.len() == 8) && h.is_ascii())
+            .ok_or_else(|| format!("{} isn't a #rrggbb or #rrggbbaa color", s))?;
+        let mut rgba = [0, 0, 0, 0xff];
+        for (i, channel) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("{} isn't a #rrggbb or #rrggbbaa color", s))?;
+        }
+        Ok(Color(rgba))
+    }
+}
+
+/// Topics may contain `{device_id}`, which is swapped out for the id of the
+/// robot the message is about, or `{name}` for its name under `[[devices]]`,
+/// lower case with underscores for spaces, or its id if it hasn't got one.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Which decoded methods get published, and where to.
+    pub topics: HashMap<String, String>,
+    /// Commands published here are forwarded to the robot, and its reply is
+    /// published to the same topic with `/reply` tacked on.
+    pub command_topic: String,
+    /// Where the robot's connection state (`handshake`, `time_synced`,
+    /// `established` or `stale`) is published, retained.
+    pub connection_topic: String,
+    /// Where each robot's consumable wear is published, retained.
+    pub consumables_topic: String,
+    /// Where warnings like worn out parts are published.
+    pub alert_topic: String,
+    /// Home Assistant discovery is only announced when this section is
+    /// present.
+    pub homeassistant: Option<HomeAssistantConfig>,
+}
+
+/// Makes robots show up in Home Assistant as a vacuum with battery and error
+/// sensors. Topics may contain `{device_id}` like the other MQTT topics.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HomeAssistantConfig {
+    /// Home Assistant's discovery prefix.
+    pub prefix: String,
+    pub state_topic: String,
+    /// Takes Home Assistant's vacuum commands like `start` or
+    /// `return_to_base`.
+    pub command_topic: String,
+}
+
+/// Something to do when the robot sends one of `methods`, for example
+/// running a script when the dustbin is full.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotificationConfig {
+    pub methods: Vec<String>,
+    /// Run with the details in `DUMMYCLOUD_DEVICE_ID`, `DUMMYCLOUD_METHOD`
+    /// and `DUMMYCLOUD_PARAMS`.
+    pub exec: Option<String>,
+    /// POSTed the message as JSON.
+    pub webhook: Option<String>,
+}
+
+/// Every decoded message is POSTed to each of `urls`. Failed deliveries are
+/// retried up to `retries` times, waiting `backoff_ms` before the first retry
+/// and twice as long before each one after that.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub retries: u32,
+    pub backoff_ms: u64,
+}
+
+/// Writes numbers from the robot's messages as line protocol points, POSTed
+/// to `url` (an InfluxDB write endpoint), appended to `file`, or both, every
+/// `flush_secs`. Points are tagged with the robot's device id, name and the
+/// method.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InfluxConfig {
+    pub url: Option<String>,
+    /// Sent as `Authorization: Token <token>`.
+    pub token: Option<String>,
+    pub file: Option<PathBuf>,
+    pub measurement: String,
+    pub flush_secs: u64,
+    /// The fields to write for each method, which may end in `*` to match a
+    /// prefix, along with the param each is taken from: a field of the
+    /// params (or of the first object in them) or a JSON pointer.
+    pub fields: HashMap<String, HashMap<String, String>>,
+}
+
+/// Drops, cuts short or holds back for `delay_ms` the given percentages of
+/// the packets sent to robots, to see how they, the integrations and our
+/// own retries and keep-alives cope with a bad network.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChaosConfig {
+    pub drop_percent: f64,
+    pub truncate_percent: f64,
+    pub delay_percent: f64,
+    pub delay_ms: u64,
+}
+
+/// Waits `ms`, and up to `jitter_ms` more picked at random, before
+/// answering calls whose method matches `method`, which may end in `*` to
+/// match a prefix.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DelayConfig {
+    pub method: String,
+    pub ms: u64,
+    pub jitter_ms: u64,
+}
+
+/// Drops, renames or adds to messages from the robot whose method matches
+/// `method`, which may end in `*` to match a prefix, before they're passed
+/// on over MQTT and webhooks.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub method: String,
+    pub drop: bool,
+    pub rename: Option<String>,
+    /// Names for the codes found in a field of the params, keyed by the
+    /// field, e.g. `state`. They're added next to it as `state_name`.
+    pub names: HashMap<String, HashMap<String, String>>,
+    /// Drops a message that repeats the last one passed on less than this
+    /// many seconds ago. 0 passes them all on.
+    pub dedupe_secs: u64,
+}
+
+/// Some firmwares also want NTP to work before they're happy with the time,
+/// even though we answer the timesync handshake.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct NtpConfig {
+    pub bind: SocketAddr,
+}
+
+/// Answers queries for `domains` with our advertised address, so handing
+/// the robot this server over DHCP is all it takes to redirect it. Patterns
+/// like `*.ot.io.mi.com` match any subdomain. Everything else is forwarded
+/// to `upstream`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub bind: SocketAddr,
+    pub upstream: SocketAddr,
+    pub domains: Vec<String>,
+}
+
+/// Answers miio hello packets on the robot's behalf while it's cut off from
+/// the cloud, for every robot that has checked in or only those in
+/// `devices`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub bind: SocketAddr,
+    pub devices: Vec<u32>,
+}
+
+/// The robot's stamp counts up from when it booted, so a packet with a stamp
+/// lower than the last one is a replay or garbage, unless the robot has
+/// just restarted.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Drop packets whose stamp went backwards instead of only logging them.
+    pub enforce: bool,
+    /// How many seconds a stamp may go backwards by, for packets that
+    /// arrive out of order.
+    pub tolerance: u32,
+    /// A stamp this low (in seconds) is taken to mean the robot rebooted
+    /// rather than that the packet is stale.
+    pub reboot_window: u32,
+    /// Seconds without a packet before a robot is considered gone.
+    pub idle_timeout: u64,
+    /// Decrypt packets whose checksum doesn't match instead of dropping
+    /// them, see `--lenient`.
+    pub lenient: bool,
+    /// After this many packets in a row from a robot fail to decrypt, its
+    /// key is reported as wrong and its keep-alives aren't echoed any more.
+    /// 0 never gives up on it.
+    pub wrong_key_after: u32,
+    /// Milliseconds a handler may take to answer before it's logged as
+    /// slow. Robots that have to wait too long decide the cloud is gone.
+    pub handler_budget_ms: u64,
+    /// Packets waiting to go out to a robot before the oldest are dropped,
+    /// e.g. when it asks for a burst of upload URLs faster than they can be
+    /// sent.
+    pub send_queue: usize,
+    /// Seconds a robot's clock may drift from ours, going by its stamps,
+    /// before it's warned about. Robots that drift far enough start turning
+    /// down our timesyncs and reconnecting over and over. 0 never warns.
+    pub max_drift: u32,
+}
+
+/// Some firmwares give up on the cloud when it never says anything unasked,
+/// so established robots get sent an empty packet every `interval` seconds.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    pub interval: u64,
+}
+
+/// Asks established robots how worn their brushes, filter and sensors are
+/// every `interval` seconds, warning once a part has `warn_below` percent of
+/// its life or less left.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConsumablesConfig {
+    pub interval: u64,
+    pub warn_below: u8,
+}
+
+/// A window of local time, possibly running past midnight, during which
+/// dummycloud won't send the robot anything but the `allow`ed methods,
+/// whether it's a scheduled job or comes from the API, MQTT or the control
+/// socket.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DndConfig {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+    /// Methods, or prefixes ending in `*`, that still go through.
+    pub allow: Vec<String>,
+    /// Also give the robot the window with `set_dnd_timer`, so it keeps its
+    /// voice down too.
+    pub set_robot: bool,
+}
+
+/// Keeps the robot on the firmware it has. With `block` on, the robot is
+/// told there's never an update, its cloud storage calls are turned down and
+/// `miIO.ota` commands aren't sent to it.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct OtaConfig {
+    pub block: bool,
+}
+
+/// Relays robots to the real cloud instead of answering them ourselves.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Where the genuine OT server is, as `host:port`.
+    pub upstream: String,
+    /// A proxy to reach `upstream` through, `socks5://host:port` or
+    /// `http://host:port`, with `user:password@` before the host if it
+    /// wants a login. HTTP proxies only tunnel TCP, so through one the
+    /// packets go to `upstream` over TCP.
+    pub via: Option<String>,
+    /// What to do with calls no rule matches.
+    pub default: Action,
+    /// Rules by method name, or by prefix if it ends in `*`.
+    pub methods: HashMap<String, Action>,
+    /// Rules for single robots, which take precedence over the ones above.
+    pub devices: Vec<DevicePolicyConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DevicePolicyConfig {
+    pub id: u32,
+    pub default: Option<Action>,
+    #[serde(default)]
+    pub methods: HashMap<String, Action>,
+}
+
+/// Cleanings dummycloud starts by itself, now that the app's cloud can't.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Days jobs with `skip_holidays` don't run on, as `2026-12-25`.
+    pub holidays: Vec<chrono::NaiveDate>,
+    pub jobs: Vec<JobConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobConfig {
+    pub name: String,
+    pub cron: Cron,
+    pub device_id: u32,
+    /// What to send, unless `rooms` is given.
+    #[serde(default = "start_cleaning")]
+    pub method: String,
+    #[serde(default = "no_params")]
+    pub params: serde_json::Value,
+    /// Cleans just these rooms with `app_segment_clean` instead.
+    #[serde(default)]
+    pub rooms: Vec<RoomRef>,
+    #[serde(default = "yes")]
+    pub skip_holidays: bool,
+}
+
+fn start_cleaning() -> String {
+    String::from("app_start")
+}
+
+fn no_params() -> serde_json::Value {
+    serde_json::json!([])
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Flood protection for the robot listener.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Packets per second each source address may send, 0 for no limit.
+    pub rate: f64,
+    /// How many packets a source may send at once before `rate` kicks in.
+    pub burst: u32,
+    /// Only answer these addresses, or anyone if empty.
+    pub allow_ips: Vec<IpAddr>,
+    /// Only answer these device ids, or any if empty.
+    pub allow_devices: Vec<u32>,
+}
+
+// So a single `bind = "..."` keeps working next to lists of addresses.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("could not parse config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl Config {
+    /// Loads a TOML config, or the Node.js dummycloud's `config.json` for
+    /// anyone moving over from it.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            return compat::parse(&contents);
+        }
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(contents)?;
+        // names are told apart the way they are in MQTT topics
+        let mut names = HashSet::new();
+        for name in config.devices.iter().filter_map(|d| d.name.as_deref()) {
+            let slug = mqtt::slug(name);
+            if slug.parse::<u32>().is_ok() {
+                return Err(ConfigError::Invalid(format!(
+                    "{} would be taken for a device id, robots need names with letters in",
+                    name
+                )));
+            }
+            if !names.insert(slug) {
+                return Err(ConfigError::Invalid(format!(
+                    "more than one robot is called {}",
+                    name
+                )));
+            }
+        }
+        if let Some(influx) = &config.influx {
+            if influx.url.is_none() && influx.file.is_none() {
+                return Err(ConfigError::Invalid(String::from(
+                    "[influx] needs a url or a file to write to",
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn key_for(&self, device_id: u32) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .map(|d| d.key.as_str())
+            .or(self.cloud_key.as_deref())
+    }
+
+    /// What the robot is called under `[[devices]]`, if it's named.
+    pub fn name_for(&self, device_id: u32) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .and_then(|d| d.name.as_deref())
+    }
+
+    pub fn model_for(&self, device_id: u32) -> Model {
+        self.devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .and_then(|d| d.model)
+            .unwrap_or(self.model)
+    }
+
+    /// The robot's model's preset, with the check-in interval and first
+    /// connection test put off as long as the config says.
+    pub fn preset_for(&self, device_id: u32) -> Preset {
+        let mut preset = self.model_for(device_id).preset();
+        let device = self.devices.iter().find(|d| d.id == device_id);
+        if let Some(interval) = device.and_then(|d| d.otc_interval).or(self.otc_interval) {
+            preset.otc_interval = interval;
+        }
+        if let Some(firsttest) = device.and_then(|d| d.otc_firsttest).or(self.otc_firsttest) {
+            preset.otc_firsttest = firsttest;
+        }
+        preset
+    }
+
+    /// What the robot's room with this segment id is called, if it's named.
+    pub fn room_name(&self, device_id: u32, segment: u8) -> Option<&str> {
+        let device = self.devices.iter().find(|d| d.id == device_id)?;
+        device
+            .rooms
+            .iter()
+            .find(|(_, id)| **id == segment)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The segment id of the robot's room with this name.
+    pub fn room_segment(&self, device_id: u32, name: &str) -> Option<u8> {
+        let device = self.devices.iter().find(|d| d.id == device_id)?;
+        device.rooms.get(name).copied()
+    }
+
+    /// The segment id of a room given by id or name, unnamed rooms going by
+    /// `Room 17`.
+    pub fn resolve_room(&self, device_id: u32, room: &RoomRef) -> Option<u8> {
+        match room {
+            RoomRef::Id(id) => Some(*id),
+            RoomRef::Name(name) => self
+                .room_segment(device_id, name)
+                .or_else(|| name.strip_prefix("Room ")?.parse().ok()),
+        }
+    }
+
+    pub fn has_keys(&self) -> bool {
+        self.cloud_key.is_some() || !self.devices.is_empty()
+    }
+
+    /// Moves a listener to another port, keeping the address it binds to:
+    /// `robots`, `http`, `control`, `https`, `grpc`, `ntp`, `dns` or
+    /// `discovery`.
+    /// What the robot is told to find us at stays as it is, so a container
+    /// can listen on unprivileged ports and leave the mapping to the host.
+    pub fn set_port(&mut self, listener: &str, port: u16) -> Result<(), String> {
+        let section = if listener == "https" { "tls" } else { listener };
+        let absent = || format!("there's no [{}] section to move", section);
+        match listener {
+            "robots" => self.listener.bind.iter_mut().for_each(|a| a.set_port(port)),
+            "http" => self.listener.http_bind.set_port(port),
+            "control" => self.listener.control_bind.set_port(port),
+            "https" => self.tls.as_mut().ok_or_else(absent)?.bind.set_port(port),
+            "grpc" => self.grpc.as_mut().ok_or_else(absent)?.bind.set_port(port),
+            "ntp" => self.ntp.as_mut().ok_or_else(absent)?.bind.set_port(port),
+            "dns" => self.dns.as_mut().ok_or_else(absent)?.bind.set_port(port),
+            "discovery" => self
+                .discovery
+                .as_mut()
+                .ok_or_else(absent)?
+                .bind
+                .set_port(port),
+            _ => {
+                return Err(format!(
+                "there's no {} listener, only robots, http, control, https, grpc, ntp, dns and discovery",
+                listener
+            ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            bind: vec![([0, 0, 0, 0], 8053).into()],
+            interface: None,
+            http_bind: ([0, 0, 0, 0], 8079).into(),
+            control_bind: ([127, 0, 0, 1], 8054).into(),
+            tenants: Vec::new(),
+            max_datagram: 65535,
+            workers: 0,
+        }
+    }
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            drop_percent: 0.0,
+            truncate_percent: 0.0,
+            delay_percent: 0.0,
+            delay_ms: 1000,
+        }
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            bind: ([0, 0, 0, 0], 50051).into(),
+        }
+    }
+}
+
+impl Default for AdvertiseConfig {
+    fn default() -> Self {
+        AdvertiseConfig {
+            ip: None,
+            port: 8053,
+            http_port: 8079,
+            https_port: 8443,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            scale: 4,
+            background: Color([0, 0, 0, 0]),
+            floor: Color([0x56, 0xaf, 0xfc, 0xff]),
+            obstacle: Color([0x1b, 0x4f, 0x8a, 0xff]),
+            draw_path: true,
+            path: Color([0xff, 0xff, 0xff, 0xff]),
+            charger: Color([0x2e, 0xcc, 0x71, 0xff]),
+            robot: Color([0xe7, 0x4c, 0x3c, 0xff]),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            map_dir: PathBuf::from("maps"),
+            log_dir: PathBuf::from("logs"),
+            keep: 10,
+            voice_dir: PathBuf::from("voices"),
+            database: None,
+            history_days: 30,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            urls: Vec::new(),
+            retries: 5,
+            backoff_ms: 500,
+        }
+    }
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        let status = ["battery", "state", "error_code", "clean_area"]
+            .iter()
+            .map(|f| (f.to_string(), f.to_string()))
+            .collect();
+        let props = std::iter::once(("battery".to_string(), "battery".to_string())).collect();
+        InfluxConfig {
+            url: None,
+            token: None,
+            file: None,
+            measurement: String::from("dummycloud"),
+            flush_secs: 10,
+            fields: vec![
+                (String::from("event.status"), status),
+                (String::from("props"), props),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+}
+
+impl Default for NtpConfig {
+    fn default() -> Self {
+        NtpConfig {
+            bind: ([0, 0, 0, 0], 123).into(),
+        }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        let domains = [
+            "ot.io.mi.com",
+            "*.ot.io.mi.com",
+            "ott.io.mi.com",
+            "*.ott.io.mi.com",
+        ];
+        DnsConfig {
+            bind: ([0, 0, 0, 0], 53).into(),
+            upstream: ([1, 1, 1, 1], 53).into(),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            bind: ([0, 0, 0, 0], crate::handshake::MIIO_PORT).into(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            enforce: true,
+            tolerance: 5,
+            reboot_window: 300,
+            idle_timeout: 300,
+            lenient: false,
+            wrong_key_after: 5,
+            handler_budget_ms: 100,
+            send_queue: 64,
+            max_drift: 30,
+        }
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig { interval: 60 }
+    }
+}
+
+impl Default for ConsumablesConfig {
+    fn default() -> Self {
+        ConsumablesConfig {
+            interval: 3600,
+            warn_below: 10,
+        }
+    }
+}
+
+impl Default for FdsConfig {
+    fn default() -> Self {
+        // without a secret of its own, a different one each run will do
+        FdsConfig {
+            secret: crate::codec::to_hex(&crate::codec::random_bytes::<16>()),
+            expiry: 3600,
+            single_use: true,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            bind: ([0, 0, 0, 0], 8443).into(),
+            cert: None,
+            key: None,
+        }
+    }
+}
+
+impl Default for DndConfig {
+    fn default() -> Self {
+        DndConfig {
+            start: chrono::NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: chrono::NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+            allow: ["get_*", "app_stop", "app_pause", "app_charge"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            set_robot: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            rate: 20.0,
+            burst: 40,
+            allow_ips: Vec::new(),
+            allow_devices: Vec::new(),
+        }
+    }
+}
+
+impl Default for OtaConfig {
+    fn default() -> Self {
+        OtaConfig { block: true }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            upstream: String::from("ot.io.mi.com:8053"),
+            via: None,
+            default: Action::Forward,
+            methods: HashMap::new(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        let topics = [
+            ("props", "dummycloud/{device_id}/props"),
+            ("event.status", "dummycloud/{device_id}/status"),
+            (
+                "event.low_power_back",
+                "dummycloud/{device_id}/low_power_back",
+            ),
+        ];
+        MqttConfig {
+            host: String::from("localhost"),
+            port: 1883,
+            client_id: String::from("dummycloud"),
+            username: None,
+            password: None,
+            topics: topics
+                .iter()
+                .map(|(method, topic)| (method.to_string(), topic.to_string()))
+                .collect(),
+            command_topic: String::from("dummycloud/{device_id}/command"),
+            connection_topic: String::from("dummycloud/{device_id}/connection"),
+            consumables_topic: String::from("dummycloud/{device_id}/consumables"),
+            alert_topic: String::from("dummycloud/{device_id}/alert"),
+            homeassistant: None,
+        }
+    }
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        HomeAssistantConfig {
+            prefix: String::from("homeassistant"),
+            state_topic: String::from("dummycloud/{device_id}/ha/state"),
+            command_topic: String::from("dummycloud/{device_id}/ha/command"),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: String::from("info"),
+            json: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.listener.bind, vec![([0, 0, 0, 0], 8053).into()]);
+        assert_eq!(config.advertise.http_port, 8079);
+        assert!(!config.has_keys());
+    }
+
+    #[test]
+    fn device_keys_take_precedence_over_cloud_key() {
+        let config = Config::parse(
+            r#"
+            cloud_key = "fallback"
+
+            [advertise]
+            ip = "192.168.1.2"
+
+            [[devices]]
+            id = 1234
+            key = "specific"
+            name = "Upstairs S5"
+            model = "s7"
+            otc_interval = 600
+            rooms = { Kitchen = 16 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.advertise.ip, Some([192, 168, 1, 2].into()));
+        let listener =
+            Config::parse("[listener]\nbind = [\"0.0.0.0:8053\", \"[::]:8053\"]").unwrap();
+        assert_eq!(listener.listener.bind.len(), 2);
+        assert_eq!(config.key_for(1234), Some("specific"));
+        assert_eq!(config.key_for(5678), Some("fallback"));
+        assert_eq!(config.model_for(1234), Model::S7);
+        assert_eq!(config.model_for(5678), Model::S5);
+        let s7 = config.preset_for(1234);
+        assert_eq!((s7.otc_interval, s7.otc_firsttest), (600, 600));
+        assert_eq!(config.preset_for(5678), Model::S5.preset());
+        assert_eq!(config.room_name(1234, 16), Some("Kitchen"));
+        assert_eq!(config.room_name(1234, 17), None);
+        assert_eq!(config.name_for(1234), Some("Upstairs S5"));
+        assert_eq!(config.name_for(5678), None);
+        let twice = "[[devices]]\nid = 1\nname = \"S5\"\n[[devices]]\nid = 2\nname = \"s5\"";
+        assert!(matches!(Config::parse(twice), Err(ConfigError::Invalid(_))));
+        let same_topic = "[[devices]]\nid = 1\nname = \"Upstairs S5\"\n[[devices]]\nid = 2\nname = \"upstairs-s5\"";
+        assert!(matches!(
+            Config::parse(same_topic),
+            Err(ConfigError::Invalid(_))
+        ));
+        let numbered = "[[devices]]\nid = 1\nname = \"678\"";
+        assert!(matches!(
+            Config::parse(numbered),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert_eq!(config.room_segment(1234, "Kitchen"), Some(16));
+        let room = |name: &str| RoomRef::Name(name.to_string());
+        assert_eq!(config.resolve_room(1234, &room("Room 17")), Some(17));
+        assert_eq!(config.resolve_room(1234, &room("Attic")), None);
+    }
+
+    #[test]
+    fn moves_listeners_to_other_ports() {
+        let mut config = Config::parse("[ntp]\nbind = \"0.0.0.0:123\"").unwrap();
+        config.set_port("robots", 18053).unwrap();
+        config.set_port("ntp", 1123).unwrap();
+        assert_eq!(config.listener.bind, vec![([0, 0, 0, 0], 18053).into()]);
+        assert_eq!(
+            config.ntp.as_ref().unwrap().bind,
+            ([0, 0, 0, 0], 1123).into()
+        );
+        assert_eq!(config.advertise.port, 8053);
+        assert_eq!(
+            config.set_port("dns", 1053),
+            Err("there's no [dns] section to move".to_string())
+        );
+        assert!(config.set_port("mqtt", 1883).is_err());
+    }
+
+    #[test]
+    fn parses_colors() {
+        let config = Config::parse("[render]\nfloor = \"#102030\"\npath = \"#ffffff80\"").unwrap();
+        assert_eq!(config.render.floor, Color([0x10, 0x20, 0x30, 0xff]));
+        assert_eq!(config.render.path, Color([0xff, 0xff, 0xff, 0x80]));
+        assert!(Config::parse("[render]\nfloor = \"blue\"").is_err());
+        assert!(Config::parse("[render]\nfloor = \"#12345\"").is_err());
+    }
+}
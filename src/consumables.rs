@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::config::ConsumablesConfig;
+use crate::devices::Connection;
+use crate::events::{ConsumableReport, Event};
+use crate::Context;
+
+/// What `get_consumable` reports, how it's called here and how many hours
+/// each part lasts before it should be swapped or cleaned.
+const PARTS: [(&str, &str, f64); 4] = [
+    ("main_brush_work_time", "main_brush", 300.0),
+    ("side_brush_work_time", "side_brush", 200.0),
+    ("filter_work_time", "filter", 150.0),
+    ("sensor_dirty_time", "sensor", 30.0),
+];
+
+/// One part's wear.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Part {
+    pub name: &'static str,
+    /// What `reset_consumable` takes to start this part over.
+    pub key: &'static str,
+    pub used_hours: f64,
+    /// How much of its life is left, as a percentage.
+    pub remaining: u8,
+}
+
+/// Picks the parts out of a `get_consumable` reply, which is either the
+/// object itself or a list holding it.
+pub fn parse(result: &Value) -> Vec<Part> {
+    let counters = match result {
+        Value::Array(list) => list.first().unwrap_or(&Value::Null),
+        other => other,
+    };
+    PARTS
+        .iter()
+        .filter_map(|&(key, name, lifetime)| {
+            let used_hours = counters.get(key)?.as_u64()? as f64 / 3600.0;
+            let remaining = (100.0 - used_hours / lifetime * 100.0).clamp(0.0, 100.0);
+            Some(Part {
+                name,
+                key,
+                used_hours,
+                remaining: remaining.round() as u8,
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Reading {
+    pub parts: Vec<Part>,
+    pub updated: u64,
+}
+
+/// The last wear reported by each robot, and which parts we've already
+/// warned about.
+#[derive(Default)]
+pub struct ConsumableStore {
+    readings: Mutex<HashMap<u32, Reading>>,
+    warned: Mutex<HashSet<(u32, &'static str)>>,
+}
+
+impl ConsumableStore {
+    /// Keeps a reading, returning the parts that have just dropped to
+    /// `warn_below` percent or less. A part only comes up again once it's
+    /// been reset.
+    pub fn update(&self, device_id: u32, parts: Vec<Part>, warn_below: u8, now: u64) -> Vec<Part> {
+        let mut warned = self.warned.lock().unwrap();
+        let mut worn = Vec::new();
+        for part in &parts {
+            if part.remaining > warn_below {
+                warned.remove(&(device_id, part.name));
+            } else if warned.insert((device_id, part.name)) {
+                worn.push(part.clone());
+            }
+        }
+        self.readings.lock().unwrap().insert(
+            device_id,
+            Reading {
+                parts,
+                updated: now,
+            },
+        );
+        worn
+    }
+
+    pub fn get(&self, device_id: u32) -> Option<Reading> {
+        self.readings.lock().unwrap().get(&device_id).cloned()
+    }
+}
+
+/// The parts `reset_consumable` can start over, by name.
+pub fn reset_key(name: &str) -> Option<&'static str> {
+    PARTS.iter().find(|p| p.1 == name).map(|p| p.0)
+}
+
+/// Asks the robot how worn its parts are and passes on what it says.
+pub(crate) async fn poll(device_id: u32, config: &ConsumablesConfig, context: &Context) {
+    let reply = match context
+        .send_command(device_id, "get_consumable", &json!([]))
+        .await
+    {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!(device_id, error = %e, "could not ask the robot about its consumables");
+            return;
+        }
+    };
+    let parts = match &reply.result {
+        Some(result) => parse(result),
+        None => return,
+    };
+    if parts.is_empty() {
+        debug!(device_id, "robot didn't report any consumables");
+        return;
+    }
+    let now = context.clock.epoch_secs();
+    let worn = context
+        .consumables
+        .update(device_id, parts.clone(), config.warn_below, now);
+    for part in &worn {
+        info!(
+            device_id,
+            part = part.name,
+            remaining = part.remaining,
+            "part is worn out"
+        );
+    }
+    context.events.publish(Event::Consumables(ConsumableReport {
+        device_id,
+        parts,
+        worn,
+        timestamp: now,
+    }));
+}
+
+/// Polls every established robot's consumables every `interval` seconds.
+pub(crate) async fn run(config: ConsumablesConfig, context: Arc<Context>) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval));
+    loop {
+        ticks.tick().await;
+        for device in context.devices.all() {
+            if device.connection == Connection::Established {
+                poll(device.id, &config, &context).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_once_per_worn_part() {
+        let reply = json!([{
+            "main_brush_work_time": 54000,
+            "side_brush_work_time": 684000,
+            "filter_work_time": 486000,
+            "sensor_dirty_time": 0,
+            "dust_collection_work_times": 3
+        }]);
+        let parts = parse(&reply);
+        let remaining: Vec<(&str, u8)> = parts.iter().map(|p| (p.name, p.remaining)).collect();
+        assert_eq!(
+            remaining,
+            [
+                ("main_brush", 95),
+                ("side_brush", 5),
+                ("filter", 10),
+                ("sensor", 100)
+            ]
+        );
+
+        let store = ConsumableStore::default();
+        let worn: Vec<&str> = store
+            .update(1, parts.clone(), 10, 100)
+            .iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(worn, ["side_brush", "filter"]);
+        assert!(store.update(1, parts.clone(), 10, 200).is_empty());
+        assert_eq!(store.get(1).unwrap().updated, 200);
+
+        // once it's been reset it can wear out all over again
+        let mut reset = parts.clone();
+        reset[2].remaining = 100;
+        store.update(1, reset, 10, 300);
+        assert_eq!(store.update(1, parts, 10, 400)[0].name, "filter");
+        assert_eq!(reset_key("filter"), Some("filter_work_time"));
+    }
+}
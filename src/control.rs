@@ -0,0 +1,292 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+use crate::codec::epoch_secs;
+use crate::config::ListenerConfig;
+use crate::devices::Device;
+use crate::error::Result;
+use crate::settings;
+use crate::Context;
+
+/// One request per line on the control socket, answered with one line of
+/// JSON. For example:
+///
+/// `{"type": "send", "device_id": 12345, "method": "get_status", "params": []}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlRequest {
+    Devices,
+    Status,
+    Send {
+        device_id: u32,
+        method: String,
+        #[serde(default = "no_params")]
+        params: serde_json::Value,
+    },
+    /// One of [`crate::settings`], e.g. `{"type": "set", "device_id": 12345,
+    /// "setting": "fan_speed", "value": "turbo"}`.
+    Set {
+        device_id: u32,
+        setting: String,
+        value: String,
+    },
+}
+
+fn no_params() -> serde_json::Value {
+    json!([])
+}
+
+fn status_json(device: &Device, context: &Context, now: SystemTime) -> serde_json::Value {
+    let stats = context.stats.get(device.id);
+    let connected_for = now
+        .duration_since(device.connected_since)
+        .map_or(0, |d| d.as_secs());
+    json!({
+        "id": device.id,
+        "name": context.config.name_for(device.id),
+        "addr": device.addr,
+        "connection": device.connection,
+        "connected_for": connected_for,
+        "last_seen": device.last_seen_secs(),
+        "drift": device.drift,
+        "packets": stats.packets,
+        "methods": stats.methods,
+        "last_map_upload": stats.last_map_upload,
+        "decrypt_failures": context.keys.failures(device.id),
+        "wrong_key": context.keys.looks_wrong(device.id)
+    })
+}
+
+fn error_line(message: &str) -> serde_json::Value {
+    json!({ "error": { "message": message } })
+}
+
+async fn answer(line: &str, context: &Context) -> serde_json::Value {
+    let request: ControlRequest = match serde_json::from_str(line) {
+        Ok(r) => r,
+        Err(e) => return error_line(&format!("invalid request: {}", e)),
+    };
+    match request {
+        ControlRequest::Devices => {
+            let devices: Vec<serde_json::Value> = context
+                .devices
+                .all()
+                .iter()
+                .map(|d| json!({ "id": d.id, "addr": d.addr, "last_seen": d.last_seen_secs() }))
+                .collect();
+            json!({ "devices": devices })
+        }
+        ControlRequest::Status => {
+            let now = context.clock.now();
+            let devices: Vec<serde_json::Value> = context
+                .devices
+                .all()
+                .iter()
+                .map(|d| status_json(d, context, now))
+                .collect();
+            json!({ "devices": devices })
+        }
+        ControlRequest::Send {
+            device_id,
+            method,
+            params,
+        } => match context.send_command(device_id, &method, &params).await {
+            Ok(reply) => json!(reply),
+            Err(e) => error_line(&e.to_string()),
+        },
+        ControlRequest::Set {
+            device_id,
+            setting,
+            value,
+        } => {
+            let model = context.config.model_for(device_id);
+            let command = match settings::by_name(model, &setting, &value) {
+                Ok(command) => command,
+                Err(e) => return error_line(&e.to_string()),
+            };
+            match context
+                .send_command(device_id, command.method, &command.params)
+                .await
+            {
+                Ok(reply) => json!(reply),
+                Err(e) => error_line(&e.to_string()),
+            }
+        }
+    }
+}
+
+async fn handle_client(stream: TcpStream, context: Arc<Context>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut reply = answer(&line, &context).await.to_string();
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn serve(addr: SocketAddr, context: Arc<Context>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "control socket is now listening");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let context = Arc::clone(&context);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, context).await {
+                warn!(%peer, error = %e, "control client disconnected");
+            }
+        });
+    }
+}
+
+fn ago(then: u64, now: u64) -> String {
+    match now.saturating_sub(then) {
+        secs if secs < 120 => format!("{}s ago", secs),
+        secs if secs < 7200 => format!("{}m ago", secs / 60),
+        secs => format!("{}h ago", secs / 3600),
+    }
+}
+
+/// Turns the daemon's answer to `{"type": "status"}` into something to read.
+fn format_status(status: &serde_json::Value, now: u64) -> String {
+    let devices = match status["devices"].as_array() {
+        Some(devices) if !devices.is_empty() => devices,
+        _ => return String::from("no robots have checked in\n"),
+    };
+    let mut out = String::new();
+    for device in devices {
+        let methods: Vec<String> = device["methods"]
+            .as_object()
+            .map(|m| m.iter().map(|(k, v)| format!("{}={}", k, v)).collect())
+            .unwrap_or_default();
+        let last_map = match device["last_map_upload"].as_u64() {
+            Some(at) => ago(at, now),
+            None => String::from("never"),
+        };
+        let robot = match device["name"].as_str() {
+            Some(name) => format!("{} ({})", name, device["id"]),
+            None => device["id"].to_string(),
+        };
+        out.push_str(&format!(
+            "{} at {}\n  connection  {} for {}s\n  last seen   {}\n  packets     {}\n  last map    {}\n  methods     {}\n",
+            robot,
+            device["addr"].as_str().unwrap_or("?"),
+            device["connection"].as_str().unwrap_or("?"),
+            device["connected_for"],
+            ago(device["last_seen"].as_u64().unwrap_or(0), now),
+            device["packets"],
+            last_map,
+            methods.join(" "),
+        ));
+        match device["drift"].as_i64().unwrap_or(0) {
+            0 => {}
+            drift if drift > 0 => out.push_str(&format!("  clock       {}s ahead\n", drift)),
+            drift => out.push_str(&format!("  clock       {}s behind\n", -drift)),
+        }
+        if device["wrong_key"].as_bool() == Some(true) {
+            out.push_str(&format!(
+                "  key         likely wrong, the last {} packets didn't decrypt\n",
+                device["decrypt_failures"]
+            ));
+        }
+    }
+    out
+}
+
+/// `dummycloud status [control address]`: asks the running daemon how each
+/// robot is doing.
+pub async fn status_command(addr: Option<SocketAddr>) -> Result<()> {
+    let status = ask(addr, &json!({"type": "status"})).await?;
+    print!("{}", format_status(&status, epoch_secs(SystemTime::now())));
+    Ok(())
+}
+
+/// `dummycloud send <device id> <method> [params]`: has the running daemon
+/// send a robot one command, and prints what it says back.
+pub async fn send_command(
+    addr: Option<SocketAddr>,
+    device_id: u32,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<()> {
+    let request =
+        json!({"type": "send", "device_id": device_id, "method": method, "params": params});
+    let reply = ask(addr, &request).await?;
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
+
+/// `dummycloud set <device id> <setting> <value>`: has the running daemon
+/// change one of a robot's settings, and prints what it says back.
+pub async fn set_command(
+    addr: Option<SocketAddr>,
+    device_id: u32,
+    setting: &str,
+    value: &str,
+) -> Result<()> {
+    let request =
+        json!({"type": "set", "device_id": device_id, "setting": setting, "value": value});
+    let reply = ask(addr, &request).await?;
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
+
+/// Sends one request to the control socket, `[listener] control_bind` by
+/// default, and reads back its answer.
+async fn ask(addr: Option<SocketAddr>, request: &serde_json::Value) -> Result<serde_json::Value> {
+    let addr = addr.unwrap_or_else(|| ListenerConfig::default().control_bind);
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .unwrap_or_default();
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_status_for_people() {
+        let status = json!({"devices": [{
+            "id": 12345,
+            "name": "Upstairs S5",
+            "addr": "192.168.1.50:54321",
+            "connection": "established",
+            "connected_for": 3600,
+            "last_seen": 990,
+            "drift": -4,
+            "packets": 12,
+            "methods": {"event.status": 1, "props": 10},
+            "last_map_upload": null
+        }]});
+        let text = format_status(&status, 1000);
+        assert!(text.starts_with("Upstairs S5 (12345) at 192.168.1.50:54321\n"));
+        assert!(text.contains("connection  established for 3600s"));
+        assert!(text.contains("last seen   10s ago"));
+        assert!(text.contains("last map    never"));
+        assert!(text.contains("methods     event.status=1 props=10"));
+        assert!(text.contains("clock       4s behind"));
+        assert_eq!(
+            format_status(&json!({"devices": []}), 0),
+            "no robots have checked in\n"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+// systemd and signal handling. systemd only exists on Linux and signals
+// only on Unix, so elsewhere there's no socket to inherit, nobody to
+// notify, nothing to reload on and only Ctrl-C to stop on.
+
+use std::env;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(unix)]
+use tracing::info;
+use tracing::warn;
+
+use crate::config::Config;
+
+// systemd hands over sockets starting at the first fd after stdio.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+// How many sockets systemd passed us, as taken by `claim_listen_fds`.
+#[cfg(unix)]
+static LISTEN_FDS: OnceLock<RawFd> = OnceLock::new();
+
+fn meant_for_us(pid_var: &str) -> bool {
+    env::var(pid_var)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id())
+}
+
+/// Takes note of the sockets systemd passed us and clears the variables
+/// telling of them, so they aren't passed on to anything we start. Changing
+/// the environment is only sound while nothing else is running, so this
+/// has to be called first thing in `main`, before the runtime starts.
+#[cfg(unix)]
+pub fn claim_listen_fds() {
+    let count: RawFd = if meant_for_us("LISTEN_PID") {
+        env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let _ = LISTEN_FDS.set(count);
+}
+
+#[cfg(not(unix))]
+pub fn claim_listen_fds() {}
+
+/// The UDP socket from systemd socket activation, if we were started that
+/// way and `claim_listen_fds` took note of it. Only the first socket is
+/// used.
+#[cfg(unix)]
+pub fn inherited_socket() -> io::Result<Option<std::net::UdpSocket>> {
+    let count = LISTEN_FDS.get().copied().unwrap_or(0);
+    if count <= 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!(
+            count,
+            "systemd passed in more than one socket, only using the first"
+        );
+    }
+    // Safety: systemd guarantees the fd is open and ours, and nothing else
+    // in this process takes ownership of it.
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(LISTEN_FDS_START) };
+    socket.set_nonblocking(true)?;
+    Ok(Some(socket))
+}
+
+#[cfg(not(unix))]
+pub fn inherited_socket() -> io::Result<Option<std::net::UdpSocket>> {
+    Ok(None)
+}
+
+/// Tells systemd about a state change, e.g. `READY=1`. Does nothing unless
+/// systemd is listening.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // a leading @ means the abstract namespace
+        Some(name) => {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = name;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets only exist on Linux",
+                ));
+            }
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// Pets the systemd watchdog often enough to keep it happy, if it's
+/// enabled for us.
+pub async fn watchdog() {
+    let usec: u64 = match env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse().ok()) {
+        Some(usec) if env::var("WATCHDOG_PID").is_err() || meant_for_us("WATCHDOG_PID") => usec,
+        _ => return,
+    };
+    // half of less than two microseconds is no interval at all, and
+    // nobody could keep up with it anyway
+    if usec < 2 {
+        warn!(
+            usec,
+            "the systemd watchdog is set too short to keep up with"
+        );
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        interval.tick().await;
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!(error = %e, "could not pet the systemd watchdog");
+        }
+    }
+}
+
+/// Resolves on Ctrl-C or SIGTERM, whichever comes first.
+#[cfg(unix)]
+pub async fn terminated() -> io::Result<()> {
+    let mut terms = signal(SignalKind::terminate())?;
+    tokio::select! {
+        interrupted = tokio::signal::ctrl_c() => interrupted,
+        _ = terms.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn terminated() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Calls `reload` with a freshly loaded config every time we get a SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup<L, A>(load: L, apply: A) -> io::Result<()>
+where
+    L: Fn() -> crate::Result<Config>,
+    A: Fn(Config),
+{
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("got SIGHUP, reloading config");
+        let _ = notify("RELOADING=1");
+        match load() {
+            Ok(config) => apply(config),
+            Err(e) => warn!(error = %e, "keeping the old config"),
+        }
+        let _ = notify("READY=1");
+    }
+    Ok(())
+}
+
+/// There's no SIGHUP to reload on, so this returns straight away.
+#[cfg(not(unix))]
+pub async fn reload_on_sighup<L, A>(_load: L, _apply: A) -> io::Result<()>
+where
+    L: Fn() -> crate::Result<Config>,
+    A: Fn(Config),
+{
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, Connection as Sqlite};
+
+use crate::codec::epoch_secs;
+use crate::devices::{Connection, Device};
+use crate::events::DeviceMessage;
+use crate::state::DeviceState;
+use crate::stats::DeviceStats;
+use crate::summary::Run;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS devices (
+        id INTEGER PRIMARY KEY,
+        addr TEXT NOT NULL,
+        listener INTEGER NOT NULL,
+        stamp INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        booted INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS states (
+        device_id INTEGER PRIMARY KEY,
+        props TEXT NOT NULL,
+        status TEXT,
+        updated INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS stats (
+        device_id INTEGER PRIMARY KEY,
+        packets INTEGER NOT NULL,
+        methods TEXT NOT NULL,
+        last_map_upload INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        device_id INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        method TEXT NOT NULL,
+        params TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_time ON messages (device_id, time);
+    CREATE TABLE IF NOT EXISTS uploads (
+        device_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        uploaded INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS runs (
+        device_id INTEGER NOT NULL,
+        started INTEGER NOT NULL,
+        duration INTEGER NOT NULL,
+        area REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS runs_by_time ON runs (device_id, started);
+";
+
+fn from_epoch_secs(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn to_json_error(e: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+}
+
+fn from_json_error(column: usize, e: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(e))
+}
+
+/// Which of a robot's messages to look up. The times are in seconds since
+/// the epoch, and both ends are included.
+#[derive(Debug, Default)]
+pub struct HistoryQuery<'a> {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub method: Option<&'a str>,
+    pub limit: usize,
+}
+
+/// Everything we know about the robots, as it was last saved.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub devices: Vec<Device>,
+    pub states: Vec<(u32, DeviceState)>,
+    pub stats: Vec<(u32, DeviceStats)>,
+}
+
+/// Keeps what we know about the robots in an SQLite file, so that it
+/// survives a restart.
+pub struct Database {
+    conn: Mutex<Sqlite>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Database> {
+        Database::new(Sqlite::open(path)?)
+    }
+
+    fn new(conn: Sqlite) -> rusqlite::Result<Database> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Database {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Replaces what's stored with `snapshot`, all at once.
+    pub fn save(&self, snapshot: &Snapshot) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for device in &snapshot.devices {
+            tx.execute(
+                "INSERT OR REPLACE INTO devices VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    device.id,
+                    device.addr.to_string(),
+                    device.listener,
+                    device.stamp,
+                    device.last_seen_secs(),
+                    device.booted_secs()
+                ],
+            )?;
+        }
+        for (device_id, state) in &snapshot.states {
+            let props = serde_json::to_string(&state.props).map_err(to_json_error)?;
+            let status = match &state.status {
+                Some(status) => Some(serde_json::to_string(status).map_err(to_json_error)?),
+                None => None,
+            };
+            tx.execute(
+                "INSERT OR REPLACE INTO states VALUES (?1, ?2, ?3, ?4)",
+                params![device_id, props, status, state.updated],
+            )?;
+        }
+        for (device_id, stats) in &snapshot.stats {
+            let methods = serde_json::to_string(&stats.methods).map_err(to_json_error)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO stats VALUES (?1, ?2, ?3, ?4)",
+                params![device_id, stats.packets, methods, stats.last_map_upload],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Reads back what was last saved. Robots come back as stale, until they
+    /// check in again.
+    pub fn load(&self) -> rusqlite::Result<Snapshot> {
+        let conn = self.conn.lock().unwrap();
+        let now = SystemTime::now();
+        let devices = conn
+            .prepare("SELECT id, addr, listener, stamp, last_seen, booted FROM devices")?
+            .query_map([], |row| {
+                let addr: String = row.get(1)?;
+                Ok(Device {
+                    id: row.get(0)?,
+                    addr: addr.parse().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?,
+                    listener: row.get(2)?,
+                    stamp: row.get(3)?,
+                    last_seen: from_epoch_secs(row.get(4)?),
+                    booted: from_epoch_secs(row.get(5)?),
+                    drift: 0,
+                    connection: Connection::Stale,
+                    connected_since: now,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let states = conn
+            .prepare("SELECT device_id, props, status, updated FROM states")?
+            .query_map([], |row| {
+                let props: String = row.get(1)?;
+                let status: Option<String> = row.get(2)?;
+                let state = DeviceState {
+                    props: serde_json::from_str(&props).map_err(|e| from_json_error(1, e))?,
+                    status: match status {
+                        Some(s) => {
+                            Some(serde_json::from_str(&s).map_err(|e| from_json_error(2, e))?)
+                        }
+                        None => None,
+                    },
+                    updated: row.get(3)?,
+                };
+                Ok((row.get(0)?, state))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let stats = conn
+            .prepare("SELECT device_id, packets, methods, last_map_upload FROM stats")?
+            .query_map([], |row| {
+                let methods: String = row.get(2)?;
+                let stats = DeviceStats {
+                    packets: row.get(1)?,
+                    methods: serde_json::from_str(&methods).map_err(|e| from_json_error(2, e))?,
+                    last_map_upload: row.get(3)?,
+                };
+                Ok((row.get(0)?, stats))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Snapshot {
+            devices,
+            states,
+            stats,
+        })
+    }
+
+    /// Keeps a message from a robot for the history API.
+    pub fn record_message(&self, message: &DeviceMessage) -> rusqlite::Result<()> {
+        let params = serde_json::to_string(&message.params).map_err(to_json_error)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO messages VALUES (?1, ?2, ?3, ?4)",
+            params![message.device_id, message.timestamp, message.method, params],
+        )?;
+        Ok(())
+    }
+
+    /// The robot's messages that match `query`, oldest first.
+    pub fn history(
+        &self,
+        device_id: u32,
+        query: &HistoryQuery,
+    ) -> rusqlite::Result<Vec<DeviceMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT time, method, params FROM messages
+             WHERE device_id = ?1 AND time >= ?2 AND time <= ?3
+                AND (?4 IS NULL OR method = ?4)
+             ORDER BY time, rowid LIMIT ?5",
+        )?;
+        let messages = statement
+            .query_map(
+                params![
+                    device_id,
+                    query.from.unwrap_or(0),
+                    // as far as SQLite's integers go
+                    query.to.unwrap_or(i64::MAX as u64).min(i64::MAX as u64),
+                    query.method,
+                    query.limit
+                ],
+                |row| {
+                    let params: String = row.get(2)?;
+                    Ok(DeviceMessage {
+                        device_id,
+                        timestamp: row.get(0)?,
+                        method: row.get(1)?,
+                        params: serde_json::from_str(&params).map_err(|e| from_json_error(2, e))?,
+                    })
+                },
+            )?
+            .collect();
+        messages
+    }
+
+    /// Drops messages from before `before`, returning how many went.
+    pub fn prune_messages(&self, before: u64) -> rusqlite::Result<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM messages WHERE time < ?1", params![before])
+    }
+
+    /// Keeps a finished cleaning run for the statistics.
+    pub fn record_run(&self, device_id: u32, run: &Run) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO runs VALUES (?1, ?2, ?3, ?4)",
+            params![device_id, run.started, run.duration, run.area],
+        )?;
+        Ok(())
+    }
+
+    /// The robot's cleaning runs that started at `from` or later, oldest
+    /// first.
+    pub fn runs(&self, device_id: u32, from: u64) -> rusqlite::Result<Vec<Run>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT started, duration, area FROM runs
+             WHERE device_id = ?1 AND started >= ?2 ORDER BY started",
+        )?;
+        let runs = statement
+            .query_map(params![device_id, from], |row| {
+                Ok(Run {
+                    started: row.get(0)?,
+                    duration: row.get(1)?,
+                    area: row.get(2)?,
+                })
+            })?
+            .collect();
+        runs
+    }
+
+    /// Notes down an upload we stored, `kind` being e.g. `map` or `rooms`.
+    pub fn upload_stored(
+        &self,
+        device_id: u32,
+        kind: &str,
+        path: &Path,
+        size: usize,
+        now: SystemTime,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO uploads VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                device_id,
+                kind,
+                path.to_string_lossy(),
+                size,
+                epoch_secs(now)
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn snapshots_survive_a_round_trip() {
+        let db = Database::new(Sqlite::open_in_memory().unwrap()).unwrap();
+        let seen = from_epoch_secs(1_600_000_000);
+        let device = Device {
+            id: 12345,
+            addr: ([192, 168, 1, 50], 54321).into(),
+            listener: 1,
+            last_seen: seen,
+            stamp: 900,
+            booted: seen - Duration::from_secs(900),
+            drift: 0,
+            connection: Connection::Established,
+            connected_since: seen,
+        };
+        let state = DeviceState {
+            props: json!({"battery": 87}).as_object().unwrap().clone(),
+            status: Some(json!({"state": 8})),
+            updated: 1_600_000_000,
+        };
+        let mut stats = DeviceStats {
+            packets: 42,
+            ..DeviceStats::default()
+        };
+        stats.methods.insert("props".into(), 40);
+        db.save(&Snapshot {
+            devices: vec![device.clone()],
+            states: vec![(12345, state)],
+            stats: vec![(12345, stats.clone())],
+        })
+        .unwrap();
+        db.upload_stored(12345, "map", Path::new("maps/1.bin"), 100, seen)
+            .unwrap();
+
+        let loaded = db.load().unwrap();
+        let restored = &loaded.devices[0];
+        assert_eq!(restored.addr, device.addr);
+        assert_eq!(restored.stamp, 900);
+        assert_eq!(restored.last_seen, seen);
+        assert_eq!(restored.connection, Connection::Stale);
+        assert_eq!(loaded.states[0].1.status, Some(json!({"state": 8})));
+        assert_eq!(loaded.stats[0], (12345, stats));
+        let uploads: u64 = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM uploads WHERE kind = 'map'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(uploads, 1);
+    }
+
+    #[test]
+    fn looks_up_history_by_time_and_method() {
+        let db = Database::new(Sqlite::open_in_memory().unwrap()).unwrap();
+        let message = |method: &str, timestamp| DeviceMessage {
+            device_id: 1,
+            method: method.to_string(),
+            params: json!([{"battery": timestamp}]),
+            timestamp,
+        };
+        for (method, time) in [
+            ("props", 10),
+            ("event.status", 20),
+            ("props", 30),
+            ("props", 40),
+        ] {
+            db.record_message(&message(method, time)).unwrap();
+        }
+        let query = |from, to, method| HistoryQuery {
+            from,
+            to,
+            method,
+            limit: 100,
+        };
+        let times = |q: &HistoryQuery| -> Vec<u64> {
+            db.history(1, q)
+                .unwrap()
+                .iter()
+                .map(|m| m.timestamp)
+                .collect()
+        };
+        assert_eq!(times(&query(None, None, None)), [10, 20, 30, 40]);
+        assert_eq!(times(&query(Some(20), Some(30), None)), [20, 30]);
+        assert_eq!(times(&query(None, None, Some("props"))), [10, 30, 40]);
+        assert_eq!(db.history(2, &query(None, None, None)).unwrap().len(), 0);
+        assert_eq!(
+            db.history(1, &query(None, None, None)).unwrap()[1].params,
+            json!([{"battery": 20}])
+        );
+
+        assert_eq!(db.prune_messages(30).unwrap(), 2);
+        assert_eq!(times(&query(None, None, None)), [30, 40]);
+
+        let run = |started| Run {
+            started,
+            duration: 600,
+            area: 12.5,
+        };
+        db.record_run(1, &run(100)).unwrap();
+        db.record_run(1, &run(200)).unwrap();
+        db.record_run(2, &run(300)).unwrap();
+        assert_eq!(db.runs(1, 150).unwrap(), [run(200)]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use base64::Engine;
+
+use crate::codec::{self, PacketHeader, UDPCodec, HEADER_SIZE};
+use crate::error::{Error, Result};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_NANO_MAGIC: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+// The link types packets are likely to turn up with: what tcpdump writes on
+// ethernet, "any" and loopback interfaces, and what `--capture` writes.
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_USER0: u32 = 147;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER: usize = 8;
+
+fn invalid(message: String) -> Error {
+    Error::Invalid(message)
+}
+
+fn truncated(what: &str) -> Error {
+    invalid(format!("{} is cut short", what))
+}
+
+/// Reads the integers in pcap files, which are in whichever byte order
+/// the machine that wrote them used.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+const BIG: Endian = Endian { big: true };
+const LITTLE: Endian = Endian { big: false };
+
+impl Endian {
+    fn u16(self, data: &[u8], at: usize) -> Option<u16> {
+        let bytes = data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, data: &[u8], at: usize) -> Option<u32> {
+        let bytes = data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn udp_payload(ip: &[u8]) -> Option<&[u8]> {
+    let udp = match ip.first()? >> 4 {
+        4 => {
+            if *ip.get(9)? != IPPROTO_UDP {
+                return None;
+            }
+            ip.get(usize::from(ip[0] & 0x0f) * 4..)?
+        }
+        // extension headers are rare enough on a LAN not to bother with
+        6 => {
+            if *ip.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            ip.get(40..)?
+        }
+        _ => return None,
+    };
+    udp.get(UDP_HEADER..)
+}
+
+/// Digs the UDP payload out of a captured frame, if it carries one.
+fn datagram(link_type: u32, frame: &[u8], endian: Endian) -> Option<&[u8]> {
+    match link_type {
+        LINKTYPE_USER0 => Some(frame),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => udp_payload(frame),
+        LINKTYPE_NULL => {
+            // the address family, in the capturing machine's byte order
+            match endian.u32(frame, 0)? {
+                2 | 24 | 28 | 30 => udp_payload(frame.get(4..)?),
+                _ => None,
+            }
+        }
+        LINKTYPE_ETHERNET => {
+            let (ethertype, offset) = match be16(frame, 12)? {
+                ETHERTYPE_VLAN => (be16(frame, 16)?, 18),
+                ethertype => (ethertype, 14),
+            };
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => udp_payload(frame.get(offset..)?),
+                _ => None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => udp_payload(frame.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => udp_payload(frame.get(20..)?),
+        _ => None,
+    }
+}
+
+fn pcap_frames(file: &[u8]) -> Result<Vec<(u32, Endian, &[u8])>> {
+    let endian = match BIG.u32(file, 0) {
+        Some(PCAP_MAGIC) | Some(PCAP_NANO_MAGIC) => BIG,
+        _ => LITTLE,
+    };
+    let link_type = endian
+        .u32(file, 20)
+        .ok_or_else(|| truncated("pcap header"))?;
+    let mut frames = Vec::new();
+    let mut at = 24;
+    while at < file.len() {
+        let len = endian
+            .u32(file, at + 8)
+            .ok_or_else(|| truncated("pcap record"))? as usize;
+        let frame = file
+            .get(at + 16..at + 16 + len)
+            .ok_or_else(|| truncated("pcap record"))?;
+        frames.push((link_type, endian, frame));
+        at += 16 + len;
+    }
+    Ok(frames)
+}
+
+fn pcapng_frames(file: &[u8]) -> Result<Vec<(u32, Endian, &[u8])>> {
+    let mut frames = Vec::new();
+    let mut endian = LITTLE;
+    let mut interfaces = Vec::new();
+    let mut at = 0;
+    while at < file.len() {
+        let block = &file[at..];
+        let kind = endian
+            .u32(block, 0)
+            .ok_or_else(|| truncated("pcapng block"))?;
+        if kind == PCAPNG_SECTION_HEADER {
+            // each section says for itself which byte order it's in
+            endian = match LITTLE.u32(block, 8) {
+                Some(PCAPNG_BYTE_ORDER_MAGIC) => LITTLE,
+                Some(_) => BIG,
+                None => return Err(truncated("pcapng section header")),
+            };
+            interfaces.clear();
+        }
+        let len = endian
+            .u32(block, 4)
+            .filter(|len| *len >= 12)
+            .ok_or_else(|| truncated("pcapng block"))? as usize;
+        let block = block.get(..len).ok_or_else(|| truncated("pcapng block"))?;
+        let body = &block[8..len - 4];
+        match kind {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = endian
+                    .u16(body, 0)
+                    .ok_or_else(|| truncated("pcapng interface"))?;
+                interfaces.push(u32::from(link_type));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = endian
+                    .u32(body, 0)
+                    .ok_or_else(|| truncated("pcapng packet"))?;
+                let captured = endian
+                    .u32(body, 12)
+                    .ok_or_else(|| truncated("pcapng packet"))?;
+                let frame = body
+                    .get(20..20 + captured as usize)
+                    .ok_or_else(|| truncated("pcapng packet"))?;
+                let link_type = interfaces
+                    .get(interface as usize)
+                    .ok_or_else(|| invalid(format!("no interface {} in the pcapng", interface)))?;
+                frames.push((*link_type, endian, frame));
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let link_type = interfaces
+                    .first()
+                    .ok_or_else(|| invalid("no interfaces in the pcapng".to_string()))?;
+                frames.push((*link_type, endian, body.get(4..).unwrap_or_default()));
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    Ok(frames)
+}
+
+/// The miio packets in a pcap or pcapng file, leaving out whatever else was
+/// captured along with them.
+pub fn pcap_packets(file: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let frames = match BIG.u32(file, 0) {
+        Some(PCAPNG_SECTION_HEADER) => pcapng_frames(file)?,
+        _ => pcap_frames(file)?,
+    };
+    Ok(frames
+        .into_iter()
+        .filter_map(|(link_type, endian, frame)| datagram(link_type, frame, endian))
+        .filter(|packet| PacketHeader::parse(packet).is_ok())
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+fn is_pcap(file: &[u8]) -> bool {
+    let magic = match file.get(..4) {
+        Some(magic) => magic,
+        None => return false,
+    };
+    [PCAP_MAGIC, PCAP_NANO_MAGIC, PCAPNG_SECTION_HEADER]
+        .iter()
+        .any(|m| magic == m.to_be_bytes() || magic == m.to_le_bytes())
+}
+
+/// The packets `input` stands for: a pcap or pcapng file, a file holding a
+/// single packet, or the packet itself in hex or base64.
+pub fn read_input(input: &str) -> Result<Vec<Vec<u8>>> {
+    let path = Path::new(input);
+    if path.is_file() {
+        let file = fs::read(path).map_err(|source| Error::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if is_pcap(&file) {
+            return pcap_packets(&file);
+        }
+        return Ok(vec![file]);
+    }
+    let text: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(packet) = codec::from_hex(&text) {
+        return Ok(vec![packet]);
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(&text)
+        .map(|packet| vec![packet])
+        .map_err(|_| invalid(format!("{} isn't a file, hex or base64", input)))
+}
+
+/// A packet's header fields one per line, followed by its JSON if `codec`
+/// decrypts it.
+pub fn describe(packet: &[u8], codec: Option<&UDPCodec>) -> String {
+    let header = match PacketHeader::parse(packet) {
+        Ok(header) => header,
+        Err(e) => return format!("not a miio packet: {}\n", e),
+    };
+    let mut out = format!(
+        "length:    {}\nunknown:   {:08x}\ndevice id: {}\nstamp:     {}\nchecksum:  {}\n",
+        header.length,
+        header.unknown,
+        header.device_id,
+        header.stamp,
+        codec::to_hex(&header.checksum)
+    );
+    if packet.len() > HEADER_SIZE {
+        let body = match codec {
+            Some(codec) => match codec.decode(packet) {
+                Ok(json) => json,
+                Err(e) => format!("could not decrypt: {}", e),
+            },
+            None => format!(
+                "{} bytes of body, pass --key to decrypt it",
+                packet.len() - HEADER_SIZE
+            ),
+        };
+        out.push_str(&body);
+        out.push('\n');
+    }
+    out
+}
+
+/// `dummycloud decode <packet> [--key key]`: prints each packet's header
+/// and, given the key it was signed with, what it says.
+pub fn decode_command(input: &str, key: Option<&str>) -> Result<()> {
+    let packets = read_input(input)?;
+    let codec = key.map(UDPCodec::new);
+    if packets.is_empty() {
+        println!("no miio packets in {}", input);
+    }
+    for (i, packet) in packets.iter().enumerate() {
+        if packets.len() > 1 {
+            println!("packet {}:", i + 1);
+        }
+        print!("{}", describe(packet, codec.as_ref()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ethernet_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, IPPROTO_UDP];
+        ip.resize(20, 0);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&[0; UDP_HEADER]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn finds_packets_in_pcaps_and_strings() {
+        let packet = codec::encode("key", 1234, 5, br#"{"id":1,"method":"get_status"}"#);
+
+        let mut pcap = Vec::new();
+        for word in &[PCAP_MAGIC, 0x0004_0002, 0, 0, 65535, LINKTYPE_ETHERNET] {
+            pcap.extend_from_slice(&word.to_le_bytes());
+        }
+        for frame in &[ethernet_frame(&packet), ethernet_frame(b"some dns")] {
+            pcap.extend_from_slice(&[0; 8]);
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(frame);
+        }
+        assert!(is_pcap(&pcap));
+        assert_eq!(pcap_packets(&pcap).unwrap(), [&packet[..]]);
+
+        let base64 = base64::engine::general_purpose::STANDARD.encode(&packet);
+        assert_eq!(read_input(&base64).unwrap(), [&packet[..]]);
+        assert_eq!(read_input(&codec::to_hex(&packet)).unwrap(), [&packet[..]]);
+
+        let described = describe(&packet, Some(&UDPCodec::new("key")));
+        assert!(described.contains("device id: 1234\nstamp:     5\n"));
+        assert!(described.ends_with("{\"id\":1,\"method\":\"get_status\"}\n"));
+        assert!(describe(&packet, Some(&UDPCodec::new("wrong"))).contains("could not decrypt"));
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::codec::epoch_secs;
+use crate::config::SessionConfig;
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub id: u32,
+    pub addr: SocketAddr,
+    /// Which of our listener sockets the robot talks to.
+    pub listener: usize,
+    pub last_seen: SystemTime,
+    /// The stamp of the last packet, which counts the robot's uptime in
+    /// seconds.
+    pub stamp: u32,
+    /// When the robot booted, going by its stamp and our clock.
+    pub booted: SystemTime,
+    /// Seconds the robot's clock has gained on ours since it booted, or
+    /// lost if it's negative: how far back its boot time, going by its
+    /// stamps, has moved since the first of them.
+    pub drift: i64,
+    pub connection: Connection,
+    /// When it moved to its current connection state.
+    pub connected_since: SystemTime,
+}
+
+/// Where a robot is in talking to us.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Connection {
+    /// It said hello and is waiting for the time.
+    Handshake,
+    /// It has the time but hasn't sent anything we could read yet.
+    TimeSynced,
+    /// We're decoding its calls.
+    Established,
+    /// It hasn't sent anything in `[session] idle_timeout` seconds.
+    Stale,
+}
+
+impl Device {
+    pub fn last_seen_secs(&self) -> u64 {
+        epoch_secs(self.last_seen)
+    }
+
+    pub fn booted_secs(&self) -> u64 {
+        epoch_secs(self.booted)
+    }
+}
+
+/// What a packet's stamp says about it, compared to the ones before it.
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+    Fresh,
+    /// The stamp started over, so the robot has restarted.
+    Rebooted,
+    /// The stamp went backwards, so this is a replayed or mangled packet.
+    Stale {
+        last: u32,
+    },
+}
+
+/// Every robot we've heard from, keyed by device id, so that we know where to
+/// send commands to.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: Mutex<HashMap<u32, Device>>,
+    session: SessionConfig,
+}
+
+impl DeviceRegistry {
+    pub fn new(session: SessionConfig) -> DeviceRegistry {
+        DeviceRegistry {
+            devices: Mutex::default(),
+            session,
+        }
+    }
+
+    fn freshness(&self, previous: Option<&Device>, stamp: u32) -> Freshness {
+        let last = match previous {
+            Some(d) => d.stamp,
+            None => return Freshness::Fresh,
+        };
+        // a stamp of 0 is the hello that starts every session
+        if stamp == 0 || stamp.saturating_add(self.session.tolerance) >= last {
+            Freshness::Fresh
+        } else if stamp <= self.session.reboot_window {
+            Freshness::Rebooted
+        } else {
+            Freshness::Stale { last }
+        }
+    }
+
+    /// Records that the robot sent us a packet. A stale packet only counts
+    /// if `[session] enforce` is off; either way, the caller is told.
+    pub fn check_in(&self, id: u32, addr: SocketAddr, listener: usize, stamp: u32) -> Freshness {
+        self.check_in_at(id, addr, listener, stamp, SystemTime::now())
+    }
+
+    fn check_in_at(
+        &self,
+        id: u32,
+        addr: SocketAddr,
+        listener: usize,
+        stamp: u32,
+        now: SystemTime,
+    ) -> Freshness {
+        let mut devices = self.devices.lock().unwrap();
+        let previous = devices.get(&id);
+        let freshness = self.freshness(previous, stamp);
+        match freshness {
+            Freshness::Stale { last } if self.session.enforce => {
+                warn!(
+                    device_id = id,
+                    stamp, last, "dropping packet, stamp went backwards"
+                );
+                return freshness;
+            }
+            Freshness::Stale { last } => warn!(device_id = id, stamp, last, "stamp went backwards"),
+            Freshness::Rebooted => info!(device_id = id, stamp, "device rebooted"),
+            _ => {}
+        }
+        if previous.is_none_or(|d| d.addr != addr) {
+            info!(device_id = id, %addr, "device checked in");
+        }
+        let (connection, connected_since) = previous.map_or((Connection::Handshake, now), |d| {
+            (d.connection, d.connected_since)
+        });
+        let booted = now - Duration::from_secs(u64::from(stamp));
+        let drift = match previous {
+            // a hello's stamp says nothing about the robot's clock
+            Some(d) if d.stamp != 0 && stamp != 0 && freshness != Freshness::Rebooted => {
+                epoch_secs(d.booted) as i64 + d.drift - epoch_secs(booted) as i64
+            }
+            _ => 0,
+        };
+        let limit = i64::from(self.session.max_drift);
+        let drifted = |drift: i64| limit > 0 && drift.abs() > limit;
+        if drifted(drift) && !previous.is_some_and(|d| drifted(d.drift)) {
+            warn!(
+                device_id = id,
+                drift, "robot's clock has drifted from ours, it may stop taking our timesyncs"
+            );
+        }
+        let device = Device {
+            id,
+            addr,
+            listener,
+            connection,
+            connected_since,
+            last_seen: now,
+            stamp,
+            booted,
+            drift,
+        };
+        devices.insert(id, device);
+        freshness
+    }
+
+    /// Moves a robot on to another state, returning the one it was in if
+    /// that's a change.
+    pub fn transition(&self, id: u32, to: Connection) -> Option<Connection> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(&id)?;
+        let from = device.connection;
+        if from == to {
+            return None;
+        }
+        device.connection = to;
+        device.connected_since = SystemTime::now();
+        Some(from)
+    }
+
+    /// Marks robots that have gone quiet as stale, returning them along with
+    /// the state they were in.
+    pub fn expire(&self, now: SystemTime) -> Vec<(u32, Connection)> {
+        let timeout = Duration::from_secs(self.session.idle_timeout);
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .values_mut()
+            .filter(|d| d.connection != Connection::Stale)
+            .filter(|d| {
+                now.duration_since(d.last_seen)
+                    .is_ok_and(|idle| idle > timeout)
+            })
+            .map(|d| {
+                let from = d.connection;
+                d.connection = Connection::Stale;
+                d.connected_since = now;
+                (d.id, from)
+            })
+            .collect()
+    }
+
+    /// Whether a packet with this freshness should be handled.
+    pub fn accepts(&self, freshness: &Freshness) -> bool {
+        !(self.session.enforce && matches!(freshness, Freshness::Stale { .. }))
+    }
+
+    /// Puts back a robot we knew about before a restart, unless it's already
+    /// checked in since.
+    pub fn restore(&self, device: Device) {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(device.id)
+            .or_insert(device);
+    }
+
+    pub fn get(&self, id: u32) -> Option<Device> {
+        self.devices.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<Device> {
+        let mut devices: Vec<Device> = self.devices.lock().unwrap().values().cloned().collect();
+        devices.sort_by_key(|d| d.id);
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_the_connection_lifecycle() {
+        let registry = DeviceRegistry::default();
+        let addr: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        registry.check_in(1, addr, 0, 0);
+        assert_eq!(registry.get(1).unwrap().connection, Connection::Handshake);
+        assert_eq!(
+            registry.transition(1, Connection::TimeSynced),
+            Some(Connection::Handshake)
+        );
+        registry.check_in(1, addr, 0, 10);
+        assert_eq!(registry.get(1).unwrap().connection, Connection::TimeSynced);
+        registry.transition(1, Connection::Established);
+        assert_eq!(registry.transition(1, Connection::Established), None);
+
+        assert!(registry.expire(SystemTime::now()).is_empty());
+        let later = SystemTime::now() + Duration::from_secs(301);
+        assert_eq!(registry.expire(later), vec![(1, Connection::Established)]);
+        assert!(registry.expire(later).is_empty());
+        // checking in again doesn't say anything about the session by itself
+        registry.check_in(1, addr, 0, 400);
+        assert_eq!(registry.get(1).unwrap().connection, Connection::Stale);
+        assert_eq!(registry.transition(7, Connection::Stale), None);
+    }
+
+    #[test]
+    fn rejects_stamps_that_go_backwards() {
+        let registry = DeviceRegistry::default();
+        let addr: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        assert_eq!(registry.check_in(1, addr, 0, 0), Freshness::Fresh);
+        assert_eq!(registry.check_in(1, addr, 0, 1000), Freshness::Fresh);
+        // a little out of order is fine
+        assert_eq!(registry.check_in(1, addr, 0, 998), Freshness::Fresh);
+        assert_eq!(
+            registry.check_in(1, addr, 0, 500),
+            Freshness::Stale { last: 998 }
+        );
+        assert!(!registry.accepts(&Freshness::Stale { last: 998 }));
+        assert_eq!(registry.get(1).unwrap().stamp, 998);
+
+        assert_eq!(registry.check_in(1, addr, 0, 12), Freshness::Rebooted);
+        assert_eq!(registry.check_in(1, addr, 0, 0), Freshness::Fresh);
+    }
+
+    #[test]
+    fn tracks_how_far_the_robots_clock_drifts() {
+        let registry = DeviceRegistry::default();
+        let addr: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + secs);
+        let drift = |stamp, secs| {
+            registry.check_in_at(1, addr, 0, stamp, at(secs));
+            registry.get(1).unwrap().drift
+        };
+        assert_eq!(drift(0, 0), 0);
+        assert_eq!(drift(1000, 1), 0);
+        // ten seconds ahead after an hour, then losing it again
+        assert_eq!(drift(4610, 3601), 10);
+        assert_eq!(drift(4620, 3621), 0);
+        assert_eq!(drift(8220, 7241), -20);
+        // a reboot starts over
+        assert_eq!(drift(5, 7300), 0);
+        assert_eq!(drift(65, 7357), 3);
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::codec::{PacketHeader, HEADER_SIZE};
+use crate::devices::Device;
+use crate::Context;
+
+/// Answers a hello the way the robot itself would, so that local tools can
+/// still find it: its device id, and its stamp as of now.
+fn hello_reply(device: &Device, now: SystemTime) -> [u8; HEADER_SIZE] {
+    let elapsed = now
+        .duration_since(device.last_seen)
+        .map_or(0, |d| d.as_secs());
+    let stamp = device.stamp.wrapping_add(elapsed as u32);
+    // a robot that's been set up fills the token field with 0xff as well
+    PacketHeader {
+        unknown: 0,
+        device_id: device.id,
+        ..PacketHeader::hello(stamp)
+    }
+    .to_bytes()
+}
+
+/// Answers miio discovery on behalf of the robots that have checked in with
+/// us, or just the ones in `devices` if it isn't empty.
+pub async fn serve(
+    addr: SocketAddr,
+    devices: Vec<u32>,
+    context: Arc<Context>,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    socket.set_broadcast(true)?;
+    info!(%addr, "discovery responder is now listening");
+    let mut buf = [0; 1024];
+    loop {
+        let (amt, src) = socket.recv_from(&mut buf).await?;
+        let is_hello =
+            amt == HEADER_SIZE && PacketHeader::parse(&buf[..amt]).is_ok_and(|h| h.is_discovery());
+        if !is_hello {
+            debug!(%src, len = amt, "ignoring packet that isn't a hello");
+            continue;
+        }
+        let now = SystemTime::now();
+        for device in context.devices.all() {
+            if !devices.is_empty() && !devices.contains(&device.id) {
+                continue;
+            }
+            debug!(%src, device_id = device.id, "answering hello");
+            if let Err(e) = socket.send_to(&hello_reply(&device, now), src).await {
+                warn!(%src, error = %e, "could not answer hello");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::parse_hello_reply;
+    use std::time::Duration;
+
+    #[test]
+    fn replies_carry_the_robots_uptime() {
+        let last_seen = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let device = Device {
+            id: 277_123_456,
+            addr: ([192, 168, 1, 50], 54321).into(),
+            listener: 0,
+            last_seen,
+            stamp: 500,
+            booted: last_seen - Duration::from_secs(500),
+            drift: 0,
+            connection: crate::devices::Connection::Established,
+            connected_since: last_seen,
+        };
+        let reply =
+            parse_hello_reply(&hello_reply(&device, last_seen + Duration::from_secs(30))).unwrap();
+        assert_eq!(reply.device_id, 277_123_456);
+        assert_eq!(reply.stamp, 530);
+        assert_eq!(reply.token, None);
+    }
+}
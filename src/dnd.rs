@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, NaiveTime, Timelike};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::config::DndConfig;
+use crate::devices::Connection;
+use crate::events::Event;
+use crate::Context;
+
+/// Whether `time` falls in the window, which runs past midnight when it
+/// ends before it starts.
+pub fn is_quiet(config: &DndConfig, time: NaiveTime) -> bool {
+    if config.start <= config.end {
+        config.start <= time && time < config.end
+    } else {
+        time >= config.start || time < config.end
+    }
+}
+
+/// Whether `method` may still be sent during the window, going by `allow`.
+pub fn allows(config: &DndConfig, method: &str) -> bool {
+    method == "set_dnd_timer"
+        || config
+            .allow
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => method == pattern,
+            })
+}
+
+/// Whether the config keeps `method` from being sent right now.
+pub(crate) fn refuses(context: &Context, method: &str) -> bool {
+    let config = match &context.config.dnd {
+        Some(config) => config,
+        None => return false,
+    };
+    let now: DateTime<Local> = DateTime::from(context.clock.now());
+    is_quiet(config, now.time()) && !allows(config, method)
+}
+
+async fn set_timer(device_id: u32, config: &DndConfig, context: &Context) {
+    let params = json!([
+        config.start.hour(),
+        config.start.minute(),
+        config.end.hour(),
+        config.end.minute()
+    ]);
+    match context
+        .send_command(device_id, "set_dnd_timer", &params)
+        .await
+    {
+        Ok(_) => info!(device_id, "set the robot's own do not disturb timer"),
+        Err(e) => warn!(device_id, error = %e, "could not set the robot's do not disturb timer"),
+    }
+}
+
+/// Gives each robot the same window with `set_dnd_timer` as soon as its
+/// connection is established.
+pub(crate) async fn run(config: DndConfig, context: Arc<Context>) {
+    let mut events = context.events.subscribe();
+    loop {
+        let change = match events.recv().await {
+            Ok(Event::Connection(change)) => change,
+            Ok(Event::Message(_))
+            | Ok(Event::Exchange(_))
+            | Ok(Event::Consumables(_))
+            | Ok(Event::Binary(_)) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "do not disturb fell behind and skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if change.to == Connection::Established {
+            let config = config.clone();
+            let context = Arc::clone(&context);
+            tokio::spawn(async move { set_timer(change.device_id, &config, &context).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn windows_can_run_past_midnight() {
+        let overnight: DndConfig = toml::from_str("start = \"22:00\"\nend = \"08:00\"").unwrap();
+        assert!(is_quiet(&overnight, at(23, 30)));
+        assert!(is_quiet(&overnight, at(7, 59)));
+        assert!(!is_quiet(&overnight, at(8, 0)));
+        assert!(!is_quiet(&overnight, at(12, 0)));
+
+        let daytime: DndConfig = toml::from_str("start = \"13:00\"\nend = \"15:00\"").unwrap();
+        assert!(is_quiet(&daytime, at(14, 0)));
+        assert!(!is_quiet(&daytime, at(23, 0)));
+
+        assert!(allows(&overnight, "get_status"));
+        assert!(allows(&overnight, "app_stop"));
+        assert!(allows(&overnight, "set_dnd_timer"));
+        assert!(!allows(&overnight, "app_start"));
+        assert!(!allows(&overnight, "app_segment_clean"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::config::DnsConfig;
+use crate::Context;
+
+// Just enough of RFC 1035 to answer A queries for the cloud hostnames
+// ourselves and pass everything else on untouched.
+const HEADER_SIZE: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+// Short enough that the robot notices quickly if dummycloud moves.
+const ANSWER_TTL: u32 = 60;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+#[derive(Debug, PartialEq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// Where the question section ends in the query.
+    end: usize,
+}
+
+fn read_u16(packet: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]))
+}
+
+/// Pulls the only question out of a standard query, or None if the packet is
+/// anything else.
+fn parse_question(packet: &[u8]) -> Option<Question> {
+    let flags = read_u16(packet, 2)?;
+    let qdcount = read_u16(packet, 4)?;
+    if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 || qdcount != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut at = HEADER_SIZE;
+    loop {
+        let len = usize::from(*packet.get(at)?);
+        at += 1;
+        if len == 0 {
+            break;
+        }
+        // questions never use compression pointers, and labels are short
+        if len > 63 {
+            return None;
+        }
+        let label = packet.get(at..at + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        at += len;
+    }
+    Some(Question {
+        name: labels.join("."),
+        qtype: read_u16(packet, at)?,
+        qclass: read_u16(packet, at + 2)?,
+        end: at + 4,
+    })
+}
+
+/// `*.example.com` matches any subdomain of example.com, but not
+/// example.com itself.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => name
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Answers the query ourselves, with `ip` for A queries and no records for
+/// anything else so the robot can't find its way to the real cloud.
+fn answer(query: &[u8], question: &Question, ip: Option<Ipv4Addr>) -> Vec<u8> {
+    let flags = read_u16(query, 2).unwrap_or(0);
+    let ip = ip.filter(|_| question.qtype == TYPE_A && question.qclass == CLASS_IN);
+
+    let mut out = Vec::with_capacity(question.end + 16);
+    out.extend_from_slice(&query[..2]);
+    let flags = FLAG_RESPONSE
+        | FLAG_AUTHORITATIVE
+        | FLAG_RECURSION_AVAILABLE
+        | (flags & FLAG_RECURSION_DESIRED);
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&u16::from(ip.is_some()).to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&query[HEADER_SIZE..question.end]);
+    if let Some(ip) = ip {
+        // the name is a pointer back to the question
+        out.extend_from_slice(&[0xc0, HEADER_SIZE as u8]);
+        out.extend_from_slice(&TYPE_A.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        out.extend_from_slice(&4u16.to_be_bytes());
+        out.extend_from_slice(&ip.octets());
+    }
+    out
+}
+
+async fn forward(upstream: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if upstream.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 4096];
+    let amt = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "upstream timed out"))??;
+    buf.truncate(amt);
+    Ok(buf)
+}
+
+async fn handle_query(
+    query: &[u8],
+    src: SocketAddr,
+    config: &DnsConfig,
+    context: &Context,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let question = match parse_question(query) {
+        Some(q) if config.domains.iter().any(|d| matches(d, &q.name)) => q,
+        _ => return forward(config.upstream, query).await.map(Some),
+    };
+    let ip = match context.advertised_ip(src)? {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    };
+    debug!(name = %question.name, ?ip, "answering for the cloud");
+    Ok(Some(answer(query, &question, ip)))
+}
+
+/// Points the Xiaomi cloud hostnames at us and forwards every other query to
+/// the upstream resolver.
+pub async fn serve(config: DnsConfig, context: Arc<Context>) -> std::io::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(config.bind).await?);
+    info!(addr = %config.bind, "DNS server is now listening");
+    let config = Arc::new(config);
+    loop {
+        let mut buf = [0; 512];
+        let (amt, src) = socket.recv_from(&mut buf).await?;
+        let query = buf[..amt].to_vec();
+        let socket = Arc::clone(&socket);
+        let config = Arc::clone(&config);
+        let context = Arc::clone(&context);
+        // forwarding can take a while, don't make everyone else wait for it
+        tokio::spawn(async move {
+            match handle_query(&query, src, &config, &context).await {
+                Ok(Some(reply)) => {
+                    if let Err(e) = socket.send_to(&reply, src).await {
+                        warn!(%src, error = %e, "could not answer DNS query");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(%src, error = %e, "could not resolve DNS query"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&CLASS_IN.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn wildcards_only_match_subdomains() {
+        assert!(matches("*.ott.io.mi.com", "de.ott.io.mi.com"));
+        assert!(matches("ot.io.mi.com", "OT.io.mi.com"));
+        assert!(!matches("*.ott.io.mi.com", "ott.io.mi.com"));
+        assert!(!matches("*.ott.io.mi.com", "evilott.io.mi.com"));
+    }
+
+    #[test]
+    fn answers_a_queries_with_our_address() {
+        let q = query("de.ot.io.mi.com", TYPE_A);
+        let question = parse_question(&q).unwrap();
+        assert_eq!(question.name, "de.ot.io.mi.com");
+        assert_eq!(question.end, q.len());
+
+        let reply = answer(&q, &question, Some(Ipv4Addr::new(192, 168, 1, 2)));
+        assert_eq!(&reply[..4], &[0x12, 0x34, 0x85, 0x80]);
+        // one answer
+        assert_eq!(&reply[6..8], &[0, 1]);
+        assert_eq!(&reply[reply.len() - 4..], &[192, 168, 1, 2]);
+
+        // AAAA gets an empty answer rather than the real cloud's address
+        let q = query("de.ot.io.mi.com", 28);
+        let reply = answer(&q, &parse_question(&q).unwrap(), Some([1, 2, 3, 4].into()));
+        assert_eq!(&reply[6..8], &[0, 0]);
+        assert_eq!(reply.len(), q.len());
+
+        assert!(parse_question(&q[..q.len() - 1]).is_none());
+    }
+}
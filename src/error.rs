@@ -0,0 +1,55 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::codec::PacketError;
+use crate::commands::CommandError;
+use crate::config::ConfigError;
+
+/// What can go wrong setting up or running a [`crate::Server`], or one of
+/// the tools that go with it.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Opening a file we were pointed at, e.g. the capture.
+    #[error("{}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("could not bind to {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{}: {source}", path.display())]
+    Config {
+        path: PathBuf,
+        #[source]
+        source: ConfigError,
+    },
+    #[error("{}: {source}", path.display())]
+    Database {
+        path: PathBuf,
+        #[source]
+        source: rusqlite::Error,
+    },
+    /// A `[scripts]` entry that doesn't compile.
+    #[error("script {}: {reason}", path.display())]
+    Script { path: PathBuf, reason: String },
+    #[error(transparent)]
+    Packet(#[from] PacketError),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    /// Input from the command line or a file that isn't what it should be,
+    /// e.g. a packet that isn't hex.
+    #[error("{0}")]
+    Invalid(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::consumables::Part;
+use crate::devices::Connection;
+
+// Subscribers that fall this far behind start missing events rather than
+// holding up the packet handlers.
+const EVENT_BACKLOG: usize = 256;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceMessage {
+    pub device_id: u32,
+    pub method: String,
+    pub params: serde_json::Value,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// The robot called us.
+    Robot,
+    /// We pushed a command to the robot.
+    Cloud,
+}
+
+/// A call along with what was said back to it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Exchange {
+    pub device_id: u32,
+    pub origin: Origin,
+    pub method: String,
+    pub params: serde_json::Value,
+    /// None when the call went unanswered.
+    pub response: Option<serde_json::Value>,
+    pub timestamp: u64,
+}
+
+/// What binary frames are classed as on the event stream, in place of a
+/// method they don't have.
+pub const BINARY_FRAME: &str = "binary frame";
+
+/// A body the robot sent that decrypted but isn't miio JSON, hex encoded,
+/// for working out what newer firmwares are saying.
+#[derive(Clone, Debug, Serialize)]
+pub struct BinaryFrame {
+    pub device_id: u32,
+    pub origin: Origin,
+    /// Always null, there being no call to name.
+    pub method: Option<String>,
+    /// [`BINARY_FRAME`].
+    pub kind: &'static str,
+    pub payload: String,
+    pub length: usize,
+    pub timestamp: u64,
+}
+
+/// A robot moving from one connection state to another.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionChange {
+    pub device_id: u32,
+    pub from: Connection,
+    pub to: Connection,
+    pub timestamp: u64,
+}
+
+/// How worn a robot's parts are, and which of them have just worn out.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConsumableReport {
+    pub device_id: u32,
+    pub parts: Vec<Part>,
+    pub worn: Vec<Part>,
+    pub timestamp: u64,
+}
+
+/// Things worth telling the bridges (MQTT etc.) about.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A successfully decoded call from the robot.
+    Message(DeviceMessage),
+    /// Published once a call from either end has been answered, or given up
+    /// on.
+    Exchange(Exchange),
+    Connection(ConnectionChange),
+    Consumables(ConsumableReport),
+    /// A body from the robot that isn't miio JSON, which nothing handles.
+    Binary(BinaryFrame),
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BACKLOG);
+        EventBus { tx }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // an error only means nobody is listening right now
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::util::fixed_time_eq;
+
+use crate::codec::{random_bytes, to_hex};
+use crate::config::FdsConfig;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum FdsError {
+    /// The URL doesn't carry `Expires`, `Nonce` and `Signature`.
+    #[error("the URL isn't signed")]
+    Unsigned,
+    #[error("the URL expired at {0}")]
+    Expired(u64),
+    #[error("the signature doesn't match")]
+    BadSignature,
+    #[error("the URL has already been uploaded to")]
+    Reused,
+    #[error("the URL is being uploaded to already")]
+    Uploading,
+}
+
+/// Where a single-use URL's upload has got to.
+#[derive(Clone, Copy, PartialEq)]
+enum Nonce {
+    Uploading,
+    Used,
+}
+
+type Nonces = Arc<Mutex<HashMap<String, (u64, Nonce)>>>;
+
+/// Sets each URL apart from the others handed out for the same object in
+/// the same second, so that using one up leaves the rest alone.
+fn nonce() -> String {
+    to_hex(&random_bytes::<8>())
+}
+
+/// Signs the upload URLs we hand out and checks them when they're used, the
+/// way Xiaomi's FDS object store does: each carries when it expires, a nonce
+/// and a signature over those and the object's name, which also goes out as
+/// the `pwd` next to the URL. With `single_use`, each URL only takes one
+/// upload.
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+    expiry: u64,
+    single_use: bool,
+    /// The nonces of URLs that are being or have been uploaded to, and
+    /// when each URL expires, after which there's no need to remember it.
+    used: Nonces,
+}
+
+/// An upload on a URL we signed, which with `single_use` keeps any other
+/// upload off the URL while it's underway. Dropping it lets the URL be
+/// uploaded to again, unless it's been [`Upload::use_up`]'d.
+#[must_use]
+pub struct Upload {
+    nonce: Option<(Nonces, String)>,
+}
+
+impl Upload {
+    /// Uses up the URL, once the upload's been stored, so a robot whose
+    /// upload was turned away or couldn't be written can try again on it.
+    pub fn use_up(mut self) {
+        if let Some((used, nonce)) = self.nonce.take() {
+            if let Some((_, state)) = used.lock().unwrap().get_mut(&nonce) {
+                *state = Nonce::Used;
+            }
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some((used, nonce)) = self.nonce.take() {
+            used.lock().unwrap().remove(&nonce);
+        }
+    }
+}
+
+impl Signer {
+    pub fn new(config: &FdsConfig) -> Signer {
+        Signer {
+            secret: config.secret.as_bytes().to_vec(),
+            expiry: config.expiry,
+            single_use: config.single_use,
+            used: Arc::default(),
+        }
+    }
+
+    /// How long URLs handed out now stay good for, in seconds.
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
+    pub fn sign(&self, obj_name: &str, expires: u64, nonce: &str) -> String {
+        let mut mac = Hmac::new(Sha1::new(), &self.secret);
+        mac.input(format!("{}\n{}\n{}", obj_name, expires, nonce).as_bytes());
+        to_hex(mac.result().code())
+    }
+
+    /// `url` with its expiry, a fresh nonce and the signature tacked on,
+    /// along with the signature.
+    pub fn sign_url(&self, url: &str, obj_name: &str, expires: u64) -> (String, String) {
+        let nonce = nonce();
+        let signature = self.sign(obj_name, expires, &nonce);
+        let url = format!(
+            "{}?GalaxyAccessKeyId=dummycloud&Expires={}&Nonce={}&Signature={}",
+            url, expires, nonce, signature
+        );
+        (url, signature)
+    }
+
+    /// Checks a URL we signed is being used for what it was handed out for,
+    /// and in time.
+    pub fn verify(&self, obj_name: &str, url: &SignedUrl, now: u64) -> Result<(), FdsError> {
+        let (expires, nonce, signature) = match (url.expires, url.nonce, url.signature) {
+            (Some(expires), Some(nonce), Some(signature)) => (expires, nonce, signature),
+            _ => return Err(FdsError::Unsigned),
+        };
+        if !fixed_time_eq(
+            self.sign(obj_name, expires, nonce).as_bytes(),
+            signature.as_bytes(),
+        ) {
+            return Err(FdsError::BadSignature);
+        }
+        if now > expires {
+            return Err(FdsError::Expired(expires));
+        }
+        Ok(())
+    }
+
+    /// Like [`Signer::verify`], for an upload: with `single_use` it's
+    /// turned away if the URL has been used up already or another upload
+    /// is underway on it, and otherwise held for this one until the
+    /// [`Upload`] is used up or dropped.
+    pub fn verify_upload(
+        &self,
+        obj_name: &str,
+        url: &SignedUrl,
+        now: u64,
+    ) -> Result<Upload, FdsError> {
+        self.verify(obj_name, url, now)?;
+        if !self.single_use {
+            return Ok(Upload { nonce: None });
+        }
+        let (expires, nonce) = (url.expires.unwrap_or(now), url.nonce.unwrap_or(""));
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, (expires, state)| *state == Nonce::Uploading || *expires >= now);
+        match used.get(nonce) {
+            Some((_, Nonce::Used)) => return Err(FdsError::Reused),
+            Some((_, Nonce::Uploading)) => return Err(FdsError::Uploading),
+            None => {}
+        }
+        used.insert(nonce.to_string(), (expires, Nonce::Uploading));
+        Ok(Upload {
+            nonce: Some((Arc::clone(&self.used), nonce.to_string())),
+        })
+    }
+}
+
+/// What a signed URL carries in its query string.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignedUrl<'a> {
+    pub expires: Option<u64>,
+    pub nonce: Option<&'a str>,
+    pub signature: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_signature_and_expiry() {
+        let signer = Signer::new(&FdsConfig {
+            secret: "secret".to_string(),
+            expiry: 3600,
+            single_use: true,
+        });
+        let (url, pwd) = signer.sign_url("http://host/robomap/1/map/5", "1/map/5", 100);
+        assert!(url.ends_with(&format!("&Signature={}", pwd)));
+        assert_eq!(pwd.len(), 40);
+        let nonce = url
+            .split("Nonce=")
+            .nth(1)
+            .unwrap()
+            .split('&')
+            .next()
+            .unwrap();
+        let signed = SignedUrl {
+            expires: Some(100),
+            nonce: Some(nonce),
+            signature: Some(&pwd),
+        };
+
+        assert_eq!(signer.verify("1/map/5", &signed, 100), Ok(()));
+        assert_eq!(
+            signer.verify("1/map/5", &signed, 101),
+            Err(FdsError::Expired(100))
+        );
+        // stretching the expiry, swapping the nonce or pointing it at another
+        // object breaks it
+        let stretched = SignedUrl {
+            expires: Some(200),
+            ..signed
+        };
+        assert_eq!(
+            signer.verify("1/map/5", &stretched, 100),
+            Err(FdsError::BadSignature)
+        );
+        let other_nonce = SignedUrl {
+            nonce: Some("0000000000000000"),
+            ..signed
+        };
+        assert_eq!(
+            signer.verify("1/map/5", &other_nonce, 100),
+            Err(FdsError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify("1/map/6", &signed, 100),
+            Err(FdsError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify("1/map/5", &SignedUrl::default(), 100),
+            Err(FdsError::Unsigned)
+        );
+
+        // each URL takes one upload, though it can still be read back, and
+        // only a stored upload uses it up
+        let upload = signer.verify_upload("1/map/5", &signed, 100).unwrap();
+        assert_eq!(
+            signer.verify_upload("1/map/5", &signed, 100).err(),
+            Some(FdsError::Uploading)
+        );
+        drop(upload);
+        let upload = signer.verify_upload("1/map/5", &signed, 100).unwrap();
+        upload.use_up();
+        assert_eq!(
+            signer.verify_upload("1/map/5", &signed, 100).err(),
+            Some(FdsError::Reused)
+        );
+        assert_eq!(signer.verify("1/map/5", &signed, 100), Ok(()));
+        let (again, _) = signer.sign_url("http://host/robomap/1/map/5", "1/map/5", 100);
+        assert_ne!(again, url);
+    }
+}
@@ -0,0 +1,212 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::api;
+use crate::auth::{self, Access};
+use crate::commands::CommandError;
+use crate::events::Event;
+use crate::shutdown::Stage;
+use crate::Context;
+
+mod proto {
+    tonic::include_proto!("dummycloud");
+}
+
+use proto::dummycloud_server::{Dummycloud, DummycloudServer};
+use proto::{
+    CommandReply, CommandRequest, Device, DeviceState, ListDevicesRequest, ListDevicesResponse,
+    WatchStateRequest,
+};
+
+// How many state updates a slow client can fall behind by before it's cut
+// off.
+const STATE_BACKLOG: usize = 16;
+
+fn command_status(e: CommandError) -> Status {
+    let message = e.to_string();
+    match e {
+        CommandError::UnknownDevice(_) => Status::not_found(message),
+        CommandError::OtaBlocked | CommandError::DoNotDisturb => Status::permission_denied(message),
+        CommandError::Timeout => Status::deadline_exceeded(message),
+        CommandError::NoKey(_) | CommandError::Io(_) => Status::internal(message),
+    }
+}
+
+/// A command's params, which are sent as JSON.
+fn params(json: &str) -> Result<Value, Status> {
+    if json.trim().is_empty() {
+        return Ok(Value::Array(Vec::new()));
+    }
+    serde_json::from_str(json)
+        .map_err(|e| Status::invalid_argument(format!("params aren't JSON: {}", e)))
+}
+
+struct Service {
+    context: Arc<Context>,
+}
+
+impl Service {
+    /// Turns away calls without an `[auth]` token good for them, sent as
+    /// `authorization: Bearer <token>` metadata like over HTTP.
+    fn check<T>(&self, request: &Request<T>, needed: Access) -> Result<(), Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match auth::access(self.context.config.auth.as_ref(), token) {
+            Some(access) if access >= needed => Ok(()),
+            Some(_) => Err(Status::permission_denied("this token can only read")),
+            None => Err(Status::unauthenticated("a valid bearer token is needed")),
+        }
+    }
+
+    fn state(&self, device_id: u32) -> Option<DeviceState> {
+        let json = api::state_json(&self.context, device_id)?;
+        Some(DeviceState {
+            device_id,
+            json: json.to_string(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Dummycloud for Service {
+    async fn list_devices(
+        &self,
+        request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesResponse>, Status> {
+        self.check(&request, Access::Read)?;
+        let context = &self.context;
+        let devices = context
+            .devices
+            .all()
+            .iter()
+            .map(|device| Device {
+                id: device.id,
+                addr: device.addr.to_string(),
+                last_seen: device.last_seen_secs(),
+                stamp: device.stamp,
+                booted: device.booted_secs(),
+                connection: serde_json::to_value(device.connection)
+                    .ok()
+                    .and_then(|c| c.as_str().map(str::to_string))
+                    .unwrap_or_default(),
+                wrong_key: context.keys.looks_wrong(device.id),
+            })
+            .collect();
+        Ok(Response::new(ListDevicesResponse { devices }))
+    }
+
+    type WatchStateStream = ReceiverStream<Result<DeviceState, Status>>;
+
+    async fn watch_state(
+        &self,
+        request: Request<WatchStateRequest>,
+    ) -> Result<Response<Self::WatchStateStream>, Status> {
+        self.check(&request, Access::Read)?;
+        let device_id = request.into_inner().device_id;
+        let first = self.state(device_id).ok_or_else(|| {
+            Status::not_found(format!("device {} hasn't checked in yet", device_id))
+        })?;
+        let (tx, rx) = mpsc::channel(STATE_BACKLOG);
+        let mut events = self.context.events.subscribe();
+        let service = Service {
+            context: Arc::clone(&self.context),
+        };
+        tokio::spawn(async move {
+            if tx.send(Ok(first)).await.is_err() {
+                return;
+            }
+            let mut updated = service.context.state.get(device_id).map(|s| s.updated);
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(Event::Message(m)) if m.device_id == device_id => {}
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(missed, "gRPC state watcher fell behind and skipped events");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = tx.closed() => return,
+                }
+                // only what changes the state is worth sending
+                let now = service.context.state.get(device_id).map(|s| s.updated);
+                if now == updated {
+                    continue;
+                }
+                updated = now;
+                let state = match service.state(device_id) {
+                    Some(state) => state,
+                    None => return,
+                };
+                if tx.send(Ok(state)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn send_command(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<CommandReply>, Status> {
+        self.check(&request, Access::Command)?;
+        let command = request.into_inner();
+        let params = params(&command.params)?;
+        let reply = self
+            .context
+            .send_command(command.device_id, &command.method, &params)
+            .await
+            .map_err(command_status)?;
+        Ok(Response::new(CommandReply {
+            result: reply.result.map(|r| r.to_string()),
+            error: reply.error.map(|e| e.to_string()),
+        }))
+    }
+}
+
+/// Serves gRPC on `addr` until we're closing down.
+pub async fn serve(addr: SocketAddr, context: Arc<Context>) -> Result<(), tonic::transport::Error> {
+    info!(%addr, "gRPC server is now listening");
+    let closing = Arc::clone(&context);
+    tonic::transport::Server::builder()
+        .add_service(DummycloudServer::new(Service { context }))
+        .serve_with_shutdown(addr, async move {
+            closing.shutdown.reached(Stage::Closing).await
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_commands_onto_grpc() {
+        assert_eq!(params("").unwrap(), serde_json::json!([]));
+        assert_eq!(params("[1, 2]").unwrap(), serde_json::json!([1, 2]));
+        assert_eq!(
+            params("{").unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            command_status(CommandError::Timeout).code(),
+            tonic::Code::DeadlineExceeded
+        );
+        assert_eq!(
+            command_status(CommandError::UnknownDevice(5)).code(),
+            tonic::Code::NotFound
+        );
+    }
+}
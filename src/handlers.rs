@@ -0,0 +1,677 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::fds::Signer;
+use crate::http;
+use crate::models::{Model, Preset, UrlStyle};
+use crate::payload::{MessagePayload, ResponsePayload};
+use crate::scripting::Script;
+use crate::uploads::ExpectedUploads;
+
+// JSON-RPC's "method not found", which firmwares take as a final answer.
+const METHOD_NOT_SUPPORTED: i32 = -32601;
+
+/// How many room map URLs are handed out when the robot doesn't say.
+const DEFAULT_ROOM_URLS: usize = 4;
+/// And the most handed out at once, however many it asks for.
+const MAX_ROOM_URLS: usize = 32;
+
+/// What a handler gets to know about where a message came from.
+pub struct Request {
+    pub device_id: u32,
+    /// Our address as far as the robot is concerned, i.e. what it should use
+    /// to get back to us.
+    pub advertised_ip: IpAddr,
+    /// The port to go with it, when the robot's listener advertises one of
+    /// its own.
+    pub advertised_port: Option<u16>,
+    pub model: Model,
+    /// The model's preset, as the config has it for this robot.
+    pub preset: Preset,
+}
+
+pub trait Handler: Send + Sync {
+    /// Returns the reply for the robot, or None to leave the message
+    /// unanswered.
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload>;
+}
+
+/// The robot is only telling us something, all it wants back is an "ok".
+pub struct Acknowledge;
+
+impl Handler for Acknowledge {
+    fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::new(msg.id, json!("ok")))
+    }
+}
+
+/// Turns the robot down, for calls it would otherwise keep retrying until
+/// it got an answer, e.g. uploads to cloud storage we don't have.
+pub struct Refuse {
+    pub code: i32,
+    pub message: &'static str,
+}
+
+impl Handler for Refuse {
+    fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::error(msg.id, self.code, self.message))
+    }
+}
+
+/// Whatever the robot asks about firmware updates, there aren't any.
+pub struct NoUpdate;
+
+impl Handler for NoUpdate {
+    fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::new(msg.id, json!({ "ota_state": "idle" })))
+    }
+}
+
+/// Tells the robot which region it's registered in.
+pub struct CountryCode {
+    pub code: String,
+}
+
+impl Handler for CountryCode {
+    fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::new(
+            msg.id,
+            json!({ "ctry_code": self.code }),
+        ))
+    }
+}
+
+/// Settings the app would have stored with the cloud, of which there are
+/// none.
+pub struct AppData;
+
+impl Handler for AppData {
+    fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::new(msg.id, json!({})))
+    }
+}
+
+/// Tells the robot which cloud servers to use, which had better be us.
+pub struct OtcInfo {
+    pub port: u16,
+}
+
+impl Handler for OtcInfo {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        let preset = &req.preset;
+        let endpoint = json!({
+            "ip": req.advertised_ip.to_string(),
+            "port": req.advertised_port.unwrap_or(self.port)
+        });
+        let endpoints = vec![endpoint; preset.otc_endpoints];
+        Some(ResponsePayload::new(
+            msg.id,
+            json!({
+                "otc_list": endpoints,
+                "otc_test": {
+                    "list": endpoints,
+                    "interval": preset.otc_interval,
+                    "firsttest": preset.otc_firsttest
+                }
+            }),
+        ))
+    }
+}
+
+/// Answers `miIO.info` the way a robot would describe itself, only for the
+/// cloud side: what it's called and where it's reached.
+pub struct MiioInfo {
+    pub port: u16,
+}
+
+impl Handler for MiioInfo {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        Some(ResponsePayload::new(
+            msg.id,
+            json!({
+                "hw_ver": "dummycloud",
+                "fw_ver": env!("CARGO_PKG_VERSION"),
+                "did": req.device_id.to_string(),
+                "netif": { "localIp": req.advertised_ip.to_string() },
+                "otc": {
+                    "ip": req.advertised_ip.to_string(),
+                    "port": req.advertised_port.unwrap_or(self.port)
+                }
+            }),
+        ))
+    }
+}
+
+/// Something is trying to put a robot on a wifi network, as the app does
+/// with freshly reset ones. All that's done with it is logging which
+/// network, never its password.
+pub struct ConfigRouter;
+
+impl Handler for ConfigRouter {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        let field = |name: &str| msg.params.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        info!(
+            device_id = req.device_id,
+            ssid = field("ssid"),
+            uid = %msg.params.get("uid").unwrap_or(&serde_json::Value::Null),
+            country = field("country_domain"),
+            tz = field("tz"),
+            "robot is being provisioned onto a wifi network"
+        );
+        Some(ResponsePayload::new(msg.id, json!("ok")))
+    }
+}
+
+const CONFIG_ROUTER: &str = "miIO.config_router";
+const BLANKED: &str = "********";
+
+/// Blanks out the wifi password in a `miIO.config_router` call, before it
+/// gets anywhere near the history, MQTT or webhooks.
+pub fn redact(message: &mut MessagePayload) {
+    if message.method != CONFIG_ROUTER {
+        return;
+    }
+    if let Some(passwd) = message.params.get_mut("passwd") {
+        *passwd = json!(BLANKED);
+    }
+}
+
+/// [`redact`] for a message as the robot sent it, a single call or a batch
+/// of them, so that not even captures and logs of it see the password.
+/// Text mentioning a password that can't be read as JSON is left out
+/// altogether.
+pub fn redact_plaintext(plaintext: &[u8]) -> Cow<'_, [u8]> {
+    if !plaintext.windows(6).any(|w| w == b"passwd") {
+        return Cow::Borrowed(plaintext);
+    }
+    let mut body: Value = match serde_json::from_slice(plaintext) {
+        Ok(body) => body,
+        Err(_) => return Cow::Borrowed(b"(unreadable, with a password in it)"),
+    };
+    let calls = match &mut body {
+        Value::Array(calls) => calls.iter_mut().collect(),
+        call => vec![call],
+    };
+    let mut blanked = false;
+    for call in calls {
+        if call.get("method").and_then(Value::as_str) != Some(CONFIG_ROUTER) {
+            continue;
+        }
+        if let Some(passwd) = call.pointer_mut("/params/passwd") {
+            *passwd = json!(BLANKED);
+            blanked = true;
+        }
+    }
+    if !blanked {
+        return Cow::Borrowed(plaintext);
+    }
+    Cow::Owned(serde_json::to_vec(&body).unwrap_or_default())
+}
+
+/// Hands out an upload URL on our own HTTP server for the robot's map, or
+/// its logs and crash dumps.
+pub struct PresignedUrl {
+    /// What the upload is stored as, `map` or `logs`.
+    pub kind: &'static str,
+    pub http_port: u16,
+    /// Hand out `https://` URLs on this port instead.
+    pub https_port: Option<u16>,
+    /// Signs the URLs so uploads can be checked, see `[fds]`.
+    pub signer: Option<Signer>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// The URL to hand out for `obj_name`, its `pwd` and when it expires.
+fn presign(
+    signer: Option<&Signer>,
+    url: String,
+    obj_name: &str,
+    now: u64,
+) -> (String, String, u64) {
+    match signer {
+        Some(signer) => {
+            let expires = now + signer.expiry();
+            let (url, pwd) = signer.sign_url(&url, obj_name, expires);
+            (url, pwd, expires)
+        }
+        None => (url, "password".to_string(), now + 3600),
+    }
+}
+
+impl Handler for PresignedUrl {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        let now = self.clock.epoch_secs();
+        let host = req.advertised_ip.to_string();
+        let obj_name = format!("{}/{}/{}", req.device_id, self.kind, now);
+        let url = http::upload_url(&host, self.http_port, self.https_port, &obj_name);
+        let (url, pwd, expires) = presign(self.signer.as_ref(), url, &obj_name, now);
+        let upload = json!({
+            "url": url,
+            "obj_name": obj_name,
+            "method": "PUT",
+            "expires_time": expires,
+            "ok": true,
+            "pwd": pwd
+        });
+        let result = match req.preset.url_style {
+            UrlStyle::Flat => upload,
+            UrlStyle::Keyed => json!({ "": upload }),
+        };
+        Some(ResponsePayload::new(msg.id, result))
+    }
+}
+
+/// Same as [`PresignedUrl`], but for the per-room maps, as many of them as
+/// the robot asks for. Only uploads to the URLs handed out are taken.
+pub struct BatchRoomUrls {
+    pub http_port: u16,
+    pub https_port: Option<u16>,
+    pub signer: Option<Signer>,
+    pub clock: Arc<dyn Clock>,
+    pub expected: Arc<ExpectedUploads>,
+}
+
+/// How many URLs the robot wants: a number, alone, in a list or as `count`
+/// or `num`, or else one for each thing in the list it sent.
+fn room_count(params: &serde_json::Value) -> usize {
+    use serde_json::Value;
+    let count = match params {
+        Value::Number(n) => n.as_u64(),
+        Value::Array(items) => match &items[..] {
+            [Value::Number(n)] => n.as_u64(),
+            [] => None,
+            items => Some(items.len() as u64),
+        },
+        Value::Object(fields) => ["count", "num"]
+            .iter()
+            .find_map(|key| fields.get(*key)?.as_u64()),
+        _ => None,
+    };
+    count.map_or(DEFAULT_ROOM_URLS, |n| (n as usize).clamp(1, MAX_ROOM_URLS))
+}
+
+impl Handler for BatchRoomUrls {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        let now = self.clock.epoch_secs();
+        let host = req.advertised_ip.to_string();
+        let urls: Vec<String> = (1..=room_count(&msg.params))
+            .map(|room| {
+                let obj_name = format!("{}/rooms/{}", req.device_id, room);
+                let url = http::upload_url(&host, self.http_port, self.https_port, &obj_name);
+                let (url, _, expires) = presign(self.signer.as_ref(), url, &obj_name, now);
+                self.expected.expect(obj_name, expires, now);
+                url
+            })
+            .collect();
+        Some(ResponsePayload::new(msg.id, json!(urls)))
+    }
+}
+
+/// Answers with a JSON file's contents, after filling in `{{id}}` (the
+/// message's), `{{now}}` (in seconds since the epoch) and `{{host}}` (our
+/// advertised address, unquoted).
+pub struct Template {
+    pub body: String,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Template {
+    fn render(&self, id: u32, now: u64, host: &str) -> serde_json::Result<serde_json::Value> {
+        let body = self
+            .body
+            .replace("{{id}}", &id.to_string())
+            .replace("{{now}}", &now.to_string())
+            .replace("{{host}}", host);
+        serde_json::from_str(&body)
+    }
+}
+
+impl Handler for Template {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        let host = req.advertised_ip.to_string();
+        match self.render(msg.id, self.clock.epoch_secs(), &host) {
+            Ok(result) => Some(ResponsePayload::new(msg.id, result)),
+            Err(e) => {
+                warn!(method = %msg.method, error = %e, "template isn't valid JSON");
+                None
+            }
+        }
+    }
+}
+
+/// Looks up the handler for a method. Registering a method a second time
+/// replaces whatever handled it before. Exact matches win over prefixes.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Box<dyn Handler>>,
+    prefixes: Vec<(String, Box<dyn Handler>)>,
+    /// What [`BatchRoomUrls`] handed out URLs for, for the upload server to
+    /// check against.
+    uploads: Arc<ExpectedUploads>,
+}
+
+impl HandlerRegistry {
+    pub fn with_defaults(config: &Config, clock: Arc<dyn Clock>) -> HandlerRegistry {
+        let mut registry = HandlerRegistry::default();
+        // keep-alives and status reports
+        for method in &["props", "_otc.ncinfo", "_otc.ncstat"] {
+            registry.register(method, Acknowledge);
+        }
+        // event.status, event.bin_full, event.back_to_dock, event.error_code
+        // and friends are all just the robot letting us know
+        registry.register_prefix("event.", Acknowledge);
+        registry.register(
+            "_async.store_sds",
+            Refuse {
+                code: METHOD_NOT_SUPPORTED,
+                message: "not supported",
+            },
+        );
+        if config.ota.block {
+            // miIO.ota, miIO.ota_state, ...
+            registry.register_prefix("miIO.ota", NoUpdate);
+            registry.register("miIO.get_ota_state", NoUpdate);
+            registry.register_prefix(
+                "_async.",
+                Refuse {
+                    code: METHOD_NOT_SUPPORTED,
+                    message: "no update available",
+                },
+            );
+        }
+        // the S6 asks for these every 30 seconds until it gets an answer
+        registry.register(
+            "_sync.getctrycode",
+            CountryCode {
+                code: config.country.clone().unwrap_or_else(|| "DE".to_string()),
+            },
+        );
+        registry.register("_sync.getAppData", AppData);
+        registry.register(
+            "_otc.info",
+            OtcInfo {
+                port: config.advertise.port,
+            },
+        );
+        // provisioning, which we only look on at
+        registry.register(
+            "miIO.info",
+            MiioInfo {
+                port: config.advertise.port,
+            },
+        );
+        registry.register("miIO.config_router", ConfigRouter);
+        let https_port = config.tls.as_ref().map(|_| config.advertise.https_port);
+        let signer = config.fds.as_ref().map(Signer::new);
+        registry.register(
+            "_sync.gen_presigned_url",
+            PresignedUrl {
+                kind: "map",
+                http_port: config.advertise.http_port,
+                https_port,
+                signer: signer.clone(),
+                clock: Arc::clone(&clock),
+            },
+        );
+        for method in ["_sync.gen_tmp_presigned_url", "_sync.upload_artifacts"] {
+            registry.register(
+                method,
+                PresignedUrl {
+                    kind: http::LOGS,
+                    http_port: config.advertise.http_port,
+                    https_port,
+                    signer: signer.clone(),
+                    clock: Arc::clone(&clock),
+                },
+            );
+        }
+        registry.register(
+            "_sync.batch_gen_room_up_url",
+            BatchRoomUrls {
+                http_port: config.advertise.http_port,
+                https_port,
+                signer,
+                clock: Arc::clone(&clock),
+                expected: registry.uploads(),
+            },
+        );
+        if let Some(dir) = &config.handler_dir {
+            match registry.load_templates(dir, clock) {
+                Ok(count) => info!(dir = %dir.display(), count, "loaded response templates"),
+                Err(e) => {
+                    warn!(dir = %dir.display(), error = %e, "could not load response templates")
+                }
+            }
+        }
+        for (method, path) in &config.scripts {
+            match Script::load(path) {
+                Ok(script) => registry.register(method, script),
+                Err(e) => warn!(%method, error = %e, "could not load script"),
+            }
+        }
+        registry
+    }
+
+    /// Registers a [`Template`] for every `<method>.json` in `dir`, skipping
+    /// the ones that wouldn't be valid JSON once filled in. Returns how many
+    /// were registered.
+    pub fn load_templates(&mut self, dir: &Path, clock: Arc<dyn Clock>) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let method = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(method) => method.to_string(),
+                None => continue,
+            };
+            let template = Template {
+                body: fs::read_to_string(&path)?,
+                clock: Arc::clone(&clock),
+            };
+            if let Err(e) = template.render(1, 0, "127.0.0.1") {
+                warn!(path = %path.display(), error = %e, "skipping template that isn't valid JSON");
+                continue;
+            }
+            self.register(&method, template);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The room map uploads the robot has been handed URLs for.
+    pub fn uploads(&self) -> Arc<ExpectedUploads> {
+        Arc::clone(&self.uploads)
+    }
+
+    pub fn register<H: Handler + 'static>(&mut self, method: &str, handler: H) {
+        self.handlers.insert(method.to_string(), Box::new(handler));
+    }
+
+    pub fn register_prefix<H: Handler + 'static>(&mut self, prefix: &str, handler: H) {
+        self.prefixes.retain(|(p, _)| p != prefix);
+        self.prefixes.push((prefix.to_string(), Box::new(handler)));
+    }
+
+    fn lookup(&self, method: &str) -> Option<&dyn Handler> {
+        if let Some(handler) = self.handlers.get(method) {
+            return Some(handler.as_ref());
+        }
+        // the longest prefix is the most specific one
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| method.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler.as_ref())
+    }
+
+    /// None either means nobody handles this method, the robot's model
+    /// doesn't expect an answer to it, or the handler decided not to answer.
+    pub fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        if !req.model.answers(&msg.method) {
+            return None;
+        }
+        self.lookup(&msg.method)?.handle(msg, req)
+    }
+
+    pub fn handles(&self, method: &str) -> bool {
+        self.lookup(method).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle(&self, msg: &MessagePayload, _req: &Request) -> Option<ResponsePayload> {
+            Some(ResponsePayload::new(msg.id, msg.params.clone()))
+        }
+    }
+
+    fn message(method: &str) -> MessagePayload {
+        serde_json::from_value(json!({"id": 7, "method": method, "params": [1, 2]})).unwrap()
+    }
+
+    #[test]
+    fn registered_handlers_override_defaults() {
+        let req = Request {
+            device_id: 1234,
+            advertised_ip: [192, 168, 1, 2].into(),
+            advertised_port: None,
+            model: Model::default(),
+            preset: Model::default().preset(),
+        };
+        let clock = Arc::new(FakeClock::at_epoch_secs(1_600_000_000));
+        let mut registry = HandlerRegistry::with_defaults(&Config::default(), clock.clone());
+        assert_eq!(
+            json!(registry.handle(&message("props"), &req)),
+            json!({"id": 7, "result": "ok"})
+        );
+
+        registry.register("props", Echo);
+        assert_eq!(
+            json!(registry.handle(&message("props"), &req)),
+            json!({"id": 7, "result": [1, 2]})
+        );
+        assert!(registry.handle(&message("nope"), &req).is_none());
+        assert_eq!(
+            json!(registry.handle(&message("miIO.ota_state"), &req)),
+            json!({"id": 7, "result": {"ota_state": "idle"}})
+        );
+        assert!(!HandlerRegistry::with_defaults(
+            &Config::parse("[ota]\nblock = false").unwrap(),
+            clock.clone()
+        )
+        .handles("miIO.ota"));
+        assert_eq!(
+            json!(registry.handle(&message("_sync.getctrycode"), &req)),
+            json!({"id": 7, "result": {"ctry_code": "DE"}})
+        );
+        assert_eq!(
+            json!(registry.handle(&message("_async.store_sds"), &req)),
+            json!({"id": 7, "error": {"code": -32601, "message": "not supported"}})
+        );
+
+        clock.advance(std::time::Duration::from_secs(60));
+        let url = json!(registry.handle(&message("_sync.gen_presigned_url"), &req));
+        assert_eq!(url["result"][""]["obj_name"], "1234/map/1600000060");
+        assert_eq!(url["result"][""]["expires_time"], 1_600_003_660);
+        let logs = json!(registry.handle(&message("_sync.gen_tmp_presigned_url"), &req));
+        assert_eq!(logs["result"][""]["obj_name"], "1234/logs/1600000060");
+        let rooms = json!(registry.handle(&message("_sync.batch_gen_room_up_url"), &req));
+        assert_eq!(rooms["result"].as_array().unwrap().len(), 2);
+        assert!(registry.uploads().take("1234/rooms/2", 1_600_000_060));
+        assert!(!registry.uploads().take("1234/rooms/3", 1_600_000_060));
+        assert_eq!(room_count(&json!({"count": 6})), 6);
+        assert_eq!(room_count(&json!([3])), 3);
+        assert_eq!(room_count(&json!({})), DEFAULT_ROOM_URLS);
+        assert_eq!(room_count(&json!(1000)), MAX_ROOM_URLS);
+
+        let info = json!(registry.handle(&message("miIO.info"), &req));
+        assert_eq!(info["result"]["did"], "1234");
+        assert_eq!(
+            info["result"]["otc"],
+            json!({"ip": "192.168.1.2", "port": 8053})
+        );
+        let mut provisioning: MessagePayload = serde_json::from_value(json!({
+            "id": 7,
+            "method": "miIO.config_router",
+            "params": {"ssid": "home", "passwd": "hunter22", "uid": 42}
+        }))
+        .unwrap();
+        redact(&mut provisioning);
+        assert_eq!(provisioning.params["passwd"], "********");
+        let batch = br#"[{"id":6,"method":"props","params":{}},
+            {"id":7,"method":"miIO.config_router","params":{"ssid":"home","passwd":"hunter22"}}]"#;
+        let redacted = redact_plaintext(batch);
+        assert!(!String::from_utf8_lossy(&redacted).contains("hunter22"));
+        assert!(String::from_utf8_lossy(&redacted).contains(r#""ssid":"home""#));
+        assert!(
+            !String::from_utf8_lossy(&redact_plaintext(&batch[..batch.len() - 4]))
+                .contains("hunter22")
+        );
+        let props = br#"{"id":6,"method":"props","params":{"passwd_set":true}}"#;
+        assert_eq!(&redact_plaintext(props)[..], &props[..]);
+        assert_eq!(
+            json!(registry.handle(&provisioning, &req)),
+            json!({"id": 7, "result": "ok"})
+        );
+
+        assert!(registry.handles("event.bin_full"));
+        registry.register_prefix("event.bin", Echo);
+        assert_eq!(
+            json!(registry.handle(&message("event.bin_full"), &req)),
+            json!({"id": 7, "result": [1, 2]})
+        );
+    }
+
+    #[test]
+    fn serves_templates_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("dummycloud-templates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("_sync.getctrycode.json"),
+            r#"{"ctry_code": "US", "id": {{id}}, "at": {{now}}, "url": "http://{{host}}/"}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("broken.json"), "{{{").unwrap();
+        fs::write(dir.join("notes.txt"), "not a template").unwrap();
+        let config = Config {
+            handler_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let registry =
+            HandlerRegistry::with_defaults(&config, Arc::new(FakeClock::at_epoch_secs(1000)));
+        let req = Request {
+            device_id: 1234,
+            advertised_ip: [192, 168, 1, 2].into(),
+            advertised_port: None,
+            model: Model::default(),
+            preset: Model::default().preset(),
+        };
+        assert_eq!(
+            json!(registry.handle(&message("_sync.getctrycode"), &req)),
+            json!({"id": 7, "result": {
+                "ctry_code": "US", "id": 7, "at": 1000, "url": "http://192.168.1.2/"
+            }})
+        );
+        assert!(!registry.handles("broken"));
+        assert!(!registry.handles("notes"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::codec::{self, PacketHeader, HEADER_SIZE};
+use crate::error::{Error, Result};
+
+/// Where robots listen for miio packets on the local network.
+pub const MIIO_PORT: u16 = 54321;
+
+// Where the robot can be found while it's running its own access point,
+// waiting to be set up.
+const PROVISIONING_IP: [u8; 4] = [192, 168, 8, 1];
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The discovery hello: a bare header with everything but the magic and
+/// length set to 0xff.
+pub fn hello_packet() -> [u8; HEADER_SIZE] {
+    PacketHeader::hello(codec::UNSET).to_bytes()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HelloReply {
+    pub device_id: u32,
+    pub stamp: u32,
+    /// Only robots that haven't been set up yet hand out their token, the
+    /// rest fill the field with 0x00 or 0xff.
+    pub token: Option<[u8; 16]>,
+}
+
+pub fn parse_hello_reply(packet: &[u8]) -> Result<HelloReply, codec::PacketError> {
+    let header = PacketHeader::parse(packet)?;
+    Ok(HelloReply {
+        device_id: header.device_id,
+        stamp: header.stamp,
+        token: if header.is_unsigned() {
+            None
+        } else {
+            Some(header.checksum)
+        },
+    })
+}
+
+/// Says hello to the robot at `addr` until it answers.
+pub async fn hello(addr: SocketAddr) -> std::io::Result<HelloReply> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let mut buf = [0; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&hello_packet(), addr).await?;
+        let received = tokio::time::timeout(ATTEMPT_TIMEOUT, socket.recv_from(&mut buf)).await;
+        if let Ok(received) = received {
+            let (amt, _) = received?;
+            return parse_hello_reply(&buf[..amt])
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()));
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "the robot didn't answer",
+    ))
+}
+
+/// `dummycloud extract-token [ip]`: prints the device id and token of a robot
+/// that's waiting to be set up, by default at the address it uses for its own
+/// access point.
+pub async fn extract_token_command(ip: Option<IpAddr>) -> Result<()> {
+    let ip = ip.unwrap_or_else(|| PROVISIONING_IP.into());
+    let reply = hello((ip, MIIO_PORT).into()).await?;
+    println!("device id: {}", reply.device_id);
+    match reply.token {
+        Some(token) => println!("token: {}", codec::to_hex(&token)),
+        None => return Err(Error::Invalid("the robot is keeping its token to itself, reset its Wi-Fi settings to put it back into setup mode".to_string())),
+    }
+    Ok(())
+}
+
+/// What cloud keys are made of: the robot's own are 16 letters and digits.
+const KEY_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// A random cloud key of the same shape as the ones robots come with, for
+/// provisioning a robot to dummycloud from scratch.
+pub fn generate_key() -> Result<String> {
+    let random: [u8; 16] = codec::random_bytes();
+    Ok(random
+        .iter()
+        // 256 isn't a multiple of 62, but a slight bias does no harm here
+        .map(|b| KEY_CHARS[*b as usize % KEY_CHARS.len()] as char)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{DEVICE_ID_OFFSET, STAMP_OFFSET};
+
+    const TOKEN_OFFSET: usize = 16;
+
+    #[test]
+    fn generates_keys_like_the_robots_own() {
+        let key = generate_key().unwrap();
+        assert_eq!(key.len(), 16);
+        assert!(key.bytes().all(|b| KEY_CHARS.contains(&b)));
+        assert_ne!(key, generate_key().unwrap());
+    }
+
+    #[test]
+    fn reads_the_token_from_unprovisioned_robots() {
+        let mut reply = hello_packet();
+        reply[4..8].copy_from_slice(&[0; 4]);
+        reply[DEVICE_ID_OFFSET..STAMP_OFFSET].copy_from_slice(&277_123_456u32.to_be_bytes());
+        reply[STAMP_OFFSET..TOKEN_OFFSET].copy_from_slice(&42u32.to_be_bytes());
+        assert_eq!(parse_hello_reply(&reply).unwrap().token, None);
+
+        reply[TOKEN_OFFSET..].copy_from_slice(b"0123456789abcdef");
+        assert_eq!(
+            parse_hello_reply(&reply).unwrap(),
+            HelloReply {
+                device_id: 277_123_456,
+                stamp: 42,
+                token: Some(*b"0123456789abcdef"),
+            }
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use serde_json::{json, Value};
+
+use crate::config::HomeAssistantConfig;
+use crate::state::DeviceState;
+
+/// The robot's `state` codes as Home Assistant's vacuum states.
+pub(crate) fn vacuum_state(code: u64) -> &'static str {
+    match code {
+        // cleaning, remote control, spot, go to, zone and room cleaning
+        4 | 5 | 7 | 11 | 16 | 17 | 18 => "cleaning",
+        6 | 15 => "returning",
+        // charging, and done charging
+        8 | 100 => "docked",
+        10 => "paused",
+        9 | 12 => "error",
+        _ => "idle",
+    }
+}
+
+/// What gets published to the state topic, which the vacuum and both sensors
+/// read from.
+pub fn state_json(state: &DeviceState) -> Value {
+    let mut out = json!({
+        "state": state
+            .field("state")
+            .and_then(Value::as_u64)
+            .map_or("idle", vacuum_state),
+        "error": state.field("error_code").and_then(Value::as_u64).unwrap_or(0),
+    });
+    if let Some(battery) = state.field("battery").and_then(Value::as_u64) {
+        out["battery_level"] = json!(battery);
+    }
+    out
+}
+
+/// The miio method for one of Home Assistant's vacuum commands.
+pub fn method_for(command: &str) -> Option<&'static str> {
+    match command {
+        "start" => Some("app_start"),
+        "pause" => Some("app_pause"),
+        "stop" => Some("app_stop"),
+        "return_to_base" => Some("app_charge"),
+        "clean_spot" => Some("app_spot"),
+        "locate" => Some("find_me"),
+        _ => None,
+    }
+}
+
+/// The retained config messages that make Home Assistant pick up a robot,
+/// as (topic, payload) pairs.
+pub fn discovery_configs(
+    config: &HomeAssistantConfig,
+    device_id: u32,
+    name: Option<&str>,
+    state_topic: &str,
+    command_topic: &str,
+) -> Vec<(String, Value)> {
+    let object_id = format!("dummycloud_{}", device_id);
+    let device = json!({
+        "identifiers": [object_id],
+        "name": name.map_or_else(|| format!("Robot {}", device_id), String::from),
+        "manufacturer": "Xiaomi",
+    });
+    let vacuum = json!({
+        "name": null,
+        "unique_id": object_id,
+        "device": device,
+        "state_topic": state_topic,
+        "command_topic": command_topic,
+        "supported_features": ["start", "pause", "stop", "return_home", "clean_spot", "locate"],
+    });
+    let battery = json!({
+        "name": "Battery",
+        "unique_id": format!("{}_battery", object_id),
+        "device": device,
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.battery_level }}",
+        "device_class": "battery",
+        "unit_of_measurement": "%",
+    });
+    let error = json!({
+        "name": "Error",
+        "unique_id": format!("{}_error", object_id),
+        "device": device,
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.error }}",
+        "entity_category": "diagnostic",
+    });
+    vec![
+        (
+            format!("{}/vacuum/{}/config", config.prefix, object_id),
+            vacuum,
+        ),
+        (
+            format!("{}/sensor/{}_battery/config", config.prefix, object_id),
+            battery,
+        ),
+        (
+            format!("{}/sensor/{}_error/config", config.prefix, object_id),
+            error,
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_vacuum_state_from_status_and_props() {
+        let mut state = DeviceState::default();
+        assert_eq!(state_json(&state), json!({"state": "idle", "error": 0}));
+
+        state.props.insert("battery".to_string(), json!(64));
+        state.status = Some(json!({"state": 5, "battery": 87}));
+        assert_eq!(
+            state_json(&state),
+            json!({"state": "cleaning", "error": 0, "battery_level": 87})
+        );
+
+        state.status = Some(json!({"state": 12, "error_code": 3}));
+        assert_eq!(
+            state_json(&state),
+            json!({"state": "error", "error": 3, "battery_level": 64})
+        );
+
+        let configs = discovery_configs(&HomeAssistantConfig::default(), 1234, None, "s", "c");
+        assert_eq!(configs[0].0, "homeassistant/vacuum/dummycloud_1234/config");
+        assert_eq!(configs[0].1["command_topic"], "c");
+        assert_eq!(
+            configs[1].0,
+            "homeassistant/sensor/dummycloud_1234_battery/config"
+        );
+        assert_eq!(method_for("return_to_base"), Some("app_charge"));
+    }
+}
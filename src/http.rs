@@ -0,0 +1,515 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::middleware;
+use axum::response::IntoResponse;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::api;
+use crate::auth;
+use crate::fds::{SignedUrl, Upload};
+use crate::map::{self, MapError, RRMap};
+use crate::openapi;
+use crate::render;
+use crate::shutdown::Stage;
+use crate::storage::MapStore;
+use crate::valetudo;
+use crate::voice;
+use crate::ws;
+use crate::Context;
+
+/// The kind log and crash dump uploads are stored as.
+pub const LOGS: &str = "logs";
+
+// Maps are usually a couple hundred kilobytes, but leave plenty of headroom
+// for bigger floorplans.
+const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn upload_url(host: &str, http_port: u16, https_port: Option<u16>, obj_name: &str) -> String {
+    match https_port {
+        Some(port) => format!("https://{}:{}/robomap/{}", host, port, obj_name),
+        None => format!("http://{}:{}/robomap/{}", host, http_port, obj_name),
+    }
+}
+
+/// Checks that a map upload is a complete rr map before it's stored, gzipped
+/// or not. The other uploads aren't rr maps, so they're taken as they are.
+fn validate_upload(obj_name: &str, body: &[u8]) -> Result<(), MapError> {
+    if obj_name.split('/').nth(1) != Some("map") {
+        return Ok(());
+    }
+    let map = map::parse(body)?;
+    info!(
+        major_version = map.header.major_version,
+        minor_version = map.header.minor_version,
+        blocks = ?map.block_types,
+        "map upload is valid"
+    );
+    Ok(())
+}
+
+/// Logs and crash dumps are kept apart from the maps.
+fn store_for<'a>(context: &'a Context, kind: &str) -> &'a MapStore {
+    if kind == LOGS {
+        &context.logs
+    } else {
+        &context.maps
+    }
+}
+
+fn kind_of(obj_name: &str) -> &str {
+    obj_name.split('/').nth(1).unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+struct FdsParams {
+    #[serde(rename = "Expires")]
+    expires: Option<u64>,
+    #[serde(rename = "Nonce")]
+    nonce: Option<String>,
+    #[serde(rename = "Signature")]
+    signature: Option<String>,
+}
+
+impl FdsParams {
+    fn url(&self) -> SignedUrl<'_> {
+        SignedUrl {
+            expires: self.expires,
+            nonce: self.nonce.as_deref(),
+            signature: self.signature.as_deref(),
+        }
+    }
+}
+
+/// Turns away uploads and downloads whose URL we didn't sign, once `[fds]`
+/// is on, and uploads to a URL that's been used up already or is being
+/// uploaded to. An upload that gets through holds its URL until it's used
+/// up or dropped.
+fn check_signature(
+    context: &Context,
+    obj_name: &str,
+    params: &FdsParams,
+    upload: bool,
+) -> Result<Option<Upload>, StatusCode> {
+    let signer = match &context.fds {
+        Some(signer) => signer,
+        None => return Ok(None),
+    };
+    let now = context.clock.epoch_secs();
+    let url = params.url();
+    let verified = if upload {
+        signer.verify_upload(obj_name, &url, now).map(Some)
+    } else {
+        signer.verify(obj_name, &url, now).map(|_| None)
+    };
+    verified.map_err(|e| {
+        warn!(%obj_name, error = %e, "turning away object store request");
+        StatusCode::FORBIDDEN
+    })
+}
+
+async fn receive_map(
+    State(context): State<Arc<Context>>,
+    Path(obj_name): Path<String>,
+    Query(params): Query<FdsParams>,
+    body: Bytes,
+) -> StatusCode {
+    let upload = match check_signature(&context, &obj_name, &params, true) {
+        Ok(upload) => upload,
+        Err(status) => return status,
+    };
+    // room maps only come in after the robot has asked where to put them,
+    // and only one that's been stored uses up the URL it was given
+    let rooms = kind_of(&obj_name) == "rooms";
+    if rooms
+        && !context
+            .uploads
+            .expects(&obj_name, context.clock.epoch_secs())
+    {
+        warn!(%obj_name, "turning away room map nobody asked to upload");
+        return StatusCode::FORBIDDEN;
+    }
+    info!(%obj_name, bytes = body.len(), "received upload");
+    let upload_context = Arc::clone(&context);
+    let saved = tokio::task::spawn_blocking(move || {
+        if let Err(e) = validate_upload(&obj_name, &body) {
+            warn!(error = %e, "rejecting map upload");
+            return Err(match e {
+                MapError::Decompress(_) => StatusCode::BAD_REQUEST,
+                MapError::BadMagic | MapError::Truncated | MapError::BadImage { .. } => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                MapError::TooBig => StatusCode::PAYLOAD_TOO_LARGE,
+            });
+        }
+        let store = store_for(&context, kind_of(&obj_name));
+        let path = store.save(&obj_name, &body).map_err(|e| {
+            warn!(error = %e, "could not store upload");
+            if e.kind() == std::io::ErrorKind::InvalidInput {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+        if let Some(upload) = upload {
+            upload.use_up();
+        }
+        if rooms {
+            context.uploads.take(&obj_name, context.clock.epoch_secs());
+        }
+        let (device_id, kind) = match obj_name.split('/').collect::<Vec<_>>()[..] {
+            [device_id, kind, _] => (device_id.parse().ok(), kind),
+            _ => (None, ""),
+        };
+        if let (Some(db), Some(device_id)) = (&context.db, device_id) {
+            let now = context.clock.now();
+            if let Err(e) = db.upload_stored(device_id, kind, &path, body.len(), now) {
+                warn!(error = %e, "could not note down map upload");
+            }
+        }
+        // only map uploads count, not the room ones
+        Ok((device_id.filter(|_| kind == "map"), path))
+    })
+    .await;
+    match saved {
+        Ok(Ok((device_id, path))) => {
+            info!(path = %path.display(), "stored map upload");
+            if let Some(device_id) = device_id {
+                let now = upload_context.clock.epoch_secs();
+                upload_context.stats.map_uploaded(device_id, now);
+            }
+            StatusCode::OK
+        }
+        Ok(Err(status)) => status,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Hands back what was uploaded to an upload URL, for firmwares that check
+/// the upload made it.
+async fn send_object(
+    State(context): State<Arc<Context>>,
+    Path(obj_name): Path<String>,
+    Query(params): Query<FdsParams>,
+) -> Result<Vec<u8>, StatusCode> {
+    check_signature(&context, &obj_name, &params, false)?;
+    let read = tokio::task::spawn_blocking(move || {
+        match store_for(&context, kind_of(&obj_name)).find(&obj_name)? {
+            Some(path) => std::fs::read(path).map(Some),
+            None => Ok(None),
+        }
+    })
+    .await;
+    match read {
+        Ok(Ok(Some(object))) => Ok(object),
+        Ok(Ok(None)) => Err(StatusCode::NOT_FOUND),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => Err(StatusCode::BAD_REQUEST),
+        Ok(Err(e)) => {
+            warn!(error = %e, "could not read stored object");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn read_latest(
+    context: Arc<Context>,
+    device_id: String,
+    kind: &'static str,
+) -> Result<Vec<u8>, StatusCode> {
+    let read = tokio::task::spawn_blocking(move || {
+        match store_for(&context, kind).latest(&device_id, kind)? {
+            Some(path) => std::fs::read(path).map(Some),
+            None => Ok(None),
+        }
+    })
+    .await;
+    match read {
+        Ok(Ok(Some(upload))) => Ok(upload),
+        Ok(Ok(None)) => Err(StatusCode::NOT_FOUND),
+        Ok(Err(e)) => {
+            warn!(error = %e, kind, "could not read stored upload");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/maps/{device_id}/latest",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    responses(
+        (status = 200, description = "The robot's latest map, as it uploaded it", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 404, description = "No map from this robot"),
+    )
+)]
+async fn latest_map(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<String>,
+) -> Result<Vec<u8>, StatusCode> {
+    read_latest(context, device_id, "map").await
+}
+
+/// The most recent logs the robot uploaded, as it sent them.
+async fn latest_logs(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<String>,
+) -> Result<Vec<u8>, StatusCode> {
+    read_latest(context, device_id, LOGS).await
+}
+
+/// The most recent map the robot uploaded, picked apart.
+pub(crate) async fn latest_parsed_map(
+    context: Arc<Context>,
+    device_id: String,
+) -> Result<RRMap, StatusCode> {
+    let data = read_latest(context, device_id, "map").await?;
+    map::parse(&data).map_err(|e| {
+        warn!(error = %e, "could not parse stored map");
+        StatusCode::UNPROCESSABLE_ENTITY
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/maps/{device_id}/latest.json",
+    params(("device_id" = u32, Path, description = "The robot's device id")),
+    responses(
+        (status = 200, description = "The robot's latest map, picked apart", body = Object),
+        (status = 404, description = "No map from this robot"),
+        (status = 422, description = "The map couldn't be read"),
+    )
+)]
+async fn latest_map_json(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<String>,
+) -> Result<Json<RRMap>, StatusCode> {
+    latest_parsed_map(context, device_id).await.map(Json)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+struct RenderParams {
+    /// Pixels per map pixel, `[render] scale` by default.
+    scale: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/devices/{device_id}/map.png",
+    params(("device_id" = u32, Path, description = "The robot's device id"), RenderParams),
+    responses(
+        (status = 200, description = "The robot's latest map, drawn", content_type = "image/png", body = Vec<u8>),
+        (status = 404, description = "No map from this robot"),
+        (status = 422, description = "The map couldn't be read"),
+    )
+)]
+async fn latest_map_png(
+    State(context): State<Arc<Context>>,
+    Path(device_id): Path<String>,
+    Query(params): Query<RenderParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let data = read_latest(Arc::clone(&context), device_id, "map").await?;
+    let rendered = tokio::task::spawn_blocking(move || {
+        let map = map::parse(&data).map_err(|e| {
+            warn!(error = %e, "could not parse stored map");
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+        let config = &context.config.render;
+        let scale = params.scale.unwrap_or(config.scale);
+        render::render_png(&map, config, scale).map_err(|e| {
+            warn!(error = %e, "could not render map");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    Ok(([(header::CONTENT_TYPE, "image/png")], rendered))
+}
+
+/// A voice pack pushed through the API, for the robot to download.
+async fn voice_pack(
+    State(context): State<Arc<Context>>,
+    Path(name): Path<String>,
+) -> Result<Vec<u8>, StatusCode> {
+    let read = tokio::task::spawn_blocking(move || {
+        match voice::find(&context.config.storage.voice_dir, &name) {
+            Some(path) => std::fs::read(path).map(Some),
+            None => Ok(None),
+        }
+    })
+    .await;
+    match read {
+        Ok(Ok(Some(pack))) => Ok(pack),
+        Ok(Ok(None)) => Err(StatusCode::NOT_FOUND),
+        Ok(Err(e)) => {
+            warn!(error = %e, "could not read voice pack");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn metrics(State(context): State<Arc<Context>>) -> String {
+    context.metrics.render(&context.devices.all())
+}
+
+#[derive(Deserialize)]
+struct HealthParams {
+    /// Seconds without a packet from any robot after which we're unhealthy.
+    max_age: Option<u64>,
+}
+
+/// For container health checks: 200 while every robot listener is being
+/// read from, 503 once one stops or we're shutting down, and with
+/// `?max_age=` also when no robot has been heard from for that long.
+async fn healthz(
+    State(context): State<Arc<Context>>,
+    Query(params): Query<HealthParams>,
+) -> (StatusCode, Json<Value>) {
+    let listeners: Vec<Value> = context
+        .listeners
+        .iter()
+        .zip(&context.receiving)
+        .map(|(socket, receiving)| {
+            json!({
+                "addr": socket.local_addr().ok().map(|a| a.to_string()),
+                "receiving": receiving.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    let devices = context.devices.all();
+    let now = context.clock.epoch_secs();
+    let last_packet_age = devices
+        .iter()
+        .map(|d| d.last_seen_secs())
+        .max()
+        .map(|seen| now.saturating_sub(seen));
+    let stale = match params.max_age {
+        Some(max_age) => last_packet_age.is_none_or(|age| age > max_age),
+        None => false,
+    };
+    let healthy = context.shutdown.stage() == Stage::Running
+        && context.receiving.iter().all(|r| r.load(Ordering::Relaxed))
+        && !stale;
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "status": if healthy { "ok" } else { "unhealthy" },
+        "listeners": listeners,
+        "last_packet_age": last_packet_age,
+        "devices": devices.len(),
+    });
+    (status, Json(body))
+}
+
+pub(crate) fn router(context: Arc<Context>) -> Router {
+    // what's for people rather than robots, which is kept to holders of a
+    // token with `[auth]`
+    let mut protected = Router::new()
+        .merge(api::router())
+        .merge(ws::router())
+        .route("/maps/{device_id}/latest", get(latest_map))
+        .route("/maps/{device_id}/latest.json", get(latest_map_json))
+        .route("/logs/{device_id}/latest", get(latest_logs))
+        .route("/api/devices/{device_id}/map.png", get(latest_map_png));
+    if context.config.valetudo.is_some() {
+        protected = protected.merge(valetudo::router());
+    }
+    // stored objects are only handed out on a signed URL with `[fds]`, so
+    // without it they take a token like the rest of the maps do
+    let public_robomap = match &context.fds {
+        Some(_) => put(receive_map).get(send_object),
+        None => {
+            protected = protected.route("/robomap/{*obj_name}", get(send_object));
+            put(receive_map)
+        }
+    };
+    let protected = protected.route_layer(middleware::from_fn_with_state(
+        Arc::clone(&context),
+        auth::check,
+    ));
+    Router::new()
+        .merge(protected)
+        .merge(openapi::router())
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/voice/{name}", get(voice_pack))
+        // GET answers HEAD too
+        .route("/robomap/{*obj_name}", public_robomap)
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .with_state(context)
+}
+
+pub async fn serve(addr: SocketAddr, context: Arc<Context>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "HTTP server is now listening");
+    let closing = Arc::clone(&context);
+    axum::serve(listener, router(context))
+        .with_graceful_shutdown(async move { closing.shutdown.reached(Stage::Closing).await })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn stored_objects_take_a_token_without_fds() {
+        let mut config = Config::parse(
+            r#"
+            [[auth.tokens]]
+            token = "dashboard"
+            access = "read"
+            "#,
+        )
+        .unwrap();
+        let storage = std::env::temp_dir().join(format!("dummycloud-http-{}", std::process::id()));
+        config.storage.map_dir = storage.join("maps");
+        config.storage.log_dir = storage.join("logs");
+        let server = Server::new(config, Vec::new()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(server.context());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/robomap/12345/map/1700000000", addr);
+        let status = |request: reqwest::RequestBuilder| async move {
+            request.send().await.unwrap().status().as_u16()
+        };
+        assert_eq!(status(client.get(&url)).await, 401);
+        assert_eq!(status(client.get(&url).bearer_auth("dashboard")).await, 404);
+        // robots upload without a token
+        assert_eq!(status(client.put(&url).body("not a map")).await, 422);
+
+        // a room map that couldn't be stored can be sent again
+        let rooms = format!("http://{}/robomap/12345/rooms/1", addr);
+        assert_eq!(status(client.put(&rooms).body("rooms")).await, 403);
+        server
+            .context()
+            .uploads
+            .expect("12345/rooms/1".to_string(), u64::MAX, 0);
+        std::fs::create_dir_all(&storage).unwrap();
+        std::fs::write(storage.join("maps"), b"").unwrap();
+        assert_eq!(status(client.put(&rooms).body("rooms")).await, 500);
+        std::fs::remove_file(storage.join("maps")).unwrap();
+        assert_eq!(status(client.put(&rooms).body("rooms")).await, 200);
+        assert_eq!(status(client.put(&rooms).body("rooms")).await, 403);
+        let _ = std::fs::remove_dir_all(&storage);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::config::InfluxConfig;
+use crate::events::{DeviceMessage, Event};
+use crate::rules::matches;
+use crate::shutdown::Stage;
+use crate::Context;
+
+// A write to InfluxDB that takes longer than this is given up on, so a hung
+// server can't keep us from the next events or from shutting down.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn escaped(name: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A measurement as line protocol has it, with commas and spaces escaped.
+/// An escaped `=` would be taken literally, so it isn't.
+fn measurement(name: &str) -> String {
+    escaped(name, &[',', ' '])
+}
+
+/// A tag key, tag value or field key as line protocol has it, with commas,
+/// equals signs and spaces escaped.
+fn key(name: &str) -> String {
+    escaped(name, &[',', '=', ' '])
+}
+
+/// Finds `param` in a message's params: a JSON pointer if it starts with
+/// `/`, otherwise a field of the params, or of the first object in a list
+/// of them, the way `event.status` has it.
+fn lookup<'a>(params: &'a Value, param: &str) -> Option<&'a Value> {
+    if param.starts_with('/') {
+        return params.pointer(param);
+    }
+    match params {
+        Value::Object(object) => object.get(param),
+        Value::Array(list) => list.iter().find_map(|v| v.get(param)),
+        _ => None,
+    }
+}
+
+/// A field value as line protocol has it: integers suffixed with `i`,
+/// floats and booleans as they are. Anything else isn't written.
+fn field_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) if n.is_f64() => Some(n.to_string()),
+        Value::Number(n) => Some(format!("{}i", n)),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// The point `message` makes, or None if no mapping covers its method or
+/// none of the mapped fields are there. Only the closest mapping applies:
+/// the method itself, or else the longest prefix it has.
+pub(crate) fn point(
+    config: &InfluxConfig,
+    name: Option<&str>,
+    message: &DeviceMessage,
+) -> Option<String> {
+    let mut mappings: Vec<(&String, &HashMap<String, String>)> = config.fields.iter().collect();
+    // exact methods before prefixes, and the longest prefix first
+    mappings.sort_by_key(|(method, _)| (method.ends_with('*'), std::cmp::Reverse(method.len())));
+    let (_, fields) = mappings
+        .into_iter()
+        .find(|(method, _)| matches(method, &message.method))?;
+    let mut fields: Vec<String> = fields
+        .iter()
+        .filter_map(|(field, param)| {
+            let value = field_value(lookup(&message.params, param)?)?;
+            Some(format!("{}={}", key(field), value))
+        })
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    fields.sort();
+    let mut tags = format!(
+        "{},device_id={},method={}",
+        measurement(&config.measurement),
+        message.device_id,
+        key(&message.method)
+    );
+    // InfluxDB won't take a tag with nothing in it
+    if let Some(name) = name.filter(|n| !n.is_empty()) {
+        tags.push_str(&format!(",name={}", key(name)));
+    }
+    Some(format!(
+        "{} {} {}",
+        tags,
+        fields.join(","),
+        u128::from(message.timestamp) * 1_000_000_000
+    ))
+}
+
+async fn post(
+    client: &reqwest::Client,
+    config: &InfluxConfig,
+    url: &str,
+    body: &str,
+) -> Result<(), reqwest::Error> {
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body.to_string());
+    if let Some(token) = &config.token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn append(path: PathBuf, body: String) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(body.as_bytes())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Writes out the points gathered since the last time. A write that
+/// fails loses them, rather than piling them up while InfluxDB is away.
+async fn flush(client: &reqwest::Client, config: &InfluxConfig, lines: &mut Vec<String>) {
+    if lines.is_empty() {
+        return;
+    }
+    let body = lines.join("\n") + "\n";
+    debug!(points = lines.len(), "writing points to influx");
+    lines.clear();
+    if let Some(url) = &config.url {
+        if let Err(e) = post(client, config, url, &body).await {
+            warn!(%url, error = %e, "could not write points to influx");
+        }
+    }
+    if let Some(path) = &config.file {
+        if let Err(e) = append(path.clone(), body).await {
+            warn!(path = %path.display(), error = %e, "could not write points to file");
+        }
+    }
+}
+
+/// Turns the numbers in messages from the robot into line protocol points,
+/// writing them out every `flush_secs` and once more on the way down.
+pub async fn run(config: InfluxConfig, context: Arc<Context>) {
+    let client = reqwest::Client::builder()
+        .timeout(WRITE_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let mut events = context.events.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.flush_secs.max(1)));
+    let mut lines = Vec::new();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = interval.tick() => {
+                flush(&client, &config, &mut lines).await;
+                continue;
+            }
+            _ = context.shutdown.reached(Stage::Closing) => break,
+        };
+        let message = match event {
+            Ok(Event::Message(m)) => m,
+            Ok(Event::Exchange(_))
+            | Ok(Event::Connection(_))
+            | Ok(Event::Consumables(_))
+            | Ok(Event::Binary(_)) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "influx fell behind and skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let name = context.config.name_for(message.device_id);
+        lines.extend(point(&config, name, &message));
+    }
+    flush(&client, &config, &mut lines).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(method: &str, params: Value) -> DeviceMessage {
+        DeviceMessage {
+            device_id: 12345,
+            method: method.to_string(),
+            params,
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn writes_mapped_numbers_as_line_protocol() {
+        let mut config = InfluxConfig::default();
+        let status = json!([{"battery": 87, "state": 5, "error_code": 0, "clean_area": 12.5, "fan_power": 102}]);
+        assert_eq!(
+            point(&config, Some("Hall way"), &message("event.status", status)).unwrap(),
+            "dummycloud,device_id=12345,method=event.status,name=Hall\\ way \
+             battery=87i,clean_area=12.5,error_code=0i,state=5i 1700000000000000000"
+        );
+        assert_eq!(
+            point(&config, None, &message("props", json!({"battery": 40}))).unwrap(),
+            "dummycloud,device_id=12345,method=props battery=40i 1700000000000000000"
+        );
+        // nothing mapped, nothing written
+        assert!(point(&config, None, &message("props", json!({"state": "busy"}))).is_none());
+        assert!(point(&config, None, &message("_otc.info", json!({}))).is_none());
+
+        config.fields = toml::from_str(
+            r#"
+            "event.*" = { wifi = "/0/wifi/rssi" }
+            "event.clean_summary" = { area = "area" }
+            "#,
+        )
+        .unwrap();
+        let summary = message("event.clean_summary", json!({"area": 30}));
+        assert_eq!(
+            point(&config, None, &summary).unwrap(),
+            "dummycloud,device_id=12345,method=event.clean_summary area=30i 1700000000000000000"
+        );
+        let wifi = message("event.wifi", json!([{"wifi": {"rssi": -50}}]));
+        assert!(point(&config, None, &wifi).unwrap().contains(" wifi=-50i "));
+
+        // measurements keep their equals signs, and an empty name is no tag
+        config.measurement = String::from("robot=vacuum, s5");
+        let point = point(&config, Some(""), &summary).unwrap();
+        assert!(point
+            .starts_with("robot=vacuum\\,\\ s5,device_id=12345,method=event.clean_summary area"));
+    }
+}
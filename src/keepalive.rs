@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::config::KeepaliveConfig;
+use crate::devices::Connection;
+use crate::Context;
+
+/// Pings every established robot on an interval, until the process exits.
+pub async fn run(config: KeepaliveConfig, context: Arc<Context>) {
+    let interval = Duration::from_secs(config.interval.max(1));
+    info!(
+        interval_secs = interval.as_secs(),
+        "pinging established robots"
+    );
+    let mut ticks = tokio::time::interval(interval);
+    // the first tick is immediate, and nobody has checked in yet
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let devices = context.devices.all();
+        for device in devices
+            .iter()
+            .filter(|d| d.connection == Connection::Established)
+        {
+            let codec = match context.keys.codec_for(device.id) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            let packet = codec.encode_keepalive(device.id, context.clock.as_ref());
+            debug!(device_id = device.id, "sending keep-alive");
+            if let Err(e) = context
+                .transmit(&packet, device.addr, device.listener, None)
+                .await
+            {
+                warn!(device_id = device.id, error = %e, "could not send keep-alive");
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::codec::UDPCodec;
+use crate::config::Config;
+
+#[derive(Default)]
+struct Keys {
+    fallback: Option<Arc<UDPCodec>>,
+    devices: HashMap<u32, Arc<UDPCodec>>,
+}
+
+impl Keys {
+    fn from_config(config: &Config) -> Keys {
+        Keys {
+            fallback: config
+                .cloud_key
+                .as_deref()
+                .map(|key| Arc::new(UDPCodec::new(key))),
+            // going through key_for keeps the first entry if a device is
+            // listed twice
+            devices: config
+                .devices
+                .iter()
+                .filter_map(|d| Some((d.id, Arc::new(UDPCodec::new(config.key_for(d.id)?)))))
+                .collect(),
+        }
+    }
+}
+
+/// A codec for every robot we have a key for. The whole set is swapped at
+/// once when the config is reloaded, so a packet is never checked against a
+/// mix of old and new keys.
+///
+/// It also keeps count of the packets in a row each robot's key couldn't
+/// open, which after `[session] wrong_key_after` of them means the key is
+/// most likely wrong.
+pub struct KeyStore {
+    current: RwLock<Arc<Keys>>,
+    failures: Mutex<HashMap<u32, u32>>,
+    wrong_key_after: u32,
+}
+
+impl KeyStore {
+    pub fn new(config: &Config) -> KeyStore {
+        KeyStore {
+            current: RwLock::new(Arc::new(Keys::from_config(config))),
+            failures: Mutex::default(),
+            wrong_key_after: config.session.wrong_key_after,
+        }
+    }
+
+    /// Same precedence as [`Config::key_for`].
+    pub fn codec_for(&self, device_id: u32) -> Option<Arc<UDPCodec>> {
+        let keys = Arc::clone(&self.current.read().unwrap());
+        keys.devices
+            .get(&device_id)
+            .or(keys.fallback.as_ref())
+            .cloned()
+    }
+
+    /// Counts a packet from the robot that didn't decrypt, returning how
+    /// many have in a row.
+    pub fn failed(&self, device_id: u32) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(device_id).or_default();
+        *count = count.saturating_add(1);
+        *count
+    }
+
+    /// Starts the count over once one of its packets decrypts.
+    pub fn opened(&self, device_id: u32) {
+        self.failures.lock().unwrap().remove(&device_id);
+    }
+
+    pub fn failures(&self, device_id: u32) -> u32 {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&device_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Whether enough of the robot's packets in a row failed to decrypt
+    /// that its key is most likely wrong.
+    pub fn looks_wrong(&self, device_id: u32) -> bool {
+        self.wrong_key_after > 0 && self.failures(device_id) >= self.wrong_key_after
+    }
+
+    /// Swaps in the keys from `config`, giving every robot a fresh start
+    /// with them.
+    pub fn replace(&self, config: &Config) {
+        let keys = Arc::new(Keys::from_config(config));
+        *self.current.write().unwrap() = keys;
+        self.failures.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloading_swaps_every_key() {
+        let mut config = Config::parse(
+            r#"
+            cloud_key = "fallback"
+
+            [[devices]]
+            id = 1234
+            key = "old"
+            "#,
+        )
+        .unwrap();
+        let keys = KeyStore::new(&config);
+        assert_eq!(keys.codec_for(1234).unwrap().token, "old");
+        assert_eq!(keys.codec_for(5678).unwrap().token, "fallback");
+
+        config.cloud_key = None;
+        config.devices[0].key = String::from("new");
+        keys.replace(&config);
+        assert_eq!(keys.codec_for(1234).unwrap().token, "new");
+        assert!(keys.codec_for(5678).is_none());
+    }
+
+    #[test]
+    fn keys_look_wrong_after_failures_in_a_row() {
+        let mut config = Config::default();
+        config.session.wrong_key_after = 3;
+        let keys = KeyStore::new(&config);
+        keys.failed(1234);
+        keys.failed(1234);
+        assert!(!keys.looks_wrong(1234));
+        keys.opened(1234);
+        for _ in 0..3 {
+            keys.failed(1234);
+        }
+        assert!(keys.looks_wrong(1234));
+        assert!(!keys.looks_wrong(5678));
+
+        // a reloaded config might have fixed it
+        keys.replace(&config);
+        assert!(!keys.looks_wrong(1234));
+    }
+}
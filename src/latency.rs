@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::config::DelayConfig;
+use crate::rules;
+
+/// A random number for picking jitter with.
+pub(crate) fn roll() -> u64 {
+    u64::from_le_bytes(crate::codec::random_bytes())
+}
+
+/// How long to hold back a reply to the calls to `methods`: the longest of
+/// the delays the first matching `[[delays]]` entry gives each of them,
+/// with `roll` picking how much of the jitter each gets.
+pub(crate) fn delay(
+    delays: &[DelayConfig],
+    methods: &[String],
+    mut roll: impl FnMut() -> u64,
+) -> Duration {
+    methods
+        .iter()
+        .filter_map(|method| delays.iter().find(|d| rules::matches(&d.method, method)))
+        .map(|d| {
+            let jitter = match d.jitter_ms {
+                0 => 0,
+                most => roll() % (most + 1),
+            };
+            Duration::from_millis(d.ms.saturating_add(jitter))
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_matching_replies() {
+        let delays = vec![
+            DelayConfig {
+                method: "_sync.*".to_string(),
+                ms: 200,
+                jitter_ms: 100,
+            },
+            DelayConfig {
+                method: "*".to_string(),
+                ms: 50,
+                jitter_ms: 0,
+            },
+        ];
+        let methods =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|m| m.to_string()).collect() };
+        let never = || -> u64 { panic!("no jitter to pick") };
+        assert_eq!(
+            delay(&delays, &methods(&["props"]), never),
+            Duration::from_millis(50)
+        );
+        assert_eq!(
+            delay(&delays, &methods(&["_sync.gen_presigned_url"]), || 135),
+            Duration::from_millis(234)
+        );
+        // a batch waits for its slowest
+        assert_eq!(
+            delay(&delays, &methods(&["props", "_sync.x"]), || 100),
+            Duration::from_millis(300)
+        );
+        assert_eq!(delay(&[], &methods(&["props"]), never), Duration::ZERO);
+    }
+}
@@ -1,6 +1,90 @@
+//! A stand-in for the Xiaomi cloud that Roborock vacuums talk to, so they
+//! can be run without it. [`Server`] is all it takes to run one; the rest is
+//! exported for embedding it, or parts of it, into something else.
+
+mod api;
+pub mod auth;
+mod buffers;
+pub mod capture;
+mod chaos;
+pub mod cleaning;
+pub mod clock;
+pub mod codec;
+pub mod codes;
+pub mod commands;
+pub mod compat;
+pub mod config;
+pub mod consumables;
+pub mod control;
+pub mod daemon;
+mod db;
+pub mod decode;
+pub mod devices;
+mod discovery;
+mod dnd;
+mod dns;
+pub mod error;
+pub mod events;
+pub mod fds;
+#[cfg(feature = "grpc")]
+mod grpc;
+pub mod handlers;
+pub mod handshake;
+mod homeassistant;
+mod http;
+mod influx;
+mod keepalive;
+mod keys;
+mod latency;
+mod limits;
+pub mod listener;
+pub mod logs;
+pub mod map;
+mod metrics;
+pub mod models;
+mod mqtt;
+mod notify;
+mod ntp;
+mod openapi;
+mod outbox;
+pub mod payload;
+pub mod platform;
+pub mod policy;
+mod proxy;
+mod remote;
+mod render;
+pub mod replay;
+pub mod rules;
+pub mod schedule;
+pub mod scripting;
+pub mod secrets;
+mod server;
+pub mod settings;
+mod shutdown;
+pub mod simulate;
+mod state;
+mod stats;
+mod storage;
+mod summary;
+mod tls;
+pub mod uploads;
+mod upstream;
+mod valetudo;
+pub mod voice;
+mod webhooks;
+mod workers;
+mod ws;
+
+pub use codec::UDPCodec as Codec;
+pub use devices::DeviceRegistry;
+pub use error::{Error, Result};
+pub use handlers::{Handler, HandlerRegistry, Request};
+pub use server::Server;
+
+pub(crate) use server::Context;
+
 #[cfg(test)]
 mod tests {
     #[test]
-    fn it_works() {
-    }
+    fn it_works() {}
 }
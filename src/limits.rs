@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::config::LimitsConfig;
+
+// Forget sources that have been quiet for this long, once there are enough
+// of them to be worth cleaning up, and look for them no more often than
+// that either.
+const IDLE_AFTER: Duration = Duration::from_secs(60);
+const PRUNE_ABOVE: usize = 1024;
+// Past this many sources anyone new is turned away until the quiet ones
+// are forgotten, so spoofed addresses can't grow the map without end.
+const MAX_SOURCES: usize = 16384;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Whether we've already complained about this source.
+    dropping: bool,
+}
+
+/// Keeps anyone on the network from using us to bounce traffic around: each
+/// source gets a token bucket, and optionally only known addresses and
+/// devices get an answer at all.
+pub struct Limiter {
+    config: LimitsConfig,
+    sources: Mutex<Sources>,
+}
+
+struct Sources {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Limiter {
+        Limiter {
+            config,
+            sources: Mutex::new(Sources {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn admits_ip(&self, ip: IpAddr) -> bool {
+        self.config.allow_ips.is_empty() || self.config.allow_ips.contains(&ip)
+    }
+
+    /// Whether `ip` is still within its rate limit for a packet arriving at
+    /// `now`.
+    pub fn admit(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.config.rate <= 0.0 {
+            return true;
+        }
+        let mut sources = self.sources.lock().unwrap();
+        let Sources {
+            buckets,
+            last_prune,
+        } = &mut *sources;
+        if buckets.len() > PRUNE_ABOVE && now.duration_since(*last_prune) >= IDLE_AFTER {
+            buckets.retain(|_, b| now.duration_since(b.last) < IDLE_AFTER);
+            *last_prune = now;
+        }
+        if buckets.len() >= MAX_SOURCES && !buckets.contains_key(&ip) {
+            return false;
+        }
+        let burst = f64::from(self.config.burst.max(1));
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+            dropping: false,
+        });
+        let refill = now.duration_since(bucket.last).as_secs_f64() * self.config.rate;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.dropping = false;
+            return true;
+        }
+        if !bucket.dropping {
+            bucket.dropping = true;
+            warn!(%ip, "source is sending too fast, dropping its packets");
+        }
+        false
+    }
+
+    pub fn admits_device(&self, device_id: u32) -> bool {
+        self.config.allow_devices.is_empty() || self.config.allow_devices.contains(&device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_get_a_burst_then_the_rate() {
+        let limiter = Limiter::new(LimitsConfig {
+            rate: 2.0,
+            burst: 3,
+            ..LimitsConfig::default()
+        });
+        let ip: IpAddr = [192, 168, 1, 50].into();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.admit(ip, start));
+        }
+        assert!(!limiter.admit(ip, start));
+        // someone else isn't held up by it
+        assert!(limiter.admit([192, 168, 1, 51].into(), start));
+        assert!(limiter.admit(ip, start + Duration::from_millis(500)));
+        assert!(!limiter.admit(ip, start + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn too_many_sources_turn_new_ones_away() {
+        let limiter = Limiter::new(LimitsConfig {
+            rate: 1.0,
+            burst: 1,
+            ..LimitsConfig::default()
+        });
+        let start = Instant::now();
+        for n in 0..MAX_SOURCES as u32 {
+            assert!(limiter.admit(IpAddr::from((0x0a00_0000 + n).to_be_bytes()), start));
+        }
+        let stranger: IpAddr = [192, 168, 1, 50].into();
+        assert!(!limiter.admit(stranger, start + Duration::from_secs(1)));
+        // those we know still get their share
+        assert!(limiter.admit([10, 0, 0, 0].into(), start + Duration::from_secs(1)));
+        // and once the quiet ones are forgotten there's room again
+        assert!(limiter.admit(stranger, start + IDLE_AFTER + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn allowlists_shut_out_everyone_else() {
+        let limiter = Limiter::new(LimitsConfig {
+            allow_ips: vec![[192, 168, 1, 50].into()],
+            allow_devices: vec![1234],
+            ..LimitsConfig::default()
+        });
+        assert!(limiter.admits_ip([192, 168, 1, 50].into()));
+        assert!(!limiter.admits_ip([192, 168, 1, 66].into()));
+        assert!(limiter.admits_device(1234));
+        assert!(!limiter.admits_device(5678));
+    }
+}
@@ -0,0 +1,49 @@
+use std::io;
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Binds one of the robot listener's sockets. IPv6 sockets only take IPv6,
+/// so `[::]` and `0.0.0.0` can both be bound on the same port, and
+/// `interface` keeps the socket to one network interface on Linux.
+pub fn bind(addr: SocketAddr, interface: Option<&str>) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface only works on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_both_address_families_on_one_port() {
+        let v4 = bind(([127, 0, 0, 1], 0).into(), None).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        // not every sandbox has IPv6
+        if let Ok(v6) = bind((std::net::Ipv6Addr::LOCALHOST, port).into(), None) {
+            assert_eq!(v6.local_addr().unwrap().port(), port);
+        }
+        assert!(bind(([127, 0, 0, 1], port).into(), None).is_err());
+    }
+}
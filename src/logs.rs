@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Log tarballs are a few megabytes at most, this is to keep a hostile one
+// from filling up the memory.
+const MAX_UNPACKED: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogError {
+    #[error("could not unpack logs: {0}")]
+    Unpack(#[from] io::Error),
+    #[error("logs unpack to more than {MAX_UNPACKED} bytes")]
+    TooBig,
+}
+
+/// One of the log files in the tarball, split into lines.
+#[derive(Debug, Serialize)]
+pub struct LogFile {
+    pub name: String,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LogMatch<'a> {
+    pub file: &'a str,
+    /// Counting from 1, like an editor would.
+    pub line: usize,
+    pub text: &'a str,
+}
+
+/// Reads all of `reader`, as long as that's no more than `budget`, which is
+/// then what's left of it.
+fn read_capped(reader: impl Read, budget: &mut u64) -> Result<Vec<u8>, LogError> {
+    let mut data = Vec::new();
+    reader.take(*budget + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > *budget {
+        return Err(LogError::TooBig);
+    }
+    *budget -= data.len() as u64;
+    Ok(data)
+}
+
+fn gunzip(data: Vec<u8>, budget: &mut u64) -> Result<Vec<u8>, LogError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        read_capped(GzDecoder::new(&data[..]), budget)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Whether an entry is one of the logs worth reading: the system log and
+/// the vacuum software's own, rotated ones included.
+fn is_log(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.contains("syslog")
+        || name.contains("rockrobo")
+        || name.contains("messages")
+        || path.extension().is_some_and(|ext| ext == "log")
+        || name.contains(".log.")
+}
+
+/// Picks the logs out of an uploaded tarball, gzipped or not. Files inside
+/// it that are gzipped themselves, as rotated logs tend to be, get unpacked
+/// too.
+pub fn parse(upload: &[u8]) -> Result<Vec<LogFile>, LogError> {
+    let mut budget = MAX_UNPACKED;
+    let tar = gunzip(upload.to_vec(), &mut budget)?;
+    let mut archive = tar::Archive::new(&tar[..]);
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if !is_log(&path) {
+            continue;
+        }
+        let data = read_capped(entry, &mut budget)?;
+        let data = gunzip(data, &mut budget)?;
+        let text = String::from_utf8_lossy(&data);
+        files.push(LogFile {
+            name: path.to_string_lossy().into_owned(),
+            lines: text.lines().map(str::to_string).collect(),
+        });
+    }
+    Ok(files)
+}
+
+/// The lines containing `query`, ignoring case, in files whose name
+/// contains `file`.
+pub fn search<'a>(
+    files: &'a [LogFile],
+    query: &str,
+    file: Option<&str>,
+    limit: usize,
+) -> Vec<LogMatch<'a>> {
+    let query = query.to_lowercase();
+    files
+        .iter()
+        .filter(|f| file.is_none_or(|name| f.name.contains(name)))
+        .flat_map(|f| {
+            f.lines.iter().enumerate().map(move |(i, text)| LogMatch {
+                file: &f.name,
+                line: i + 1,
+                text,
+            })
+        })
+        .filter(|m| m.text.to_lowercase().contains(&query))
+        .take(limit)
+        .collect()
+}
+
+/// The last upload parsed for each robot, so searching it again doesn't
+/// mean unpacking it again.
+#[derive(Default)]
+pub struct LogIndex {
+    parsed: Mutex<HashMap<u32, Parsed>>,
+}
+
+/// Which upload was parsed, and what was in it.
+type Parsed = (PathBuf, Arc<Vec<LogFile>>);
+
+impl LogIndex {
+    /// The logs in the upload at `path`, parsing it unless it's the one
+    /// parsed last time.
+    pub fn get(&self, device_id: u32, path: &Path) -> Result<Arc<Vec<LogFile>>, LogError> {
+        if let Some((parsed, files)) = self.parsed.lock().unwrap().get(&device_id) {
+            if parsed == path {
+                return Ok(Arc::clone(files));
+            }
+        }
+        let files = Arc::new(parse(&std::fs::read(path)?)?);
+        self.parsed
+            .lock()
+            .unwrap()
+            .insert(device_id, (path.to_path_buf(), Arc::clone(&files)));
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        gzip(&builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn finds_lines_in_nested_logs() {
+        let rotated = gzip(b"Oct 13 boot\nOct 13 Navigation FAILED near dock\n");
+        let upload = tarball(&[
+            ("var/log/syslog", b"Oct 14 started\nOct 14 wifi up\n"),
+            ("mnt/data/rockrobo/rrlog/SLAM_fprintf.log.1.gz", &rotated),
+            ("etc/os-release", b"navigation isn't a log"),
+        ]);
+        let files = parse(&upload).unwrap();
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "var/log/syslog",
+                "mnt/data/rockrobo/rrlog/SLAM_fprintf.log.1.gz"
+            ]
+        );
+
+        assert_eq!(
+            search(&files, "navigation failed", None, 10),
+            [LogMatch {
+                file: "mnt/data/rockrobo/rrlog/SLAM_fprintf.log.1.gz",
+                line: 2,
+                text: "Oct 13 Navigation FAILED near dock",
+            }]
+        );
+        assert_eq!(search(&files, "oct", Some("syslog"), 10).len(), 2);
+        assert_eq!(search(&files, "oct", None, 3).len(), 3);
+    }
+}
@@ -1,158 +1,417 @@
-use std::env;
-use std::net::UdpSocket;
-use std::time::SystemTime;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use bytes::{Buf, BufMut, BytesMut};
-use getopts::Options;
-use serde_json::json;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
-mod codec;
-mod payload;
+use dummycloud::config::{Config, LoggingConfig};
+use dummycloud::{
+    compat, control, daemon, decode, handshake, listener, platform, replay, secrets, simulate,
+    Error, Result, Server,
+};
 
-fn create_timesync_packet() -> BytesMut {
-    let mut packet = BytesMut::with_capacity(32);
-
-    // byte 0: set headers for sending timesync packet
-    packet.put_u8(0x21);
-    packet.put_u8(0x31);
+#[derive(Parser)]
+#[command(
+    about = "A stand-in for the Xiaomi cloud that Roborock vacuums talk to.",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Running without a subcommand is the same as `serve`.
+    #[command(flatten)]
+    serve: ServeArgs,
+}
 
-    // byte 2: size of the timestamp field
-    packet.put_u8(0x00);
-    packet.put_u8(0x20);
+#[derive(Subcommand)]
+enum Command {
+    /// Answer robots, the default.
+    Serve(ServeArgs),
+    /// Have a running dummycloud send a robot one command and print its reply.
+    Send {
+        device_id: u32,
+        /// e.g. get_status.
+        method: String,
+        /// The command's params, as JSON.
+        #[arg(default_value = "[]")]
+        params: String,
+        /// Where the running dummycloud's control socket is.
+        #[arg(long, value_name = "127.0.0.1:8054")]
+        control: Option<SocketAddr>,
+    },
+    /// Have a running dummycloud change one of a robot's settings, checking
+    /// the value against the robot's model.
+    Set {
+        device_id: u32,
+        /// fan_speed, carpet_mode or water_level.
+        setting: String,
+        /// e.g. turbo for fan_speed, on or off for carpet_mode.
+        value: String,
+        /// Where the running dummycloud's control socket is.
+        #[arg(long, value_name = "127.0.0.1:8054")]
+        control: Option<SocketAddr>,
+    },
+    /// Print the header of a captured packet and, given its key, decrypt it.
+    Decode {
+        /// A pcap or pcapng file, or the packet itself in hex or base64.
+        packet: String,
+        /// Cloud key or token the packet was signed with.
+        #[arg(short, long, value_name = "SoMeALPhaCHars")]
+        key: Option<String>,
+    },
+    /// Make up a random cloud key to provision a robot with.
+    Keygen,
+    /// Ask a robot in setup mode for its token.
+    ExtractToken {
+        /// Where the robot is.
+        #[arg(value_name = "192.168.8.1")]
+        ip: Option<IpAddr>,
+    },
+    /// Play an NDJSON capture back to a running dummycloud.
+    Replay {
+        capture: PathBuf,
+        #[arg(value_name = "127.0.0.1:8053")]
+        server: Option<SocketAddr>,
+        /// Run the captured calls through the handlers directly instead of sending them to a server.
+        #[arg(long)]
+        handlers: bool,
+        /// Config for the handlers to use with --handlers.
+        #[arg(short, long, value_name = "dummycloud.toml")]
+        config: Option<PathBuf>,
+        /// Key to decrypt the server's replies with.
+        #[arg(short, long, value_name = "SoMeALPhaCHars")]
+        key: Option<String>,
+    },
+    /// Act like a robot against a running dummycloud, to try handlers out
+    /// without one.
+    Simulate {
+        #[arg(value_name = "127.0.0.1:8053")]
+        server: Option<SocketAddr>,
+        /// Cloud key the server knows the robot by.
+        #[arg(short, long, value_name = "SoMeALPhaCHars")]
+        key: String,
+        #[arg(long, default_value_t = 12345)]
+        device_id: u32,
+        /// Seconds between status reports.
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+    },
+    /// Print what a running dummycloud knows about its robots.
+    Status {
+        #[arg(value_name = "127.0.0.1:8054")]
+        control: Option<SocketAddr>,
+    },
+}
 
-    // byte 4 - 11: emptiness
-    packet.put_slice(&[0xff; 8]);
+#[derive(Args, Clone)]
+struct ServeArgs {
+    /// TOML file to load settings from, or the Node.js dummycloud's config.json. Flags given on the command line take precedence.
+    #[arg(short, long, value_name = "dummycloud.toml")]
+    config: Option<PathBuf>,
+    /// Cloud key used to identify your robot to Xiaomi.
+    #[arg(short, long, value_name = "SoMeALPhaCHars")]
+    key: Option<String>,
+    /// Address to listen for robots on. Can be given more than once.
+    #[arg(short, long, value_name = "0.0.0.0:8053")]
+    bind: Vec<SocketAddr>,
+    /// IP the robot should use to reach this machine. Defaults to the address facing the robot.
+    #[arg(short, long, value_name = "192.168.1.2")]
+    advertise_ip: Option<IpAddr>,
+    /// Port the robot should use to reach this machine, if it differs from the one we bind to.
+    #[arg(short = 'p', long, value_name = "8053")]
+    advertise_port: Option<u16>,
+    /// Moves a listener to another port, e.g. to run unprivileged behind port mapping: robots, http, control, https, grpc, ntp, dns or discovery. Can be given more than once.
+    #[arg(long = "port", value_name = "ntp=1123")]
+    ports: Vec<String>,
+    /// How much to log, e.g. debug or info,dummycloud=trace.
+    #[arg(short, long, value_name = "info")]
+    log_level: Option<String>,
+    /// Log one JSON object per line.
+    #[arg(long)]
+    log_json: bool,
+    /// Write every packet, decrypted where possible, to a file. NDJSON unless it ends in .pcapng.
+    #[arg(long, value_name = "capture.ndjson")]
+    capture: Option<PathBuf>,
+    /// Relay robots to the real Xiaomi cloud, logging what both sides say, instead of answering them.
+    #[arg(long, value_name = "ot.io.mi.com:8053", num_args = 0..=1, require_equals = true)]
+    proxy: Option<Option<String>>,
+    /// Decrypt packets whose checksum doesn't match instead of dropping them.
+    #[arg(long)]
+    lenient: bool,
+    /// Run as a systemd service: take the socket from socket activation, report readiness and reload the config on SIGHUP.
+    #[arg(long)]
+    daemon: bool,
+}
 
-    let epoch = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
+/// Lets the log level be changed while we're running.
+type LogFilter = reload::Handle<EnvFilter, Registry>;
 
-    // byte 12-15: write the epoch timestamp, yes this will fail in 2038.
-    packet.put_u32(epoch);
+fn init_logging(config: &LoggingConfig) -> Result<LogFilter> {
+    let filter = EnvFilter::try_new(&config.level)
+        .map_err(|e| Error::Invalid(format!("invalid log level {}: {}", config.level, e)))?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let (json, plain) = if config.json {
+        (Some(tracing_subscriber::fmt::layer().json()), None)
+    } else {
+        (None, Some(tracing_subscriber::fmt::layer()))
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(plain)
+        .init();
+    Ok(handle)
+}
 
-    // fill the rest of the 32 byte packet with emptiness
-    packet.put_slice(&[0xff; 16]);
-    packet
+/// Applies the parts of a new config that can change while we're running:
+/// the keys and the log level.
+fn reload(config: &Config, server: &Server, log_filter: &LogFilter) {
+    if !config.has_keys() {
+        warn!("new config has no keys, keeping the old config");
+        return;
+    }
+    server.replace_keys(config);
+    match EnvFilter::try_new(&config.logging.level) {
+        Ok(filter) => {
+            if let Err(e) = log_filter.reload(filter) {
+                warn!(error = %e, "could not change the log level");
+            }
+        }
+        Err(e) => warn!(error = %e, "invalid log level"),
+    }
+    info!("reloaded keys and log level, other settings need a restart");
 }
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} abcdef [options]", program);
-    print!("{}", opts.usage(&brief));
+/// The config file if there is one, with the command line flags on top.
+fn load_config(args: &ServeArgs) -> Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).map_err(|source| Error::Config {
+            path: path.clone(),
+            source,
+        })?,
+        None => Config::default(),
+    };
+    // the Node.js dummycloud's, so it can be swapped for us as it's run
+    compat::apply_env(&mut config, |name| std::env::var(name).ok()).map_err(Error::Invalid)?;
+    secrets::resolve(
+        &mut config,
+        |name| std::env::var(name).ok(),
+        secrets::passphrase,
+    )
+    .map_err(Error::Invalid)?;
+    if let Some(key) = &args.key {
+        config.cloud_key = Some(key.clone());
+    }
+    if !args.bind.is_empty() {
+        config.listener.bind = args.bind.clone();
+    }
+    if let Some(ip) = args.advertise_ip {
+        config.advertise.ip = Some(ip);
+    }
+    if let Some(port) = args.advertise_port {
+        config.advertise.port = port;
+    }
+    for moved in &args.ports {
+        let (listener, port) = moved
+            .split_once('=')
+            .and_then(|(listener, port)| Some((listener, port.parse().ok()?)))
+            .ok_or_else(|| Error::Invalid(format!("--port {} isn't listener=port", moved)))?;
+        config.set_port(listener, port).map_err(Error::Invalid)?;
+    }
+    if let Some(level) = &args.log_level {
+        config.logging.level = level.clone();
+    }
+    if args.log_json {
+        config.logging.json = true;
+    }
+    if let Some(upstream) = &args.proxy {
+        let mut proxy = config.proxy.take().unwrap_or_default();
+        if let Some(upstream) = upstream {
+            proxy.upstream = upstream.clone();
+        }
+        config.proxy = Some(proxy);
+    }
+    if args.lenient {
+        config.session.lenient = true;
+    }
+    if let Some(path) = &args.capture {
+        config.capture = Some(path.clone());
+    }
+    Ok(config)
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-
-    let mut opts = Options::new();
-    opts.opt(
-        "k",
-        "key",
-        "Cloud key used to identify your robot to Xiaomi.",
-        "SoMeALPhaCHars",
-        getopts::HasArg::Yes,
-        getopts::Occur::Req,
-    );
-
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(_) => {
-            print_usage(&args[0].clone(), opts);
+fn main() {
+    // before any threads are started, while the environment is still ours
+    // alone to change
+    daemon::claim_listen_fds();
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            println!("could not start the runtime: {}", e);
             std::process::exit(1);
         }
     };
-    let cloud_key = match matches.opt_str("k") {
-        Some(s) => s,
+    if let Err(e) = runtime.block_on(run(Cli::parse())) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Serve(args)) => serve(args).await,
+        Some(Command::Send {
+            device_id,
+            method,
+            params,
+            control,
+        }) => {
+            let params = serde_json::from_str(&params)
+                .map_err(|e| Error::Invalid(format!("params aren't JSON: {}", e)))?;
+            control::send_command(control, device_id, &method, params).await
+        }
+        Some(Command::Set {
+            device_id,
+            setting,
+            value,
+            control,
+        }) => control::set_command(control, device_id, &setting, &value).await,
+        Some(Command::Decode { packet, key }) => decode::decode_command(&packet, key.as_deref()),
+        Some(Command::Keygen) => {
+            println!("{}", handshake::generate_key()?);
+            Ok(())
+        }
+        Some(Command::ExtractToken { ip }) => handshake::extract_token_command(ip).await,
+        Some(Command::Replay {
+            capture,
+            server,
+            handlers: true,
+            config,
+            ..
+        }) => {
+            if server.is_some() {
+                return Err(Error::Invalid(
+                    "--handlers doesn't take a server address".to_string(),
+                ));
+            }
+            replay::replay_handlers_command(&capture, config.as_deref())
+        }
+        Some(Command::Replay {
+            capture,
+            server,
+            key,
+            ..
+        }) => replay::replay_command(&capture, server, key.as_deref()).await,
+        Some(Command::Simulate {
+            server,
+            key,
+            device_id,
+            interval,
+        }) => {
+            let interval = Duration::from_secs(interval.max(1));
+            simulate::simulate_command(server, device_id, &key, interval).await
+        }
+        Some(Command::Status { control }) => control::status_command(control).await,
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<()> {
+    let config = load_config(&args)?;
+    if !config.has_keys() {
+        println!("no keys to talk to robots with, pass -k or give some in the config\n");
+        let _ = Cli::command().print_help();
+        std::process::exit(1);
+    }
+    let daemon = args.daemon;
+    if let Some(feature) = platform::unsupported(&config, daemon).first() {
+        return Err(Error::Invalid(format!(
+            "{} isn't possible on {}",
+            feature,
+            std::env::consts::OS
+        )));
+    }
+    let log_filter = init_logging(&config.logging)?;
+
+    let inherited = if daemon {
+        daemon::inherited_socket()?
+    } else {
+        None
+    };
+    let mut listeners = match inherited {
+        Some(socket) => {
+            info!("using the socket passed in by systemd");
+            vec![UdpSocket::from_std(socket)?]
+        }
         None => {
-            print_usage(&args[0].clone(), opts);
-            std::process::exit(1);
+            let mut listeners = Vec::new();
+            for addr in &config.listener.bind {
+                let socket = listener::bind(*addr, config.listener.interface.as_deref())
+                    .and_then(UdpSocket::from_std)
+                    .map_err(|source| Error::Bind {
+                        addr: *addr,
+                        source,
+                    })?;
+                listeners.push(socket);
+            }
+            listeners
         }
     };
+    // tenants get sockets of their own, even under socket activation
+    for tenant in &config.listener.tenants {
+        let interface = tenant
+            .interface
+            .as_ref()
+            .or(config.listener.interface.as_ref());
+        let socket = listener::bind(tenant.bind, interface.map(String::as_str))
+            .and_then(UdpSocket::from_std)
+            .map_err(|source| Error::Bind {
+                addr: tenant.bind,
+                source,
+            })?;
+        listeners.push(socket);
+    }
+    let server = Server::new(config, listeners)?;
 
-    let socket = UdpSocket::bind("0.0.0.0:8053").expect("Could not bind to address");
-    println!("Dummycloud is now listening");
-
-    loop {
-        let mut buf = [0; 1024];
-        let (amt, src) = socket.recv_from(&mut buf)?;
-        println!("connected from: {} with a message of length: {}", src, amt);
-
-        let c = codec::UDPCodec::new(&cloud_key);
-
-        // truncate the size of the buffer to appropriately handle later
-        let buf = &buf[..amt];
-
-        let header = &buf[..32];
-        let encrypted_body = &buf[32..];
-        let stamp = (&header[12..]).get_u32();
-        let device_id = (&header[8..]).get_u32();
-        let response = match c.decode_response(header, encrypted_body) {
-            Some(s) => s,
-            None => {
-                if stamp == 0 {
-                    println!("Robot connected!");
-                    socket.send_to(create_timesync_packet().bytes(), &src)?;
-                } else {
-                    socket.send_to(&buf, &src)?;
-                }
-                continue;
-            }
-        };
-
-        let response: payload::MessagePayload = match serde_json::from_str(&response) {
-            Ok(r) => r,
-            Err(_) => continue,
-        };
-        let response_function = response.method.as_str();
-        let reply_json: payload::ResponsePayload = match response_function {
-            "_otc.info" => payload::ResponsePayload::new(
-                response.id,
-                json!({
-                    "otc_list": [{
-                        "ip": "130.83.47.181",
-                        "port": 8053
-                    }
-                    ],
-                    "otc_test": {
-                        "list": [{
-                            "ip": "130.83.47.181",
-                            "port": 8053
-                        }
-                        ],
-                        "interval": 1800,
-                        "firsttest": 1193
-                    }
-                }),
-            ),
-            "props" | "event.status" | "event.low_power_back" => {
-                payload::ResponsePayload::new(response.id, serde_json::to_value("ok")?)
-            }
-            "_sync.gen_presigned_url" => payload::ResponsePayload::new(
-                response.id,
-                json!({"" : { "url": "http://us.ott.io.mi.com/robomap", "obj_name": "something", "method": "PUT",
-                     "expires_time": (SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() + 3600),
-                        "ok": true,
-                        "pwd": "password"
-                }}),
-            ),
-            "_sync.batch_gen_room_up_url" => payload::ResponsePayload::new(
-                response.id,
-                json!([
-                    "http://us.ott.io.mi.com/robomap/1",
-                    "http://us.ott.io.mi.com/robomap/2",
-                    "http://us.ott.io.mi.com/robomap/3",
-                    "http://us.ott.io.mi.com/robomap/4"
-                ]),
-            ),
-            _ => {
-                println!("unknown event: {}", response_function);
-                continue;
-            }
-        };
-        let reply = c.encode_response(&serde_json::to_vec(&reply_json)?, device_id);
-        socket.send_to(&reply, &src)?;
+    let reload_server = server.clone();
+    tokio::spawn(async move {
+        let reloaded = daemon::reload_on_sighup(
+            || load_config(&args),
+            |config| reload(&config, &reload_server, &log_filter),
+        );
+        if let Err(e) = reloaded.await {
+            error!(error = %e, "can't reload the config on SIGHUP");
+        }
+    });
+
+    let stopping_server = server.clone();
+    tokio::spawn(async move {
+        if let Err(e) = daemon::terminated().await {
+            error!(error = %e, "can't shut down cleanly on Ctrl-C");
+            return;
+        }
+        info!("shutting down, do that again to quit right away");
+        if daemon {
+            let _ = daemon::notify("STOPPING=1");
+        }
+        stopping_server.shutdown();
+        if daemon::terminated().await.is_ok() {
+            warn!("quitting without waiting");
+            std::process::exit(1);
+        }
+    });
+
+    if daemon {
+        tokio::spawn(daemon::watchdog());
+        if let Err(e) = daemon::notify("READY=1") {
+            warn!(error = %e, "could not tell systemd we're ready");
+        }
     }
+
+    server.run().await
 }
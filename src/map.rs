@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const MAP_MAGIC: [u8; 2] = *b"rr";
+
+// Block types as they appear in the map header, see Valetudo's RRMapParser.
+const CHARGER_LOCATION: u16 = 1;
+const IMAGE: u16 = 2;
+const PATH: u16 = 3;
+const GOTO_PATH: u16 = 4;
+const GOTO_PREDICTED_PATH: u16 = 5;
+const CURRENTLY_CLEANED_ZONES: u16 = 6;
+const GOTO_TARGET: u16 = 7;
+const ROBOT_POSITION: u16 = 8;
+const FORBIDDEN_ZONES: u16 = 9;
+const VIRTUAL_WALLS: u16 = 10;
+const CURRENTLY_CLEANED_BLOCKS: u16 = 11;
+const FORBIDDEN_MOP_ZONES: u16 = 12;
+
+// Even the biggest floorplans come to a few megabytes unpacked, this is to
+// keep a hostile upload from filling up the memory.
+const MAX_MAP: u64 = 32 * 1024 * 1024;
+// Robots' images are around a thousand pixels a side, nothing real comes
+// close to this.
+const MAX_SIDE: i32 = 4096;
+
+/// Positions are in millimetres, and each image pixel is this many across.
+pub const MM_PER_PIXEL: i32 = 50;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MapError {
+    #[error("could not decompress map: {0}")]
+    Decompress(#[source] std::io::Error),
+    #[error("not an rr map")]
+    BadMagic,
+    #[error("map ends in the middle of a block")]
+    Truncated,
+    #[error("map unpacks to more than {MAX_MAP} bytes")]
+    TooBig,
+    #[error("map image is {width}x{height} pixels, which can't be right")]
+    BadImage { width: i32, height: i32 },
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct MapHeader {
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub map_index: u32,
+    pub map_sequence: u32,
+}
+
+/// The occupancy grid. Pixels are listed by their index into the
+/// `width * height` raster, and row 0 is the bottom of the map.
+#[derive(Serialize, Debug, Default)]
+pub struct MapImage {
+    pub top: i32,
+    pub left: i32,
+    pub height: i32,
+    pub width: i32,
+    pub floor: Vec<u32>,
+    /// Which segment each of the `floor` pixels is in, 0 for none.
+    #[serde(skip)]
+    pub floor_segments: Vec<u8>,
+    pub obstacle: Vec<u32>,
+    /// The rooms the floor is split into, on maps from firmware that does
+    /// that, ordered by id.
+    pub segments: Vec<Segment>,
+}
+
+/// A room as the robot split the floor up. Its id is the one
+/// `app_segment_clean` takes, and stays the same from one upload to the next
+/// until the map is redone.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub id: u8,
+    /// How many 5cm pixels of floor it covers.
+    pub pixels: u32,
+    /// `[x1, y1, x2, y2]`, in the same millimetres as the positions.
+    pub bounds: [i32; 4],
+    pub center: Point,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct MapPath {
+    pub current_angle: u32,
+    pub points: Vec<Point>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RobotPosition {
+    pub position: Point,
+    pub angle: Option<i32>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RRMap {
+    pub header: MapHeader,
+    pub charger: Option<Point>,
+    pub image: Option<MapImage>,
+    pub path: Option<MapPath>,
+    pub goto_path: Option<MapPath>,
+    pub goto_predicted_path: Option<MapPath>,
+    pub goto_target: Option<Point>,
+    pub robot: Option<RobotPosition>,
+    /// Rectangles as `[x1, y1, x2, y2]`.
+    pub currently_cleaned_zones: Vec<[i32; 4]>,
+    /// Quadrilaterals as four corners.
+    pub forbidden_zones: Vec<[Point; 4]>,
+    pub forbidden_mop_zones: Vec<[Point; 4]>,
+    /// Lines as `[x1, y1, x2, y2]`.
+    pub virtual_walls: Vec<[i32; 4]>,
+    pub currently_cleaned_blocks: Vec<u8>,
+    /// Every block type in the map in order, including ones we skip over.
+    pub block_types: Vec<u16>,
+}
+
+/// Bounds-checked little endian reads, so a corrupt upload turns into an
+/// error instead of a panic.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], MapError> {
+        let end = offset.checked_add(len).ok_or(MapError::Truncated)?;
+        self.buf.get(offset..end).ok_or(MapError::Truncated)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, MapError> {
+        let b = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, MapError> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&self, offset: usize) -> Result<i32, MapError> {
+        Ok(self.u32(offset)? as i32)
+    }
+
+    fn point16(&self, offset: usize) -> Result<Point, MapError> {
+        Ok(Point {
+            x: i32::from(self.u16(offset)?),
+            y: i32::from(self.u16(offset + 2)?),
+        })
+    }
+}
+
+/// Undoes the gzip the robot wraps its uploads in, if there is any.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, MapError> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data.to_vec());
+    }
+    let mut decompressed = vec![];
+    GzDecoder::new(data)
+        .take(MAX_MAP + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(MapError::Decompress)?;
+    if decompressed.len() as u64 > MAX_MAP {
+        return Err(MapError::TooBig);
+    }
+    Ok(decompressed)
+}
+
+pub fn parse(data: &[u8]) -> Result<RRMap, MapError> {
+    let data = decompress(data)?;
+    let r = Reader { buf: &data };
+    if r.bytes(0, 2)? != MAP_MAGIC {
+        return Err(MapError::BadMagic);
+    }
+
+    let mut map = RRMap {
+        header: MapHeader {
+            major_version: r.u16(0x08)?,
+            minor_version: r.u16(0x0a)?,
+            map_index: r.u32(0x0c)?,
+            map_sequence: r.u32(0x10)?,
+        },
+        ..RRMap::default()
+    };
+
+    let mut offset = usize::from(r.u16(0x02)?);
+    while offset < data.len() {
+        let block_type = r.u16(offset)?;
+        let header_length = usize::from(r.u16(offset + 0x02)?);
+        let data_length = r.u32(offset + 0x04)? as usize;
+        // every block header holds at least its type and lengths, anything
+        // shorter would never move us forward
+        if header_length < 8 {
+            return Err(MapError::Truncated);
+        }
+        let body = offset + header_length;
+        // make sure the whole block is there before picking it apart
+        r.bytes(body, data_length)?;
+        map.block_types.push(block_type);
+
+        match block_type {
+            CHARGER_LOCATION => {
+                map.charger = Some(Point {
+                    x: r.i32(offset + 0x08)?,
+                    y: r.i32(offset + 0x0c)?,
+                });
+            }
+            IMAGE => map.image = Some(parse_image(&r, offset, header_length, data_length)?),
+            PATH => map.path = Some(parse_path(&r, offset, body, data_length)?),
+            GOTO_PATH => map.goto_path = Some(parse_path(&r, offset, body, data_length)?),
+            GOTO_PREDICTED_PATH => {
+                map.goto_predicted_path = Some(parse_path(&r, offset, body, data_length)?)
+            }
+            CURRENTLY_CLEANED_ZONES => {
+                map.currently_cleaned_zones = parse_rects(&r, offset, body)?;
+            }
+            GOTO_TARGET => map.goto_target = Some(r.point16(offset + 0x08)?),
+            ROBOT_POSITION => {
+                map.robot = Some(RobotPosition {
+                    position: Point {
+                        x: r.i32(offset + 0x08)?,
+                        y: r.i32(offset + 0x0c)?,
+                    },
+                    angle: if data_length > 8 {
+                        Some(r.i32(offset + 0x10)?)
+                    } else {
+                        None
+                    },
+                });
+            }
+            FORBIDDEN_ZONES => map.forbidden_zones = parse_quads(&r, offset, body)?,
+            FORBIDDEN_MOP_ZONES => map.forbidden_mop_zones = parse_quads(&r, offset, body)?,
+            VIRTUAL_WALLS => map.virtual_walls = parse_rects(&r, offset, body)?,
+            CURRENTLY_CLEANED_BLOCKS => {
+                let count = r.u32(offset + 0x08)? as usize;
+                map.currently_cleaned_blocks = r.bytes(body, count)?.to_vec();
+            }
+            _ => {}
+        }
+        offset = body + data_length;
+    }
+    Ok(map)
+}
+
+fn parse_image(
+    r: &Reader,
+    offset: usize,
+    header_length: usize,
+    data_length: usize,
+) -> Result<MapImage, MapError> {
+    // newer firmwares squeeze a segment count in before the dimensions
+    let g3offset = if header_length > 24 { 4 } else { 0 };
+    let mut image = MapImage {
+        top: r.i32(offset + 0x08 + g3offset)?,
+        left: r.i32(offset + 0x0c + g3offset)?,
+        height: r.i32(offset + 0x10 + g3offset)?,
+        width: r.i32(offset + 0x14 + g3offset)?,
+        ..MapImage::default()
+    };
+    let (width, height) = (image.width, image.height);
+    if !(1..=MAX_SIDE).contains(&width) || !(1..=MAX_SIDE).contains(&height) {
+        return Err(MapError::BadImage { width, height });
+    }
+    let pixels = r.bytes(offset + header_length, data_length)?;
+    // pixel count, coordinate sums and [min x, min y, max x, max y]
+    let mut segments: BTreeMap<u8, (u32, i64, i64, [i32; 4])> = BTreeMap::new();
+    for (i, pixel) in pixels.iter().enumerate() {
+        match *pixel {
+            0 => {}
+            1 => image.obstacle.push(i as u32),
+            255 => {
+                image.floor.push(i as u32);
+                image.floor_segments.push(0);
+            }
+            // the low bits say what it is, the rest which segment it's in
+            p if p & 0x07 == 0x07 => {
+                image.floor.push(i as u32);
+                image.floor_segments.push(p >> 3);
+                let (x, y) = (i as i32 % width, i as i32 / width);
+                let (count, sum_x, sum_y, bounds) =
+                    segments.entry(p >> 3).or_insert((0, 0, 0, [x, y, x, y]));
+                *count += 1;
+                *sum_x += i64::from(x);
+                *sum_y += i64::from(y);
+                *bounds = [
+                    bounds[0].min(x),
+                    bounds[1].min(y),
+                    bounds[2].max(x),
+                    bounds[3].max(y),
+                ];
+            }
+            p if p & 0x07 == 0x01 => image.obstacle.push(i as u32),
+            _ => {}
+        }
+    }
+    let to_mm = |x: i32, y: i32| Point {
+        x: (image.left + x) * MM_PER_PIXEL,
+        y: (image.top + y) * MM_PER_PIXEL,
+    };
+    image.segments = segments
+        .into_iter()
+        .filter(|(id, _)| *id != 0)
+        .map(|(id, (count, sum_x, sum_y, [x1, y1, x2, y2]))| {
+            let (low, high) = (to_mm(x1, y1), to_mm(x2 + 1, y2 + 1));
+            Segment {
+                id,
+                pixels: count,
+                bounds: [low.x, low.y, high.x, high.y],
+                center: to_mm(
+                    (sum_x / i64::from(count)) as i32,
+                    (sum_y / i64::from(count)) as i32,
+                ),
+            }
+        })
+        .collect();
+    Ok(image)
+}
+
+fn parse_path(
+    r: &Reader,
+    offset: usize,
+    body: usize,
+    data_length: usize,
+) -> Result<MapPath, MapError> {
+    let points = (0..data_length / 4)
+        .map(|i| r.point16(body + i * 4))
+        .collect::<Result<_, _>>()?;
+    Ok(MapPath {
+        current_angle: r.u32(offset + 0x10)?,
+        points,
+    })
+}
+
+fn parse_rects(r: &Reader, offset: usize, body: usize) -> Result<Vec<[i32; 4]>, MapError> {
+    let count = r.u32(offset + 0x08)? as usize;
+    (0..count)
+        .map(|i| {
+            let a = r.point16(body + i * 8)?;
+            let b = r.point16(body + i * 8 + 4)?;
+            Ok([a.x, a.y, b.x, b.y])
+        })
+        .collect()
+}
+
+fn parse_quads(r: &Reader, offset: usize, body: usize) -> Result<Vec<[Point; 4]>, MapError> {
+    let count = r.u32(offset + 0x08)? as usize;
+    (0..count)
+        .map(|i| {
+            let zone = body + i * 16;
+            Ok([
+                r.point16(zone)?,
+                r.point16(zone + 4)?,
+                r.point16(zone + 8)?,
+                r.point16(zone + 12)?,
+            ])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn block(block_type: u16, header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut b = vec![];
+        b.extend_from_slice(&block_type.to_le_bytes());
+        b.extend_from_slice(&((8 + header.len()) as u16).to_le_bytes());
+        b.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        b.extend_from_slice(header);
+        b.extend_from_slice(body);
+        b
+    }
+
+    fn sample_map() -> Vec<u8> {
+        let mut map = vec![];
+        map.extend_from_slice(b"rr");
+        map.extend_from_slice(&0x14u16.to_le_bytes());
+        map.extend_from_slice(&0u32.to_le_bytes());
+        map.extend_from_slice(&1u16.to_le_bytes());
+        map.extend_from_slice(&0u16.to_le_bytes());
+        map.extend_from_slice(&7u32.to_le_bytes());
+        map.extend_from_slice(&42u32.to_le_bytes());
+
+        let charger = [25600i32.to_le_bytes(), 25500i32.to_le_bytes()].concat();
+        map.extend(block(CHARGER_LOCATION, &charger, &[]));
+
+        let dims = [
+            10i32.to_le_bytes(),
+            20i32.to_le_bytes(),
+            2i32.to_le_bytes(),
+            2i32.to_le_bytes(),
+        ]
+        .concat();
+        map.extend(block(IMAGE, &dims, &[0, 1, 255, 0x17]));
+
+        let path_header = [2u32.to_le_bytes(), 4u32.to_le_bytes(), 90u32.to_le_bytes()].concat();
+        let points = [100u16, 200, 101, 201]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<u8>>();
+        map.extend(block(PATH, &path_header, &points));
+
+        map.extend(block(GOTO_TARGET, &[10, 0, 20, 0], &[]));
+        map
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let map = parse(&sample_map()).unwrap();
+        assert_eq!(map.header.map_sequence, 42);
+        assert_eq!(map.charger, Some(Point { x: 25600, y: 25500 }));
+        let image = map.image.unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.obstacle, vec![1]);
+        assert_eq!(image.floor, vec![2, 3]);
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                id: 2,
+                pixels: 1,
+                bounds: [1050, 550, 1100, 600],
+                center: Point { x: 1050, y: 550 },
+            }]
+        );
+        let path = map.path.unwrap();
+        assert_eq!(path.current_angle, 90);
+        assert_eq!(path.points[1], Point { x: 101, y: 201 });
+        assert_eq!(map.goto_target, Some(Point { x: 10, y: 20 }));
+    }
+
+    #[test]
+    fn parses_gzipped_maps() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&sample_map()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let map = parse(&gzipped).unwrap();
+        assert!(map.image.is_some());
+        assert_eq!(
+            map.block_types,
+            vec![CHARGER_LOCATION, IMAGE, PATH, GOTO_TARGET]
+        );
+        assert!(matches!(
+            parse(&gzipped[..gzipped.len() / 2]),
+            Err(MapError::Decompress(_))
+        ));
+
+        let mut bomb = GzEncoder::new(vec![], Compression::fast());
+        bomb.write_all(&vec![0; MAX_MAP as usize + 1]).unwrap();
+        let bomb = bomb.finish().unwrap();
+        assert!(matches!(decompress(&bomb), Err(MapError::TooBig)));
+    }
+
+    #[test]
+    fn rejects_truncated_maps() {
+        let map = sample_map();
+        assert!(matches!(
+            parse(&map[..map.len() - 3]),
+            Err(MapError::Truncated)
+        ));
+        assert!(matches!(parse(b"not a map"), Err(MapError::BadMagic)));
+
+        // an image with no width, or less than none, is turned away too
+        for width in [0i32, -2].iter() {
+            let mut map = sample_map();
+            let at = map.windows(4).position(|w| w == [2, 0, 24, 0]).unwrap() + 20;
+            map[at..at + 4].copy_from_slice(&width.to_le_bytes());
+            assert!(matches!(
+                parse(&map),
+                Err(MapError::BadImage { height: 2, .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::devices::Device;
+
+// Upper bounds of the reply latency buckets, in seconds. Replies are normally
+// well under a millisecond, the long tail is for handlers that do I/O.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Counters for the `/metrics` endpoint, in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    packets_received: AtomicU64,
+    packets_decoded: AtomicU64,
+    checksum_mismatches: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_failed: Mutex<BTreeMap<&'static str, u64>>,
+    packets_unsent: Mutex<BTreeMap<&'static str, u64>>,
+    header_only: Mutex<BTreeMap<&'static str, u64>>,
+    requests: Mutex<BTreeMap<String, u64>>,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+    handlers: Mutex<BTreeMap<String, HandlerTimes>>,
+}
+
+/// How long one method's handler has taken, all told.
+#[derive(Default)]
+struct HandlerTimes {
+    count: u64,
+    sum_micros: u64,
+    /// Calls that went over `[session] handler_budget_ms`.
+    slow: u64,
+}
+
+// Label values come straight from the robot, so they need escaping.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    pub fn packet_received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn packet_decoded(&self) {
+        self.packets_decoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counted whether the packet ends up dropped or, with `--lenient`,
+    /// handled anyway.
+    pub fn checksum_mismatch(&self) {
+        self.checksum_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_failed(&self, reason: &'static str) {
+        *self
+            .packets_failed
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    /// A packet for a robot that never went out: `coalesced` into a newer
+    /// one, dropped because the robot's queue was `queue_full`, or
+    /// `send_failed`.
+    pub fn packet_unsent(&self, reason: &'static str) {
+        *self
+            .packets_unsent
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    /// A packet with nothing but a header: a `hello` or a keep-alive
+    /// `ping`. These never count as decoded.
+    pub fn header_only(&self, kind: &'static str) {
+        *self.header_only.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn request(&self, method: &str) {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.get_mut(method) {
+            Some(count) => *count += 1,
+            None => {
+                requests.insert(method.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// How long it took from receiving a message to sending the reply.
+    pub fn reply_latency(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// How long the handler for `method` took, and whether that was over
+    /// budget.
+    pub fn handler_duration(&self, method: &str, elapsed: Duration, slow: bool) {
+        let mut handlers = self.handlers.lock().unwrap();
+        if !handlers.contains_key(method) {
+            handlers.insert(method.to_string(), HandlerTimes::default());
+        }
+        let times = handlers.get_mut(method).unwrap();
+        times.count += 1;
+        times.sum_micros += elapsed.as_micros() as u64;
+        times.slow += u64::from(slow);
+    }
+
+    pub fn render(&self, devices: &[Device]) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "dummycloud_packets_received_total",
+                "Datagrams received from robots.",
+                &self.packets_received,
+            ),
+            (
+                "dummycloud_packets_decoded_total",
+                "Messages that decrypted and checked out.",
+                &self.packets_decoded,
+            ),
+            (
+                "dummycloud_checksum_mismatches_total",
+                "Packets whose checksum didn't match the key.",
+                &self.checksum_mismatches,
+            ),
+            (
+                "dummycloud_received_bytes_total",
+                "Bytes received from robots.",
+                &self.bytes_received,
+            ),
+            (
+                "dummycloud_sent_bytes_total",
+                "Bytes sent to robots.",
+                &self.bytes_sent,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let name = "dummycloud_packets_failed_total";
+        header(&mut out, name, "counter", "Packets that were dropped.");
+        for (reason, count) in self.packets_failed.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, reason, count);
+        }
+
+        let name = "dummycloud_packets_unsent_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Packets for robots that were never sent.",
+        );
+        for (reason, count) in self.packets_unsent.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, reason, count);
+        }
+
+        let name = "dummycloud_header_only_packets_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Hellos and keep-alive pings from robots.",
+        );
+        for (kind, count) in self.header_only.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, count);
+        }
+
+        let name = "dummycloud_requests_total";
+        header(&mut out, name, "counter", "Messages from robots by method.");
+        for (method, count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{method=\"{}\"}} {}", name, escape(method), count);
+        }
+
+        let name = "dummycloud_reply_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time taken to reply to a robot.",
+        );
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        let handlers = self.handlers.lock().unwrap();
+        let name = "dummycloud_handler_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "summary",
+            "Time taken by each method's handler.",
+        );
+        for (method, times) in handlers.iter() {
+            let method = escape(method);
+            let sum = times.sum_micros as f64 / 1e6;
+            let _ = writeln!(out, "{}_sum{{method=\"{}\"}} {}", name, method, sum);
+            let _ = writeln!(
+                out,
+                "{}_count{{method=\"{}\"}} {}",
+                name, method, times.count
+            );
+        }
+        let name = "dummycloud_slow_handlers_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Handler calls that went over the budget.",
+        );
+        for (method, times) in handlers.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{method=\"{}\"}} {}",
+                name,
+                escape(method),
+                times.slow
+            );
+        }
+        drop(handlers);
+
+        let name = "dummycloud_device_last_seen_seconds";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "When a robot was last heard from, as a unix timestamp.",
+        );
+        for device in devices {
+            let _ = writeln!(
+                out,
+                "{}{{device_id=\"{}\"}} {}",
+                name,
+                device.id,
+                device.last_seen_secs()
+            );
+        }
+
+        let name = "dummycloud_device_clock_drift_seconds";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "How far a robot's clock has drifted from ours since it booted.",
+        );
+        for device in devices {
+            let _ = writeln!(
+                out,
+                "{}{{device_id=\"{}\"}} {}",
+                name, device.id, device.drift
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.packet_received(64);
+        metrics.packet_failed("checksum_mismatch");
+        metrics.checksum_mismatch();
+        metrics.packet_unsent("queue_full");
+        metrics.header_only("ping");
+        metrics.request("props");
+        metrics.request("say \"hi\"");
+        metrics.reply_latency(Duration::from_millis(3));
+        metrics.handler_duration("props", Duration::from_millis(2), false);
+        metrics.handler_duration("props", Duration::from_millis(250), true);
+
+        let text = metrics.render(&[]);
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in &[
+            "dummycloud_packets_received_total 1",
+            "dummycloud_received_bytes_total 64",
+            "dummycloud_packets_failed_total{reason=\"checksum_mismatch\"} 1",
+            "dummycloud_checksum_mismatches_total 1",
+            "dummycloud_packets_unsent_total{reason=\"queue_full\"} 1",
+            "dummycloud_header_only_packets_total{kind=\"ping\"} 1",
+            "dummycloud_requests_total{method=\"props\"} 1",
+            "dummycloud_requests_total{method=\"say \\\"hi\\\"\"} 1",
+            "dummycloud_reply_duration_seconds_bucket{le=\"0.0025\"} 0",
+            "dummycloud_reply_duration_seconds_bucket{le=\"0.005\"} 1",
+            "dummycloud_reply_duration_seconds_count 1",
+            "dummycloud_handler_duration_seconds_sum{method=\"props\"} 0.252",
+            "dummycloud_handler_duration_seconds_count{method=\"props\"} 2",
+            "dummycloud_slow_handlers_total{method=\"props\"} 1",
+        ] {
+            assert!(lines.contains(expected), "missing {}", expected);
+        }
+    }
+}
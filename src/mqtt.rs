@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Outgoing, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::auth::{self, Access};
+use crate::codes;
+use crate::config::{Config, HomeAssistantConfig, MqttConfig};
+use crate::events::Event;
+use crate::homeassistant;
+use crate::rules::Rules;
+use crate::shutdown::Stage;
+use crate::Context;
+
+const DEVICE_ID_PLACEHOLDER: &str = "{device_id}";
+const NAME_PLACEHOLDER: &str = "{name}";
+
+#[derive(Deserialize, Debug)]
+struct MqttCommand {
+    method: String,
+    #[serde(default = "no_params")]
+    params: serde_json::Value,
+    /// One of the `[auth]` tokens, if there are any.
+    #[serde(default)]
+    token: Option<String>,
+}
+
+fn no_params() -> serde_json::Value {
+    json!([])
+}
+
+/// A robot's name the way it goes in a topic: lower case, with anything but
+/// letters and digits made an underscore, e.g. `upstairs_s5`.
+pub(crate) fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Fills in `{device_id}`, and `{name}` with the robot's name, or its device
+/// id if it hasn't been given one.
+fn topic_for(template: &str, device_id: u32, config: &Config) -> String {
+    let name = config
+        .name_for(device_id)
+        .map_or_else(|| device_id.to_string(), slug);
+    template
+        .replace(DEVICE_ID_PLACEHOLDER, &device_id.to_string())
+        .replace(NAME_PLACEHOLDER, &name)
+}
+
+/// Works out which device a message on the command topic is meant for by
+/// matching it up against the configured template.
+fn device_id_from_topic(template: &str, topic: &str, config: &Config) -> Option<u32> {
+    let (placeholder, at) = [DEVICE_ID_PLACEHOLDER, NAME_PLACEHOLDER]
+        .iter()
+        .find_map(|p| Some((*p, template.find(p)?)))?;
+    let prefix = &template[..at];
+    let suffix = &template[at + placeholder.len()..];
+    let device = topic.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    // robots without a name go by their device id either way
+    device.parse().ok().or_else(|| {
+        config
+            .devices
+            .iter()
+            .find(|d| d.name.as_deref().map(slug).as_deref() == Some(device))
+            .map(|d| d.id)
+    })
+}
+
+/// What a topic filter for the template subscribes to every robot with.
+fn filter_for(template: &str) -> String {
+    template
+        .replace(DEVICE_ID_PLACEHOLDER, "+")
+        .replace(NAME_PLACEHOLDER, "+")
+}
+
+async fn publish(client: &AsyncClient, topic: String, retain: bool, body: String) {
+    debug!(%topic, "publishing to mqtt");
+    if let Err(e) = client.publish(topic, QoS::AtLeastOnce, retain, body).await {
+        warn!(error = %e, "could not publish to mqtt");
+    }
+}
+
+/// Announces the robot to Home Assistant the first time we hear from it, and
+/// keeps its state topic up to date after that.
+async fn publish_homeassistant(
+    client: &AsyncClient,
+    config: &HomeAssistantConfig,
+    context: &Context,
+    device_id: u32,
+    announced: &mut HashSet<u32>,
+) {
+    let state_topic = topic_for(&config.state_topic, device_id, &context.config);
+    if announced.insert(device_id) {
+        let command_topic = topic_for(&config.command_topic, device_id, &context.config);
+        let name = context.config.name_for(device_id);
+        for (topic, body) in
+            homeassistant::discovery_configs(config, device_id, name, &state_topic, &command_topic)
+        {
+            publish(client, topic, true, body.to_string()).await;
+        }
+    }
+    if let Some(state) = context.state.get(device_id) {
+        let mut body = homeassistant::state_json(&state);
+        body["labels"] = codes::labels(context.config.model_for(device_id), &state);
+        let body = body.to_string();
+        publish(client, state_topic, true, body).await;
+    }
+}
+
+async fn publish_events(client: AsyncClient, config: MqttConfig, context: Arc<Context>) {
+    let mut events = context.events.subscribe();
+    let mut announced = HashSet::new();
+    let mut rules = Rules::new(&context.config.rules);
+    loop {
+        let message = match events.recv().await {
+            Ok(Event::Message(m)) => match rules.apply(m) {
+                Some(m) => m,
+                None => continue,
+            },
+            Ok(Event::Connection(change)) => {
+                let topic = topic_for(&config.connection_topic, change.device_id, &context.config);
+                let body = json!(change.to).as_str().unwrap_or_default().to_string();
+                publish(&client, topic, true, body).await;
+                continue;
+            }
+            Ok(Event::Consumables(report)) => {
+                let topic = topic_for(&config.consumables_topic, report.device_id, &context.config);
+                publish(&client, topic, true, json!(report.parts).to_string()).await;
+                let topic = topic_for(&config.alert_topic, report.device_id, &context.config);
+                for part in &report.worn {
+                    let body = json!({"alert": "consumables", "part": part});
+                    publish(&client, topic.clone(), false, body.to_string()).await;
+                }
+                continue;
+            }
+            Ok(Event::Exchange(_)) | Ok(Event::Binary(_)) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "mqtt bridge fell behind and skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if let Some(template) = config.topics.get(&message.method) {
+            let topic = topic_for(template, message.device_id, &context.config);
+            publish(&client, topic, false, message.params.to_string()).await;
+        }
+        if let Some(ha) = &config.homeassistant {
+            if message.method == "props" || message.method == "event.status" {
+                publish_homeassistant(&client, ha, &context, message.device_id, &mut announced)
+                    .await;
+            }
+        }
+    }
+}
+
+/// Home Assistant sends bare commands like `start` rather than our JSON ones.
+async fn forward_homeassistant_command(context: Arc<Context>, device_id: u32, body: Vec<u8>) {
+    if context
+        .config
+        .auth
+        .as_ref()
+        .is_some_and(|auth| !auth.trust_homeassistant)
+    {
+        warn!(
+            device_id,
+            "ignoring home assistant command, set trust_homeassistant under [auth] to take them"
+        );
+        return;
+    }
+    let command = String::from_utf8_lossy(&body);
+    let method = match homeassistant::method_for(command.trim()) {
+        Some(m) => m,
+        None => {
+            warn!(%command, "ignoring unsupported home assistant command");
+            return;
+        }
+    };
+    if let Err(e) = context.send_command(device_id, method, &json!([])).await {
+        warn!(device_id, method, error = %e, "home assistant command failed");
+    }
+}
+
+async fn forward_command(
+    client: AsyncClient,
+    context: Arc<Context>,
+    command_topic: String,
+    topic: String,
+    body: Vec<u8>,
+) {
+    let device_id = match device_id_from_topic(&command_topic, &topic, &context.config) {
+        Some(id) => id,
+        None => return,
+    };
+    let command: MqttCommand = match serde_json::from_slice(&body) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(%topic, error = %e, "ignoring malformed mqtt command");
+            return;
+        }
+    };
+    let allowed = auth::access(context.config.auth.as_ref(), command.token.as_deref());
+    let reply = if allowed != Some(Access::Command) {
+        warn!(%topic, "ignoring mqtt command without a token that may send commands");
+        json!({ "error": { "message": "a token that may send commands is needed" } })
+    } else {
+        match context
+            .send_command(device_id, &command.method, &command.params)
+            .await
+        {
+            Ok(reply) => json!(reply),
+            Err(e) => json!({ "error": { "message": e.to_string() } }),
+        }
+    };
+    let reply_topic = format!("{}/reply", topic);
+    if let Err(e) = client
+        .publish(reply_topic, QoS::AtLeastOnce, false, reply.to_string())
+        .await
+    {
+        warn!(error = %e, "could not publish to mqtt");
+    }
+}
+
+pub async fn run(config: MqttConfig, context: Arc<Context>) {
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let config_host = config.host.clone();
+    let command_topic = config.command_topic.clone();
+    let ha_command_topic = config
+        .homeassistant
+        .as_ref()
+        .map(|ha| ha.command_topic.clone());
+    let mut filters = vec![filter_for(&command_topic)];
+    filters.extend(ha_command_topic.iter().map(|topic| filter_for(topic)));
+    tokio::spawn(publish_events(client.clone(), config, Arc::clone(&context)));
+
+    info!(host = %config_host, "connecting to mqtt broker");
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = context.shutdown.reached(Stage::Closing) => break,
+        };
+        match event {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker");
+                // subscriptions don't survive a reconnect
+                for filter in &filters {
+                    if let Err(e) = client.subscribe(filter.clone(), QoS::AtLeastOnce).await {
+                        warn!(topic = %filter, error = %e, "could not subscribe to mqtt topic");
+                    }
+                }
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                let ha_device = ha_command_topic.as_ref().and_then(|template| {
+                    device_id_from_topic(template, &publish.topic, &context.config)
+                });
+                if let Some(device_id) = ha_device {
+                    tokio::spawn(forward_homeassistant_command(
+                        Arc::clone(&context),
+                        device_id,
+                        publish.payload.to_vec(),
+                    ));
+                    continue;
+                }
+                tokio::spawn(forward_command(
+                    client.clone(),
+                    Arc::clone(&context),
+                    command_topic.clone(),
+                    publish.topic,
+                    publish.payload.to_vec(),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(error = %e, "lost connection to mqtt broker");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+
+    // the disconnect only goes out as the event loop is polled
+    if client.try_disconnect().is_ok() {
+        while let Ok(event) = eventloop.poll().await {
+            if let MqttEvent::Outgoing(Outgoing::Disconnect) = event {
+                info!("disconnected from mqtt broker");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_id_round_trips_through_topic() {
+        let config = Config::parse(
+            r#"
+            [[devices]]
+            id = 12345
+            key = "SoMeALPhaCHars"
+            name = "Upstairs S5"
+            "#,
+        )
+        .unwrap();
+        let template = "dummycloud/{device_id}/command";
+        let topic = topic_for(template, 12345, &config);
+        assert_eq!(topic, "dummycloud/12345/command");
+        assert_eq!(device_id_from_topic(template, &topic, &config), Some(12345));
+        assert_eq!(
+            device_id_from_topic(template, "dummycloud/abc/command", &config),
+            None
+        );
+        assert_eq!(
+            device_id_from_topic(template, "other/12345/command", &config),
+            None
+        );
+
+        let template = "dummycloud/{name}/command";
+        let topic = topic_for(template, 12345, &config);
+        assert_eq!(topic, "dummycloud/upstairs_s5/command");
+        assert_eq!(device_id_from_topic(template, &topic, &config), Some(12345));
+        assert_eq!(topic_for(template, 678, &config), "dummycloud/678/command");
+        assert_eq!(
+            device_id_from_topic(template, "dummycloud/678/command", &config),
+            Some(678)
+        );
+        assert_eq!(filter_for(template), "dummycloud/+/command");
+    }
+}
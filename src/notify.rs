@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::config::{NotificationConfig, WebhookConfig};
+use crate::events::{DeviceMessage, Event};
+use crate::webhooks;
+use crate::Context;
+
+async fn run_script(script: &str, message: &DeviceMessage) {
+    let status = Command::new(script)
+        .env("DUMMYCLOUD_DEVICE_ID", message.device_id.to_string())
+        .env("DUMMYCLOUD_METHOD", &message.method)
+        .env("DUMMYCLOUD_PARAMS", message.params.to_string())
+        .status()
+        .await;
+    match status {
+        Ok(s) if s.success() => {}
+        Ok(s) => warn!(%script, status = %s, "notification script failed"),
+        Err(e) => warn!(%script, error = %e, "could not run notification script"),
+    }
+}
+
+async fn notify(
+    client: &reqwest::Client,
+    retry: &WebhookConfig,
+    rule: &NotificationConfig,
+    message: &DeviceMessage,
+) {
+    info!(
+        device_id = message.device_id,
+        method = %message.method,
+        "sending notification"
+    );
+    if let Some(script) = &rule.exec {
+        run_script(script, message).await;
+    }
+    if let Some(url) = &rule.webhook {
+        let body = serde_json::to_vec(message).unwrap_or_default();
+        if let Err(e) = webhooks::deliver(client, retry, url, &body).await {
+            warn!(%url, error = %e, "notification webhook failed");
+        }
+    }
+}
+
+/// Runs a script and/or calls a webhook whenever the robot sends one of the
+/// methods a rule is interested in, e.g. `event.bin_full`.
+pub async fn run(rules: Vec<NotificationConfig>, context: Arc<Context>) {
+    let client = reqwest::Client::new();
+    let rules = Arc::new(rules);
+    let mut events = context.events.subscribe();
+    loop {
+        let message = match events.recv().await {
+            Ok(Event::Message(m)) => m,
+            Ok(Event::Exchange(_))
+            | Ok(Event::Connection(_))
+            | Ok(Event::Consumables(_))
+            | Ok(Event::Binary(_)) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "notifications fell behind and skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        for (i, rule) in rules.iter().enumerate() {
+            if !rule.methods.contains(&message.method) {
+                continue;
+            }
+            // a slow script shouldn't hold up the next event
+            let client = client.clone();
+            let rules = Arc::clone(&rules);
+            let context = Arc::clone(&context);
+            let message = message.clone();
+            tokio::spawn(async move {
+                let retry = &context.config.webhooks;
+                notify(&client, retry, &rules[i], &message).await
+            });
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+// A minimal SNTP (RFC 4330) server: just enough for the robot's NTP client
+// to accept our clock, with no attempt at being a proper time source.
+const PACKET_SIZE: usize = 48;
+// Seconds between the NTP epoch (1900) and the unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const STRATUM: u8 = 1;
+// about a microsecond, as a power of two
+const PRECISION: i8 = -20;
+const REFERENCE_ID: &[u8; 4] = b"LOCL";
+
+const ORIGINATE_OFFSET: usize = 24;
+const RECEIVE_OFFSET: usize = 32;
+const TRANSMIT_OFFSET: usize = 40;
+const REFERENCE_OFFSET: usize = 16;
+
+fn ntp_timestamp(time: SystemTime) -> [u8; 8] {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    // NTP seconds wrap in 2036, which is fine for a 32 bit field
+    let secs = (since_epoch.as_secs() + NTP_UNIX_OFFSET) as u32;
+    let fraction = ((u64::from(since_epoch.subsec_nanos()) << 32) / 1_000_000_000) as u32;
+    let mut out = [0; 8];
+    out[..4].copy_from_slice(&secs.to_be_bytes());
+    out[4..].copy_from_slice(&fraction.to_be_bytes());
+    out
+}
+
+/// Builds the answer to a client request, or None if it isn't one.
+fn reply(request: &[u8], received: SystemTime, now: SystemTime) -> Option<[u8; PACKET_SIZE]> {
+    if request.len() < PACKET_SIZE || request[0] & 0x07 != MODE_CLIENT {
+        return None;
+    }
+    let version = (request[0] >> 3) & 0x07;
+    let mut out = [0; PACKET_SIZE];
+    // leap indicator 0, the client's version, server mode
+    out[0] = (version << 3) | MODE_SERVER;
+    out[1] = STRATUM;
+    out[2] = request[2];
+    out[3] = PRECISION as u8;
+    out[12..16].copy_from_slice(REFERENCE_ID);
+    out[REFERENCE_OFFSET..REFERENCE_OFFSET + 8].copy_from_slice(&ntp_timestamp(now));
+    // the client matches our reply up using its own transmit timestamp
+    out[ORIGINATE_OFFSET..ORIGINATE_OFFSET + 8]
+        .copy_from_slice(&request[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8]);
+    out[RECEIVE_OFFSET..RECEIVE_OFFSET + 8].copy_from_slice(&ntp_timestamp(received));
+    out[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8].copy_from_slice(&ntp_timestamp(now));
+    Some(out)
+}
+
+/// Answers NTP queries with the system time.
+pub async fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    info!(%addr, "NTP server is now listening");
+    let mut buf = [0; 512];
+    loop {
+        let (amt, src) = socket.recv_from(&mut buf).await?;
+        let received = SystemTime::now();
+        match reply(&buf[..amt], received, SystemTime::now()) {
+            Some(answer) => {
+                debug!(%src, "answering NTP request");
+                if let Err(e) = socket.send_to(&answer, src).await {
+                    warn!(%src, error = %e, "could not answer NTP request");
+                }
+            }
+            None => debug!(%src, len = amt, "ignoring packet that isn't an NTP request"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_client_requests_with_our_time() {
+        let mut request = [0u8; PACKET_SIZE];
+        // version 4, client mode
+        request[0] = (4 << 3) | MODE_CLIENT;
+        request[TRANSMIT_OFFSET..].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_500);
+
+        let answer = reply(&request, now, now).unwrap();
+        assert_eq!(answer[0], (4 << 3) | MODE_SERVER);
+        assert_eq!(answer[1], STRATUM);
+        assert_eq!(
+            &answer[ORIGINATE_OFFSET..RECEIVE_OFFSET],
+            &[1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        let secs = u32::from_be_bytes([answer[40], answer[41], answer[42], answer[43]]);
+        assert_eq!(u64::from(secs), 1_600_000_000 + NTP_UNIX_OFFSET);
+        // half a second
+        assert_eq!(answer[44], 0x80);
+
+        request[0] = (4 << 3) | MODE_SERVER;
+        assert!(reply(&request, now, now).is_none());
+        assert!(reply(&request[..10], now, now).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::Value;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::cleaning::{RoomRef, Units};
+use crate::devices::Connection;
+use crate::payload::ReplyPayload;
+use crate::{api, http, Context};
+
+// What the handlers put together with `json!`, written out for the schema.
+// Keep these in step with `api::device_json` and `api::device_rooms`.
+
+/// A robot that has checked in.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct Device {
+    id: u32,
+    /// What it's called under `[[devices]]`, if anything.
+    name: Option<String>,
+    /// Where its packets come from, as `ip:port`.
+    addr: String,
+    /// Seconds since the epoch.
+    last_seen: u64,
+    /// The stamp of its last packet, its uptime in seconds.
+    stamp: u32,
+    /// When it booted, in seconds since the epoch.
+    booted: u64,
+    /// Seconds its clock has gained on ours since it booted.
+    drift: i64,
+    connection: Connection,
+    /// Whether its packets keep failing to decode with the key we have.
+    wrong_key: bool,
+}
+
+/// A room in the robot's latest map.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct Room {
+    /// The segment id, as `clean_segments` takes it.
+    id: u8,
+    /// Its name under `[[devices]]`, or `Room <id>`.
+    name: String,
+    /// In square metres.
+    area: f64,
+    /// `[x1, y1, x2, y2]` in map millimetres.
+    bounds: Value,
+    /// `[x, y]` in map millimetres.
+    center: Value,
+}
+
+/// What failed requests answer with.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct ApiError {
+    error: ErrorMessage,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct ErrorMessage {
+    message: String,
+}
+
+/// What the derive can't say: how tokens are sent, which is only needed
+/// with `[auth]`.
+struct Extras;
+
+impl Modify for Extras {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        // Cargo.toml doesn't give one, which would come out as a blank name
+        openapi.info.license = None;
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "dummycloud",
+        description = "Looking after and commanding robots talking to dummycloud."
+    ),
+    paths(
+        api::list_devices,
+        api::device_state,
+        api::device_rooms,
+        api::send_command,
+        api::clean_segments,
+        api::clean_zone,
+        api::goto_target,
+        http::latest_map_json,
+        http::latest_map_png,
+        http::latest_map,
+    ),
+    components(schemas(Device, Room, ApiError, ReplyPayload, RoomRef, Units)),
+    modifiers(&Extras),
+    security(("token" = []))
+)]
+pub(crate) struct ApiDoc;
+
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Describes the API, for dashboards to build against.
+pub fn router() -> Router<Arc<Context>> {
+    Router::new().route("/api/openapi.json", get(openapi_json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_the_api() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+        for path in [
+            "/api/devices",
+            "/api/devices/{device_id}/state",
+            "/api/devices/{device_id}/command",
+            "/api/devices/{device_id}/map.png",
+            "/maps/{device_id}/latest.json",
+        ] {
+            assert!(paths.contains_key(path), "{} isn't documented", path);
+        }
+        let command = &paths["/api/devices/{device_id}/command"]["post"];
+        assert!(command["requestBody"].is_object());
+        let device = &doc["components"]["schemas"]["Device"]["properties"];
+        assert!(device["connection"].is_object());
+        assert!(doc["components"]["securitySchemes"]["token"].is_object());
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::time::Instant;
+
+use crate::codec::{self, PacketHeader};
+
+/// How many times a send that fails for lack of buffer space is tried
+/// again before the packet's given up on.
+const SEND_RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// What a packet is, as far as holding it back goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// Answers and commands, every one of which has to get there.
+    Message,
+    /// Timesyncs and keep-alives, of which only the newest one matters.
+    Timesync,
+    Keepalive,
+}
+
+impl Kind {
+    fn of(packet: &[u8]) -> Kind {
+        if packet.len() > codec::HEADER_SIZE {
+            return Kind::Message;
+        }
+        match PacketHeader::parse(packet) {
+            Ok(header) if header.unknown == codec::UNSET => Kind::Timesync,
+            _ => Kind::Keepalive,
+        }
+    }
+}
+
+pub(crate) struct Outgoing {
+    pub packet: Vec<u8>,
+    /// What the packet says, for captures.
+    pub plaintext: Option<Vec<u8>>,
+    seq: u64,
+    kind: Kind,
+}
+
+impl Outgoing {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    packets: VecDeque<Outgoing>,
+    /// Whether someone is sending what's queued already.
+    sending: bool,
+}
+
+/// Where a robot is reached: which listener, and its address.
+pub(crate) type Peer = (usize, SocketAddr);
+
+/// What happened to a packet handed to [`Outbox::push`].
+#[derive(Debug, PartialEq)]
+pub(crate) struct Pushed {
+    /// Tells the packet apart from the others queued for the robot.
+    pub seq: u64,
+    /// Nobody is sending to the robot yet, so it's up to the caller.
+    pub drain: bool,
+    /// Why a packet was dropped to make room, if one was.
+    pub dropped: Option<&'static str>,
+}
+
+/// The packets waiting to go out to each robot. Whoever queues a packet
+/// when nothing is being sent to that robot sends everything queued for it
+/// until the queue's empty, so packets to one robot go out in order while
+/// others are sent at the same time.
+pub(crate) struct Outbox {
+    /// Packets each robot's queue holds before the oldest are dropped.
+    cap: usize,
+    queues: Mutex<HashMap<Peer, Queue>>,
+    next_seq: Mutex<u64>,
+}
+
+impl Outbox {
+    pub fn new(cap: usize) -> Outbox {
+        Outbox {
+            cap: cap.max(1),
+            queues: Mutex::new(HashMap::new()),
+            next_seq: Mutex::new(0),
+        }
+    }
+
+    /// Queues a packet for `peer`. A timesync or keep-alive replaces one
+    /// that hasn't gone out yet, and a queue that's full loses its oldest
+    /// packet.
+    pub fn push(&self, peer: Peer, packet: &[u8], plaintext: Option<&[u8]>) -> Pushed {
+        let seq = {
+            let mut next_seq = self.next_seq.lock().unwrap();
+            *next_seq += 1;
+            *next_seq
+        };
+        let outgoing = Outgoing {
+            packet: packet.to_vec(),
+            plaintext: plaintext.map(<[u8]>::to_vec),
+            seq,
+            kind: Kind::of(packet),
+        };
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(peer).or_default();
+        let mut dropped = None;
+        let stale = match outgoing.kind {
+            Kind::Message => None,
+            kind => queue.packets.iter().position(|p| p.kind == kind),
+        };
+        match stale {
+            Some(i) => {
+                queue.packets.remove(i);
+                dropped = Some("coalesced");
+            }
+            None if queue.packets.len() >= self.cap => {
+                queue.packets.pop_front();
+                dropped = Some("queue_full");
+            }
+            None => {}
+        }
+        queue.packets.push_back(outgoing);
+        let drain = !queue.sending;
+        queue.sending = true;
+        Pushed {
+            seq,
+            drain,
+            dropped,
+        }
+    }
+
+    /// Sends what's queued for `peer`, after [`Outbox::push`] said to.
+    pub fn sending(&self, peer: Peer) -> Sending<'_> {
+        Sending {
+            outbox: self,
+            peer,
+            finished: false,
+        }
+    }
+
+    fn next(&self, peer: Peer) -> Option<Outgoing> {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.get_mut(&peer)?;
+        let next = queue.packets.pop_front();
+        if next.is_none() {
+            queues.remove(&peer);
+        }
+        next
+    }
+
+    fn release(&self, peer: Peer) {
+        if let Some(queue) = self.queues.lock().unwrap().get_mut(&peer) {
+            queue.sending = false;
+        }
+    }
+}
+
+/// Hands out a robot's queued packets one at a time. Given up on before the
+/// queue's empty, e.g. when the API request that sent a command goes away,
+/// it leaves the rest to whoever queues the next packet.
+pub(crate) struct Sending<'a> {
+    outbox: &'a Outbox,
+    peer: Peer,
+    finished: bool,
+}
+
+impl Sending<'_> {
+    /// The next packet, or None once there's nothing left, which leaves it
+    /// to whoever queues the next one to send it.
+    pub fn next(&mut self) -> Option<Outgoing> {
+        let next = self.outbox.next(self.peer);
+        self.finished = next.is_none();
+        next
+    }
+}
+
+impl Drop for Sending<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.outbox.release(self.peer);
+        }
+    }
+}
+
+/// A packet waiting for `due` before it goes out.
+pub(crate) struct Held {
+    pub due: Instant,
+    pub packet: Vec<u8>,
+    pub plaintext: Option<Vec<u8>>,
+}
+
+/// What's held back for one robot, in the order it's to go out.
+pub(crate) struct Lane {
+    pub peer: Peer,
+    held: mpsc::UnboundedReceiver<Held>,
+}
+
+impl Lane {
+    /// The next packet, or None once there's nothing left, which closes
+    /// the lane.
+    pub fn next(&mut self, holdback: &Holdback) -> Option<Held> {
+        match self.held.try_recv() {
+            Ok(held) => return Some(held),
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {}
+        }
+        // nothing can be added to it while it's being closed
+        let mut lanes = holdback.lanes.lock().unwrap();
+        match self.held.try_recv() {
+            Ok(held) => Some(held),
+            Err(_) => {
+                lanes.remove(&self.peer);
+                None
+            }
+        }
+    }
+}
+
+/// Packets held back a while before they're sent, to play a slow cloud
+/// or a bad network. Each robot with packets held back gets a [`Lane`],
+/// which everything else for it goes through until it's empty: its packets
+/// still go out in order, but whoever sent them isn't kept waiting, nor
+/// are the other robots handled after it.
+pub(crate) struct Holdback {
+    lanes: Mutex<HashMap<Peer, mpsc::UnboundedSender<Held>>>,
+    opened: mpsc::UnboundedSender<Lane>,
+    opening: Mutex<Option<mpsc::UnboundedReceiver<Lane>>>,
+}
+
+impl Holdback {
+    pub fn new() -> Holdback {
+        let (opened, opening) = mpsc::unbounded_channel();
+        Holdback {
+            lanes: Mutex::new(HashMap::new()),
+            opened,
+            opening: Mutex::new(Some(opening)),
+        }
+    }
+
+    /// Holds `packet` back for `delay`, and behind whatever is held back
+    /// for `peer` already. False when there's neither, in which case it's
+    /// up to the caller to send it now.
+    pub fn hold(
+        &self,
+        peer: Peer,
+        delay: Duration,
+        packet: &[u8],
+        plaintext: Option<&[u8]>,
+    ) -> bool {
+        let mut lanes = self.lanes.lock().unwrap();
+        if delay.is_zero() && !lanes.contains_key(&peer) {
+            return false;
+        }
+        let held = Held {
+            due: Instant::now() + delay,
+            packet: packet.to_vec(),
+            plaintext: plaintext.map(<[u8]>::to_vec),
+        };
+        if let Some(lane) = lanes.get(&peer) {
+            // only fails if nobody is sending what's held back any more
+            let _ = lane.send(held);
+            return true;
+        }
+        let (lane, receiver) = mpsc::unbounded_channel();
+        let _ = lane.send(held);
+        lanes.insert(peer, lane);
+        let _ = self.opened.send(Lane {
+            peer,
+            held: receiver,
+        });
+        true
+    }
+
+    /// The lanes as they're opened, for the one task that sends what's
+    /// held back in them.
+    pub fn lanes(&self) -> Option<mpsc::UnboundedReceiver<Lane>> {
+        self.opening.lock().unwrap().take()
+    }
+}
+
+/// Sends a datagram, waiting for room in the socket's buffer and trying
+/// again a few times when the kernel is short of it, rather than losing it.
+pub(crate) async fn send(socket: &UdpSocket, packet: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    let mut retries = 0;
+    loop {
+        socket.writable().await?;
+        match socket.try_send_to(packet, addr) {
+            Ok(sent) => return Ok(sent),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(_) if retries < SEND_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(RETRY_DELAY * retries).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_coalesces_and_caps() {
+        let outbox = Outbox::new(3);
+        let robot: Peer = (0, ([192, 168, 1, 50], 54321).into());
+        let other: Peer = (0, ([192, 168, 1, 51], 54321).into());
+        let message = [vec![0x21; codec::HEADER_SIZE], vec![1; 16]].concat();
+        let timesync = |stamp| codec::TimesyncPacket { epoch: stamp }.to_bytes();
+
+        let first = outbox.push(robot, &message, None);
+        assert!(first.drain);
+        assert!(!outbox.push(robot, &timesync(1), None).drain);
+        assert!(outbox.push(other, &message, None).drain);
+        // only the newest timesync is worth sending
+        let newer = outbox.push(robot, &timesync(2), None);
+        assert_eq!(newer.dropped, Some("coalesced"));
+        outbox.push(robot, &message, None);
+        let full = outbox.push(robot, &message, None);
+        assert_eq!(full.dropped, Some("queue_full"));
+
+        // the first message was the one dropped to make room
+        let mut sending = outbox.sending(robot);
+        let sent: Vec<u64> = std::iter::from_fn(|| sending.next())
+            .map(|p| p.seq())
+            .collect();
+        drop(sending);
+        assert_eq!(sent.len(), 3);
+        assert!(!sent.contains(&first.seq));
+        assert!(sent.contains(&newer.seq));
+        assert!(outbox.push(robot, &message, None).drain);
+
+        // giving up partway lets the next packet's sender carry on
+        let mut sending = outbox.sending(other);
+        assert!(sending.next().is_some());
+        drop(sending);
+        assert!(outbox.push(other, &message, None).drain);
+    }
+
+    #[test]
+    fn holds_back_in_order() {
+        let holdback = Holdback::new();
+        let robot: Peer = (0, ([192, 168, 1, 50], 54321).into());
+        let other: Peer = (0, ([192, 168, 1, 51], 54321).into());
+        let mut lanes = holdback.lanes().unwrap();
+        assert!(holdback.lanes().is_none());
+
+        assert!(!holdback.hold(robot, Duration::ZERO, b"now", None));
+        assert!(holdback.hold(robot, Duration::from_secs(1), b"slow", None));
+        // nothing overtakes what's held back
+        assert!(holdback.hold(robot, Duration::ZERO, b"after", None));
+        assert!(!holdback.hold(other, Duration::ZERO, b"elsewhere", None));
+
+        let mut lane = lanes.try_recv().unwrap();
+        assert_eq!(lane.peer, robot);
+        assert!(lanes.try_recv().is_err());
+        let first = lane.next(&holdback).unwrap();
+        let second = lane.next(&holdback).unwrap();
+        assert_eq!(
+            (&first.packet[..], &second.packet[..]),
+            (&b"slow"[..], &b"after"[..])
+        );
+        assert!(second.due <= first.due);
+        assert!(lane.next(&holdback).is_none());
+        // with the lane closed the robot's packets go straight out again
+        assert!(!holdback.hold(robot, Duration::ZERO, b"now", None));
+    }
+}
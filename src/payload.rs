@@ -1,24 +1,144 @@
 use serde::{Deserialize, Serialize};
 
+/// Anything the robot sends us is either a call of its own, or the answer to
+/// a command we pushed to it earlier.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum IncomingPayload {
+    Message(MessagePayload),
+    Reply(ReplyPayload),
+}
+
+/// Some firmwares batch several calls into one packet as a JSON array, and
+/// expect an array of answers back.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum IncomingBody {
+    Single(IncomingPayload),
+    Batch(Vec<IncomingPayload>),
+}
+
+impl IncomingBody {
+    pub fn is_batch(&self) -> bool {
+        matches!(self, IncomingBody::Batch(_))
+    }
+
+    pub fn into_payloads(self) -> Vec<IncomingPayload> {
+        match self {
+            IncomingBody::Single(payload) => vec![payload],
+            IncomingBody::Batch(payloads) => payloads,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MessagePayload {
     pub method: String,
-    partner_id: Option<String>,
+    /// Usually an empty string, but nothing's done with it, so whatever the
+    /// firmware puts there is fine.
+    #[allow(dead_code)]
+    partner_id: Option<serde_json::Value>,
     pub id: u32,
+    /// Null for the odd call that leaves them out.
+    #[serde(default)]
     pub params: serde_json::Value,
 }
 
+#[derive(Serialize, Debug)]
+pub struct ErrorPayload {
+    code: i32,
+    message: String,
+}
+
+/// Our answer to a call from the robot, carrying either a result or an
+/// error.
 #[derive(Serialize, Debug)]
 pub struct ResponsePayload {
     id: u32,
-    result: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorPayload>,
 }
 
 impl ResponsePayload {
     pub fn new(id: u32, result: serde_json::Value) -> ResponsePayload {
         ResponsePayload {
-            id: id,
-            result: result,
+            id,
+            result: Some(result),
+            error: None,
         }
     }
+
+    pub fn error(id: u32, code: i32, message: &str) -> ResponsePayload {
+        ResponsePayload {
+            id,
+            result: None,
+            error: Some(ErrorPayload {
+                code,
+                message: message.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommandPayload<'a> {
+    pub id: u32,
+    pub method: &'a str,
+    pub params: &'a serde_json::Value,
+}
+
+/// The robot's answer to a command.
+#[derive(Serialize, Deserialize, Debug, utoipa::ToSchema)]
+pub struct ReplyPayload {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_single_and_batched_bodies() {
+        let single: IncomingBody =
+            serde_json::from_str(r#"{"id": 1, "method": "props", "params": {}}"#).unwrap();
+        assert!(!single.is_batch());
+        assert_eq!(single.into_payloads().len(), 1);
+
+        let batch: IncomingBody = serde_json::from_str(
+            r#"[{"id": 2, "method": "props", "params": {}}, {"id": 100000, "result": ["ok"]}]"#,
+        )
+        .unwrap();
+        assert!(batch.is_batch());
+        let payloads = batch.into_payloads();
+        assert!(matches!(payloads[0], IncomingPayload::Message(_)));
+        assert!(matches!(payloads[1], IncomingPayload::Reply(_)));
+
+        let odd: IncomingBody =
+            serde_json::from_str(r#"{"id": 3, "method": "event.bin_full", "partner_id": 0}"#)
+                .unwrap();
+        match &odd.into_payloads()[0] {
+            IncomingPayload::Message(message) => {
+                assert_eq!(message.params, serde_json::Value::Null)
+            }
+            other => panic!("not a message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn responses_carry_either_a_result_or_an_error() {
+        assert_eq!(
+            serde_json::to_string(&ResponsePayload::new(1, serde_json::json!("ok"))).unwrap(),
+            r#"{"id":1,"result":"ok"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ResponsePayload::error(2, -32601, "not supported")).unwrap(),
+            r#"{"id":2,"error":{"code":-32601,"message":"not supported"}}"#
+        );
+    }
 }
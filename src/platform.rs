@@ -0,0 +1,70 @@
+use std::fmt;
+
+use crate::config::Config;
+
+/// What only some platforms can do. A config asking for one of them where
+/// it isn't available is turned down at startup, rather than left to fail
+/// halfway through or, worse, quietly do something else.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    /// Keeping a listener to one network interface with SO_BINDTODEVICE,
+    /// see `[listener] interface`.
+    BindToDevice,
+    /// Socket activation, readiness and the watchdog, see `--daemon`.
+    Systemd,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Feature::BindToDevice => "binding to a network interface",
+            Feature::Systemd => "running under systemd",
+        })
+    }
+}
+
+/// Whether this build's platform can do `feature`.
+pub fn supported(feature: Feature) -> bool {
+    match feature {
+        Feature::BindToDevice => cfg!(any(target_os = "linux", target_os = "android")),
+        Feature::Systemd => cfg!(target_os = "linux"),
+    }
+}
+
+/// The features `config`, and `--daemon` if `daemon`, need that aren't
+/// available here.
+pub fn unsupported(config: &Config, daemon: bool) -> Vec<Feature> {
+    let listener = &config.listener;
+    let interface =
+        listener.interface.is_some() || listener.tenants.iter().any(|t| t.interface.is_some());
+    [
+        (Feature::BindToDevice, interface),
+        (Feature::Systemd, daemon),
+    ]
+    .iter()
+    .filter(|(feature, wanted)| *wanted && !supported(*feature))
+    .map(|(feature, _)| *feature)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_turns_down_what_the_config_asks_for() {
+        let mut config = Config::default();
+        assert!(unsupported(&config, false).is_empty());
+        config.listener.interface = Some("eth0".to_string());
+        let expected = if cfg!(target_os = "linux") {
+            vec![]
+        } else {
+            vec![Feature::BindToDevice, Feature::Systemd]
+        };
+        assert_eq!(unsupported(&config, true), expected);
+        assert_eq!(
+            Feature::BindToDevice.to_string(),
+            "binding to a network interface"
+        );
+    }
+}
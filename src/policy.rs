@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::config::ProxyConfig;
+
+/// What proxy mode does with a call from the robot.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Answer it ourselves, like outside proxy mode.
+    Local,
+    /// Relay it to the real cloud.
+    Forward,
+    /// Leave it unanswered.
+    Drop,
+}
+
+// Firmware update calls stay local while `[ota]` blocks updates, unless a
+// rule says otherwise, so proxying doesn't sneak an update past us.
+const OTA_METHODS: [&str; 3] = ["miIO.ota*", "miIO.get_ota_state", "_async.*"];
+
+/// Finds the rule for a method. Rules are either exact method names or a
+/// prefix ending in `*`, and exact names win over the longest prefix.
+fn lookup(rules: &HashMap<String, Action>, method: &str) -> Option<Action> {
+    if let Some(action) = rules.get(method) {
+        return Some(*action);
+    }
+    rules
+        .iter()
+        .filter_map(|(pattern, action)| Some((pattern.strip_suffix('*')?, action)))
+        .filter(|(prefix, _)| method.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, action)| *action)
+}
+
+/// Decides, per device and method, which calls proxy mode answers itself,
+/// relays or drops.
+pub struct Policy {
+    default: Action,
+    methods: HashMap<String, Action>,
+    devices: HashMap<u32, (Option<Action>, HashMap<String, Action>)>,
+    block_ota: bool,
+}
+
+impl Policy {
+    pub fn new(config: &ProxyConfig, block_ota: bool) -> Policy {
+        Policy {
+            default: config.default,
+            methods: config.methods.clone(),
+            devices: config
+                .devices
+                .iter()
+                .map(|d| (d.id, (d.default, d.methods.clone())))
+                .collect(),
+            block_ota,
+        }
+    }
+
+    /// What to do with a call, or with a hello if `method` is None.
+    pub fn action_for(&self, device_id: u32, method: Option<&str>) -> Action {
+        let device = self.devices.get(&device_id);
+        if let Some(method) = method {
+            let rule = device
+                .and_then(|(_, methods)| lookup(methods, method))
+                .or_else(|| lookup(&self.methods, method));
+            if let Some(action) = rule {
+                return action;
+            }
+            let ota = OTA_METHODS
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => method.starts_with(prefix),
+                    None => method == *pattern,
+                });
+            if self.block_ota && ota {
+                return Action::Local;
+            }
+        }
+        device
+            .and_then(|(default, _)| *default)
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn device_rules_win_over_global_ones() {
+        let config = Config::parse(
+            r#"
+            [proxy]
+            default = "forward"
+
+            [proxy.methods]
+            "_sync.gen_presigned_url" = "local"
+            "event.*" = "drop"
+            "event.status" = "forward"
+
+            [[proxy.devices]]
+            id = 1234
+            default = "local"
+            methods = { "_otc.info" = "forward", "event.bin*" = "local" }
+            "#,
+        )
+        .unwrap();
+        let policy = Policy::new(config.proxy.as_ref().unwrap(), true);
+
+        assert_eq!(policy.action_for(1, Some("_otc.info")), Action::Forward);
+        assert_eq!(
+            policy.action_for(1, Some("_sync.gen_presigned_url")),
+            Action::Local
+        );
+        assert_eq!(policy.action_for(1, Some("event.bin_full")), Action::Drop);
+        assert_eq!(policy.action_for(1, Some("event.status")), Action::Forward);
+        assert_eq!(policy.action_for(1, Some("miIO.ota_state")), Action::Local);
+        assert_eq!(policy.action_for(1, None), Action::Forward);
+
+        assert_eq!(policy.action_for(1234, Some("_otc.info")), Action::Forward);
+        assert_eq!(
+            policy.action_for(1234, Some("event.bin_full")),
+            Action::Local
+        );
+        assert_eq!(policy.action_for(1234, Some("props")), Action::Local);
+        assert_eq!(policy.action_for(1234, None), Action::Local);
+
+        let unblocked = Policy::new(config.proxy.as_ref().unwrap(), false);
+        assert_eq!(
+            unblocked.action_for(1, Some("miIO.ota_state")),
+            Action::Forward
+        );
+    }
+}
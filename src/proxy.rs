@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::codec::{self, PacketHeader};
+use crate::config::ProxyConfig;
+use crate::handlers;
+use crate::payload::{IncomingBody, IncomingPayload};
+use crate::policy::{Action, Policy};
+use crate::upstream::{Upstream, Via};
+use crate::Context;
+
+// The robot talks to the cloud at least every couple of minutes, so a
+// session that's been quiet for this long has gone away.
+const SESSION_IDLE: Duration = Duration::from_secs(600);
+
+/// Relays robots to the real Xiaomi cloud, logging what both sides say. Each
+/// robot gets its own upstream socket, so the cloud's answers can find their
+/// way back to it.
+pub struct Proxy {
+    upstream: String,
+    via: Via,
+    policy: Policy,
+    sessions: Mutex<HashMap<SocketAddr, Arc<Upstream>>>,
+}
+
+/// Logs what a packet says, if we have the key for it.
+fn log_packet(context: &Context, packet: &[u8], to_cloud: bool) {
+    let (header, body) = match codec::split_packet(packet) {
+        Ok(parts) => parts,
+        Err(e) => {
+            debug!(error = %e, to_cloud, "relaying packet we can't read");
+            return;
+        }
+    };
+    let device_id = header.device_id;
+    if body.is_empty() {
+        debug!(device_id, to_cloud, "relaying hello");
+        return;
+    }
+    // logs are no place for the wifi password the robot is given
+    let decoded = context.keys.codec_for(device_id).map(|c| {
+        c.decode_response(&header, body).map(|payload| {
+            String::from_utf8_lossy(&handlers::redact_plaintext(payload.as_bytes())).into_owned()
+        })
+    });
+    match (decoded, to_cloud) {
+        (Some(Ok(payload)), true) => info!(device_id, %payload, "robot -> cloud"),
+        (Some(Ok(payload)), false) => info!(device_id, %payload, "cloud -> robot"),
+        (Some(Err(e)), _) => {
+            warn!(device_id, error = %e, to_cloud, "could not decode relayed packet")
+        }
+        (None, _) => debug!(device_id, to_cloud, "no key to decode relayed packet"),
+    }
+}
+
+/// Hands whatever the cloud sends back on to the robot until the session
+/// goes quiet.
+async fn relay_replies(
+    context: Arc<Context>,
+    upstream: Arc<Upstream>,
+    robot: SocketAddr,
+    listener: usize,
+) {
+    let mut buf = [0; 65536];
+    loop {
+        let len = match timeout(SESSION_IDLE, upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) => {
+                warn!(%robot, error = %e, "lost connection to the cloud");
+                break;
+            }
+            Err(_) => break,
+        };
+        let packet = &buf[..len];
+        log_packet(&context, packet, false);
+        if let Err(e) = context.transmit(packet, robot, listener, None).await {
+            warn!(%robot, error = %e, "could not relay to robot");
+        }
+    }
+    debug!(%robot, "proxy session ended");
+    if let Some(proxy) = &context.proxy {
+        proxy.sessions.lock().await.remove(&robot);
+    }
+}
+
+impl Proxy {
+    pub fn new(config: &ProxyConfig, block_ota: bool) -> Result<Proxy, String> {
+        Ok(Proxy {
+            upstream: config.upstream.clone(),
+            via: Via::parse(config.via.as_deref())?,
+            policy: Policy::new(config, block_ota),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Decides what to do with a packet from the robot. Replies to commands
+    /// we sent stay local so the API keeps working, and a batch goes to the
+    /// cloud if any call in it does.
+    pub fn route(&self, context: &Context, packet: &[u8]) -> Action {
+        let (header, body) = match codec::split_packet(packet) {
+            Ok(parts) => parts,
+            Err(_) => return Action::Forward,
+        };
+        let device_id = header.device_id;
+        if body.is_empty() {
+            return self.policy.action_for(device_id, None);
+        }
+        let payload = context
+            .keys
+            .codec_for(device_id)
+            .and_then(|c| c.decode_response(&header, body).ok())
+            .and_then(|json| serde_json::from_str::<IncomingBody>(&json).ok());
+        let payloads = match payload {
+            Some(body) => body.into_payloads(),
+            // nothing to go on, the cloud may know what to do with it
+            None => return self.policy.action_for(device_id, None),
+        };
+        let actions: Vec<Action> = payloads
+            .iter()
+            .map(|payload| match payload {
+                IncomingPayload::Message(m) => self.policy.action_for(device_id, Some(&m.method)),
+                IncomingPayload::Reply(r) if context.commands.is_pending(device_id, r.id) => {
+                    Action::Local
+                }
+                IncomingPayload::Reply(_) => Action::Forward,
+            })
+            .collect();
+        if actions.is_empty() {
+            self.policy.action_for(device_id, None)
+        } else if actions.contains(&Action::Forward) {
+            Action::Forward
+        } else if actions.contains(&Action::Local) {
+            Action::Local
+        } else {
+            Action::Drop
+        }
+    }
+
+    async fn session(
+        &self,
+        context: &Arc<Context>,
+        robot: SocketAddr,
+        listener: usize,
+    ) -> io::Result<Arc<Upstream>> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(upstream) = sessions.get(&robot) {
+            return Ok(Arc::clone(upstream));
+        }
+        let upstream = Upstream::connect(&self.via, &self.upstream).await?;
+        info!(%robot, cloud = %self.upstream, via = %self.via, "relaying robot to the cloud");
+        let upstream = Arc::new(upstream);
+        sessions.insert(robot, Arc::clone(&upstream));
+        tokio::spawn(relay_replies(
+            Arc::clone(context),
+            Arc::clone(&upstream),
+            robot,
+            listener,
+        ));
+        Ok(upstream)
+    }
+
+    pub async fn forward(
+        &self,
+        context: &Arc<Context>,
+        packet: &[u8],
+        robot: SocketAddr,
+        listener: usize,
+    ) -> io::Result<()> {
+        if let Ok(header) = PacketHeader::parse(packet) {
+            if !context.limiter.admits_device(header.device_id) {
+                context.metrics.packet_failed("not_allowed");
+                return Ok(());
+            }
+        }
+        log_packet(context, packet, true);
+        self.session(context, robot, listener)
+            .await?
+            .send(packet)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::cleaning::Command;
+use crate::Context;
+
+/// As fast as the app lets the robot go, in metres a second.
+const MAX_VELOCITY: f64 = 0.3;
+/// As fast as it lets it turn, in radians a second.
+const MAX_ROTATION: f64 = std::f64::consts::PI;
+
+/// How long the robot is left driving without hearing from whoever's
+/// steering before it's stopped, in case they've gone away mid-drive.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// `app_rc_move`, driving at `velocity` while turning at `rotation` for
+/// `duration` milliseconds. All three are kept to what's safe, moves no
+/// longer than the idle timeout included.
+pub fn rc_move(velocity: f64, rotation: f64, duration: u64, seqnum: u32) -> Command {
+    let duration = duration.min(IDLE_TIMEOUT.as_millis() as u64);
+    Command {
+        method: "app_rc_move",
+        params: json!([{
+            "velocity": velocity.clamp(-MAX_VELOCITY, MAX_VELOCITY),
+            "omega": rotation.clamp(-MAX_ROTATION, MAX_ROTATION),
+            "duration": duration,
+            "seqnum": seqnum,
+        }]),
+    }
+}
+
+struct Session {
+    id: u64,
+    /// Numbers the moves, which the robot wants counting up.
+    seqnum: u32,
+    last: Instant,
+}
+
+/// The robots being driven by hand right now.
+#[derive(Default)]
+pub struct RemoteSessions {
+    sessions: Mutex<HashMap<u32, Session>>,
+    next_id: Mutex<u64>,
+}
+
+impl RemoteSessions {
+    /// Starts over driving a robot, returning which session this is.
+    pub fn start(&self, device_id: u32, now: Instant) -> u64 {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        let session = Session {
+            id: *next_id,
+            seqnum: 0,
+            last: now,
+        };
+        self.sessions.lock().unwrap().insert(device_id, session);
+        *next_id
+    }
+
+    /// The seqnum for the next move, or None when the robot isn't being
+    /// driven.
+    pub fn moved(&self, device_id: u32, now: Instant) -> Option<u32> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&device_id)?;
+        session.seqnum += 1;
+        session.last = now;
+        Some(session.seqnum)
+    }
+
+    /// Returns whether the robot was being driven.
+    pub fn end(&self, device_id: u32) -> bool {
+        self.sessions.lock().unwrap().remove(&device_id).is_some()
+    }
+
+    /// Ends session `id` if it's been idle too long, returning whether it
+    /// did, or None once the session is over anyway.
+    pub fn expire(&self, device_id: u32, id: u64, now: Instant) -> Option<bool> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(&device_id).filter(|s| s.id == id)?;
+        if now.duration_since(session.last) < IDLE_TIMEOUT {
+            return Some(false);
+        }
+        sessions.remove(&device_id);
+        Some(true)
+    }
+}
+
+/// Stops the robot once session `id` goes quiet for too long.
+pub(crate) async fn watch(device_id: u32, id: u64, context: Arc<Context>) {
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        match context.remote.expire(device_id, id, Instant::now()) {
+            Some(false) => continue,
+            Some(true) => break,
+            None => return,
+        }
+    }
+    info!(
+        device_id,
+        "no remote control commands for a while, stopping"
+    );
+    if let Err(e) = context
+        .send_command(device_id, "app_rc_end", &json!([]))
+        .await
+    {
+        warn!(device_id, error = %e, "could not stop remote control");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_idle_sessions() {
+        let command = rc_move(1.0, -0.5, 5000, 3);
+        assert_eq!(
+            command.params,
+            json!([{"velocity": 0.3, "omega": -0.5, "duration": 2000, "seqnum": 3}])
+        );
+
+        let sessions = RemoteSessions::default();
+        let start = Instant::now();
+        assert_eq!(sessions.moved(1, start), None);
+        let id = sessions.start(1, start);
+        assert_eq!(sessions.moved(1, start + Duration::from_secs(1)), Some(1));
+        assert_eq!(sessions.moved(1, start + Duration::from_secs(2)), Some(2));
+        assert_eq!(
+            sessions.expire(1, id, start + Duration::from_secs(3)),
+            Some(false)
+        );
+        assert_eq!(
+            sessions.expire(1, id, start + Duration::from_secs(4)),
+            Some(true)
+        );
+        assert_eq!(sessions.expire(1, id, start + Duration::from_secs(5)), None);
+        assert!(!sessions.end(1));
+
+        // starting again leaves the old session's watch to give up
+        let old = sessions.start(1, start);
+        let new = sessions.start(1, start);
+        assert_eq!(
+            sessions.expire(1, old, start + Duration::from_secs(9)),
+            None
+        );
+        assert_eq!(sessions.expire(1, new, start), Some(false));
+        assert!(sessions.end(1));
+    }
+}
@@ -0,0 +1,226 @@
+use std::convert::TryFrom;
+
+use crate::config::{Color, RenderConfig};
+use crate::map::{Point, RRMap, MM_PER_PIXEL};
+
+// Upper bounds on `?scale=` and the size it comes out at, so one request
+// can't ask for a gigapixel image.
+const MAX_SCALE: u32 = 16;
+const MAX_SIDE: i32 = 4096;
+
+/// An RGBA image, row 0 at the top the way PNGs have it.
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, background: Color) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: background.0.repeat((width * height) as usize),
+        }
+    }
+
+    /// Blends `color` over the pixel, if it's on the canvas.
+    fn plot(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let i = ((y as u32 * self.width + x as u32) * 4) as usize;
+        let alpha = u32::from(color.0[3]);
+        for (channel, value) in self.pixels[i..i + 4].iter_mut().zip(color.0.iter()) {
+            let blended = (u32::from(*value) * alpha + u32::from(*channel) * (255 - alpha)) / 255;
+            *channel = blended as u8;
+        }
+        // what's drawn over a see-through background shouldn't stay see-through
+        self.pixels[i + 3] = self.pixels[i + 3].max(color.0[3]);
+    }
+
+    fn fill_rect(&mut self, x: i32, y: i32, size: i32, color: Color) {
+        for dy in 0..size {
+            for dx in 0..size {
+                self.plot(x + dx, y + dy, color);
+            }
+        }
+    }
+
+    fn fill_circle(&mut self, (cx, cy): (i32, i32), radius: i32, color: Color) {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= radius * radius {
+                    self.plot(cx + dx, cy + dy, color);
+                }
+            }
+        }
+    }
+
+    /// Bresenham, `width` pixels thick.
+    fn line(&mut self, from: (i32, i32), to: (i32, i32), width: i32, color: Color) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (sx, sy) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut err = dx + dy;
+        loop {
+            self.fill_rect(x - width / 2, y - width / 2, width.max(1), color);
+            if (x, y) == to {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    fn to_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+        Ok(out)
+    }
+}
+
+/// Draws the map as a PNG, `scale` canvas pixels to each map pixel, or fewer
+/// if that would come out more than 4096 pixels across. Maps without a
+/// usable image come out as a single background pixel.
+pub fn render_png(
+    map: &RRMap,
+    config: &RenderConfig,
+    scale: u32,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let blank = || Canvas::new(1, 1, config.background).to_png();
+    let image = match &map.image {
+        Some(image)
+            if (1..=MAX_SIDE).contains(&image.width) && (1..=MAX_SIDE).contains(&image.height) =>
+        {
+            image
+        }
+        _ => return blank(),
+    };
+    let (width, height) = (image.width, image.height);
+    let scale = (scale.clamp(1, MAX_SCALE) as i32)
+        .min(MAX_SIDE / width.max(height))
+        .max(1);
+    let scaled = |side: i32| u32::try_from(side).ok()?.checked_mul(scale as u32);
+    let mut canvas = match (scaled(width), scaled(height)) {
+        (Some(w), Some(h)) => Canvas::new(w, h, config.background),
+        _ => return blank(),
+    };
+
+    // the map's row 0 is its bottom, so everything gets flipped
+    let mut fill = |index: u32, color| {
+        let (x, y) = (index as i32 % width, index as i32 / width);
+        canvas.fill_rect(x * scale, (height - 1 - y) * scale, scale, color);
+    };
+    for &index in &image.floor {
+        fill(index, config.floor);
+    }
+    for &index in &image.obstacle {
+        fill(index, config.obstacle);
+    }
+    let to_canvas = |p: &Point| {
+        let x = p.x as f32 / MM_PER_PIXEL as f32 - image.left as f32;
+        let y = p.y as f32 / MM_PER_PIXEL as f32 - image.top as f32;
+        (
+            (x * scale as f32) as i32,
+            ((height as f32 - y) * scale as f32) as i32,
+        )
+    };
+
+    if config.draw_path {
+        if let Some(path) = &map.path {
+            let points: Vec<(i32, i32)> = path.points.iter().map(to_canvas).collect();
+            for pair in points.windows(2) {
+                canvas.line(pair[0], pair[1], scale / 2, config.path);
+            }
+        }
+    }
+    if let Some(charger) = &map.charger {
+        canvas.fill_circle(to_canvas(charger), scale * 2, config.charger);
+    }
+    if let Some(robot) = &map.robot {
+        let centre = to_canvas(&robot.position);
+        let radius = scale * 2;
+        canvas.fill_circle(centre, radius, config.robot);
+        // a notch towards where it's facing
+        if let Some(angle) = robot.angle {
+            let radians = (angle as f32).to_radians();
+            let tip = (
+                centre.0 + (radians.cos() * (radius * 2) as f32) as i32,
+                centre.1 - (radians.sin() * (radius * 2) as f32) as i32,
+            );
+            canvas.line(centre, tip, (scale / 2).max(1), config.robot);
+        }
+    }
+    canvas.to_png()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapImage;
+
+    fn decode(png: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info, pixels)
+    }
+
+    #[test]
+    fn draws_the_map_the_right_way_up() {
+        let map = RRMap {
+            image: Some(MapImage {
+                top: 0,
+                left: 0,
+                width: 2,
+                height: 2,
+                // bottom left floor, top right wall
+                floor: vec![0],
+                obstacle: vec![3],
+                ..MapImage::default()
+            }),
+            ..RRMap::default()
+        };
+        let config = RenderConfig {
+            draw_path: false,
+            ..RenderConfig::default()
+        };
+        let (info, pixels) = decode(&render_png(&map, &config, 2).unwrap());
+        assert_eq!((info.width, info.height), (4, 4));
+        let pixel = |x: usize, y: usize| &pixels[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+        assert_eq!(pixel(0, 3), &config.floor.0);
+        assert_eq!(pixel(3, 0), &config.obstacle.0);
+        assert_eq!(pixel(0, 0), &config.background.0);
+
+        let (empty, _) = decode(&render_png(&RRMap::default(), &config, 2).unwrap());
+        assert_eq!((empty.width, empty.height), (1, 1));
+
+        // nor does an image with no width, or less than none
+        for width in [0, -3].iter() {
+            let map = RRMap {
+                image: Some(MapImage {
+                    width: *width,
+                    height: 5,
+                    floor: vec![0, 7],
+                    ..MapImage::default()
+                }),
+                ..RRMap::default()
+            };
+            let (blank, _) = decode(&render_png(&map, &config, 2).unwrap());
+            assert_eq!((blank.width, blank.height), (1, 1));
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::capture::{Direction, Record};
+use crate::clock::SystemClock;
+use crate::codec::{self, from_hex, PacketHeader, UDPCodec};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::handlers::{HandlerRegistry, Request};
+use crate::payload::{IncomingBody, IncomingPayload};
+
+// Not every packet is answered, so don't wait long for the ones that aren't.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn invalid(message: String) -> Error {
+    Error::Invalid(message)
+}
+
+/// Reads an NDJSON capture written by `--capture`.
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    fs::read_to_string(path)
+        .map_err(|source| Error::File {
+            path: path.to_path_buf(),
+            source,
+        })?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| invalid(format!("line {}: {}", i + 1, e)))
+        })
+        .collect()
+}
+
+/// Sends the robot's half of a capture to a running dummycloud, one socket
+/// per robot so the server sees the same sessions, and prints what comes
+/// back. The server has to have the same keys as when it was captured.
+async fn replay_to_server(records: &[Record], server: SocketAddr, key: Option<&str>) -> Result<()> {
+    let mut sockets: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+    let mut buf = [0; 65536];
+    let codec = key.map(UDPCodec::new);
+    for record in records.iter().filter(|r| r.direction == Direction::In) {
+        let packet = from_hex(&record.packet)
+            .ok_or_else(|| invalid(format!("not hex: {}", record.packet)))?;
+        let socket = match sockets.entry(record.peer) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+                socket.connect(server).await?;
+                entry.insert(socket)
+            }
+        };
+        socket.send(&packet).await?;
+        println!(
+            "{} -> {}",
+            record.peer,
+            record.json.as_deref().unwrap_or("(no payload)")
+        );
+        match timeout(REPLY_TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(received) => {
+                let reply = &buf[..received?];
+                let shown = match &codec {
+                    Some(codec) if reply.len() > codec::HEADER_SIZE => codec
+                        .decode(reply)
+                        .unwrap_or_else(|e| format!("(could not decode: {})", e)),
+                    _ => format!("({} bytes)", reply.len()),
+                };
+                println!("{} <- {}", record.peer, shown);
+            }
+            Err(_) => println!("{} <- (no reply)", record.peer),
+        }
+    }
+    Ok(())
+}
+
+/// Feeds every call the robot made straight to the handlers, without a
+/// server or keys. Returns each call along with our answer, if any.
+pub fn replay_to_handlers(
+    records: &[Record],
+    handlers: &HandlerRegistry,
+    config: &Config,
+) -> Vec<(String, Option<String>)> {
+    let advertised_ip: IpAddr = config.advertise.ip.unwrap_or(Ipv4Addr::LOCALHOST.into());
+    let mut calls = Vec::new();
+    for record in records.iter().filter(|r| r.direction == Direction::In) {
+        let header = from_hex(&record.packet).and_then(|p| PacketHeader::parse(&p).ok());
+        let (header, json) = match (header, &record.json) {
+            (Some(header), Some(json)) => (header, json),
+            _ => continue,
+        };
+        let device_id = header.device_id;
+        let request = Request {
+            device_id,
+            advertised_ip,
+            advertised_port: None,
+            model: config.model_for(device_id),
+            preset: config.preset_for(device_id),
+        };
+        let body: IncomingBody = match serde_json::from_str(json) {
+            Ok(body) => body,
+            Err(_) => continue,
+        };
+        for payload in body.into_payloads() {
+            if let IncomingPayload::Message(message) = payload {
+                let call = format!("{} {}", message.method, message.params);
+                let reply = handlers
+                    .handle(&message, &request)
+                    .map(|reply| serde_json::to_string(&reply).unwrap_or_default());
+                calls.push((call, reply));
+            }
+        }
+    }
+    calls
+}
+
+/// `dummycloud replay capture.ndjson [server address]`: sends the robot's
+/// side of a capture to a running dummycloud, `127.0.0.1:8053` unless told
+/// otherwise.
+pub async fn replay_command(
+    capture: &Path,
+    server: Option<SocketAddr>,
+    key: Option<&str>,
+) -> Result<()> {
+    let records = read(capture)?;
+    let server = server.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 8053)));
+    replay_to_server(&records, server, key).await
+}
+
+/// `dummycloud replay --handlers capture.ndjson`: runs each captured call
+/// straight through the handlers, configured by `config` if there is one.
+pub fn replay_handlers_command(capture: &Path, config: Option<&Path>) -> Result<()> {
+    let records = read(capture)?;
+    let config = match config {
+        Some(path) => Config::load(path).map_err(|source| Error::Config {
+            path: path.to_path_buf(),
+            source,
+        })?,
+        None => Config::default(),
+    };
+    let handlers = HandlerRegistry::with_defaults(&config, Arc::new(SystemClock));
+    for (call, reply) in replay_to_handlers(&records, &handlers, &config) {
+        println!("-> {}", call);
+        println!("<- {}", reply.as_deref().unwrap_or("(no reply)"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_calls_through_the_handlers() {
+        let peer: SocketAddr = ([192, 168, 1, 50], 54321).into();
+        let packet = codec::encode("0123456789abcdef", 1234, 100, b"{}");
+        let record = |direction, json: &str| Record {
+            time: 0.0,
+            direction,
+            peer,
+            packet: codec::to_hex(&packet),
+            json: Some(json.to_string()),
+        };
+        let records = vec![
+            record(
+                Direction::In,
+                r#"{"id": 1, "method": "props", "params": {"battery": 80}}"#,
+            ),
+            record(Direction::Out, r#"{"id": 1, "result": "ok"}"#),
+            record(
+                Direction::In,
+                r#"[{"id": 2, "method": "event.bin_full", "params": []}, {"id": 3, "method": "nope", "params": []}]"#,
+            ),
+        ];
+        let config = Config::default();
+        let handlers = HandlerRegistry::with_defaults(&config, Arc::new(SystemClock));
+        let calls = replay_to_handlers(&records, &handlers, &config);
+        assert_eq!(
+            calls,
+            vec![
+                (
+                    r#"props {"battery":80}"#.to_string(),
+                    Some(r#"{"id":1,"result":"ok"}"#.to_string())
+                ),
+                (
+                    "event.bin_full []".to_string(),
+                    Some(r#"{"id":2,"result":"ok"}"#.to_string())
+                ),
+                ("nope []".to_string(), None),
+            ]
+        );
+        assert_eq!(from_hex(&codec::to_hex(&packet)), Some(packet));
+    }
+}
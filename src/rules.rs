@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::config::RuleConfig;
+use crate::events::DeviceMessage;
+
+pub(crate) fn matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => method == pattern,
+    }
+}
+
+/// Adds `<field>_name` next to each field there's a name for the value of.
+/// Params are either an object or a list of them.
+fn name_codes(params: &mut Value, names: &HashMap<String, HashMap<String, String>>) {
+    let objects: Vec<&mut serde_json::Map<String, Value>> = match params {
+        Value::Object(object) => vec![object],
+        Value::Array(list) => list.iter_mut().filter_map(Value::as_object_mut).collect(),
+        _ => return,
+    };
+    for object in objects {
+        for (field, codes) in names {
+            let code = match object.get(field) {
+                Some(Value::String(s)) => s.clone(),
+                Some(other) => other.to_string(),
+                None => continue,
+            };
+            if let Some(name) = codes.get(&code) {
+                object.insert(format!("{}_name", field), Value::String(name.clone()));
+            }
+        }
+    }
+}
+
+/// What `[[rules]]` make of messages from the robot before they're passed
+/// on. Each bridge keeps one of its own, so what one of them has already
+/// seen doesn't make the others drop it as a duplicate.
+pub struct Rules {
+    rules: Vec<RuleConfig>,
+    /// The params and time of the last message passed on, by robot and
+    /// method.
+    last: HashMap<(u32, String), (Value, u64)>,
+}
+
+impl Rules {
+    pub fn new(rules: &[RuleConfig]) -> Rules {
+        Rules {
+            rules: rules.to_vec(),
+            last: HashMap::new(),
+        }
+    }
+
+    /// The message as it should be passed on, or None to drop it. Only the
+    /// first rule whose `method` matches applies.
+    pub fn apply(&mut self, mut message: DeviceMessage) -> Option<DeviceMessage> {
+        let rule = match self
+            .rules
+            .iter()
+            .find(|r| matches(&r.method, &message.method))
+        {
+            Some(rule) => rule,
+            None => return Some(message),
+        };
+        if rule.drop {
+            return None;
+        }
+        if let Some(method) = &rule.rename {
+            message.method = method.clone();
+        }
+        name_codes(&mut message.params, &rule.names);
+        if rule.dedupe_secs > 0 {
+            let key = (message.device_id, message.method.clone());
+            if let Some((params, at)) = self.last.get(&key) {
+                if *params == message.params && message.timestamp < at + rule.dedupe_secs {
+                    return None;
+                }
+            }
+            self.last
+                .insert(key, (message.params.clone(), message.timestamp));
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(method: &str, params: Value, timestamp: u64) -> DeviceMessage {
+        DeviceMessage {
+            device_id: 1,
+            method: method.to_string(),
+            params,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn drops_renames_names_and_dedupes() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            [[rules]]
+            method = "event.status"
+            rename = "status"
+            dedupe_secs = 30
+            [rules.names.state]
+            8 = "charging"
+            5 = "cleaning"
+
+            [[rules]]
+            method = "_otc.*"
+            drop = true
+            "#,
+        )
+        .unwrap();
+        let mut rules = Rules::new(&config.rules);
+
+        let charging = json!([{"state": 8, "battery": 100}]);
+        let passed = rules
+            .apply(message("event.status", charging.clone(), 100))
+            .unwrap();
+        assert_eq!(passed.method, "status");
+        assert_eq!(
+            passed.params,
+            json!([{"state": 8, "state_name": "charging", "battery": 100}])
+        );
+        assert!(rules
+            .apply(message("event.status", charging.clone(), 120))
+            .is_none());
+        assert!(rules
+            .apply(message("event.status", json!([{"state": 5}]), 125))
+            .is_some());
+        assert!(rules
+            .apply(message("event.status", charging, 200))
+            .is_some());
+
+        assert!(rules
+            .apply(message("_otc.ncstat", json!([]), 100))
+            .is_none());
+        assert!(rules.apply(message("props", json!([]), 100)).is_some());
+    }
+}
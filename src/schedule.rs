@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::{JobConfig, ScheduleConfig};
+use crate::Context;
+
+// How far ahead `next_run` looks before giving up, e.g. on `0 0 31 2 *`.
+const LOOKAHEAD_MINUTES: i64 = 366 * 24 * 60;
+
+/// A cron expression of minute, hour, day of month, month and day of week,
+/// each `*`, a number, a range like `1-5` or a list of those, optionally
+/// stepped as in `*/15`. Sunday is 0 or 7. Like cron, a time matches if
+/// either of the day fields does when both are given.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+    source: String,
+}
+
+fn field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("bad step in {}", part)),
+            },
+            None => (part, 1),
+        };
+        let number = |n: &str| match n.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(format!("{} isn't between {} and {}", n, min, max)),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                // `5/10` means from 5 on, every 10
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if from > to {
+            return Err(format!("{} runs backwards", part));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(source: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{} doesn't have 5 fields", source));
+        };
+        let mut weekday_bits = field(weekdays, 0, 7)?;
+        // 7 is another way of writing Sunday
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Cron {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+            source: source.to_string(),
+        })
+    }
+
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let has = |bits: u64, n: u32| bits & (1 << n) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day_matches
+    }
+
+    /// The first whole minute after `time` that matches, within a year.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = time.with_second(0)?.with_nanosecond(0)?;
+        (1..=LOOKAHEAD_MINUTES)
+            .map(|n| start + chrono::Duration::minutes(n))
+            .find(|t| self.matches(t))
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Cron, String> {
+        Cron::parse(&source)
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Which jobs have been told to sit out their next run.
+#[derive(Default)]
+pub struct Skips {
+    names: Mutex<HashSet<String>>,
+}
+
+impl Skips {
+    pub fn skip_next(&self, name: &str) {
+        self.names.lock().unwrap().insert(name.to_string());
+    }
+
+    pub fn unskip(&self, name: &str) {
+        self.names.lock().unwrap().remove(name);
+    }
+
+    pub fn is_skipped(&self, name: &str) -> bool {
+        self.names.lock().unwrap().contains(name)
+    }
+
+    /// Whether the job should sit this run out, which uses up the skip.
+    fn take(&self, name: &str) -> bool {
+        self.names.lock().unwrap().remove(name)
+    }
+}
+
+fn local_now(context: &Context) -> DateTime<Local> {
+    DateTime::from(context.clock.now())
+}
+
+async fn fire(job: JobConfig, context: Arc<Context>) {
+    let (method, params) = if job.rooms.is_empty() {
+        (job.method.clone(), job.params.clone())
+    } else {
+        let rooms: Option<Vec<u8>> = job
+            .rooms
+            .iter()
+            .map(|room| context.config.resolve_room(job.device_id, room))
+            .collect();
+        match rooms {
+            Some(rooms) => ("app_segment_clean".to_string(), serde_json::json!(rooms)),
+            None => {
+                warn!(job = %job.name, "not running, it names a room the robot doesn't have");
+                return;
+            }
+        }
+    };
+    info!(job = %job.name, device_id = job.device_id, %method, "running scheduled job");
+    if let Err(e) = context.send_command(job.device_id, &method, &params).await {
+        warn!(job = %job.name, error = %e, "scheduled job failed");
+    }
+}
+
+/// Runs the jobs under `[schedule]` as their times come round, going by
+/// the local time zone.
+pub(crate) async fn run(config: ScheduleConfig, context: Arc<Context>) {
+    info!(jobs = config.jobs.len(), "scheduler is running");
+    let mut last_minute = None;
+    loop {
+        let now = local_now(&context);
+        // wake up just after the next minute starts
+        let wait = 60 - u64::from(now.second());
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+
+        let now = local_now(&context);
+        let minute = now.with_second(0).and_then(|t| t.with_nanosecond(0));
+        if minute == last_minute {
+            continue;
+        }
+        last_minute = minute;
+        let holiday = config.holidays.contains(&now.date_naive());
+        for job in config.jobs.iter().filter(|job| job.cron.matches(&now)) {
+            if holiday && job.skip_holidays {
+                info!(job = %job.name, "skipping scheduled job, it's a holiday");
+            } else if context.skips.take(&job.name) {
+                info!(job = %job.name, "skipping scheduled job this once");
+            } else {
+                tokio::spawn(fire(job.clone(), Arc::clone(&context)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn at(date: (i32, u32, u32), hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_like_cron() {
+        // 2026-10-14 is a Wednesday
+        let weekdays = Cron::parse("30 9 * * 1-5").unwrap();
+        assert!(weekdays.matches(&at((2026, 10, 14), 9, 30)));
+        assert!(!weekdays.matches(&at((2026, 10, 14), 9, 31)));
+        assert!(!weekdays.matches(&at((2026, 10, 18), 9, 30)));
+
+        let every_quarter = Cron::parse("*/15 8-10 * * *").unwrap();
+        assert!(every_quarter.matches(&at((2026, 10, 14), 10, 45)));
+        assert!(!every_quarter.matches(&at((2026, 10, 14), 11, 0)));
+
+        // either day field will do when both are given
+        let either = Cron::parse("0 0 1 * 0").unwrap();
+        assert!(either.matches(&at((2026, 10, 1), 0, 0)));
+        assert!(either.matches(&at((2026, 10, 18), 0, 0)));
+        assert!(!either.matches(&at((2026, 10, 14), 0, 0)));
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap().weekdays & 1, 1);
+
+        for bad in [
+            "* * * *",
+            "60 * * * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Cron::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn skips_are_used_up() {
+        let skips = Skips::default();
+        skips.skip_next("mornings");
+        assert!(skips.is_skipped("mornings"));
+        assert!(skips.take("mornings"));
+        assert!(!skips.take("mornings"));
+    }
+}
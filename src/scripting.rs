@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use tracing::warn;
+
+use crate::error::Error;
+use crate::handlers::{Handler, Request};
+use crate::payload::{MessagePayload, ResponsePayload};
+
+/// Answers a method by running a rhai script. The script sees the call as
+/// `id`, `method`, `params` and `device_id`, and whatever it evaluates to is
+/// sent back as the result; `()` leaves the call unanswered. `state` is a map
+/// that's kept from one call to the next, for counters and the like.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Mutex<Map>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> crate::Result<Script> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| Error::Script {
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            })?;
+        Ok(Script::new(engine, ast))
+    }
+
+    fn new(engine: Engine, ast: AST) -> Script {
+        Script {
+            engine,
+            ast,
+            state: Mutex::new(Map::new()),
+        }
+    }
+
+    fn run(
+        &self,
+        msg: &MessagePayload,
+        req: &Request,
+    ) -> Result<Option<serde_json::Value>, Box<EvalAltResult>> {
+        // held for the whole run, so calls see each other's changes in order
+        let mut state = self.state.lock().unwrap();
+        let mut scope = Scope::new();
+        scope.push("id", i64::from(msg.id));
+        scope.push("method", msg.method.clone());
+        scope.push("device_id", i64::from(req.device_id));
+        scope.push_dynamic("params", rhai::serde::to_dynamic(&msg.params)?);
+        scope.push("state", state.clone());
+        let result: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast)?;
+        if let Some(updated) = scope.get_value::<Map>("state") {
+            *state = updated;
+        }
+        if result.is_unit() {
+            return Ok(None);
+        }
+        rhai::serde::from_dynamic(&result).map(Some)
+    }
+}
+
+impl Handler for Script {
+    fn handle(&self, msg: &MessagePayload, req: &Request) -> Option<ResponsePayload> {
+        match self.run(msg, req) {
+            Ok(result) => result.map(|r| ResponsePayload::new(msg.id, r)),
+            Err(e) => {
+                warn!(method = %msg.method, error = %e, "script failed");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Model;
+    use serde_json::json;
+
+    #[test]
+    fn scripts_keep_state_between_calls() {
+        let engine = Engine::new();
+        let ast = engine
+            .compile(
+                r#"
+                if params.len() == 0 { return (); }
+                state.calls = (state.calls ?? 0) + 1;
+                #{ calls: state.calls, first: params[0], device: device_id }
+                "#,
+            )
+            .unwrap();
+        let script = Script::new(engine, ast);
+        let req = Request {
+            device_id: 1234,
+            advertised_ip: [192, 168, 1, 2].into(),
+            advertised_port: None,
+            model: Model::default(),
+            preset: Model::default().preset(),
+        };
+        let message = |params| {
+            serde_json::from_value(json!({"id": 7, "method": "count", "params": params})).unwrap()
+        };
+        assert!(script.handle(&message(json!([])), &req).is_none());
+        script.handle(&message(json!(["a"])), &req);
+        assert_eq!(
+            json!(script.handle(&message(json!(["b"])), &req)),
+            json!({"id": 7, "result": {"calls": 2, "first": "b", "device": 1234}})
+        );
+    }
+}
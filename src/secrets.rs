@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::path::Path;
+use std::sync::Mutex;
+
+use age::secrecy::SecretString;
+use age::DecryptError;
+use serde::Deserialize;
+
+use crate::config::{Config, DeviceConfig};
+
+/// age itself won't go past this, so a file can't tie us up for minutes.
+const MAX_WORK_FACTOR: u8 = 22;
+/// The systemd credential the passphrase can come in, in place of asking.
+const PASSPHRASE_CREDENTIAL: &str = "keys_passphrase";
+
+/// What `keys_file` holds once it's decrypted.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct KeysFile {
+    cloud_key: Option<String>,
+    /// Keys by device id, e.g. `12345678 = "..."`.
+    devices: HashMap<String, String>,
+}
+
+fn described(e: DecryptError) -> String {
+    match e {
+        DecryptError::DecryptionFailed
+        | DecryptError::KeyDecryptionFailed
+        | DecryptError::NoMatchingKeys => String::from("wrong passphrase"),
+        DecryptError::ExcessiveWork { required, .. } => {
+            format!("asks for a work factor of {}, more than we'll do", required)
+        }
+        DecryptError::InvalidMac => String::from("header doesn't check out"),
+        _ => String::from("isn't an age file"),
+    }
+}
+
+/// Decrypts a file `age -p` encrypted with a passphrase.
+pub fn decrypt_age(file: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    let decryptor = age::Decryptor::new_buffered(file).map_err(described)?;
+    // age won't mix a passphrase with other recipients, and nor do we
+    if !decryptor.is_scrypt() {
+        return Err(String::from("isn't encrypted with a passphrase"));
+    }
+    let mut identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+    identity.set_max_work_factor(MAX_WORK_FACTOR);
+    let mut plaintext = Vec::new();
+    decryptor
+        .decrypt(iter::once(&identity as &dyn age::Identity))
+        .map_err(described)?
+        .read_to_end(&mut plaintext)
+        .map_err(|_| String::from("has been tampered with"))?;
+    Ok(plaintext)
+}
+
+/// A key as the config gives it: written out, or `env:NAME` for an
+/// environment variable or `credential:NAME` for a systemd credential.
+fn key(value: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        return var(name)
+            .ok_or_else(|| format!("the key is to come from {}, which isn't set", name));
+    }
+    if let Some(name) = value.strip_prefix("credential:") {
+        let dir = var("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+            format!(
+                "the key is to come from credential {}, but systemd didn't pass any",
+                name
+            )
+        })?;
+        return fs::read_to_string(Path::new(&dir).join(name))
+            .map(|key| key.trim().to_string())
+            .map_err(|e| format!("could not read credential {}: {}", name, e));
+    }
+    Ok(value.to_string())
+}
+
+/// Fills in the keys the config only says where to find, from the
+/// environment (`var` looks a variable up, so they can be made up for tests)
+/// and from `keys_file`, decrypted with what `passphrase` comes up with.
+/// Keys in the file take the place of any the config has for the same
+/// robot.
+pub fn resolve(
+    config: &mut Config,
+    var: impl Fn(&str) -> Option<String>,
+    passphrase: impl FnOnce() -> io::Result<String>,
+) -> Result<(), String> {
+    if let Some(cloud_key) = &config.cloud_key {
+        config.cloud_key = Some(key(cloud_key, &var)?);
+    }
+    for device in &mut config.devices {
+        if !device.key.is_empty() {
+            device.key = key(&device.key, &var)?;
+        }
+    }
+    if let Some(path) = &config.keys_file {
+        let file =
+            fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let passphrase = passphrase()
+            .map_err(|e| format!("could not get the passphrase for {}: {}", path.display(), e))?;
+        let plaintext =
+            decrypt_age(&file, &passphrase).map_err(|e| format!("{} {}", path.display(), e))?;
+        let keys: KeysFile = std::str::from_utf8(&plaintext)
+            .map_err(|e| e.to_string())
+            .and_then(|text| toml::from_str(text).map_err(|e| e.to_string()))
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        if keys.cloud_key.is_some() {
+            config.cloud_key = keys.cloud_key;
+        }
+        for (id, key) in keys.devices {
+            let id: u32 = id.parse().map_err(|_| {
+                format!(
+                    "{} has keys for {}, which isn't a device id",
+                    path.display(),
+                    id
+                )
+            })?;
+            match config.devices.iter_mut().find(|d| d.id == id) {
+                Some(device) => device.key = key,
+                None => config.devices.push(DeviceConfig {
+                    id,
+                    key,
+                    name: None,
+                    model: None,
+                    otc_interval: None,
+                    otc_firsttest: None,
+                    rooms: HashMap::new(),
+                }),
+            }
+        }
+    }
+    match config.devices.iter().find(|d| d.key.is_empty()) {
+        Some(device) => Err(format!("device {} has no key", device.id)),
+        None => Ok(()),
+    }
+}
+
+/// The passphrase for `keys_file`: the `keys_passphrase` credential when
+/// systemd passes one, or else asked for on the terminal. Either way it's
+/// kept, so reloading the config doesn't ask again.
+pub fn passphrase() -> io::Result<String> {
+    static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+    let mut kept = PASSPHRASE.lock().unwrap();
+    if let Some(passphrase) = &*kept {
+        return Ok(passphrase.clone());
+    }
+    let credential = env::var_os("CREDENTIALS_DIRECTORY")
+        .map(|dir| Path::new(&dir).join(PASSPHRASE_CREDENTIAL))
+        .filter(|path| path.is_file());
+    let passphrase = match credential {
+        Some(path) => fs::read_to_string(path)?.trim_end_matches('\n').to_string(),
+        None => rpassword::prompt_password("passphrase for the keys file: ")?,
+    };
+    *kept = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn finds_keys_elsewhere() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys.age");
+        let file = fs::read(&fixture).unwrap();
+        assert!(decrypt_age(&file, "correct horse")
+            .unwrap()
+            .starts_with(b"cloud_key = \"0123456789abcdef\""));
+        assert_eq!(
+            decrypt_age(&file, "wrong"),
+            Err(String::from("wrong passphrase"))
+        );
+        let mut tampered = file.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decrypt_age(&tampered, "correct horse"),
+            Err(String::from("has been tampered with"))
+        );
+
+        let mut config = Config::parse(
+            r#"
+            cloud_key = "env:DUMMYCLOUD_KEY"
+
+            [[devices]]
+            id = 12345678
+
+            [[devices]]
+            id = 87654321
+            key = "env:ROBOT_KEY"
+            "#,
+        )
+        .unwrap();
+        config.keys_file = Some(fixture);
+        let env: HashMap<&str, &str> = [
+            ("DUMMYCLOUD_KEY", "unused"),
+            ("ROBOT_KEY", "aaaabbbbccccdddd"),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let var = |name: &str| env.get(name).map(|v| v.to_string());
+        resolve(&mut config, var, || Ok(String::from("correct horse"))).unwrap();
+        assert_eq!(config.cloud_key.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(config.key_for(12345678), Some("fedcba9876543210"));
+        assert_eq!(config.key_for(87654321), Some("aaaabbbbccccdddd"));
+
+        let mut config = Config::parse("cloud_key = \"credential:cloud_key\"").unwrap();
+        assert!(resolve(&mut config, |_| None, || unreachable!())
+            .unwrap_err()
+            .contains("systemd didn't pass any"));
+        let mut config = Config::parse("[[devices]]\nid = 1").unwrap();
+        assert_eq!(
+            resolve(&mut config, |_| None, || unreachable!()),
+            Err(String::from("device 1 has no key"))
+        );
+    }
+}
@@ -0,0 +1,1253 @@
+use std::borrow::Cow;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+use crate::buffers::{BufferPool, PooledBuffer};
+use crate::capture::{Capture, Direction};
+use crate::chaos::{self, Fault};
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{self, HeaderOnly, PacketError, PacketHeader};
+use crate::commands::{CommandError, PendingCommands};
+use crate::config::{Config, TenantConfig};
+use crate::consumables::{self, ConsumableStore};
+use crate::db::{Database, Snapshot};
+use crate::devices::{Connection, DeviceRegistry};
+use crate::error::{Error, Result};
+use crate::events::{
+    self, BinaryFrame, ConnectionChange, DeviceMessage, Event, EventBus, Exchange, Origin,
+};
+use crate::fds::Signer;
+use crate::handlers::{self, HandlerRegistry};
+use crate::keys::KeyStore;
+use crate::latency;
+use crate::limits::Limiter;
+use crate::logs::LogIndex;
+use crate::metrics::Metrics;
+use crate::outbox::{self, Holdback, Outbox};
+use crate::payload::{
+    IncomingBody, IncomingPayload, MessagePayload, ReplyPayload, ResponsePayload,
+};
+use crate::policy::Action;
+use crate::proxy::Proxy;
+use crate::remote::RemoteSessions;
+use crate::schedule::{self, Skips};
+use crate::shutdown::{Shutdown, Stage};
+use crate::state::StateStore;
+use crate::stats::StatsStore;
+use crate::storage::MapStore;
+use crate::summary::{self, SummaryStore};
+use crate::uploads::ExpectedUploads;
+use crate::workers::{self, Workers};
+use crate::{
+    control, discovery, dnd, dns, http, influx, keepalive, mqtt, notify, ntp, tls, webhooks,
+};
+
+// How often robots that went quiet are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often what we know about the robots is written to the database.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long the HTTP and MQTT sides get to close once we're shutting down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything the packet handlers and the services around them share.
+pub(crate) struct Context {
+    pub(crate) config: Config,
+    /// The sockets robots talk to us on, see `[listener] bind`.
+    pub(crate) listeners: Vec<UdpSocket>,
+    /// Whether each of `listeners` is being read from, for `/healthz`.
+    pub(crate) receiving: Vec<AtomicBool>,
+    /// Which of `[listener] tenants` each of `listeners` is, if any.
+    pub(crate) tenants: Vec<Option<usize>>,
+    pub(crate) devices: DeviceRegistry,
+    pub(crate) commands: PendingCommands,
+    pub(crate) events: EventBus,
+    pub(crate) handlers: HandlerRegistry,
+    pub(crate) maps: MapStore,
+    /// The robot's logs and crash dumps, see `[storage] log_dir`.
+    pub(crate) logs: MapStore,
+    pub(crate) log_index: LogIndex,
+    pub(crate) state: StateStore,
+    pub(crate) stats: StatsStore,
+    pub(crate) metrics: Metrics,
+    /// Reloadable, unlike the copy in `config`.
+    pub(crate) keys: KeyStore,
+    pub(crate) limiter: Limiter,
+    pub(crate) capture: Option<Capture>,
+    pub(crate) db: Option<Database>,
+    pub(crate) proxy: Option<Proxy>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) shutdown: Shutdown,
+    /// Scheduled jobs told to sit out their next run.
+    pub(crate) skips: Skips,
+    pub(crate) consumables: ConsumableStore,
+    /// Cleaning runs and the robot's own totals, for `/api/devices/<id>/stats`.
+    pub(crate) summary: SummaryStore,
+    /// Robots being driven by hand through the API.
+    pub(crate) remote: RemoteSessions,
+    /// What's waiting to be sent to each robot.
+    pub(crate) outbox: Outbox,
+    pub(crate) holdback: Holdback,
+    /// Checks upload URLs, see `[fds]`.
+    pub(crate) fds: Option<Signer>,
+    /// The room map uploads URLs were handed out for.
+    pub(crate) uploads: Arc<ExpectedUploads>,
+}
+
+impl Context {
+    /// Sends a robot a command and waits for its answer. What's logged along
+    /// the way goes by the robot's name, if it has one.
+    pub async fn send_command(
+        &self,
+        device_id: u32,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<ReplyPayload, CommandError> {
+        let sent = self.send_command_to(device_id, method, params);
+        match self.config.name_for(device_id) {
+            Some(name) => sent.instrument(info_span!("robot", name)).await,
+            None => sent.await,
+        }
+    }
+
+    async fn send_command_to(
+        &self,
+        device_id: u32,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<ReplyPayload, CommandError> {
+        if self.config.ota.block && method.starts_with("miIO.ota") {
+            warn!(device_id, method, "not sending firmware update command");
+            return Err(CommandError::OtaBlocked);
+        }
+        if dnd::refuses(self, method) {
+            warn!(
+                device_id,
+                method, "not sending command during do not disturb"
+            );
+            return Err(CommandError::DoNotDisturb);
+        }
+        let device = self
+            .devices
+            .get(device_id)
+            .ok_or(CommandError::UnknownDevice(device_id))?;
+        let codec = self
+            .keys
+            .codec_for(device_id)
+            .ok_or(CommandError::NoKey(device_id))?;
+        let (id, message, rx) = self.commands.start(device_id, method, params)?;
+        let packet = codec.encode_response(&message, device_id, self.clock.as_ref());
+        let sent = self
+            .transmit(&packet, device.addr, device.listener, Some(&message))
+            .await;
+        if let Err(e) = sent {
+            self.commands.cancel(device_id, id);
+            return Err(e.into());
+        }
+        let reply = self.commands.wait(device_id, id, rx).await;
+        self.events.publish(Event::Exchange(Exchange {
+            device_id,
+            origin: Origin::Cloud,
+            method: method.to_string(),
+            params: params.clone(),
+            response: reply
+                .as_ref()
+                .ok()
+                .and_then(|r| serde_json::to_value(r).ok()),
+            timestamp: self.clock.epoch_secs(),
+        }));
+        reply
+    }
+
+    /// Sends a packet to a robot, keeping count of it. `plaintext` is what
+    /// the packet says, for captures. It goes through the robot's queue in
+    /// the [`Outbox`], so this only fails when it's this packet that
+    /// couldn't be sent, and returns once it's gone out or been queued
+    /// behind one that's being sent.
+    pub(crate) async fn transmit(
+        &self,
+        packet: &[u8],
+        addr: SocketAddr,
+        listener: usize,
+        plaintext: Option<&[u8]>,
+    ) -> io::Result<()> {
+        self.transmit_after(Duration::ZERO, packet, addr, listener, plaintext)
+            .await
+    }
+
+    /// Like [`Context::transmit`], holding the packet back for `delay`
+    /// first. A packet that's held back, or queued behind one that is, goes
+    /// out on its own, see [`Holdback`], so this returns straight away.
+    pub(crate) async fn transmit_after(
+        &self,
+        mut delay: Duration,
+        packet: &[u8],
+        addr: SocketAddr,
+        listener: usize,
+        plaintext: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let mut packet = packet;
+        if let Some(chaos) = &self.config.chaos {
+            match chaos::fault(chaos, packet.len(), latency::roll) {
+                Some(Fault::Drop) => {
+                    self.metrics.packet_unsent("chaos");
+                    debug!(%addr, "chaos: dropping packet");
+                    return Ok(());
+                }
+                Some(Fault::Truncate(len)) => {
+                    debug!(%addr, len, "chaos: cutting packet short");
+                    packet = &packet[..len];
+                }
+                Some(Fault::Delay(held)) => {
+                    debug!(%addr, delay_ms = held.as_millis() as u64, "chaos: holding packet back");
+                    delay += held;
+                }
+                None => {}
+            }
+        }
+        if self
+            .holdback
+            .hold((listener, addr), delay, packet, plaintext)
+        {
+            return Ok(());
+        }
+        self.send_out(packet, addr, listener, plaintext).await
+    }
+
+    async fn send_out(
+        &self,
+        packet: &[u8],
+        addr: SocketAddr,
+        listener: usize,
+        plaintext: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let peer = (listener, addr);
+        let pushed = self.outbox.push(peer, packet, plaintext);
+        if let Some(reason) = pushed.dropped {
+            self.metrics.packet_unsent(reason);
+            if reason == "queue_full" {
+                warn!(%addr, "robot's send queue is full, dropping the oldest packet");
+            }
+        }
+        if !pushed.drain {
+            return Ok(());
+        }
+        let mut sending = self.outbox.sending(peer);
+        let mut result = Ok(());
+        while let Some(outgoing) = sending.next() {
+            let plaintext = outgoing.plaintext.as_deref();
+            match outbox::send(&self.listeners[listener], &outgoing.packet, addr).await {
+                Ok(sent) => {
+                    self.metrics.bytes_sent(sent);
+                    if let Some(capture) = &self.capture {
+                        capture.record(Direction::Out, addr, &outgoing.packet, plaintext);
+                    }
+                }
+                Err(e) => {
+                    self.metrics.packet_unsent("send_failed");
+                    warn!(%addr, error = %e, "could not send packet to robot");
+                    if outgoing.seq() == pushed.seq {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Moves a robot to another connection state, letting everyone know if
+    /// that's news.
+    pub(crate) fn set_connection(&self, device_id: u32, to: Connection) {
+        if let Some(from) = self.devices.transition(device_id, to) {
+            self.connection_changed(device_id, from, to);
+        }
+    }
+
+    pub(crate) fn connection_changed(&self, device_id: u32, from: Connection, to: Connection) {
+        info!(device_id, ?from, ?to, "connection state changed");
+        self.events.publish(Event::Connection(ConnectionChange {
+            device_id,
+            from,
+            to,
+            timestamp: self.clock.epoch_secs(),
+        }));
+    }
+
+    /// Writes what we know about the robots to `[storage] database`, if
+    /// there is one.
+    pub(crate) fn persist(&self) {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return,
+        };
+        let snapshot = Snapshot {
+            devices: self.devices.all(),
+            states: self.state.all(),
+            stats: self.stats.all(),
+        };
+        if let Err(e) = db.save(&snapshot) {
+            warn!(error = %e, "could not save device state");
+        }
+        let keep = self.config.storage.history_days * 24 * 60 * 60;
+        let before = self.clock.epoch_secs().saturating_sub(keep);
+        match db.prune_messages(before) {
+            Ok(0) => {}
+            Ok(pruned) => debug!(pruned, "dropped old messages from the history"),
+            Err(e) => warn!(error = %e, "could not prune the history"),
+        }
+    }
+
+    fn tenant(&self, listener: usize) -> Option<&TenantConfig> {
+        let index = self.tenants.get(listener).copied().flatten()?;
+        self.config.listener.tenants.get(index)
+    }
+
+    /// Whether robots with `device_id` get answered on `listener`: a
+    /// tenant's socket only answers its own robots, and they don't get
+    /// answered anywhere else.
+    fn listener_serves(&self, listener: usize, device_id: u32) -> bool {
+        match self.tenant(listener) {
+            Some(tenant) => tenant.devices.contains(&device_id),
+            None => !self
+                .config
+                .listener
+                .tenants
+                .iter()
+                .any(|tenant| tenant.devices.contains(&device_id)),
+        }
+    }
+
+    // The robot has to be able to reach us again, so unless told otherwise
+    // hand out whichever of our addresses faces the robot.
+    pub(crate) fn advertised_ip(&self, src: SocketAddr) -> io::Result<IpAddr> {
+        match self.config.advertise.ip {
+            Some(ip) => Ok(ip),
+            None => local_ip_for(src),
+        }
+    }
+}
+
+// Connecting a UDP socket doesn't send anything, but it does make the OS pick
+// the interface it would route through to get to the peer.
+fn local_ip_for(peer: SocketAddr) -> io::Result<IpAddr> {
+    let bind_addr: SocketAddr = if peer.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let probe = std::net::UdpSocket::bind(bind_addr)?;
+    probe.connect(peer)?;
+    Ok(probe.local_addr()?.ip())
+}
+
+/// A running dummycloud: the robots' listener sockets plus the HTTP server,
+/// control socket and whichever bridges the config turns on. Cheap to clone,
+/// all clones share the same state.
+#[derive(Clone)]
+pub struct Server {
+    context: Arc<Context>,
+}
+
+impl Server {
+    #[cfg(test)]
+    pub(crate) fn context(&self) -> Arc<Context> {
+        Arc::clone(&self.context)
+    }
+
+    /// Sets up a server with the built in handlers on sockets that are
+    /// already bound, see [`crate::listener::bind`].
+    pub fn new(config: Config, listeners: Vec<UdpSocket>) -> Result<Server> {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let handlers = HandlerRegistry::with_defaults(&config, Arc::clone(&clock));
+        Server::with_handlers(config, listeners, handlers, clock)
+    }
+
+    /// Like [`Server::new`], but answering robots with `handlers` and
+    /// taking the time from `clock`.
+    pub fn with_handlers(
+        config: Config,
+        listeners: Vec<UdpSocket>,
+        handlers: HandlerRegistry,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Server> {
+        let capture = match &config.capture {
+            Some(path) => {
+                let capture = Capture::create(path).map_err(|source| Error::File {
+                    path: path.clone(),
+                    source,
+                })?;
+                info!(path = %path.display(), "capturing packets");
+                Some(capture)
+            }
+            None => None,
+        };
+        let db = match &config.storage.database {
+            Some(path) => {
+                let db = Database::open(path).map_err(|source| Error::Database {
+                    path: path.clone(),
+                    source,
+                })?;
+                Some(db)
+            }
+            None => None,
+        };
+        let tenants = listeners
+            .iter()
+            .map(|socket| {
+                let addr = socket.local_addr().ok()?;
+                config
+                    .listener
+                    .tenants
+                    .iter()
+                    .position(|tenant| tenant.bind == addr)
+            })
+            .collect();
+        let context = Context {
+            uploads: handlers.uploads(),
+            handlers,
+            clock,
+            devices: DeviceRegistry::new(config.session.clone()),
+            keys: KeyStore::new(&config),
+            limiter: Limiter::new(config.limits.clone()),
+            capture,
+            db,
+            proxy: config
+                .proxy
+                .as_ref()
+                .map(|p| Proxy::new(p, config.ota.block))
+                .transpose()
+                .map_err(Error::Invalid)?,
+            fds: config.fds.as_ref().map(Signer::new),
+            maps: MapStore::new(config.storage.map_dir.clone(), config.storage.keep),
+            logs: MapStore::new(config.storage.log_dir.clone(), config.storage.keep),
+            outbox: Outbox::new(config.session.send_queue),
+            holdback: Holdback::new(),
+            config,
+            receiving: listeners.iter().map(|_| AtomicBool::new(false)).collect(),
+            listeners,
+            tenants,
+            commands: PendingCommands::default(),
+            events: EventBus::default(),
+            state: StateStore::default(),
+            stats: StatsStore::default(),
+            metrics: Metrics::default(),
+            shutdown: Shutdown::new(),
+            skips: Skips::default(),
+            log_index: LogIndex::default(),
+            consumables: ConsumableStore::default(),
+            summary: SummaryStore::default(),
+            remote: RemoteSessions::default(),
+        };
+        if let Some(db) = &context.db {
+            let snapshot = db.load().map_err(io::Error::other)?;
+            info!(devices = snapshot.devices.len(), "restored device state");
+            for device in snapshot.devices {
+                context.devices.restore(device);
+            }
+            for (device_id, state) in snapshot.states {
+                context.state.restore(device_id, state);
+            }
+            for (device_id, stats) in snapshot.stats {
+                context.stats.restore(device_id, stats);
+            }
+        }
+        Ok(Server {
+            context: Arc::new(context),
+        })
+    }
+
+    pub fn devices(&self) -> &DeviceRegistry {
+        &self.context.devices
+    }
+
+    /// Everything robots say and get told from now on.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.context.events.subscribe()
+    }
+
+    /// Sends a command to a robot that has checked in and waits for its
+    /// reply.
+    pub async fn send_command(
+        &self,
+        device_id: u32,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<ReplyPayload, CommandError> {
+        self.context.send_command(device_id, method, params).await
+    }
+
+    /// Swaps in the keys from a new config, the rest of it needs a restart.
+    pub fn replace_keys(&self, config: &Config) {
+        self.context.keys.replace(config);
+    }
+
+    /// Stops taking packets from robots. [`Server::run`] then finishes the
+    /// ones it's already answering, closes the HTTP and MQTT sides and
+    /// returns.
+    pub fn shutdown(&self) {
+        self.context.shutdown.advance(Stage::Draining);
+    }
+
+    /// Starts the services the config asks for and answers robots until a
+    /// listener socket fails or [`Server::shutdown`] is called.
+    pub async fn run(&self) -> Result<()> {
+        let context = &self.context;
+        for socket in &context.listeners {
+            info!(addr = %socket.local_addr()?, "dummycloud is now listening");
+        }
+
+        if let Some(chaos) = &context.config.chaos {
+            warn!(
+                drop_percent = chaos.drop_percent,
+                truncate_percent = chaos.truncate_percent,
+                delay_percent = chaos.delay_percent,
+                "chaos mode is on, packets to robots will be dropped, cut short or held back"
+            );
+        }
+
+        let mut closing = tokio::task::JoinSet::new();
+        let http_context = Arc::clone(context);
+        closing.spawn(async move {
+            let http_bind = http_context.config.listener.http_bind;
+            if let Err(e) = http::serve(http_bind, http_context).await {
+                error!(error = %e, "HTTP server stopped");
+            }
+        });
+
+        if let Some(tls_config) = context.config.tls.clone() {
+            let tls_context = Arc::clone(context);
+            closing.spawn(async move {
+                if let Err(e) = tls::serve(tls_config, tls_context).await {
+                    error!(error = %e, "HTTPS server stopped");
+                }
+            });
+        }
+
+        if let Some(grpc_config) = context.config.grpc.clone() {
+            #[cfg(feature = "grpc")]
+            {
+                let grpc_context = Arc::clone(context);
+                closing.spawn(async move {
+                    if let Err(e) = crate::grpc::serve(grpc_config.bind, grpc_context).await {
+                        error!(error = %e, "gRPC server stopped");
+                    }
+                });
+            }
+            #[cfg(not(feature = "grpc"))]
+            warn!(bind = %grpc_config.bind, "not serving gRPC, this build doesn't have the grpc feature");
+        }
+
+        if let Some(mqtt_config) = context.config.mqtt.clone() {
+            closing.spawn(mqtt::run(mqtt_config, Arc::clone(context)));
+        }
+
+        if let Some(influx_config) = context.config.influx.clone() {
+            closing.spawn(influx::run(influx_config, Arc::clone(context)));
+        }
+
+        if !context.config.notifications.is_empty() {
+            let rules = context.config.notifications.clone();
+            tokio::spawn(notify::run(rules, Arc::clone(context)));
+        }
+
+        tokio::spawn(send_held(Arc::clone(context)));
+
+        if !context.config.webhooks.urls.is_empty() {
+            let config = context.config.webhooks.clone();
+            tokio::spawn(webhooks::run(config, Arc::clone(context)));
+        }
+
+        if let Some(ntp_config) = &context.config.ntp {
+            let ntp_bind = ntp_config.bind;
+            tokio::spawn(async move {
+                if let Err(e) = ntp::serve(ntp_bind).await {
+                    error!(error = %e, "NTP server stopped");
+                }
+            });
+        }
+
+        if let Some(dns_config) = context.config.dns.clone() {
+            let dns_context = Arc::clone(context);
+            tokio::spawn(async move {
+                if let Err(e) = dns::serve(dns_config, dns_context).await {
+                    error!(error = %e, "DNS server stopped");
+                }
+            });
+        }
+
+        if let Some(discovery_config) = context.config.discovery.clone() {
+            let discovery_context = Arc::clone(context);
+            tokio::spawn(async move {
+                let bind = discovery_config.bind;
+                let devices = discovery_config.devices;
+                if let Err(e) = discovery::serve(bind, devices, discovery_context).await {
+                    error!(error = %e, "discovery responder stopped");
+                }
+            });
+        }
+
+        if !context.config.schedule.jobs.is_empty() {
+            let config = context.config.schedule.clone();
+            tokio::spawn(schedule::run(config, Arc::clone(context)));
+        }
+
+        if let Some(consumables_config) = context.config.consumables.clone() {
+            tokio::spawn(consumables::run(consumables_config, Arc::clone(context)));
+        }
+
+        if let Some(dnd_config) = context.config.dnd.clone() {
+            if dnd_config.set_robot {
+                tokio::spawn(dnd::run(dnd_config, Arc::clone(context)));
+            }
+        }
+
+        tokio::spawn(summary::run(Arc::clone(context)));
+
+        if let Some(keepalive_config) = context.config.keepalive.clone() {
+            tokio::spawn(keepalive::run(keepalive_config, Arc::clone(context)));
+        }
+
+        let expiry_context = Arc::clone(context);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let now = expiry_context.clock.now();
+                for (device_id, from) in expiry_context.devices.expire(now) {
+                    expiry_context.connection_changed(device_id, from, Connection::Stale);
+                }
+            }
+        });
+
+        if context.db.is_some() {
+            let persist_context = Arc::clone(context);
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(PERSIST_INTERVAL);
+                ticks.tick().await;
+                loop {
+                    ticks.tick().await;
+                    let context = Arc::clone(&persist_context);
+                    let _ = tokio::task::spawn_blocking(move || context.persist()).await;
+                }
+            });
+        }
+
+        let control_context = Arc::clone(context);
+        tokio::spawn(async move {
+            let control_bind = control_context.config.listener.control_bind;
+            if let Err(e) = control::serve(control_bind, control_context).await {
+                error!(error = %e, "control socket stopped");
+            }
+        });
+
+        let mut working = tokio::task::JoinSet::new();
+        let workers = {
+            let context = Arc::clone(context);
+            let count = workers::count(context.config.listener.workers);
+            let answer = move |packet| answer(packet, Arc::clone(&context));
+            Arc::new(Workers::start(count, answer, &mut working))
+        };
+        let mut receivers = tokio::task::JoinSet::new();
+        for listener in 0..context.listeners.len() {
+            let context = Arc::clone(context);
+            let workers = Arc::clone(&workers);
+            receivers.spawn(async move {
+                context.receiving[listener].store(true, Ordering::Relaxed);
+                let received = receive(Arc::clone(&context), listener, workers).await;
+                context.receiving[listener].store(false, Ordering::Relaxed);
+                received
+            });
+        }
+        while let Some(finished) = receivers.join_next().await {
+            finished.map_err(io::Error::other)??;
+        }
+        // the packets already taken are answered before anything closes
+        drop(workers);
+        while working.join_next().await.is_some() {}
+
+        info!("no longer taking packets, closing down");
+        if let Some(capture) = &context.capture {
+            if let Err(e) = capture.sync() {
+                warn!(error = %e, "could not write out the capture");
+            }
+        }
+        context.persist();
+        context.shutdown.advance(Stage::Closing);
+        let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while closing.join_next().await.is_some() {}
+        });
+        if closed.await.is_err() {
+            warn!("gave up waiting for the HTTP and MQTT sides to close");
+        }
+        info!("shut down");
+        Ok(())
+    }
+}
+
+/// A packet on its way to the worker that answers its robot's.
+struct Incoming {
+    buf: PooledBuffer,
+    src: SocketAddr,
+    listener: usize,
+    span: Span,
+}
+
+/// Sends what's held back for each robot, each on a task of its own so one
+/// robot's wait doesn't hold up another's.
+async fn send_held(context: Arc<Context>) {
+    let mut lanes = match context.holdback.lanes() {
+        Some(lanes) => lanes,
+        None => return,
+    };
+    while let Some(mut lane) = lanes.recv().await {
+        let context = Arc::clone(&context);
+        tokio::spawn(async move {
+            let (listener, addr) = lane.peer;
+            while let Some(held) = lane.next(&context.holdback) {
+                tokio::time::sleep_until(held.due).await;
+                let plaintext = held.plaintext.as_deref();
+                // send_out has said why if it couldn't
+                let _ = context
+                    .send_out(&held.packet, addr, listener, plaintext)
+                    .await;
+            }
+        });
+    }
+}
+
+async fn answer(packet: Incoming, context: Arc<Context>) {
+    let Incoming {
+        buf,
+        src,
+        listener,
+        span,
+    } = packet;
+    async move {
+        let handled = match context.proxy.as_ref().map(|p| (p, p.route(&context, &buf))) {
+            Some((proxy, Action::Forward)) => proxy.forward(&context, &buf, src, listener).await,
+            Some((_, Action::Drop)) => {
+                debug!("dropping packet per proxy policy");
+                Ok(())
+            }
+            Some((_, Action::Local)) | None => handle_packet(&buf, src, listener, &context).await,
+        };
+        if let Err(e) = handled {
+            warn!(error = %e, "failed to reply");
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Reads packets off one of the listener sockets and hands each one to the
+/// worker for its robot, until we start shutting down.
+async fn receive(
+    context: Arc<Context>,
+    listener: usize,
+    workers: Arc<Workers<Incoming>>,
+) -> io::Result<()> {
+    let max_datagram = context.config.listener.max_datagram;
+    // a byte to spare, to tell a packet that's too big from one that fits
+    let pool = BufferPool::new(max_datagram + 1);
+    loop {
+        let mut buf = pool.take();
+        let (amt, src) = tokio::select! {
+            received = context.listeners[listener].recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                // Windows reports a robot having gone from its port on the
+                // next receive, which says nothing about our socket
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            },
+            _ = context.shutdown.reached(Stage::Draining) => break,
+        };
+        context.metrics.packet_received(amt);
+        if amt > max_datagram {
+            context.metrics.packet_failed("too_big");
+            warn!(%src, max_datagram, "dropping packet too big to take");
+            continue;
+        }
+        // drop floods before they cost us anything, least of all a reply
+        if !context.limiter.admits_ip(src.ip()) {
+            context.metrics.packet_failed("not_allowed");
+            continue;
+        }
+        if !context.limiter.admit(src.ip(), Instant::now()) {
+            context.metrics.packet_failed("rate_limited");
+            continue;
+        }
+        // robots with a name go by it in everything logged about their
+        // packets
+        let device_id = PacketHeader::parse(&buf[..amt]).ok().map(|h| h.device_id);
+        let robot = device_id.and_then(|id| context.config.name_for(id));
+        let span = info_span!("packet", %src, len = amt, robot);
+        span.in_scope(|| debug!("received packet"));
+
+        // hand the buffer off so that slow replies to one robot don't hold
+        // up the next datagram, it goes back to the pool once it's answered
+        buf.truncate(amt);
+        // what isn't a miio packet has no device id, but the same sender
+        // still ends up on the same worker
+        let key = device_id.unwrap_or_else(|| u32::from(src.port()));
+        let packet = Incoming {
+            buf,
+            src,
+            listener,
+            span,
+        };
+        if let Err(packet) = workers.dispatch(key, packet) {
+            context.metrics.packet_failed("workers_busy");
+            packet
+                .span
+                .in_scope(|| warn!("dropping packet, its worker has too many waiting"));
+        }
+    }
+    Ok(())
+}
+
+/// Records a call from the robot and works out what to answer, if anything.
+fn handle_message(
+    message: MessagePayload,
+    request: &handlers::Request,
+    context: &Context,
+) -> Option<ResponsePayload> {
+    let device_id = request.device_id;
+    context.metrics.request(&message.method);
+    context.stats.request(device_id, &message.method);
+    let now = context.clock.epoch_secs();
+    context
+        .state
+        .record(device_id, &message.method, &message.params, now);
+    let device_message = DeviceMessage {
+        device_id,
+        method: message.method.clone(),
+        params: message.params.clone(),
+        timestamp: now,
+    };
+    if let Some(db) = &context.db {
+        if let Err(e) = db.record_message(&device_message) {
+            warn!(error = %e, "could not keep message for the history");
+        }
+    }
+    context.events.publish(Event::Message(device_message));
+    let dispatch_span = info_span!(
+        "dispatch",
+        method = %message.method,
+        id = message.id,
+        elapsed_us = tracing::field::Empty
+    );
+    let _entered = dispatch_span.enter();
+    let started = Instant::now();
+    let reply = context.handlers.handle(&message, request);
+    let elapsed = started.elapsed();
+    dispatch_span.record("elapsed_us", elapsed.as_micros() as u64);
+    let budget = Duration::from_millis(context.config.session.handler_budget_ms);
+    context
+        .metrics
+        .handler_duration(&message.method, elapsed, elapsed > budget);
+    if elapsed > budget {
+        warn!(
+            elapsed_ms = elapsed.as_millis() as u64,
+            budget_ms = context.config.session.handler_budget_ms,
+            "handler was slow to answer, the robot may give up on the cloud"
+        );
+    }
+    if reply.is_none() {
+        if context.handlers.handles(&message.method) {
+            debug!("handler chose not to reply");
+        } else {
+            warn!(params = %message.params, "unknown event");
+        }
+    }
+    context.events.publish(Event::Exchange(Exchange {
+        device_id,
+        origin: Origin::Robot,
+        method: message.method,
+        params: message.params,
+        response: reply.as_ref().and_then(|r| serde_json::to_value(r).ok()),
+        timestamp: now,
+    }));
+    reply
+}
+
+fn capture_in(context: &Context, src: SocketAddr, packet: &[u8], plaintext: Option<&[u8]>) {
+    if let Some(capture) = &context.capture {
+        capture.record(Direction::In, src, packet, plaintext);
+    }
+}
+
+fn log_dropped_packet(context: &Context, src: SocketAddr, error: &PacketError, packet: &[u8]) {
+    context.metrics.packet_failed(error.kind());
+    warn!(%src, %error, packet = %codec::to_hex(packet), "dropping packet");
+}
+
+/// Logs and publishes a body that isn't miio JSON, which there's no
+/// handling, nor answering, until somebody works out what it is.
+fn binary_frame(device_id: u32, plaintext: &[u8], context: &Context) {
+    context.metrics.packet_failed("binary_frame");
+    let payload = codec::to_hex(plaintext);
+    info!(
+        device_id,
+        length = plaintext.len(),
+        payload = %payload,
+        "received a {}",
+        events::BINARY_FRAME
+    );
+    context.events.publish(Event::Binary(BinaryFrame {
+        device_id,
+        origin: Origin::Robot,
+        method: None,
+        kind: events::BINARY_FRAME,
+        payload,
+        length: plaintext.len(),
+        timestamp: context.clock.epoch_secs(),
+    }));
+}
+
+async fn handle_packet(
+    buf: &[u8],
+    src: SocketAddr,
+    listener: usize,
+    context: &Context,
+) -> io::Result<()> {
+    let received = Instant::now();
+    let (header, encrypted_body) = match codec::split_packet(buf) {
+        Ok(parts) => parts,
+        Err(e) => {
+            capture_in(context, src, buf, None);
+            log_dropped_packet(context, src, &e, buf);
+            return Ok(());
+        }
+    };
+    let PacketHeader {
+        device_id, stamp, ..
+    } = header;
+    if !context.limiter.admits_device(device_id) {
+        context.metrics.packet_failed("not_allowed");
+        debug!(
+            device_id,
+            "dropping packet from a device that isn't allowed"
+        );
+        return Ok(());
+    }
+    if !context.listener_serves(listener, device_id) {
+        context.metrics.packet_failed("wrong_listener");
+        debug!(
+            device_id,
+            listener, "dropping packet from a device that belongs on another listener"
+        );
+        return Ok(());
+    }
+    context.stats.packet(device_id);
+    if let Some(header_only) = HeaderOnly::of(&header, encrypted_body) {
+        capture_in(context, src, buf, None);
+        context.metrics.header_only(header_only.kind());
+        if header_only == HeaderOnly::Hello {
+            info!(device_id, "robot connected, sending timesync");
+            context.devices.check_in(device_id, src, listener, stamp);
+            context.set_connection(device_id, Connection::Handshake);
+            let timesync = codec::TimesyncPacket::at(context.clock.now()).to_bytes();
+            context.transmit(&timesync, src, listener, None).await?;
+            context.set_connection(device_id, Connection::TimeSynced);
+        } else {
+            let freshness = context.devices.check_in(device_id, src, listener, stamp);
+            if !context.devices.accepts(&freshness) {
+                context.metrics.packet_failed("stale_stamp");
+                return Ok(());
+            }
+            // a robot that got its time before we started, or before it
+            // went quiet, doesn't say hello again
+            if let Some(Connection::Handshake | Connection::Stale) =
+                context.devices.get(device_id).map(|d| d.connection)
+            {
+                context.set_connection(device_id, Connection::TimeSynced);
+            }
+            // echoing would keep a robot with the wrong key going forever
+            if context.keys.looks_wrong(device_id) {
+                context.metrics.packet_failed("wrong_key");
+                debug!(device_id, "not echoing keep-alive, the key looks wrong");
+                return Ok(());
+            }
+            let ack = match context.keys.codec_for(device_id) {
+                Some(c) => c.encode_ack(&header),
+                // without a key all there is to go on is the robot's own
+                // signature
+                None => header.to_bytes().to_vec(),
+            };
+            debug!(device_id, stamp, "acking keep-alive");
+            context.transmit(&ack, src, listener, None).await?;
+        }
+        return Ok(());
+    }
+
+    let c = match context.keys.codec_for(device_id) {
+        Some(codec) => codec,
+        None => {
+            capture_in(context, src, buf, None);
+            context.metrics.packet_failed("no_key");
+            warn!(
+                device_id,
+                "dropping packet, no cloud key configured for device"
+            );
+            return Ok(());
+        }
+    };
+    let decoded = match c.open(&header, encrypted_body) {
+        Err(PacketError::ChecksumMismatch) => {
+            context.metrics.checksum_mismatch();
+            if context.config.session.lenient {
+                warn!(device_id, "checksum doesn't match, decrypting anyway");
+                c.open_unverified(encrypted_body)
+            } else {
+                Err(PacketError::ChecksumMismatch)
+            }
+        }
+        decoded => decoded,
+    };
+    let plaintext = match decoded {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            capture_in(context, src, buf, None);
+            log_dropped_packet(context, src, &e, buf);
+            let failures = context.keys.failed(device_id);
+            if failures == context.config.session.wrong_key_after {
+                error!(
+                    device_id,
+                    failures,
+                    "cloud key likely wrong for device {}, none of its last packets decrypted",
+                    device_id
+                );
+            }
+            return Ok(());
+        }
+    };
+    context.keys.opened(device_id);
+    let binary = codec::is_binary(&plaintext);
+    // the wifi password is gone before anything is captured or logged
+    let message = if binary {
+        Cow::Borrowed(&plaintext[..])
+    } else {
+        handlers::redact_plaintext(codec::message(&plaintext))
+    };
+    capture_in(context, src, buf, Some(&message));
+
+    let freshness = context.devices.check_in(device_id, src, listener, stamp);
+    if !context.devices.accepts(&freshness) {
+        context.metrics.packet_failed("stale_stamp");
+        return Ok(());
+    }
+    context.metrics.packet_decoded();
+    context.set_connection(device_id, Connection::Established);
+
+    if binary {
+        binary_frame(device_id, &message, context);
+        return Ok(());
+    }
+    // anything that isn't text is a binary frame, so nothing's lost here
+    let response = String::from_utf8_lossy(&message);
+    debug!(device_id, stamp, payload = %response, "decoded packet");
+    let body: IncomingBody = match serde_json::from_str(&response) {
+        Ok(body) => body,
+        Err(e) => {
+            context.metrics.packet_failed("invalid_json");
+            warn!(error = %e, payload = %response, "dropping message that isn't valid miio JSON");
+            return Ok(());
+        }
+    };
+    let tenant = context.tenant(listener);
+    let request = handlers::Request {
+        device_id,
+        advertised_ip: match tenant.and_then(|t| t.advertise_ip) {
+            Some(ip) => ip,
+            None => context.advertised_ip(src)?,
+        },
+        advertised_port: tenant.and_then(|t| t.advertise_port),
+        model: context.config.model_for(device_id),
+        preset: context.config.preset_for(device_id),
+    };
+    let is_batch = body.is_batch();
+    let mut replies = Vec::new();
+    let mut methods = Vec::new();
+    for payload in body.into_payloads() {
+        match payload {
+            IncomingPayload::Message(message) => {
+                methods.push(message.method.clone());
+                replies.extend(handle_message(message, &request, context));
+            }
+            IncomingPayload::Reply(reply) => {
+                let id = reply.id;
+                if !context.commands.complete(device_id, reply) {
+                    warn!(
+                        device_id,
+                        id, "device replied to a command nobody is waiting on"
+                    );
+                }
+            }
+        }
+    }
+    let reply_json = if is_batch {
+        if replies.is_empty() {
+            return Ok(());
+        }
+        serde_json::to_vec(&replies)?
+    } else {
+        match replies.pop() {
+            Some(reply) => serde_json::to_vec(&reply)?,
+            None => return Ok(()),
+        }
+    };
+    let delay = latency::delay(&context.config.delays, &methods, latency::roll);
+    if !delay.is_zero() {
+        debug!(delay_ms = delay.as_millis() as u64, "holding back reply");
+    }
+    // stamped as if it were to go out, and then held back, like it would be
+    // by a slow cloud
+    let reply = c.encode_response(&reply_json, device_id, context.clock.as_ref());
+    context
+        .transmit_after(delay, &reply, src, listener, Some(&reply_json))
+        .await?;
+    context.metrics.reply_latency(received.elapsed());
+    debug!(bytes = reply.len(), "sent reply");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::UDPCodec;
+    use std::net::Ipv4Addr;
+    use tokio::time::timeout;
+
+    const KEY: &str = "dummycloudtests1";
+
+    /// A robot that's said hello, and the stamp the server gave it.
+    async fn robot(server: SocketAddr, device_id: u32) -> (UdpSocket, u32) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        socket.connect(server).await.unwrap();
+        let mut hello = PacketHeader::new(device_id, 0, 0);
+        hello.checksum = [0xff; 16];
+        socket.send(&hello.to_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        let len = timeout(Duration::from_secs(2), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let stamp = PacketHeader::parse(&buf[..len]).unwrap().stamp;
+        (socket, stamp)
+    }
+
+    /// A server running with `extra` config, keeping what it stores in a
+    /// directory of its own named after `test`.
+    async fn serve(test: &str, extra: &str) -> (Server, SocketAddr, std::path::PathBuf) {
+        let mut config = Config::parse(&format!(
+            r#"
+            cloud_key = "{}"
+
+            [listener]
+            http_bind = "127.0.0.1:0"
+            control_bind = "127.0.0.1:0"
+            workers = 1
+
+            [advertise]
+            ip = "127.0.0.1"
+            {}
+            "#,
+            KEY, extra
+        ))
+        .unwrap();
+        let storage =
+            std::env::temp_dir().join(format!("dummycloud-{}-{}", test, std::process::id()));
+        config.storage.map_dir = storage.join("maps");
+        config.storage.log_dir = storage.join("logs");
+        config.storage.voice_dir = storage.join("voices");
+        std::fs::create_dir_all(&storage).unwrap();
+        config.capture = Some(storage.join("capture.ndjson"));
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = Server::new(config, vec![socket]).unwrap();
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+        (server, addr, storage)
+    }
+
+    #[tokio::test]
+    async fn a_slow_reply_doesnt_hold_up_other_robots() {
+        let (server, addr, storage) = serve(
+            "held",
+            r#"
+            [[delays]]
+            method = "event.status"
+            ms = 1500
+            "#,
+        )
+        .await;
+
+        // with the one worker, both robots are handled on it
+        let codec = UDPCodec::new(KEY);
+        let (slow, slow_stamp) = robot(addr, 1).await;
+        let (quick, quick_stamp) = robot(addr, 2).await;
+        let status = br#"{"id":1,"method":"event.status","params":[{"state":8}]}"#;
+        slow.send(&codec.encode(status, 1, slow_stamp + 1))
+            .await
+            .unwrap();
+        let props = br#"{"id":1,"method":"props","params":{"battery":80}}"#;
+        let started = Instant::now();
+        for stamp in 1..=3 {
+            quick
+                .send(&codec.encode(props, 2, quick_stamp + stamp))
+                .await
+                .unwrap();
+            let mut buf = [0; 1024];
+            timeout(Duration::from_millis(500), quick.recv(&mut buf))
+                .await
+                .expect("the quick robot was kept waiting")
+                .unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_millis(1500));
+
+        // the slow one gets its answer in the end
+        let mut buf = [0; 1024];
+        let len = timeout(Duration::from_secs(3), slow.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            codec.decode(&buf[..len]).unwrap(),
+            r#"{"id":1,"result":"ok"}"#
+        );
+        assert!(started.elapsed() >= Duration::from_millis(1400));
+        server.shutdown();
+        let _ = std::fs::remove_dir_all(&storage);
+    }
+
+    #[tokio::test]
+    async fn the_wifi_password_never_reaches_the_capture() {
+        let (server, addr, storage) = serve("redacted", "").await;
+        let codec = UDPCodec::new(KEY);
+        let (robot, stamp) = robot(addr, 1).await;
+        let provisioning = br#"{"id":1,"method":"miIO.config_router","params":{"ssid":"home","passwd":"hunter22"}}"#;
+        robot
+            .send(&codec.encode(provisioning, 1, stamp + 1))
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        timeout(Duration::from_secs(2), robot.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        server.context().capture.as_ref().unwrap().sync().unwrap();
+        let capture = std::fs::read_to_string(storage.join("capture.ndjson")).unwrap();
+        assert!(capture.contains("miIO.config_router"));
+        assert!(!capture.contains("hunter22"));
+        server.shutdown();
+        let _ = std::fs::remove_dir_all(&storage);
+    }
+}
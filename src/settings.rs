@@ -0,0 +1,190 @@
+use serde_json::json;
+
+use crate::cleaning::Command;
+use crate::models::Model;
+
+/// What the first generation's fan speeds are, as percentages.
+const GEN1_FAN_SPEEDS: [(&str, u32); 4] =
+    [("quiet", 38), ("balanced", 60), ("turbo", 75), ("max", 100)];
+
+/// Later generations number their fan speeds from 101, and the ones that
+/// mop have one gentle enough for it.
+const FAN_SPEEDS: [(&str, u32); 5] = [
+    ("quiet", 101),
+    ("balanced", 102),
+    ("turbo", 103),
+    ("max", 104),
+    ("gentle", 105),
+];
+
+/// The S7 can mop without vacuuming at all.
+const S7_FAN_SPEEDS: [(&str, u32); 5] = [
+    ("quiet", 101),
+    ("balanced", 102),
+    ("turbo", 103),
+    ("max", 104),
+    ("off", 105),
+];
+
+const S7_WATER_LEVELS: [(&str, u32); 4] =
+    [("off", 200), ("low", 201), ("medium", 202), ("high", 203)];
+
+// The currents the app sends along with turning carpet mode on or off, which
+// firmwares want but nobody changes.
+const CARPET_CURRENT_HIGH: u32 = 500;
+const CARPET_CURRENT_INTEGRAL: u32 = 450;
+const CARPET_CURRENT_LOW: u32 = 400;
+const CARPET_STALL_TIME: u32 = 10;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SettingError {
+    #[error("there's no {0} setting, only fan_speed, carpet_mode and water_level")]
+    UnknownSetting(String),
+    #[error("{model:?} robots can't set their {setting}")]
+    Unsupported { setting: &'static str, model: Model },
+    #[error("{value} isn't a {setting}, it's one of {}", .levels.join(", "))]
+    UnknownValue {
+        setting: &'static str,
+        value: String,
+        levels: Vec<&'static str>,
+    },
+}
+
+fn fan_speeds(model: Model) -> &'static [(&'static str, u32)] {
+    match model {
+        Model::Gen1 => &GEN1_FAN_SPEEDS,
+        Model::S5 | Model::S6 => &FAN_SPEEDS,
+        Model::S7 => &S7_FAN_SPEEDS,
+    }
+}
+
+fn water_levels(model: Model) -> &'static [(&'static str, u32)] {
+    match model {
+        // they mop with a wet cloth and no pump
+        Model::Gen1 | Model::S5 | Model::S6 => &[],
+        Model::S7 => &S7_WATER_LEVELS,
+    }
+}
+
+fn level(
+    setting: &'static str,
+    levels: &[(&'static str, u32)],
+    model: Model,
+    value: &str,
+) -> Result<u32, SettingError> {
+    if levels.is_empty() {
+        return Err(SettingError::Unsupported { setting, model });
+    }
+    levels
+        .iter()
+        .find(|(name, _)| *name == value)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| SettingError::UnknownValue {
+            setting,
+            value: value.to_string(),
+            levels: levels.iter().map(|(name, _)| *name).collect(),
+        })
+}
+
+/// `set_custom_mode`, by the name the app gives the fan speed.
+pub fn fan_speed(model: Model, speed: &str) -> Result<Command, SettingError> {
+    let code = level("fan speed", fan_speeds(model), model, speed)?;
+    Ok(Command {
+        method: "set_custom_mode",
+        params: json!([code]),
+    })
+}
+
+/// The fan speeds the robot has, by name.
+pub fn fan_speed_names(model: Model) -> Vec<&'static str> {
+    fan_speeds(model).iter().map(|(name, _)| *name).collect()
+}
+
+/// The name of the fan speed the robot reports as `fan_power`, unless it's
+/// one the app set by percentage.
+pub fn fan_speed_name(model: Model, code: u64) -> Option<&'static str> {
+    fan_speeds(model)
+        .iter()
+        .find(|(_, c)| u64::from(*c) == code)
+        .map(|(name, _)| *name)
+}
+
+/// `set_water_box_custom_mode`, for how wet the mop is kept.
+pub fn water_level(model: Model, water: &str) -> Result<Command, SettingError> {
+    let code = level("water level", water_levels(model), model, water)?;
+    Ok(Command {
+        method: "set_water_box_custom_mode",
+        params: json!([code]),
+    })
+}
+
+/// `set_carpet_mode`, which has the robot turn its fan all the way up on
+/// carpets.
+pub fn carpet_mode(enabled: bool) -> Command {
+    Command {
+        method: "set_carpet_mode",
+        params: json!([{
+            "enable": u8::from(enabled),
+            "current_high": CARPET_CURRENT_HIGH,
+            "current_integral": CARPET_CURRENT_INTEGRAL,
+            "current_low": CARPET_CURRENT_LOW,
+            "stall_time": CARPET_STALL_TIME,
+        }]),
+    }
+}
+
+/// A setting and its value by name, as `dummycloud set` takes them. Carpet
+/// mode is `on` or `off`.
+pub fn by_name(model: Model, setting: &str, value: &str) -> Result<Command, SettingError> {
+    match setting {
+        "fan_speed" => fan_speed(model, value),
+        "water_level" => water_level(model, value),
+        "carpet_mode" => match value {
+            "on" => Ok(carpet_mode(true)),
+            "off" => Ok(carpet_mode(false)),
+            _ => Err(SettingError::UnknownValue {
+                setting: "carpet mode",
+                value: value.to_string(),
+                levels: vec!["on", "off"],
+            }),
+        },
+        _ => Err(SettingError::UnknownSetting(setting.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_values_against_the_model() {
+        assert_eq!(fan_speed(Model::Gen1, "turbo").unwrap().params, json!([75]));
+        assert_eq!(fan_speed(Model::S5, "turbo").unwrap().params, json!([103]));
+        assert_eq!(
+            fan_speed(Model::S5, "off").unwrap_err().to_string(),
+            "off isn't a fan speed, it's one of quiet, balanced, turbo, max, gentle"
+        );
+        assert_eq!(fan_speed(Model::S7, "off").unwrap().params, json!([105]));
+        assert_eq!(fan_speed_name(Model::S7, 105), Some("off"));
+        assert_eq!(fan_speed_name(Model::Gen1, 61), None);
+        assert_eq!(fan_speed_names(Model::Gen1).len(), 4);
+        assert_eq!(
+            water_level(Model::S5, "high"),
+            Err(SettingError::Unsupported {
+                setting: "water level",
+                model: Model::S5
+            })
+        );
+        let water = water_level(Model::S7, "medium").unwrap();
+        assert_eq!(water.method, "set_water_box_custom_mode");
+        assert_eq!(water.params, json!([202]));
+
+        let carpet = by_name(Model::S5, "carpet_mode", "on").unwrap();
+        assert_eq!(carpet.params[0]["enable"], 1);
+        assert!(by_name(Model::S5, "carpet_mode", "maybe").is_err());
+        assert_eq!(
+            by_name(Model::S5, "volume", "11"),
+            Err(SettingError::UnknownSetting("volume".to_string()))
+        );
+    }
+}
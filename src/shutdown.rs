@@ -0,0 +1,68 @@
+use tokio::sync::watch;
+
+/// How far along shutting down we are. The stages only ever move forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Running,
+    /// No new packets are taken, the ones already in hand are finished.
+    Draining,
+    /// The HTTP and MQTT sides are closed, now that nothing needs them.
+    Closing,
+}
+
+/// Tells the long-running tasks when it's their turn to stop.
+pub struct Shutdown {
+    stage: watch::Sender<Stage>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            stage: watch::Sender::new(Stage::Running),
+        }
+    }
+
+    /// Moves on to `to`, unless we're already there or past it.
+    pub fn advance(&self, to: Stage) {
+        self.stage.send_if_modified(|stage| {
+            if *stage < to {
+                *stage = to;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Where we are now.
+    pub fn stage(&self) -> Stage {
+        *self.stage.borrow()
+    }
+
+    /// Resolves once we've reached `stage`, straight away if we already have.
+    pub async fn reached(&self, stage: Stage) {
+        let mut changes = self.stage.subscribe();
+        // the sender lives as long as we do, so this can't fail
+        let _ = changes.wait_for(|current| *current >= stage).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moves_forward() {
+        let shutdown = Shutdown::new();
+        shutdown.advance(Stage::Closing);
+        shutdown.advance(Stage::Draining);
+        assert_eq!(*shutdown.stage.borrow(), Stage::Closing);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // both have long since been reached, so neither waits
+        runtime.block_on(shutdown.reached(Stage::Draining));
+        runtime.block_on(shutdown.reached(Stage::Closing));
+    }
+}
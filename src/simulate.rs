@@ -0,0 +1,271 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tokio::time::{interval_at, timeout, MissedTickBehavior};
+
+use crate::codec::{self, PacketHeader, UDPCodec};
+use crate::error::{Error, Result};
+
+// Real robots say hello again every couple of minutes to keep their clock
+// in line with the cloud's.
+const TIMESYNC_INTERVAL: Duration = Duration::from_secs(120);
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Status reports between map uploads.
+const REPORTS_PER_MAP: u64 = 4;
+
+/// A small rr map: a 4x4 room with the dock in one corner and the robot in
+/// the middle. `sequence` ends up in the header, so each upload differs.
+pub fn sample_map(sequence: u32) -> Vec<u8> {
+    fn block(block_type: u16, header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut b = vec![];
+        b.extend_from_slice(&block_type.to_le_bytes());
+        b.extend_from_slice(&((8 + header.len()) as u16).to_le_bytes());
+        b.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        b.extend_from_slice(header);
+        b.extend_from_slice(body);
+        b
+    }
+    let ints =
+        |values: &[i32]| -> Vec<u8> { values.iter().flat_map(|v| v.to_le_bytes()).collect() };
+
+    let mut map = vec![];
+    map.extend_from_slice(b"rr");
+    map.extend_from_slice(&0x14u16.to_le_bytes());
+    map.extend_from_slice(&0u32.to_le_bytes());
+    map.extend_from_slice(&1u16.to_le_bytes());
+    map.extend_from_slice(&0u16.to_le_bytes());
+    map.extend_from_slice(&1u32.to_le_bytes());
+    map.extend_from_slice(&sequence.to_le_bytes());
+    // charger
+    map.extend(block(1, &ints(&[25500, 25500]), &[]));
+    // image: walls all round, floor in the middle belonging to segment 1
+    let mut pixels = [1u8; 16];
+    for i in [5, 6, 9, 10] {
+        pixels[i] = 0x0f;
+    }
+    map.extend(block(2, &ints(&[510, 510, 4, 4]), &pixels));
+    // robot position
+    map.extend(block(8, &ints(&[25600, 25600]), &[]));
+    map
+}
+
+/// The robot's status, as it reports it in `event.status` and answers
+/// `get_status` with.
+fn status(battery: u64) -> Value {
+    json!({
+        "state": 8,
+        "battery": battery,
+        "error_code": 0,
+        "clean_time": 0,
+        "clean_area": 0,
+        "fan_power": 102,
+        "in_cleaning": 0,
+        "dnd_enabled": 0,
+        "map_present": 1,
+    })
+}
+
+/// What the robot says back to a command from the cloud: its status for
+/// `get_status`, and `ok` to everything else.
+fn answer(command: &Value, battery: u64) -> Value {
+    let result = match command.get("method").and_then(Value::as_str) {
+        Some("get_status") => json!([status(battery)]),
+        _ => json!(["ok"]),
+    };
+    json!({ "id": command.get("id"), "result": result })
+}
+
+/// Pulls the upload URL out of a `_sync.gen_presigned_url` answer, whether
+/// it's keyed by its empty object name or not.
+fn upload_url(result: &Value) -> Option<&str> {
+    let upload = result.get("").unwrap_or(result);
+    upload.get("url")?.as_str()
+}
+
+struct Robot {
+    socket: UdpSocket,
+    codec: UDPCodec,
+    device_id: u32,
+    /// The cloud's clock, as of `synced`.
+    epoch: u32,
+    synced: Instant,
+    next_id: u64,
+    /// Which of our calls was asking where to upload the map.
+    map_request: Option<u64>,
+    maps: u32,
+}
+
+impl Robot {
+    fn hello(&self) -> [u8; codec::HEADER_SIZE] {
+        let mut header = PacketHeader::new(self.device_id, 0, 0);
+        header.checksum = [0xff; 16];
+        header.to_bytes()
+    }
+
+    fn stamp(&self) -> u32 {
+        self.epoch
+            .wrapping_add(self.synced.elapsed().as_secs() as u32)
+    }
+
+    async fn send(&self, message: &Value) -> Result<()> {
+        let json = serde_json::to_vec(message)?;
+        let packet = self.codec.encode(&json, self.device_id, self.stamp());
+        self.socket.send(&packet).await?;
+        println!("-> {}", message);
+        Ok(())
+    }
+
+    async fn call(&mut self, method: &str, params: Value) -> Result<u64> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "id": id, "method": method, "params": params }))
+            .await?;
+        Ok(id)
+    }
+
+    fn timesync(&mut self, stamp: u32) {
+        self.epoch = stamp;
+        self.synced = Instant::now();
+    }
+
+    async fn upload_map(&mut self, url: &str) {
+        self.maps += 1;
+        let uploaded = reqwest::Client::new()
+            .put(url)
+            .body(sample_map(self.maps))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match uploaded {
+            Ok(_) => println!("uploaded map {} to {}", self.maps, url),
+            Err(e) => println!("could not upload map to {}: {}", url, e),
+        }
+    }
+
+    /// Deals with a packet from the cloud: timesyncs and keep-alives, then
+    /// answers to our calls and commands to answer ourselves.
+    async fn received(&mut self, packet: &[u8], battery: u64) -> Result<()> {
+        let header = PacketHeader::parse(packet)?;
+        if packet.len() == codec::HEADER_SIZE {
+            if header.unknown == codec::UNSET {
+                self.timesync(header.stamp);
+            }
+            return Ok(());
+        }
+        let json = self.codec.decode(packet)?;
+        println!("<- {}", json);
+        let message: Value = serde_json::from_str(&json)?;
+        if message.get("method").is_some() {
+            return self.send(&answer(&message, battery)).await;
+        }
+        let id = message.get("id").and_then(Value::as_u64);
+        if id.is_some() && id == self.map_request {
+            self.map_request = None;
+            let url = message
+                .get("result")
+                .and_then(upload_url)
+                .map(str::to_string);
+            match url {
+                Some(url) => self.upload_map(&url).await,
+                None => println!("no upload URL in the answer"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `dummycloud simulate`: acts like a robot against a running dummycloud,
+/// `127.0.0.1:8053` unless told otherwise. It says hello, reports `props`
+/// and `event.status` every `interval`, uploads a map every few reports and
+/// answers commands, printing everything it sends and gets, until stopped.
+pub async fn simulate_command(
+    server: Option<SocketAddr>,
+    device_id: u32,
+    key: &str,
+    interval: Duration,
+) -> Result<()> {
+    let server = server.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::LOCALHOST, 8053)));
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(server).await?;
+    let mut robot = Robot {
+        socket,
+        codec: UDPCodec::new(key),
+        device_id,
+        epoch: 0,
+        synced: Instant::now(),
+        next_id: 0,
+        map_request: None,
+        maps: 0,
+    };
+
+    let mut buf = [0; 65536];
+    robot.socket.send(&robot.hello()).await?;
+    let received = timeout(HELLO_TIMEOUT, robot.socket.recv(&mut buf))
+        .await
+        .map_err(|_| Error::Invalid(format!("no answer to our hello from {}", server)))??;
+    let header = PacketHeader::parse(&buf[..received])?;
+    robot.timesync(header.stamp);
+    println!("{} says it's {}", server, header.stamp);
+    robot
+        .call("_otc.info", json!({"otu_stat": 0, "mmc": 0, "mode": "sim"}))
+        .await?;
+
+    let mut reports = interval_at(tokio::time::Instant::now(), interval);
+    reports.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut timesyncs = interval_at(
+        tokio::time::Instant::now() + TIMESYNC_INTERVAL,
+        TIMESYNC_INTERVAL,
+    );
+    let mut ticks: u64 = 0;
+    loop {
+        // the battery runs down a little each report, for something to see
+        let battery = 100 - ticks % 80;
+        tokio::select! {
+            _ = reports.tick() => {
+                robot.call("props", json!({"battery": battery})).await?;
+                robot.call("event.status", json!([status(battery)])).await?;
+                if ticks.is_multiple_of(REPORTS_PER_MAP) {
+                    let id = robot.call("_sync.gen_presigned_url", json!({"suffix": ["map"]})).await?;
+                    robot.map_request = Some(id);
+                }
+                ticks += 1;
+            }
+            _ = timesyncs.tick() => { robot.socket.send(&robot.hello()).await?; }
+            received = robot.socket.recv(&mut buf) => {
+                if let Err(e) = robot.received(&buf[..received?], battery).await {
+                    println!("could not make sense of a packet: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map;
+
+    #[test]
+    fn makes_maps_and_answers_like_a_robot() {
+        let parsed = map::parse(&sample_map(7)).unwrap();
+        assert_eq!(parsed.header.map_sequence, 7);
+        assert!(parsed.charger.is_some());
+        let image = parsed.image.unwrap();
+        assert_eq!(image.floor.len(), 4);
+        assert_eq!(image.segments.len(), 1);
+
+        let reply = answer(&json!({"id": 9, "method": "get_status", "params": []}), 80);
+        assert_eq!(reply["id"], 9);
+        assert_eq!(reply["result"][0]["battery"], 80);
+        assert_eq!(
+            answer(&json!({"id": 10, "method": "app_start"}), 80),
+            json!({"id": 10, "result": ["ok"]})
+        );
+
+        let url = json!({"url": "http://x/robomap/1"});
+        assert_eq!(upload_url(&url), Some("http://x/robomap/1"));
+        assert_eq!(upload_url(&json!({ "": url })), Some("http://x/robomap/1"));
+    }
+}
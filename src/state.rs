@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// What we last heard about a robot.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DeviceState {
+    /// Everything reported through `props`, merged so each key holds its
+    /// latest value.
+    pub props: Map<String, Value>,
+    /// The last `event.status` report.
+    pub status: Option<Value>,
+    /// When either of the above last changed.
+    pub updated: u64,
+}
+
+/// Keeps track of the robot's state as it reports it, so that it can be
+/// queried without asking the robot.
+#[derive(Default)]
+pub struct StateStore {
+    states: Mutex<HashMap<u32, DeviceState>>,
+}
+
+impl DeviceState {
+    /// Picks a field from `event.status`, falling back to what `props` said.
+    pub fn field(&self, key: &str) -> Option<&Value> {
+        self.status
+            .as_ref()
+            .and_then(|status| status.get(key))
+            .or_else(|| self.props.get(key))
+    }
+}
+
+// Most calls wrap their single argument in an array.
+pub(crate) fn unwrap_single(params: &Value) -> &Value {
+    match params.as_array() {
+        Some(list) if list.len() == 1 => &list[0],
+        _ => params,
+    }
+}
+
+impl StateStore {
+    /// Updates the state from a message, returning whether it was one we
+    /// keep track of.
+    pub fn record(&self, device_id: u32, method: &str, params: &Value, now: u64) -> bool {
+        let mut states = self.states.lock().unwrap();
+        match method {
+            "props" => {
+                let props = match unwrap_single(params).as_object() {
+                    Some(p) => p,
+                    None => return false,
+                };
+                let state = states.entry(device_id).or_default();
+                for (key, value) in props {
+                    state.props.insert(key.clone(), value.clone());
+                }
+                state.updated = now;
+            }
+            "event.status" => {
+                let state = states.entry(device_id).or_default();
+                state.status = Some(unwrap_single(params).clone());
+                state.updated = now;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn get(&self, device_id: u32) -> Option<DeviceState> {
+        self.states.lock().unwrap().get(&device_id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(u32, DeviceState)> {
+        let states = self.states.lock().unwrap();
+        states.iter().map(|(id, s)| (*id, s.clone())).collect()
+    }
+
+    pub fn restore(&self, device_id: u32, state: DeviceState) {
+        self.states.lock().unwrap().insert(device_id, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_props_and_keeps_the_latest_status() {
+        let store = StateStore::default();
+        assert!(store.record(1, "props", &json!({"ota_state": "idle"}), 10));
+        assert!(store.record(1, "props", &json!([{"battery": 87}]), 11));
+        assert!(store.record(1, "event.status", &json!([{"state": 8}]), 12));
+        assert!(!store.record(1, "event.bin_full", &json!([]), 13));
+        assert!(!store.record(1, "props", &json!("nonsense"), 14));
+
+        let state = store.get(1).unwrap();
+        assert_eq!(
+            Value::Object(state.props),
+            json!({"ota_state": "idle", "battery": 87})
+        );
+        assert_eq!(state.status, Some(json!({"state": 8})));
+        assert_eq!(state.updated, 12);
+        assert!(store.get(2).is_none());
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// What a robot has been up to since we started, for `dummycloud status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DeviceStats {
+    /// Everything it sent us, hellos and keep-alives included.
+    pub packets: u64,
+    /// How often it called each method.
+    pub methods: BTreeMap<String, u64>,
+    pub last_map_upload: Option<u64>,
+}
+
+/// Per robot counterparts to the totals in [`crate::metrics::Metrics`].
+#[derive(Default)]
+pub struct StatsStore {
+    stats: Mutex<HashMap<u32, DeviceStats>>,
+}
+
+impl StatsStore {
+    pub fn packet(&self, device_id: u32) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(device_id)
+            .or_default()
+            .packets += 1;
+    }
+
+    pub fn request(&self, device_id: u32, method: &str) {
+        let mut stats = self.stats.lock().unwrap();
+        let methods = &mut stats.entry(device_id).or_default().methods;
+        match methods.get_mut(method) {
+            Some(count) => *count += 1,
+            None => {
+                methods.insert(method.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn map_uploaded(&self, device_id: u32, now: u64) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(device_id)
+            .or_default()
+            .last_map_upload = Some(now);
+    }
+
+    pub fn get(&self, device_id: u32) -> DeviceStats {
+        self.stats
+            .lock()
+            .unwrap()
+            .get(&device_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn all(&self) -> Vec<(u32, DeviceStats)> {
+        let stats = self.stats.lock().unwrap();
+        stats.iter().map(|(id, s)| (*id, s.clone())).collect()
+    }
+
+    pub fn restore(&self, device_id: u32, stats: DeviceStats) {
+        self.stats.lock().unwrap().insert(device_id, stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_device() {
+        let stats = StatsStore::default();
+        stats.packet(1);
+        stats.packet(1);
+        stats.request(1, "props");
+        stats.request(1, "props");
+        stats.request(1, "event.status");
+        stats.map_uploaded(1, 1000);
+        stats.packet(2);
+
+        let one = stats.get(1);
+        assert_eq!(one.packets, 2);
+        assert_eq!(one.methods["props"], 2);
+        assert_eq!(one.methods["event.status"], 1);
+        assert_eq!(one.last_map_upload, Some(1000));
+        assert_eq!(stats.get(2).methods.len(), 0);
+        assert_eq!(stats.get(3), DeviceStats::default());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Keeps the blobs the robot uploads on disk, laid out as
+/// `<dir>/<device_id>/<kind>/<timestamp>-<name>.bin` where `kind` is e.g.
+/// `map` or `rooms`.
+pub struct MapStore {
+    dir: PathBuf,
+    keep: usize,
+}
+
+fn is_safe_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl MapStore {
+    pub fn new(dir: PathBuf, keep: usize) -> MapStore {
+        MapStore { dir, keep }
+    }
+
+    fn kind_dir(&self, device_id: &str, kind: &str) -> Option<PathBuf> {
+        if !is_safe_segment(device_id) || !is_safe_segment(kind) {
+            return None;
+        }
+        Some(self.dir.join(device_id).join(kind))
+    }
+
+    /// Where uploads for `obj_name` go, and the name they're stored under.
+    fn locate<'a>(&self, obj_name: &'a str) -> io::Result<(PathBuf, &'a str)> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unexpected object name: {}", obj_name),
+            )
+        };
+        let mut segments = obj_name.splitn(3, '/');
+        let (device_id, kind, name) = match (segments.next(), segments.next(), segments.next()) {
+            (Some(d), Some(k), Some(n)) if is_safe_segment(n) => (d, k, n),
+            _ => return Err(invalid()),
+        };
+        let dir = self.kind_dir(device_id, kind).ok_or_else(invalid)?;
+        Ok((dir, name))
+    }
+
+    /// Stores an upload under the obj_name we handed out with the presigned
+    /// URL, returning where it ended up.
+    pub fn save(&self, obj_name: &str, data: &[u8]) -> io::Result<PathBuf> {
+        let (dir, name) = self.locate(obj_name)?;
+        fs::create_dir_all(&dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{}-{}.bin", millis, name));
+        fs::write(&path, data)?;
+        self.prune(&dir)?;
+        Ok(path)
+    }
+
+    // The timestamp prefix makes lexical order match upload order.
+    fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "bin"))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn prune(&self, dir: &Path) -> io::Result<()> {
+        // 0 keeps everything rather than even the upload just saved
+        if self.keep == 0 {
+            return Ok(());
+        }
+        let entries = MapStore::sorted_entries(dir)?;
+        if entries.len() > self.keep {
+            for old in &entries[..entries.len() - self.keep] {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The newest upload stored under `obj_name`, if it's still kept.
+    pub fn find(&self, obj_name: &str) -> io::Result<Option<PathBuf>> {
+        let (dir, name) = self.locate(obj_name)?;
+        let suffix = format!("-{}.bin", name);
+        match MapStore::sorted_entries(&dir) {
+            Ok(entries) => Ok(entries.into_iter().rev().find(|path| {
+                path.file_name()
+                    .and_then(|file| file.to_str())
+                    .is_some_and(|file| file.ends_with(&suffix))
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn latest(&self, device_id: &str, kind: &str) -> io::Result<Option<PathBuf>> {
+        let dir = match self.kind_dir(device_id, kind) {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+        match MapStore::sorted_entries(&dir) {
+            Ok(entries) => Ok(entries.last().cloned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_newest_uploads() {
+        let dir = std::env::temp_dir().join(format!("dummycloud-storage-{}", std::process::id()));
+        let store = MapStore::new(dir.clone(), 2);
+        for i in 0..4 {
+            store.save(&format!("1234/map/{}", i), &[i]).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let kept = MapStore::sorted_entries(&dir.join("1234").join("map")).unwrap();
+        assert_eq!(kept.len(), 2);
+        let latest = store.latest("1234", "map").unwrap().unwrap();
+        assert_eq!(fs::read(latest).unwrap(), vec![3]);
+        let found = store.find("1234/map/2").unwrap().unwrap();
+        assert_eq!(fs::read(found).unwrap(), vec![2]);
+        assert!(store.find("1234/map/0").unwrap().is_none());
+
+        let everything = MapStore::new(dir.clone(), 0);
+        for i in 0..3 {
+            everything.save(&format!("5678/map/{}", i), &[i]).unwrap();
+        }
+        let kept = MapStore::sorted_entries(&dir.join("5678").join("map")).unwrap();
+        assert_eq!(kept.len(), 3);
+
+        assert!(store.save("../etc/passwd", b"").is_err());
+        assert!(store.latest("..", "map").unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Datelike, FixedOffset};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::events::{Event, Origin};
+use crate::state::unwrap_single;
+use crate::Context;
+
+/// `state` codes the robot is cleaning in: cleaning, spot, zone and room
+/// cleaning.
+const CLEANING: [u64; 4] = [5, 11, 17, 18];
+
+/// `state` codes that don't end a run by themselves: paused, errors, and
+/// on the way back to the dock afterwards.
+const INTERRUPTED: [u64; 4] = [6, 10, 12, 15];
+
+// Runs kept in memory for each robot when there's no database to ask,
+// about a year of cleaning every day.
+const KEEP_RUNS: usize = 400;
+
+/// One time the robot went out cleaning.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Run {
+    pub started: u64,
+    /// In seconds.
+    pub duration: u64,
+    /// In square metres.
+    pub area: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Totals {
+    pub count: u64,
+    pub duration: u64,
+    pub area: f64,
+}
+
+impl Totals {
+    fn add(&mut self, run: &Run) {
+        self.count += 1;
+        self.duration += run.duration;
+        self.area += run.area;
+    }
+}
+
+/// The runs in a day or a week, which is e.g. `2024-05-06` or `2024-W19`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Period {
+    pub period: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// Square millimetres, as the robot reports areas in, in square metres.
+fn square_metres(value: Option<&Value>) -> Option<f64> {
+    Some(value?.as_u64()? as f64 / 1_000_000.0)
+}
+
+/// The robot's own totals from a `get_clean_summary` reply, which older
+/// firmwares give as `[time, area, count, [runs]]` and newer ones as an
+/// object.
+pub fn parse_summary(result: &Value) -> Option<Totals> {
+    let (time, area, count) = match result {
+        Value::Array(list) => (list.first(), list.get(1), list.get(2)),
+        Value::Object(object) => (
+            object.get("clean_time"),
+            object.get("clean_area"),
+            object.get("clean_count"),
+        ),
+        _ => return None,
+    };
+    Some(Totals {
+        count: count?.as_u64()?,
+        duration: time?.as_u64()?,
+        area: square_metres(area)?,
+    })
+}
+
+/// The runs by the day and by the week they started in, in `offset`'s time,
+/// oldest first.
+pub fn aggregate(runs: &[Run], offset: FixedOffset) -> (Vec<Period>, Vec<Period>) {
+    let mut days: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut weeks: BTreeMap<String, Totals> = BTreeMap::new();
+    for run in runs {
+        let started = match DateTime::from_timestamp(run.started as i64, 0) {
+            Some(started) => started.with_timezone(&offset).date_naive(),
+            None => continue,
+        };
+        let week = started.iso_week();
+        let day = started.format("%Y-%m-%d").to_string();
+        days.entry(day).or_default().add(run);
+        let week = format!("{}-W{:02}", week.year(), week.week());
+        weeks.entry(week).or_default().add(run);
+    }
+    let periods = |totals: BTreeMap<String, Totals>| {
+        totals
+            .into_iter()
+            .map(|(period, totals)| Period { period, totals })
+            .collect()
+    };
+    (periods(days), periods(weeks))
+}
+
+/// Follows each robot's runs through its `event.status` reports, and keeps
+/// what it says about itself in `get_clean_summary`.
+#[derive(Default)]
+pub struct SummaryStore {
+    running: Mutex<HashMap<u32, Run>>,
+    finished: Mutex<HashMap<u32, VecDeque<Run>>>,
+    lifetime: Mutex<HashMap<u32, Totals>>,
+}
+
+impl SummaryStore {
+    /// Takes in a status report, returning the run it just ended, if any.
+    pub fn status(&self, device_id: u32, status: &Value, now: u64) -> Option<Run> {
+        let state = status.get("state").and_then(Value::as_u64)?;
+        let mut running = self.running.lock().unwrap();
+        if CLEANING.contains(&state) || INTERRUPTED.contains(&state) {
+            let run = match running.get_mut(&device_id) {
+                Some(run) => run,
+                // the robot only starts a run by cleaning
+                None if CLEANING.contains(&state) => running.entry(device_id).or_insert(Run {
+                    started: now,
+                    duration: 0,
+                    area: 0.0,
+                }),
+                None => return None,
+            };
+            match status.get("clean_time").and_then(Value::as_u64) {
+                Some(time) => run.duration = time,
+                None if CLEANING.contains(&state) => run.duration = now.saturating_sub(run.started),
+                None => {}
+            }
+            if let Some(area) = square_metres(status.get("clean_area")) {
+                run.area = area;
+            }
+            return None;
+        }
+        let run = running.remove(&device_id)?;
+        let mut finished = self.finished.lock().unwrap();
+        let runs = finished.entry(device_id).or_default();
+        runs.push_back(run.clone());
+        if runs.len() > KEEP_RUNS {
+            runs.pop_front();
+        }
+        Some(run)
+    }
+
+    /// Runs that started at `from` or later, oldest first.
+    pub fn runs(&self, device_id: u32, from: u64) -> Vec<Run> {
+        let finished = self.finished.lock().unwrap();
+        finished
+            .get(&device_id)
+            .map(|runs| runs.iter().filter(|r| r.started >= from).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_lifetime(&self, device_id: u32, totals: Totals) {
+        self.lifetime.lock().unwrap().insert(device_id, totals);
+    }
+
+    pub fn lifetime(&self, device_id: u32) -> Option<Totals> {
+        self.lifetime.lock().unwrap().get(&device_id).cloned()
+    }
+}
+
+fn finished(context: &Context, device_id: u32, run: Run) {
+    info!(
+        device_id,
+        duration = run.duration,
+        area = run.area,
+        "robot finished cleaning"
+    );
+    if let Some(db) = &context.db {
+        if let Err(e) = db.record_run(device_id, &run) {
+            warn!(error = %e, "could not keep cleaning run for the statistics");
+        }
+    }
+}
+
+/// Picks runs out of the robot's status reports, and its own totals out of
+/// the answers to `get_clean_summary`, whoever asked for them.
+pub(crate) async fn run(context: Arc<Context>) {
+    let mut events = context.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(Event::Message(m)) if m.method == "event.status" => {
+                let status = unwrap_single(&m.params);
+                if let Some(run) = context.summary.status(m.device_id, status, m.timestamp) {
+                    finished(&context, m.device_id, run);
+                }
+            }
+            Ok(Event::Exchange(exchange)) if exchange.origin == Origin::Cloud => {
+                let result = match exchange.response.as_ref().and_then(|r| r.get("result")) {
+                    Some(result) => unwrap_single(result),
+                    None => continue,
+                };
+                let device_id = exchange.device_id;
+                match exchange.method.as_str() {
+                    "get_status" => {
+                        let now = exchange.timestamp;
+                        if let Some(run) = context.summary.status(device_id, result, now) {
+                            finished(&context, device_id, run);
+                        }
+                    }
+                    "get_clean_summary" => {
+                        if let Some(totals) = parse_summary(result) {
+                            context.summary.set_lifetime(device_id, totals);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "cleaning statistics fell behind and skipped events");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn follows_runs_and_adds_them_up() {
+        let store = SummaryStore::default();
+        assert!(store.status(1, &json!({"state": 8}), 0).is_none());
+        assert!(store.status(1, &json!({"state": 5}), 1000).is_none());
+        assert!(store.status(1, &json!({"state": 10}), 1500).is_none());
+        let status = json!({"state": 6, "clean_time": 1200, "clean_area": 25_500_000});
+        assert!(store.status(1, &status, 2200).is_none());
+        let run = store.status(1, &json!({"state": 8}), 2400).unwrap();
+        assert_eq!(
+            run,
+            Run {
+                started: 1000,
+                duration: 1200,
+                area: 25.5
+            }
+        );
+        // a run the robot doesn't give a time for lasts as long as it cleaned
+        store.status(1, &json!({"state": 18}), 90_000);
+        store.status(1, &json!({"state": 18}), 90_600);
+        assert_eq!(
+            store
+                .status(1, &json!({"state": 100}), 91_000)
+                .unwrap()
+                .duration,
+            600
+        );
+        assert_eq!(store.runs(1, 0).len(), 2);
+        assert_eq!(store.runs(1, 2000).len(), 1);
+
+        let mut runs = store.runs(1, 0);
+        runs.push(Run {
+            started: 604_800,
+            duration: 60,
+            area: 1.0,
+        });
+        let (days, weeks) = aggregate(&runs, FixedOffset::east_opt(0).unwrap());
+        let days: Vec<(&str, u64)> = days
+            .iter()
+            .map(|p| (p.period.as_str(), p.totals.count))
+            .collect();
+        assert_eq!(
+            days,
+            [("1970-01-01", 1), ("1970-01-02", 1), ("1970-01-08", 1)]
+        );
+        assert_eq!(weeks[0].period, "1970-W01");
+        assert_eq!(weeks[0].totals.duration, 1800);
+        assert_eq!(weeks[1].period, "1970-W02");
+
+        assert_eq!(
+            parse_summary(&json!([36000, 510_000_000, 12, [1, 2]])),
+            Some(Totals {
+                count: 12,
+                duration: 36000,
+                area: 510.0
+            })
+        );
+        let newer = json!({"clean_time": 60, "clean_area": 1_000_000, "clean_count": 1});
+        assert_eq!(parse_summary(&newer).unwrap().area, 1.0);
+    }
+}
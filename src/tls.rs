@@ -0,0 +1,162 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, TlsConfig};
+use crate::http;
+use crate::shutdown::Stage;
+use crate::Context;
+
+// Long enough for a robot on bad wifi, short enough that half open
+// connections don't pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn invalid(what: &str, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, e))
+}
+
+/// The certificate chain and key to serve, read from `cert` and `key` or,
+/// without them, a self-signed certificate made up on the spot for
+/// `localhost` and the advertised address.
+fn identity(
+    tls: &TlsConfig,
+    config: &Config,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    match (&tls.cert, &tls.key) {
+        (None, None) => {}
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| invalid(&cert.display().to_string(), e))?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| invalid(&key.display().to_string(), e))?;
+            return Ok((chain, key));
+        }
+        _ => return Err(invalid("[tls]", "cert and key go together")),
+    }
+    let mut names = vec!["localhost".to_string()];
+    names.extend(config.advertise.ip.map(|ip| ip.to_string()));
+    let generated =
+        rcgen::generate_simple_self_signed(names).map_err(|e| invalid("self-signed cert", e))?;
+    info!("serving HTTPS with a self-signed certificate");
+    let key = PrivateKeyDer::try_from(generated.signing_key.serialize_der())
+        .map_err(|e| invalid("self-signed cert", e))?;
+    Ok((vec![generated.cert.der().clone()], key))
+}
+
+fn server_config(tls: &TlsConfig, config: &Config) -> io::Result<ServerConfig> {
+    let (chain, key) = identity(tls, config)?;
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| invalid("tls config", e))
+}
+
+/// Hands axum connections that have already made it through the TLS
+/// handshake. Handshakes happen on tasks of their own, so a slow one
+/// doesn't hold up the rest.
+struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    addr: SocketAddr,
+}
+
+impl TlsListener {
+    fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<TlsListener> {
+        let addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // usually out of file descriptors, which passes
+                        warn!(error = %e, "could not accept HTTPS connection");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => debug!(%peer, error = %e, "TLS handshake failed"),
+                        Err(_) => debug!(%peer, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener { connections, addr })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept loop only stops once we're gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// Serves the same routes as the HTTP server over HTTPS, for firmwares that
+/// won't upload their maps over plain HTTP.
+pub(crate) async fn serve(tls: TlsConfig, context: Arc<Context>) -> io::Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(&tls, &context.config)?));
+    let listener = TlsListener::new(TcpListener::bind(tls.bind).await?, acceptor)?;
+    info!(addr = %tls.bind, "HTTPS server is now listening");
+    let closing = Arc::clone(&context);
+    axum::serve(listener, http::router(context))
+        .with_graceful_shutdown(async move { closing.shutdown.reached(Stage::Closing).await })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_given_certs_or_makes_one_up() {
+        let config = Config::default();
+        let generated = TlsConfig::default();
+        assert!(server_config(&generated, &config).is_ok());
+
+        let dir = std::env::temp_dir().join(format!("dummycloud-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let made = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, made.cert.pem()).unwrap();
+        std::fs::write(&key, made.signing_key.serialize_pem()).unwrap();
+        let given = TlsConfig {
+            cert: Some(cert.clone()),
+            key: Some(key),
+            ..TlsConfig::default()
+        };
+        assert!(server_config(&given, &config).is_ok());
+
+        std::fs::write(&cert, "not a certificate").unwrap();
+        assert!(server_config(&given, &config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
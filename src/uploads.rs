@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The room map uploads URLs have been handed out for, so that the PUTs
+/// that follow can be told apart from ones nobody asked for.
+#[derive(Default)]
+pub struct ExpectedUploads {
+    /// When each obj_name's URL stops being good, in seconds since the
+    /// epoch.
+    expected: Mutex<HashMap<String, u64>>,
+}
+
+impl ExpectedUploads {
+    /// Notes down that an upload to `obj_name` may come in until `expires`,
+    /// forgetting the ones that have expired by `now`.
+    pub fn expect(&self, obj_name: String, expires: u64, now: u64) {
+        let mut expected = self.expected.lock().unwrap();
+        expected.retain(|_, expires| *expires >= now);
+        expected.insert(obj_name, expires);
+    }
+
+    /// Whether an upload to `obj_name` is expected, leaving it expected
+    /// until the upload's been stored and [`ExpectedUploads::take`]n.
+    pub fn expects(&self, obj_name: &str, now: u64) -> bool {
+        let expected = self.expected.lock().unwrap();
+        expected
+            .get(obj_name)
+            .is_some_and(|expires| *expires >= now)
+    }
+
+    /// Whether an upload to `obj_name` is expected, which it no longer is
+    /// once it's been taken.
+    pub fn take(&self, obj_name: &str, now: u64) -> bool {
+        let mut expected = self.expected.lock().unwrap();
+        expected
+            .remove(obj_name)
+            .is_some_and(|expires| expires >= now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_each_expected_upload_once() {
+        let uploads = ExpectedUploads::default();
+        uploads.expect("1/rooms/1".to_string(), 100, 0);
+        uploads.expect("1/rooms/2".to_string(), 100, 0);
+        assert!(uploads.expects("1/rooms/1", 50));
+        assert!(uploads.expects("1/rooms/1", 50));
+        assert!(uploads.take("1/rooms/1", 50));
+        assert!(!uploads.expects("1/rooms/1", 50));
+        assert!(!uploads.take("1/rooms/1", 50));
+        assert!(!uploads.take("1/rooms/3", 50));
+        assert!(!uploads.take("1/rooms/2", 101));
+    }
+}
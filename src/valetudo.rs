@@ -0,0 +1,538 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::api::{command_error, error, room_name, ApiError};
+use crate::cleaning::Command;
+use crate::codes;
+use crate::homeassistant;
+use crate::http::latest_parsed_map;
+use crate::map::{MapImage, Point, RRMap, MM_PER_PIXEL};
+use crate::models::Model;
+use crate::settings;
+use crate::state::DeviceState;
+use crate::Context;
+
+/// Roborock maps are this many pixels on a side, with the robot's
+/// coordinates starting at the bottom left.
+const MAP_PIXELS: i32 = 1024;
+/// Valetudo measures in centimetres, with y growing downwards.
+const MM_PER_CM: i32 = 10;
+const PIXEL_AREA_CM2: usize = ((MM_PER_PIXEL / MM_PER_CM) * (MM_PER_PIXEL / MM_PER_CM)) as usize;
+
+/// Our fan speeds by the names Valetudo gives its presets.
+const FAN_PRESETS: [(&str, &str); 6] = [
+    ("gentle", "min"),
+    ("quiet", "low"),
+    ("balanced", "medium"),
+    ("turbo", "high"),
+    ("max", "max"),
+    ("off", "off"),
+];
+
+const CAPABILITIES: [&str; 3] = [
+    "BasicControlCapability",
+    "FanSpeedControlCapability",
+    "LocateCapability",
+];
+
+/// The robot's `state` code as Valetudo's status, and what it's cleaning
+/// if it is.
+fn status(code: u64) -> (&'static str, &'static str) {
+    match code {
+        11 => ("cleaning", "spot"),
+        17 => ("cleaning", "zone"),
+        18 => ("cleaning", "segment"),
+        4 | 7 => ("manual_control", "none"),
+        16 => ("moving", "none"),
+        _ => match homeassistant::vacuum_state(code) {
+            "returning" => ("returning", "none"),
+            "docked" => ("docked", "none"),
+            "paused" => ("paused", "none"),
+            "error" => ("error", "none"),
+            "cleaning" => ("cleaning", "none"),
+            _ => ("idle", "none"),
+        },
+    }
+}
+
+fn preset(fan_speed: &str) -> &'static str {
+    FAN_PRESETS
+        .iter()
+        .find(|(ours, _)| *ours == fan_speed)
+        .map_or("custom", |(_, preset)| *preset)
+}
+
+/// The robot's state as the attributes Valetudo's `/robot/state/attributes`
+/// lists.
+pub fn attributes(model: Model, state: &DeviceState) -> Value {
+    let code = |key| state.field(key).and_then(Value::as_u64);
+    let mut attributes = Vec::new();
+    if let Some(state_code) = code("state") {
+        let (value, flag) = status(state_code);
+        let mut attribute = json!({
+            "__class": "StatusStateAttribute",
+            "metaData": {},
+            "value": value,
+            "flag": flag,
+        });
+        let error_code = code("error_code").filter(|c| *c != 0);
+        if let Some(label) = error_code.and_then(|c| codes::error_label(model, c)) {
+            attribute["metaData"]["error_description"] = json!(label);
+        }
+        attributes.push(attribute);
+    }
+    if let Some(level) = code("battery") {
+        let flag = match code("state") {
+            Some(8) | Some(100) if level >= 100 => "charged",
+            Some(8) => "charging",
+            Some(_) => "discharging",
+            None => "none",
+        };
+        attributes.push(json!({
+            "__class": "BatteryStateAttribute",
+            "metaData": {},
+            "level": level,
+            "flag": flag,
+        }));
+    }
+    if let Some(fan_power) = code("fan_power") {
+        attributes.push(json!({
+            "__class": "PresetSelectionStateAttribute",
+            "metaData": {},
+            "type": "fan_speed",
+            "value": settings::fan_speed_name(model, fan_power).map_or("custom", preset),
+        }));
+    }
+    json!(attributes)
+}
+
+/// A position in the robot's millimetres, as Valetudo's centimetres.
+fn point(p: Point) -> [i32; 2] {
+    [
+        p.x / MM_PER_CM,
+        (MAP_PIXELS * MM_PER_PIXEL - p.y) / MM_PER_CM,
+    ]
+}
+
+/// The pixels a layer covers, run length encoded as Valetudo's
+/// `compressedPixels` are: `x, y, count` for every run along a row.
+fn compress(mut pixels: Vec<(i32, i32)>) -> Vec<i32> {
+    pixels.sort_unstable_by_key(|&(x, y)| (y, x));
+    let mut runs: Vec<i32> = Vec::new();
+    for (x, y) in pixels {
+        match runs.len() {
+            n if n >= 3 && runs[n - 2] == y && runs[n - 3] + runs[n - 1] == x => runs[n - 1] += 1,
+            _ => runs.extend_from_slice(&[x, y, 1]),
+        }
+    }
+    runs
+}
+
+fn dimensions(pixels: &[(i32, i32)]) -> Value {
+    let axis = |values: Vec<i32>| {
+        let min = values.iter().copied().min().unwrap_or(0);
+        let max = values.iter().copied().max().unwrap_or(0);
+        let sum: i64 = values.iter().map(|v| i64::from(*v)).sum();
+        json!({
+            "min": min,
+            "max": max,
+            "mid": (min + max) / 2,
+            "avg": sum.checked_div(values.len() as i64).unwrap_or(0),
+        })
+    };
+    json!({
+        "x": axis(pixels.iter().map(|p| p.0).collect()),
+        "y": axis(pixels.iter().map(|p| p.1).collect()),
+        "pixelCount": pixels.len(),
+    })
+}
+
+fn layer(kind: &str, pixels: Vec<(i32, i32)>, mut meta: Value) -> Value {
+    meta["area"] = json!(pixels.len() * PIXEL_AREA_CM2);
+    json!({
+        "__class": "MapLayer",
+        "type": kind,
+        "pixels": [],
+        "dimensions": dimensions(&pixels),
+        "metaData": meta,
+        "compressedPixels": compress(pixels),
+    })
+}
+
+fn layers(image: &MapImage, name: impl Fn(u8) -> String) -> Vec<Value> {
+    let width = image.width.max(1) as u32;
+    // rows count up from the bottom of the map, Valetudo's down from the top
+    let pixel = |i: u32| {
+        (
+            image.left + (i % width) as i32,
+            MAP_PIXELS - 1 - (image.top + (i / width) as i32),
+        )
+    };
+    let mut floor = Vec::new();
+    let mut segments: BTreeMap<u8, Vec<(i32, i32)>> = BTreeMap::new();
+    for (n, &i) in image.floor.iter().enumerate() {
+        match image.floor_segments.get(n) {
+            Some(&id) if id != 0 => segments.entry(id).or_default().push(pixel(i)),
+            _ => floor.push(pixel(i)),
+        }
+    }
+    let walls = image.obstacle.iter().map(|&i| pixel(i)).collect();
+    let mut layers = vec![
+        layer("floor", floor, json!({})),
+        layer("wall", walls, json!({})),
+    ];
+    layers.extend(segments.into_iter().map(|(id, pixels)| {
+        let meta = json!({
+            "segmentId": id.to_string(),
+            "name": name(id),
+            "active": false,
+        });
+        layer("segment", pixels, meta)
+    }));
+    // the floor is all rooms on maps that have them
+    layers.retain(|layer| layer["dimensions"]["pixelCount"] != 0);
+    layers
+}
+
+fn entity(class: &str, kind: &str, points: Vec<[i32; 2]>, meta: Value) -> Value {
+    json!({
+        "__class": class,
+        "type": kind,
+        "points": points.concat(),
+        "metaData": meta,
+    })
+}
+
+fn corners(rect: [i32; 4]) -> Vec<[i32; 2]> {
+    let [x1, y1, x2, y2] = rect;
+    [(x1, y1), (x2, y1), (x2, y2), (x1, y2)]
+        .iter()
+        .map(|&(x, y)| point(Point { x, y }))
+        .collect()
+}
+
+/// A parsed map as the `ValetudoMap` Valetudo's frontends and the Lovelace
+/// map card draw, with `name` naming the rooms.
+pub fn map_json(map: &RRMap, name: impl Fn(u8) -> String) -> Value {
+    let layers = map
+        .image
+        .as_ref()
+        .map(|image| layers(image, name))
+        .unwrap_or_default();
+    let total_area: u64 = layers
+        .iter()
+        .filter_map(|l| l["metaData"]["area"].as_u64())
+        .sum();
+    let mut entities = Vec::new();
+    if let Some(charger) = map.charger {
+        entities.push(entity(
+            "PointMapEntity",
+            "charger_location",
+            vec![point(charger)],
+            json!({}),
+        ));
+    }
+    if let Some(robot) = &map.robot {
+        // the robot's angle goes anticlockwise from the x axis, Valetudo's
+        // clockwise from the top
+        let angle = robot.angle.map(|a| (90 - a).rem_euclid(360));
+        entities.push(entity(
+            "PointMapEntity",
+            "robot_position",
+            vec![point(robot.position)],
+            json!({ "angle": angle }),
+        ));
+    }
+    if let Some(target) = map.goto_target {
+        entities.push(entity(
+            "PointMapEntity",
+            "go_to_target",
+            vec![point(target)],
+            json!({}),
+        ));
+    }
+    let paths = [
+        (&map.path, "path"),
+        (&map.goto_predicted_path, "predicted_path"),
+    ];
+    for (path, kind) in paths.iter() {
+        if let Some(path) = path {
+            let points = path.points.iter().map(|p| point(*p)).collect();
+            entities.push(entity("PathMapEntity", kind, points, json!({})));
+        }
+    }
+    for wall in &map.virtual_walls {
+        let [x1, y1, x2, y2] = *wall;
+        let points = vec![point(Point { x: x1, y: y1 }), point(Point { x: x2, y: y2 })];
+        entities.push(entity("LineMapEntity", "virtual_wall", points, json!({})));
+    }
+    let areas = [
+        (&map.forbidden_zones, "no_go_area"),
+        (&map.forbidden_mop_zones, "no_mop_area"),
+    ];
+    for (zones, kind) in areas.iter() {
+        for zone in zones.iter() {
+            let points = zone.iter().map(|p| point(*p)).collect();
+            entities.push(entity("PolygonMapEntity", kind, points, json!({})));
+        }
+    }
+    for zone in &map.currently_cleaned_zones {
+        entities.push(entity(
+            "PolygonMapEntity",
+            "active_zone",
+            corners(*zone),
+            json!({}),
+        ));
+    }
+    let size = MAP_PIXELS * MM_PER_PIXEL / MM_PER_CM;
+    json!({
+        "__class": "ValetudoMap",
+        "metaData": { "version": 2, "totalLayerArea": total_area },
+        "size": { "x": size, "y": size },
+        "pixelSize": MM_PER_PIXEL / MM_PER_CM,
+        "layers": layers,
+        "entities": entities,
+    })
+}
+
+/// The robot the API is about: the one under `[valetudo]`, or else the
+/// only one that has checked in.
+fn robot(context: &Context) -> Result<u32, ApiError> {
+    if let Some(device_id) = context.config.valetudo.as_ref().and_then(|v| v.device_id) {
+        return Ok(device_id);
+    }
+    match context.devices.all().as_slice() {
+        [device] => Ok(device.id),
+        [] => Err(error(StatusCode::NOT_FOUND, "no robot has checked in")),
+        _ => Err(error(
+            StatusCode::CONFLICT,
+            "more than one robot has checked in, set device_id under [valetudo]",
+        )),
+    }
+}
+
+async fn robot_info(State(context): State<Arc<Context>>) -> Result<Json<Value>, ApiError> {
+    let device_id = robot(&context)?;
+    Ok(Json(json!({
+        "manufacturer": "Roborock",
+        "modelName": format!("{:?}", context.config.model_for(device_id)),
+        "implementation": "dummycloud",
+    })))
+}
+
+fn robot_attributes(context: &Context, device_id: u32) -> Value {
+    let state = context.state.get(device_id).unwrap_or_default();
+    attributes(context.config.model_for(device_id), &state)
+}
+
+async fn robot_map(context: &Arc<Context>, device_id: u32) -> Result<Value, StatusCode> {
+    let map = latest_parsed_map(Arc::clone(context), device_id.to_string()).await?;
+    Ok(map_json(&map, |segment| {
+        room_name(context, device_id, segment)
+    }))
+}
+
+async fn state(State(context): State<Arc<Context>>) -> Result<Json<Value>, ApiError> {
+    let device_id = robot(&context)?;
+    // without a map yet, an empty one
+    let map = match robot_map(&context, device_id).await {
+        Ok(map) => map,
+        Err(_) => map_json(&RRMap::default(), |_| String::new()),
+    };
+    Ok(Json(json!({
+        "attributes": robot_attributes(&context, device_id),
+        "map": map,
+    })))
+}
+
+async fn state_attributes(State(context): State<Arc<Context>>) -> Result<Json<Value>, ApiError> {
+    let device_id = robot(&context)?;
+    Ok(Json(robot_attributes(&context, device_id)))
+}
+
+async fn state_map(State(context): State<Arc<Context>>) -> Result<Json<Value>, ApiError> {
+    let device_id = robot(&context)?;
+    robot_map(&context, device_id)
+        .await
+        .map(Json)
+        .map_err(|status| error(status, "no usable map from this robot"))
+}
+
+async fn capabilities() -> Json<Value> {
+    Json(json!(CAPABILITIES))
+}
+
+#[derive(Deserialize)]
+struct ActionRequest {
+    action: String,
+}
+
+/// Valetudo answers commands with a bare OK, whatever the robot said.
+async fn ok(context: &Context, device_id: u32, command: Command) -> Result<&'static str, ApiError> {
+    context
+        .send_command(device_id, command.method, &command.params)
+        .await
+        .map_err(command_error)?;
+    Ok("OK")
+}
+
+async fn basic_control(
+    State(context): State<Arc<Context>>,
+    Json(request): Json<ActionRequest>,
+) -> Result<&'static str, ApiError> {
+    let device_id = robot(&context)?;
+    let method = match request.action.as_str() {
+        "start" => "app_start",
+        "stop" => "app_stop",
+        "pause" => "app_pause",
+        "home" => "app_charge",
+        _ => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "action is start, stop, pause or home",
+            ))
+        }
+    };
+    let command = Command {
+        method,
+        params: json!([]),
+    };
+    ok(&context, device_id, command).await
+}
+
+async fn locate(
+    State(context): State<Arc<Context>>,
+    Json(request): Json<ActionRequest>,
+) -> Result<&'static str, ApiError> {
+    let device_id = robot(&context)?;
+    if request.action != "locate" {
+        return Err(error(StatusCode::BAD_REQUEST, "action is locate"));
+    }
+    let command = Command {
+        method: "find_me",
+        params: json!([]),
+    };
+    ok(&context, device_id, command).await
+}
+
+async fn fan_presets(State(context): State<Arc<Context>>) -> Result<Json<Value>, ApiError> {
+    let device_id = robot(&context)?;
+    let model = context.config.model_for(device_id);
+    let presets: Vec<&str> = settings::fan_speed_names(model)
+        .into_iter()
+        .map(preset)
+        .collect();
+    Ok(Json(json!(presets)))
+}
+
+#[derive(Deserialize)]
+struct PresetRequest {
+    name: String,
+}
+
+async fn set_fan_preset(
+    State(context): State<Arc<Context>>,
+    Json(request): Json<PresetRequest>,
+) -> Result<&'static str, ApiError> {
+    let device_id = robot(&context)?;
+    let model = context.config.model_for(device_id);
+    let fan_speed = FAN_PRESETS
+        .iter()
+        .find(|(_, preset)| *preset == request.name)
+        .map_or(request.name.as_str(), |(ours, _)| *ours);
+    let command = settings::fan_speed(model, fan_speed)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    ok(&context, device_id, command).await
+}
+
+async fn version() -> Json<Value> {
+    Json(json!({
+        "release": env!("CARGO_PKG_VERSION"),
+        "commit": "dummycloud",
+    }))
+}
+
+/// The part of Valetudo's REST API its companion apps and the Lovelace map
+/// card use, for the one robot.
+pub fn router() -> Router<Arc<Context>> {
+    Router::new()
+        .route("/api/v2/robot", get(robot_info))
+        .route("/api/v2/robot/state", get(state))
+        .route("/api/v2/robot/state/attributes", get(state_attributes))
+        .route("/api/v2/robot/state/map", get(state_map))
+        .route("/api/v2/robot/capabilities", get(capabilities))
+        .route(
+            "/api/v2/robot/capabilities/BasicControlCapability",
+            put(basic_control),
+        )
+        .route("/api/v2/robot/capabilities/LocateCapability", put(locate))
+        .route(
+            "/api/v2/robot/capabilities/FanSpeedControlCapability/presets",
+            get(fan_presets),
+        )
+        .route(
+            "/api/v2/robot/capabilities/FanSpeedControlCapability/preset",
+            put(set_fan_preset),
+        )
+        .route("/api/v2/valetudo/version", get(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{self, RobotPosition};
+    use crate::simulate::sample_map;
+
+    #[test]
+    fn speaks_valetudo() {
+        let state = DeviceState {
+            status: Some(json!({"state": 18, "battery": 76, "fan_power": 104, "error_code": 0})),
+            ..DeviceState::default()
+        };
+        assert_eq!(
+            attributes(Model::S5, &state),
+            json!([
+                {"__class": "StatusStateAttribute", "metaData": {}, "value": "cleaning", "flag": "segment"},
+                {"__class": "BatteryStateAttribute", "metaData": {}, "level": 76, "flag": "discharging"},
+                {"__class": "PresetSelectionStateAttribute", "metaData": {}, "type": "fan_speed", "value": "max"},
+            ])
+        );
+        assert_eq!(attributes(Model::S5, &DeviceState::default()), json!([]));
+
+        assert_eq!(
+            compress(vec![(3, 1), (1, 1), (2, 1), (5, 1), (2, 0)]),
+            vec![2, 0, 1, 1, 1, 3, 5, 1, 1]
+        );
+
+        let mut parsed = map::parse(&sample_map(1)).unwrap();
+        parsed.robot = Some(RobotPosition {
+            position: Point { x: 25600, y: 25600 },
+            angle: Some(0),
+        });
+        let map = map_json(&parsed, |id| format!("Room {}", id));
+        assert_eq!(map["__class"], "ValetudoMap");
+        let layers = map["layers"].as_array().unwrap();
+        let kinds: Vec<&str> = layers.iter().map(|l| l["type"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["wall", "segment"]);
+        assert_eq!(layers[0]["dimensions"]["pixelCount"], 12);
+        // the 2x2 room in the middle, flipped the right way up
+        assert_eq!(
+            layers[1]["compressedPixels"],
+            json!([511, 511, 2, 511, 512, 2])
+        );
+        assert_eq!(layers[1]["metaData"]["name"], "Room 1");
+        assert_eq!(layers[1]["metaData"]["area"], 100);
+        let robot = &map["entities"][1];
+        assert_eq!(robot["type"], "robot_position");
+        assert_eq!(robot["points"], json!([2560, 2560]));
+        assert_eq!(robot["metaData"]["angle"], 90);
+        assert_eq!(map["entities"][0]["points"], json!([2550, 2570]));
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use serde_json::json;
+
+use crate::cleaning::Command;
+
+/// What the robot's firmware calls voice packs that aren't one of its own.
+pub const CUSTOM_SID: u32 = 10000;
+
+pub fn md5_hex(data: &[u8]) -> String {
+    let mut md5 = Md5::new();
+    md5.input(data);
+    md5.result_str()
+}
+
+/// Whether `name` is one of the packs [`store`] names, which is all that's
+/// served out of the directory.
+pub fn is_pack_name(name: &str) -> bool {
+    match name.strip_suffix(".pkg") {
+        Some(md5) => md5.len() == 32 && md5.bytes().all(|b| b.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+/// Keeps a voice pack in `dir`, named after its md5 so pushing the same
+/// one twice doesn't store it twice. Returns its name and md5.
+pub fn store(dir: &Path, pack: &[u8]) -> io::Result<(String, String)> {
+    let md5 = md5_hex(pack);
+    let name = format!("{}.pkg", md5);
+    fs::create_dir_all(dir)?;
+    let path = dir.join(&name);
+    if !path.exists() {
+        fs::write(&path, pack)?;
+    }
+    Ok((name, md5))
+}
+
+/// Where a stored pack is, if it is.
+pub fn find(dir: &Path, name: &str) -> Option<PathBuf> {
+    if !is_pack_name(name) {
+        return None;
+    }
+    Some(dir.join(name)).filter(|path| path.is_file())
+}
+
+/// `dnld_install_sound`, which has the robot download the pack at `url`,
+/// check it against `md5` and switch to it.
+pub fn install(url: &str, md5: &str, sid: u32) -> Command {
+    Command {
+        method: "dnld_install_sound",
+        params: json!({ "url": url, "md5": md5, "sid": sid }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_packs_by_their_md5() {
+        let dir = std::env::temp_dir().join(format!("dummycloud-voice-{}", std::process::id()));
+        let (name, md5) = store(&dir, b"not really a voice pack").unwrap();
+        assert_eq!(md5, "3d205d41369e79c2ebbbec631dab2cc6");
+        assert_eq!(name, "3d205d41369e79c2ebbbec631dab2cc6.pkg");
+        assert_eq!(find(&dir, &name), Some(dir.join(&name)));
+        assert_eq!(find(&dir, "../voice.pkg"), None);
+        assert_eq!(find(&dir, &format!("{}.pkg", "0".repeat(32))), None);
+        assert_eq!(
+            install("http://host/voice/x.pkg", &md5, CUSTOM_SID).params,
+            json!({"url": "http://host/voice/x.pkg", "md5": md5, "sid": 10000})
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
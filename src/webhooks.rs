@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::config::WebhookConfig;
+use crate::events::Event;
+use crate::rules::Rules;
+use crate::Context;
+
+// However many retries are configured, don't leave a delivery waiting longer
+// than this between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn backoff(config: &WebhookConfig, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt);
+    Duration::from_millis(config.backoff_ms)
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF)
+}
+
+async fn post(client: &reqwest::Client, url: &str, body: &[u8]) -> Result<(), reqwest::Error> {
+    client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_vec())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// POSTs `body` to `url`, retrying with exponential backoff when the hook
+/// can't be reached or has a server error. Client errors aren't going to fix
+/// themselves, so those give up straight away.
+pub async fn deliver(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    url: &str,
+    body: &[u8],
+) -> Result<(), reqwest::Error> {
+    let mut attempt = 0;
+    loop {
+        let err = match post(client, url, body).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let permanent = err.status().is_some_and(|s| s.is_client_error());
+        if permanent || attempt >= config.retries {
+            return Err(err);
+        }
+        let delay = backoff(config, attempt);
+        debug!(%url, error = %err, ?delay, "webhook failed, retrying");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Forwards every decoded message from the robot to the configured webhooks,
+/// along with an alert whenever its parts wear out.
+pub async fn run(config: WebhookConfig, context: Arc<Context>) {
+    let client = reqwest::Client::new();
+    let config = Arc::new(config);
+    let mut rules = Rules::new(&context.config.rules);
+    let mut events = context.events.subscribe();
+    loop {
+        let body = match events.recv().await {
+            Ok(Event::Message(m)) => match rules.apply(m) {
+                Some(m) => serde_json::to_vec(&m),
+                None => continue,
+            },
+            Ok(Event::Consumables(report)) if !report.worn.is_empty() => {
+                serde_json::to_vec(&json!({
+                    "device_id": report.device_id,
+                    "alert": "consumables",
+                    "parts": report.worn,
+                    "timestamp": report.timestamp,
+                }))
+            }
+            Ok(Event::Exchange(_))
+            | Ok(Event::Connection(_))
+            | Ok(Event::Consumables(_))
+            | Ok(Event::Binary(_)) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "webhooks fell behind and skipped events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let body: Arc<[u8]> = match body {
+            Ok(b) => b.into(),
+            Err(_) => continue,
+        };
+        for i in 0..config.urls.len() {
+            // one slow hook shouldn't hold up the others
+            let client = client.clone();
+            let config = Arc::clone(&config);
+            let body = Arc::clone(&body);
+            tokio::spawn(async move {
+                let url = &config.urls[i];
+                if let Err(e) = deliver(&client, &config, url, &body).await {
+                    warn!(%url, error = %e, "giving up on webhook");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_a_limit() {
+        let config = WebhookConfig::default();
+        assert_eq!(backoff(&config, 0), Duration::from_millis(500));
+        assert_eq!(backoff(&config, 1), Duration::from_millis(1000));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(4000));
+        assert_eq!(backoff(&config, 40), MAX_BACKOFF);
+    }
+}
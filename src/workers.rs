@@ -0,0 +1,116 @@
+use std::future::Future;
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinSet;
+
+/// Packets that can wait for each worker before more are turned away.
+const QUEUE: usize = 1024;
+
+/// A fixed set of tasks that work through what they're handed in turn.
+/// Everything with the same key goes to the same worker, so a robot's
+/// packets are handled one at a time in the order they came in, while
+/// other robots' are handled alongside on the other workers.
+pub(crate) struct Workers<T> {
+    queues: Vec<mpsc::Sender<T>>,
+}
+
+impl<T: Send + 'static> Workers<T> {
+    /// Starts `count` workers on `tasks`, each handing its jobs to `work`.
+    /// They finish what's queued and stop once the `Workers` is dropped.
+    pub fn start<F, Fut>(count: usize, work: F, tasks: &mut JoinSet<()>) -> Workers<T>
+    where
+        F: Fn(T) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let queues = (0..count.max(1))
+            .map(|_| {
+                let (queue, mut jobs) = mpsc::channel(QUEUE);
+                let work = work.clone();
+                tasks.spawn(async move {
+                    while let Some(job) = jobs.recv().await {
+                        work(job).await;
+                    }
+                });
+                queue
+            })
+            .collect();
+        Workers { queues }
+    }
+
+    /// Which worker everything with `key` goes to.
+    pub fn shard(&self, key: u32) -> usize {
+        key as usize % self.queues.len()
+    }
+
+    /// Queues a job for the worker `key` belongs to, handing it back if
+    /// that worker has too much waiting already.
+    pub fn dispatch(&self, key: u32, job: T) -> Result<(), T> {
+        self.queues[self.shard(key)]
+            .try_send(job)
+            .map_err(|e| match e {
+                TrySendError::Full(job) | TrySendError::Closed(job) => job,
+            })
+    }
+}
+
+/// One worker per CPU unless the config says otherwise.
+pub(crate) fn count(configured: usize) -> usize {
+    match configured {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn keeps_each_keys_jobs_in_order() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        let done = Arc::new(Mutex::new(Vec::new()));
+        runtime.block_on(async {
+            let mut tasks = JoinSet::new();
+            let recorded = Arc::clone(&done);
+            let workers = Workers::start(
+                2,
+                move |(key, seq): (u32, u32)| {
+                    let recorded = Arc::clone(&recorded);
+                    async move {
+                        // the first of each key's jobs takes longest
+                        if seq == 0 {
+                            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        }
+                        recorded.lock().unwrap().push((key, seq));
+                    }
+                },
+                &mut tasks,
+            );
+            assert_eq!(workers.shard(7), workers.shard(9));
+            assert_ne!(workers.shard(7), workers.shard(8));
+            for seq in 0..3 {
+                for key in [7, 8].iter().copied() {
+                    workers.dispatch(key, (key, seq)).unwrap();
+                }
+            }
+            drop(workers);
+            while tasks.join_next().await.is_some() {}
+        });
+        let done = done.lock().unwrap();
+        for key in [7, 8].iter().copied() {
+            let seqs: Vec<u32> = done
+                .iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, s)| *s)
+                .collect();
+            assert_eq!(seqs, vec![0, 1, 2]);
+        }
+        assert_eq!(count(3), 3);
+        assert!(count(0) >= 1);
+    }
+}
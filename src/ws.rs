@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::events::Event;
+use crate::Context;
+
+async fn stream_events(mut socket: WebSocket, context: Arc<Context>) {
+    let mut events = context.events.subscribe();
+    debug!("websocket client attached");
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let text = match event {
+                    Ok(Event::Exchange(e)) => serde_json::to_string(&e),
+                    Ok(Event::Binary(frame)) => serde_json::to_string(&frame),
+                    Ok(Event::Message(_)) | Ok(Event::Connection(_)) | Ok(Event::Consumables(_)) => {
+                        continue
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "websocket client fell behind and skipped events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let text = match text {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            // we don't expect anything from the client, this is only here to
+            // notice it going away
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("websocket client went away");
+}
+
+async fn events(State(context): State<Arc<Context>>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| stream_events(socket, context))
+}
+
+/// Streams every call and its answer as JSON, for UIs that want to watch the
+/// robot live, along with the binary frames nothing could make sense of.
+pub fn router() -> Router<Arc<Context>> {
+    Router::new().route("/ws/events", get(events))
+}
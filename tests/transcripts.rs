@@ -0,0 +1,181 @@
+//! Plays the transcripts in `tests/fixtures/transcripts` to a server on an
+//! ephemeral port and checks every reply byte for byte. They're NDJSON
+//! captures, as `--capture` writes them: the robot's packets are signed with
+//! `KEY` and the server's replies follow each one. After changing what the
+//! server answers, `DUMMYCLOUD_BLESS=1 cargo test --test transcripts`
+//! writes the new replies in.
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use dummycloud::capture::{Direction, Record};
+use dummycloud::clock::Clock;
+use dummycloud::codec::{self, from_hex, to_hex, UDPCodec};
+use dummycloud::config::Config;
+use dummycloud::{replay, HandlerRegistry, Server};
+
+const KEY: &str = "dummycloudtests1";
+/// What the server's clock says throughout, so the stamps in its replies
+/// come out the same every time.
+const NOW: u64 = 1_700_000_000;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// How long to listen for replies that shouldn't be there.
+const QUIET: Duration = Duration::from_millis(300);
+
+struct StoppedClock;
+
+impl Clock for StoppedClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(NOW)
+    }
+}
+
+fn config(storage: &Path) -> Config {
+    let mut config = Config::parse(&format!(
+        r#"
+        cloud_key = "{}"
+
+        [listener]
+        http_bind = "127.0.0.1:0"
+        control_bind = "127.0.0.1:0"
+
+        [advertise]
+        ip = "127.0.0.1"
+        "#,
+        KEY
+    ))
+    .unwrap();
+    config.storage.map_dir = storage.join("maps");
+    config.storage.log_dir = storage.join("logs");
+    config.storage.voice_dir = storage.join("voices");
+    config
+}
+
+/// A server answering on a port of its own.
+async fn start(storage: &Path) -> (Server, SocketAddr) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let config = config(storage);
+    let clock: Arc<dyn Clock> = Arc::new(StoppedClock);
+    let handlers = HandlerRegistry::with_defaults(&config, Arc::clone(&clock));
+    let server = Server::with_handlers(config, vec![socket], handlers, clock).unwrap();
+    let running = server.clone();
+    tokio::spawn(async move { running.run().await });
+    (server, addr)
+}
+
+fn shown(record: &Record) -> String {
+    let direction = match record.direction {
+        Direction::In => "->",
+        Direction::Out => "<-",
+    };
+    let json = record.json.as_deref().unwrap_or("(no payload)");
+    format!("{} {}\n   {}", direction, json, record.packet)
+}
+
+fn reply(peer: SocketAddr, packet: &[u8]) -> Record {
+    let json = if packet.len() > codec::HEADER_SIZE {
+        Some(UDPCodec::new(KEY).decode(packet).unwrap())
+    } else {
+        None
+    };
+    Record {
+        time: 0.0,
+        direction: Direction::Out,
+        peer,
+        packet: to_hex(packet),
+        json,
+    }
+}
+
+/// Sends the robot's half of the transcript, returning it along with what
+/// the server said back to each packet.
+async fn play(records: &[Record], server: SocketAddr, bless: bool) -> Vec<Record> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    socket.connect(server).await.unwrap();
+    let mut buf = [0; 65536];
+    let mut played = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        if record.direction == Direction::Out {
+            continue;
+        }
+        socket
+            .send(&from_hex(&record.packet).unwrap())
+            .await
+            .unwrap();
+        played.push(Record {
+            time: record.time,
+            direction: Direction::In,
+            peer: record.peer,
+            packet: record.packet.clone(),
+            json: record.json.clone(),
+        });
+        // when blessing there's no telling how many replies are coming
+        let expected = records[i + 1..]
+            .iter()
+            .take_while(|r| r.direction == Direction::Out)
+            .count();
+        let mut replies = 0;
+        loop {
+            let wait = if bless || replies >= expected {
+                QUIET
+            } else {
+                REPLY_TIMEOUT
+            };
+            match timeout(wait, socket.recv(&mut buf)).await {
+                Ok(received) => {
+                    played.push(reply(record.peer, &buf[..received.unwrap()]));
+                    replies += 1;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    played
+}
+
+async fn check(name: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/transcripts")
+        .join(format!("{}.ndjson", name));
+    let records = replay::read(&path).unwrap();
+    let storage = std::env::temp_dir().join(format!("dummycloud-{}-{}", name, std::process::id()));
+    let (server, addr) = start(&storage).await;
+    let bless = std::env::var_os("DUMMYCLOUD_BLESS").is_some();
+    let played = play(&records, addr, bless).await;
+    server.shutdown();
+    let _ = fs::remove_dir_all(&storage);
+
+    if bless {
+        let lines: Vec<String> = played
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap())
+            .collect();
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        return;
+    }
+    let played: Vec<String> = played.iter().map(shown).collect();
+    let recorded: Vec<String> = records.iter().map(shown).collect();
+    assert_eq!(played, recorded, "{} went differently", name);
+}
+
+#[tokio::test]
+async fn handshake() {
+    check("handshake").await;
+}
+
+#[tokio::test]
+async fn messages() {
+    check("messages").await;
+}
+
+#[tokio::test]
+async fn wrong_key() {
+    check("wrong_key").await;
+}